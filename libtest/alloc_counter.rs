@@ -0,0 +1,68 @@
+//! A global allocator that counts the allocations of each thread, for
+//! `--track-allocations`.
+//!
+//! A test binary opts in by registering it:
+//!
+//! ```ignore
+//! #[global_allocator]
+//! static ALLOC: libtest::CountingAllocator = libtest::CountingAllocator;
+//! ```
+//!
+//! `--track-allocations` then reads the counters with
+//! `CountingAllocator::thread_stats` unless `Options::allocation_counter`
+//! names another counter.
+
+use super::AllocationStats;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+thread_local! {
+    static COUNTS: Cell<AllocationStats> = Cell::new(AllocationStats::default());
+}
+
+/// Passes every request on to the system allocator, counting the
+/// allocations and reallocations of each thread.
+pub struct CountingAllocator;
+
+impl CountingAllocator {
+    /// The allocations the calling thread has made so far.
+    pub fn thread_stats() -> AllocationStats {
+        COUNTS.with(Cell::get)
+    }
+}
+
+fn count(size: usize) {
+    // The counters are gone once the thread's locals have been destroyed.
+    let _ = COUNTS.try_with(|counts| {
+        let mut stats = counts.get();
+        stats.allocations += 1;
+        stats.bytes += size as u64;
+        counts.set(stats);
+    });
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(
+        &self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> *mut u8 {
+        count(new_size);
+        System.realloc(ptr, layout, new_size)
+    }
+}
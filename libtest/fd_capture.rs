@@ -0,0 +1,134 @@
+//! Capturing of output written directly to the standard output and standard
+//! error file descriptors.
+//!
+//! `io::set_print` and `io::set_panic` only intercept Rust's `print!` family
+//! of macros. Anything writing to fd 1 or 2 directly (C libraries, child
+//! processes, `libc::write`) bypasses them. An `FdCapture` redirects both
+//! descriptors into a pipe each for its lifetime and drains the pipes into
+//! the test's capture buffers.
+//!
+//! File descriptors are process-wide, so this is only meaningful when a
+//! single test runs at a time.
+
+use std::fs::File;
+use std::io::{self, prelude::*};
+use std::os::unix::io::FromRawFd;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const REDIRECTED: [libc::c_int; 2] =
+    [libc::STDOUT_FILENO, libc::STDERR_FILENO];
+
+/// How long to wait for the pipes to be drained once the descriptors are
+/// restored. A process or thread the test left behind may still hold the
+/// write end of a pipe, and the reader is then left to finish on its own.
+const DRAIN_GRACE: Duration = Duration::from_millis(100);
+
+/// Where the output of one redirected descriptor goes.
+pub(crate) type Destination = Box<dyn Write + Send>;
+
+pub(crate) struct FdCapture {
+    saved: Vec<libc::c_int>,
+    /// The number of readers draining the pipes.
+    readers: usize,
+    /// Where each reader says it's done.
+    drained: Receiver<()>,
+}
+
+impl FdCapture {
+    /// Redirects stdout and stderr into `stdout` and `stderr` until the
+    /// returned value is dropped.
+    pub(crate) fn start(
+        stdout: Destination,
+        stderr: Destination,
+    ) -> io::Result<Self> {
+        // Anything still buffered belongs to whoever wrote it before the test.
+        let _ = io::stdout().flush();
+
+        let (tx, drained) = channel();
+        let mut capture = Self {
+            saved: Vec::new(),
+            readers: 0,
+            drained,
+        };
+        for (&fd, dest) in REDIRECTED.iter().zip(vec![stdout, stderr]) {
+            // On error, dropping `capture` restores what was redirected so
+            // far.
+            let pipe = capture.redirect(fd)?;
+            let tx = tx.clone();
+            thread::spawn(move || drain(pipe, dest, tx));
+            capture.readers += 1;
+        }
+        Ok(capture)
+    }
+
+    /// Points `fd` at the write end of a new pipe and returns the read end.
+    fn redirect(&mut self, fd: libc::c_int) -> io::Result<File> {
+        let mut fds = [0; 2];
+        cvt(unsafe { libc::pipe(fds.as_mut_ptr()) })?;
+        let (read_fd, write_fd) = (fds[0], fds[1]);
+        let pipe = unsafe { File::from_raw_fd(read_fd) };
+
+        let saved = unsafe { libc::dup(fd) };
+        let redirected =
+            cvt(saved).and_then(|_| cvt(unsafe { libc::dup2(write_fd, fd) }));
+        // Only `fd` keeps the write end open now, so the reader sees EOF as
+        // soon as it is restored.
+        unsafe { libc::close(write_fd) };
+        if let Err(e) = redirected {
+            if saved >= 0 {
+                unsafe { libc::close(saved) };
+            }
+            return Err(e);
+        }
+        self.saved.push(saved);
+        Ok(pipe)
+    }
+}
+
+impl Drop for FdCapture {
+    fn drop(&mut self) {
+        let _ = io::stdout().flush();
+        for (&fd, &saved) in REDIRECTED.iter().zip(&self.saved) {
+            unsafe {
+                libc::dup2(saved, fd);
+                libc::close(saved);
+            }
+        }
+        let deadline = Instant::now() + DRAIN_GRACE;
+        for _ in 0..self.readers {
+            let now = Instant::now();
+            if now >= deadline
+                || self.drained.recv_timeout(deadline - now).is_err()
+            {
+                break;
+            }
+        }
+    }
+}
+
+fn drain(mut pipe: File, mut dest: Destination, drained: Sender<()>) {
+    let mut chunk = [0; 4096];
+    loop {
+        match pipe.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => {
+                if dest.write_all(&chunk[..n]).is_err() {
+                    break;
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) => break,
+        }
+    }
+    let _ = drained.send(());
+}
+
+fn cvt(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
@@ -0,0 +1,188 @@
+//! The `binary` output format, a stream of length-prefixed frames for tools
+//! that run the harness as a subprocess and would rather not parse JSON.
+//!
+//! Integers are little-endian. Each frame is a `u32` payload length followed
+//! by the payload, whose first byte is the frame kind. Strings and byte
+//! buffers are written as a `u32` length followed by the bytes; strings are
+//! UTF-8.
+//!
+//! | kind | frame          | fields after the kind                          |
+//! |------|----------------|------------------------------------------------|
+//! | 0    | run started    | `u32` format version, `u64` test count         |
+//! | 1    | test started   | name                                           |
+//! | 2    | test finished  | name, `u8` outcome, outcome fields             |
+//! | 3    | test slow      | name                                           |
+//! | 4    | test skipped   | name, skip reason                              |
+//! | 5    | run finished   | `u8` success, `u64` passed, failed, allowed to |
+//! |      |                | fail, ignored, measured, filtered out and      |
+//! |      |                | flaky                                          |
+//!
+//! The outcomes of a finished test are:
+//!
+//! * 0, ok;
+//! * 1, failed, followed by a `u8` failure kind (0 panic, 1 error returned,
+//!   2 message, 3 `should_panic` mismatch, 4 timed out), a message, empty
+//!   for the first two kinds, and the captured output as a byte buffer;
+//! * 2, ignored;
+//! * 3, allowed to fail;
+//! * 4, benchmark, followed by `u64` median and deviation in nanoseconds per
+//!   iteration and `u64` MB/s, 0 if not measured;
+//! * 5, ok after being retried, followed by the `u32` number of retries;
+//! * 6, ignored at run time by `skip`, followed by the reason.
+//!
+//! The version in the first frame is `VERSION`. It is bumped whenever a frame
+//! or outcome is added or changed, and readers should reject versions they
+//! don't know.
+
+use super::*;
+
+/// Version of the wire format described in the module documentation.
+const VERSION: u32 = 4;
+
+const RUN_STARTED: u8 = 0;
+const TEST_STARTED: u8 = 1;
+const TEST_FINISHED: u8 = 2;
+const TEST_SLOW: u8 = 3;
+const TEST_SKIPPED: u8 = 4;
+const RUN_FINISHED: u8 = 5;
+
+pub(crate) struct BinaryFormatter<T> {
+    out: OutputLocation<T>,
+}
+
+impl<T: Write> BinaryFormatter<T> {
+    pub fn new(out: OutputLocation<T>) -> Self {
+        Self { out }
+    }
+
+    #[cfg(test)]
+    pub fn output_location(&self) -> &OutputLocation<T> {
+        &self.out
+    }
+
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        self.out.write_all(&(frame.0.len() as u32).to_le_bytes())?;
+        self.out.write_all(&frame.0)?;
+        // The reader is usually waiting on the other end of a pipe.
+        self.out.flush()
+    }
+}
+
+/// The payload of a frame being built.
+struct Frame(Vec<u8>);
+
+impl Frame {
+    fn new(kind: u8) -> Self {
+        Frame(vec![kind])
+    }
+
+    fn u8(mut self, v: u8) -> Self {
+        self.0.push(v);
+        self
+    }
+
+    fn u32(mut self, v: u32) -> Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn u64(mut self, v: u64) -> Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn bytes(self, b: &[u8]) -> Self {
+        let mut frame = self.u32(b.len() as u32);
+        frame.0.extend_from_slice(b);
+        frame
+    }
+
+    fn str(self, s: &str) -> Self {
+        self.bytes(s.as_bytes())
+    }
+}
+
+impl<T: Write> OutputFormatter for BinaryFormatter<T> {
+    fn write_run_start(&mut self, test_count: usize) -> io::Result<()> {
+        let frame =
+            Frame::new(RUN_STARTED).u32(VERSION).u64(test_count as u64);
+        self.write_frame(&frame)
+    }
+
+    fn write_test_start(&mut self, desc: &TestDesc) -> io::Result<()> {
+        let frame = Frame::new(TEST_STARTED).str(desc.name.as_slice());
+        self.write_frame(&frame)
+    }
+
+    fn write_result(
+        &mut self,
+        desc: &TestDesc,
+        result: &TestResult,
+        _: Option<Duration>,
+        output: &TestOutput,
+    ) -> io::Result<()> {
+        let stdout = &output.combined;
+        let frame = Frame::new(TEST_FINISHED).str(desc.name.as_slice());
+        let frame = match *result {
+            TestResult::TrOk => frame.u8(0),
+            TestResult::TrOkWithRetries(n) => frame.u8(5).u32(n),
+            TestResult::TrFailed => frame.u8(1).u8(0).str("").bytes(stdout),
+            TestResult::TrFailedErr => frame.u8(1).u8(1).str("").bytes(stdout),
+            TestResult::TrFailedMsg(ref msg) => {
+                frame.u8(1).u8(2).str(msg).bytes(stdout)
+            }
+            TestResult::TrFailedShouldPanic(ref mismatch) => {
+                frame.u8(1).u8(3).str(&mismatch.to_string()).bytes(stdout)
+            }
+            TestResult::TrTimedOut(timeout) => frame
+                .u8(1)
+                .u8(4)
+                .str(&format!("timed out after {:?}", timeout))
+                .bytes(stdout),
+            TestResult::TrIgnored => frame.u8(2),
+            TestResult::TrIgnoredMsg(ref msg) => frame.u8(6).str(msg),
+            TestResult::TrAllowedFail => frame.u8(3),
+            TestResult::TrBench(ref bs) => frame
+                .u8(4)
+                .u64(bs.ns_iter_summ.median as u64)
+                .u64((bs.ns_iter_summ.max - bs.ns_iter_summ.min) as u64)
+                .u64(bs.mb_s as u64),
+        };
+        self.write_frame(&frame)
+    }
+
+    fn write_skipped(
+        &mut self,
+        desc: &TestDesc,
+        reason: SkipReason,
+    ) -> io::Result<()> {
+        let frame = Frame::new(TEST_SKIPPED)
+            .str(desc.name.as_slice())
+            .str(reason.as_str());
+        self.write_frame(&frame)
+    }
+
+    fn write_timeout(&mut self, desc: &TestDesc) -> io::Result<()> {
+        let frame = Frame::new(TEST_SLOW).str(desc.name.as_slice());
+        self.write_frame(&frame)
+    }
+
+    fn write_run_finish(
+        &mut self,
+        state: &ConsoleTestState,
+    ) -> io::Result<bool> {
+        let success = state.success();
+        let frame = Frame::new(RUN_FINISHED)
+            .u8(success as u8)
+            .u64(state.summary.passed as u64)
+            .u64(state.summary.failed as u64)
+            .u64(state.summary.allowed_fail as u64)
+            .u64(state.summary.ignored as u64)
+            .u64(state.summary.measured as u64)
+            .u64(state.summary.filtered_out.total() as u64)
+            .u64(state.summary.flaky as u64);
+        self.write_frame(&frame)?;
+
+        Ok(success)
+    }
+}
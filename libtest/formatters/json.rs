@@ -2,18 +2,49 @@ use super::*;
 
 pub(crate) struct JsonFormatter<T> {
     out: OutputLocation<T>,
+
+    /// Whether to attach a `skip_reason` to ignored tests
+    report_skipped: bool,
+
+    /// Whether to attach the captured output of passing tests, and not
+    /// only that of failing ones
+    display_output: bool,
+
+    /// Whether to attach the iterations per second to benchmarks
+    show_bench_throughput: bool,
 }
 
 impl<T: Write> JsonFormatter<T> {
-    pub fn new(out: OutputLocation<T>) -> Self {
-        Self { out }
+    pub fn new(
+        out: OutputLocation<T>,
+        report_skipped: bool,
+        display_output: bool,
+        show_bench_throughput: bool,
+    ) -> Self {
+        Self {
+            out,
+            report_skipped,
+            display_output,
+            show_bench_throughput,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn output_location(&self) -> &OutputLocation<T> {
+        &self.out
     }
 
+    /// Writes `s` as one line and flushes it, so that a reader tailing the
+    /// output only ever sees complete JSON objects, even if the process dies
+    /// before the run finishes.
     fn write_message(&mut self, s: &str) -> io::Result<()> {
         assert!(!s.contains('\n'));
 
-        self.out.write_all(s.as_ref())?;
-        self.out.write_all(b"\n")
+        let mut line = String::with_capacity(s.len() + 1);
+        line.push_str(s);
+        line.push('\n');
+        self.out.write_all(line.as_bytes())?;
+        self.out.flush()
     }
 
     fn write_event(
@@ -40,6 +71,91 @@ impl<T: Write> JsonFormatter<T> {
             ))
         }
     }
+
+    /// Writes the `--list` entry for a test, `kind` being `test` or
+    /// `benchmark`.
+    pub fn write_list_entry(
+        &mut self,
+        desc: &TestDesc,
+        kind: &str,
+    ) -> io::Result<()> {
+        self.write_message(&format!(
+            r#"{{ "name": "{}", "kind": "{}", "ignore": {}, "should_panic": {} }}"#,
+            EscapedString(desc.name.as_slice()),
+            kind,
+            desc.ignore,
+            desc.should_panic != ShouldPanic::No
+        ))
+    }
+
+    /// Writes a `test` event, with the test's type when it's known and its
+    /// execution time in seconds added to `extra` when it's known.
+    fn write_test_event(
+        &mut self,
+        desc: &TestDesc,
+        exec_time: Option<Duration>,
+        evt: &str,
+        extra: Option<String>,
+    ) -> io::Result<()> {
+        let extra = join_fields(test_type_field(desc), extra);
+        let extra = match exec_time {
+            Some(t) => join_fields(extra, Some(exec_time_field(t))),
+            None => extra,
+        };
+        self.write_event("test", desc.name.as_slice(), evt, extra)
+    }
+}
+
+/// An `exec_time` field, in seconds.
+fn exec_time_field(t: Duration) -> String {
+    let secs = t.as_secs() as f64 + f64::from(t.subsec_nanos()) / 1e9;
+    format!(r#""exec_time": {}"#, secs)
+}
+
+/// The `test_type` field of a test's events, left out for tests of unknown
+/// type.
+fn test_type_field(desc: &TestDesc) -> Option<String> {
+    match desc.test_type {
+        TestType::Unknown => None,
+        ty => Some(format!(r#""test_type": "{}""#, ty.as_str())),
+    }
+}
+
+/// The `stdout` and `stderr` fields of a test's result, each left out if
+/// the test wrote nothing to it.
+fn output_fields(output: &TestOutput) -> Option<String> {
+    let field = |name, bytes: &[u8]| {
+        if bytes.is_empty() {
+            None
+        } else {
+            Some(format!(
+                r#""{}": "{}""#,
+                name,
+                EscapedString(String::from_utf8_lossy(bytes))
+            ))
+        }
+    };
+    join_fields(
+        field("stdout", &output.stdout),
+        field("stderr", &output.stderr),
+    )
+}
+
+/// The `allocations` and `bytes_allocated` fields of a test's result, with
+/// `--track-allocations`.
+fn allocation_fields(stats: AllocationStats) -> String {
+    format!(
+        r#""allocations": {}, "bytes_allocated": {}"#,
+        stats.allocations, stats.bytes
+    )
+}
+
+fn join_fields(a: Option<String>, b: Option<String>) -> Option<String> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(format!("{}, {}", a, b)),
+        (a, None) => a,
+        (None, b) => b,
+    }
 }
 
 impl<T: Write> OutputFormatter for JsonFormatter<T> {
@@ -51,57 +167,144 @@ impl<T: Write> OutputFormatter for JsonFormatter<T> {
     }
 
     fn write_test_start(&mut self, desc: &TestDesc) -> io::Result<()> {
-        self.write_message(&*format!(
-            r#"{{ "type": "test", "event": "started", "name": "{}" }}"#,
-            EscapedString(desc.name.as_slice())
-        ))
+        match test_type_field(desc) {
+            Some(ty) => self.write_message(&format!(
+                r#"{{ "type": "test", "event": "started", "name": "{}", {} }}"#,
+                EscapedString(desc.name.as_slice()),
+                ty
+            )),
+            None => self.write_message(&*format!(
+                r#"{{ "type": "test", "event": "started", "name": "{}" }}"#,
+                EscapedString(desc.name.as_slice())
+            )),
+        }
     }
 
     fn write_result(
         &mut self,
         desc: &TestDesc,
         result: &TestResult,
-        stdout: &[u8],
+        exec_time: Option<Duration>,
+        output: &TestOutput,
     ) -> io::Result<()> {
+        // Failing tests always carry their output, passing ones only with
+        // `--show-output`.
+        let allocations = output.allocations.map(allocation_fields);
+        let panic_location = output.panic_location.as_ref().map(|l| {
+            format!(r#""panic_location": "{}""#, EscapedString(l.to_string()))
+        });
+        let output = join_fields(panic_location, output_fields(output));
+        let passed_output = if self.display_output {
+            output.clone()
+        } else {
+            None
+        };
+        let output = join_fields(allocations.clone(), output);
+        let passed_output = join_fields(allocations, passed_output);
         match *result {
             TestResult::TrOk => {
-                self.write_event("test", desc.name.as_slice(), "ok", None)
+                self.write_test_event(desc, exec_time, "ok", passed_output)
             }
 
-            TestResult::TrFailed => {
-                let extra_data = if stdout.is_empty() {
-                    None
+            TestResult::TrOkWithRetries(n) => self.write_test_event(
+                desc,
+                exec_time,
+                "ok",
+                join_fields(
+                    Some(format!(r#""retries": {}"#, n)),
+                    passed_output,
+                ),
+            ),
+
+            TestResult::TrFailed | TestResult::TrFailedErr => {
+                let kind = if *result == TestResult::TrFailedErr {
+                    "error"
                 } else {
-                    Some(format!(
-                        r#""stdout": "{}""#,
-                        EscapedString(String::from_utf8_lossy(stdout))
-                    ))
+                    "panic"
                 };
-
-                self.write_event(
-                    "test",
-                    desc.name.as_slice(),
+                let kind = format!(r#""failure_kind": "{}""#, kind);
+                self.write_test_event(
+                    desc,
+                    exec_time,
                     "failed",
-                    extra_data,
+                    join_fields(Some(kind), output),
                 )
             }
 
-            TestResult::TrFailedMsg(ref m) => self.write_event(
-                "test",
-                desc.name.as_slice(),
+            TestResult::TrFailedMsg(ref m) => self.write_test_event(
+                desc,
+                exec_time,
+                "failed",
+                join_fields(
+                    Some(format!(r#""message": "{}""#, EscapedString(m))),
+                    output,
+                ),
+            ),
+
+            // `timeout` events are the warning of `write_timeout`.
+            TestResult::TrTimedOut(timeout) => self.write_test_event(
+                desc,
+                exec_time,
                 "failed",
-                Some(format!(r#""message": "{}""#, EscapedString(m))),
+                join_fields(
+                    Some(format!(
+                        r#""failure_kind": "timeout", "message": "timed out after {:?}""#,
+                        timeout
+                    )),
+                    output,
+                ),
             ),
 
-            TestResult::TrIgnored => {
-                self.write_event("test", desc.name.as_slice(), "ignored", None)
+            TestResult::TrFailedShouldPanic(ref mismatch) => {
+                let actual = match mismatch.actual {
+                    Some(ref actual) => {
+                        format!(r#""{}""#, EscapedString(actual))
+                    }
+                    None => "null".to_owned(),
+                };
+                self.write_test_event(
+                    desc, exec_time,
+                    "failed",
+                    join_fields(Some(format!(
+                        r#""failure_kind": "should_panic_mismatch", "message": "{}", "expected_message": "{}", "actual_message": {}"#,
+                        EscapedString(mismatch.to_string()),
+                        EscapedString(&mismatch.expected),
+                        actual
+                    )), output),
+                )
+            }
+
+            TestResult::TrIgnored | TestResult::TrIgnoredMsg(_) => {
+                let message = match *result {
+                    TestResult::TrIgnoredMsg(ref msg) => Some(&**msg),
+                    _ => desc.ignore_message,
+                };
+                let mut extra = Vec::new();
+                if let Some(message) = message {
+                    extra.push(format!(
+                        r#""reason": "{}""#,
+                        EscapedString(message)
+                    ));
+                }
+                if self.report_skipped {
+                    extra.push(format!(
+                        r#""skip_reason": "{}""#,
+                        SkipReason::Ignored.as_str()
+                    ));
+                }
+                let extra_data = if extra.is_empty() {
+                    None
+                } else {
+                    Some(extra.join(", "))
+                };
+                self.write_test_event(desc, exec_time, "ignored", extra_data)
             }
 
-            TestResult::TrAllowedFail => self.write_event(
-                "test",
-                desc.name.as_slice(),
+            TestResult::TrAllowedFail => self.write_test_event(
+                desc,
+                exec_time,
                 "allowed_failure",
-                None,
+                output,
             ),
 
             TestResult::TrBench(ref bs) => {
@@ -114,13 +317,67 @@ impl<T: Write> OutputFormatter for JsonFormatter<T> {
                 } else {
                     format!(r#", "mib_per_second": {}"#, bs.mb_s)
                 };
+                let throughput = match bench_throughput(bs) {
+                    Some(ips) if self.show_bench_throughput => format!(
+                        r#", "iters_per_second": {}"#,
+                        ips.round() as u64
+                    ),
+                    _ => String::new(),
+                };
+                let percentiles =
+                    match (bs.percentile(90.0), bs.percentile(99.0)) {
+                        (Some(p90), Some(p99)) => {
+                            format!(r#", "p90": {}, "p99": {}"#, p90, p99)
+                        }
+                        _ => String::new(),
+                    };
+                let samples = if bs.samples.is_empty() {
+                    String::new()
+                } else {
+                    let samples: Vec<_> =
+                        bs.samples.iter().map(|s| s.to_string()).collect();
+                    format!(r#", "samples": [{}]"#, samples.join(", "))
+                };
+                let saturated = if bs.saturated {
+                    r#", "saturated": true"#
+                } else {
+                    ""
+                };
+                let metrics = if bs.metrics.metrics.is_empty() {
+                    String::new()
+                } else {
+                    let metrics: Vec<_> = bs
+                        .metrics
+                        .metrics
+                        .iter()
+                        .map(|(name, m)| {
+                            format!(
+                                r#""{}": {{ "value": {}, "noise": {} }}"#,
+                                EscapedString(name),
+                                m.value,
+                                m.noise
+                            )
+                        })
+                        .collect();
+                    format!(r#", "metrics": {{ {} }}"#, metrics.join(", "))
+                };
 
                 let line = format!(
                     "{{ \"type\": \"bench\", \
                      \"name\": \"{}\", \
                      \"median\": {}, \
-                     \"deviation\": {}{} }}",
-                    desc.name, median, deviation, mbps
+                     \"deviation\": {}, \
+                     \"iterations\": {}{}{}{}{}{}{} }}",
+                    desc.name,
+                    median,
+                    deviation,
+                    bs.iterations,
+                    mbps,
+                    throughput,
+                    percentiles,
+                    samples,
+                    saturated,
+                    metrics
                 );
 
                 self.write_message(&*line)
@@ -128,6 +385,19 @@ impl<T: Write> OutputFormatter for JsonFormatter<T> {
         }
     }
 
+    fn write_skipped(
+        &mut self,
+        desc: &TestDesc,
+        reason: SkipReason,
+    ) -> io::Result<()> {
+        self.write_event(
+            "test",
+            desc.name.as_slice(),
+            "skipped",
+            Some(format!(r#""skip_reason": "{}""#, reason.as_str())),
+        )
+    }
+
     fn write_timeout(&mut self, desc: &TestDesc) -> io::Result<()> {
         self.write_message(&*format!(
             r#"{{ "type": "test", "event": "timeout", "name": "{}" }}"#,
@@ -143,27 +413,57 @@ impl<T: Write> OutputFormatter for JsonFormatter<T> {
             "{{ \"type\": \"suite\", \
              \"event\": \"{}\", \
              \"passed\": {}, \
+             \"flaky\": {}, \
              \"failed\": {}, \
+             \"timed_out\": {}, \
              \"allowed_fail\": {}, \
              \"ignored\": {}, \
              \"measured\": {}, \
-             \"filtered_out\": {} }}",
-            if state.failed == 0 { "ok" } else { "failed" },
-            state.passed,
-            state.failed + state.allowed_fail,
-            state.allowed_fail,
-            state.ignored,
-            state.measured,
-            state.filtered_out
+             \"filtered_out\": {}, \
+             \"filtered_out_by\": {{ \
+             \"filter\": {}, \
+             \"skip\": {}, \
+             \"should_panic\": {}, \
+             \"shard\": {} }}{}{}{} }}",
+            if state.success() { "ok" } else { "failed" },
+            state.summary.passed,
+            state.summary.flaky,
+            state.summary.failed + state.summary.allowed_fail,
+            state.summary.timed_out,
+            state.summary.allowed_fail,
+            state.summary.ignored,
+            state.summary.measured,
+            state.summary.filtered_out.total(),
+            state.summary.filtered_out.by_filter,
+            state.summary.filtered_out.by_skip,
+            state.summary.filtered_out.by_should_panic,
+            state.summary.filtered_out.by_shard,
+            match state.min_pass_rate {
+                Some(min) => format!(
+                    ", \"pass_rate\": {}, \"min_pass_rate\": {}",
+                    state.pass_rate(),
+                    min
+                ),
+                None => String::new(),
+            },
+            match state.metrics.note {
+                Some(ref note) =>
+                    format!(", \"note\": \"{}\"", EscapedString(note)),
+                None => String::new(),
+            },
+            match state.exec_time {
+                Some(t) => format!(", {}", exec_time_field(t)),
+                None => String::new(),
+            }
         ))?;
 
-        Ok(state.failed == 0)
+        Ok(state.success())
     }
 }
 
 /// A formatting utility used to print strings with characters in need of escaping.
 /// Base code taken form `libserialize::json::escape_str`
-struct EscapedString<S: AsRef<str>>(S);
+pub(crate) struct EscapedString<S: AsRef<str>>(pub(crate) S);
 
 impl<S: AsRef<str>> ::std::fmt::Display for EscapedString<S> {
     fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
@@ -0,0 +1,171 @@
+//! The JUnit XML report written by `--junit-file`, for CI systems to ingest
+//! alongside the output of the formatter picked with `--format`.
+
+use super::*;
+
+pub(crate) struct JUnitFormatter<T> {
+    out: OutputLocation<T>,
+    /// Every finished test, held back until the run finishes because the
+    /// `<testsuite>` element opens with the totals.
+    results: Vec<(TestDesc, TestResult, Duration)>,
+}
+
+impl<T: Write> JUnitFormatter<T> {
+    pub fn new(out: OutputLocation<T>) -> Self {
+        Self {
+            out,
+            results: Vec::new(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn output_location(&self) -> &OutputLocation<T> {
+        &self.out
+    }
+
+    /// Writes `s` as one line of the report.
+    fn write_message(&mut self, s: &str) -> io::Result<()> {
+        assert!(!s.contains('\n'));
+
+        self.out.write_all(s.as_bytes())?;
+        self.out.write_all(b"\n")
+    }
+}
+
+/// Splits a test's name into the JUnit class, the module path, and the
+/// name of the test within it. Tests at the crate root are put under
+/// `(root)`.
+fn class_and_name(desc: &TestDesc) -> (&str, &str) {
+    let name = desc.name.as_slice();
+    match name.rfind("::") {
+        Some(i) => (&name[..i], &name[i + 2..]),
+        None => ("(root)", name),
+    }
+}
+
+/// Escapes `s` for use in an XML attribute value. Line breaks and tabs are
+/// written as character references, which keeps each element on one line
+/// and survives attribute value normalization. Other control characters
+/// aren't allowed in XML 1.0 at all, so they are replaced with U+FFFD.
+fn escape_xml(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\n' => escaped.push_str("&#10;"),
+            '\r' => escaped.push_str("&#13;"),
+            '\t' => escaped.push_str("&#9;"),
+            c if c.is_control() => escaped.push('\u{fffd}'),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The `type` and, if the result has one, the `message` of the `<failure>`
+/// element for a failed test, or `None` if it didn't fail.
+fn failure(result: &TestResult) -> Option<(&'static str, Option<String>)> {
+    match *result {
+        TestResult::TrFailed => Some(("panic", None)),
+        TestResult::TrFailedErr => Some(("error", None)),
+        TestResult::TrFailedMsg(ref msg) => {
+            Some(("failure", Some(msg.clone())))
+        }
+        TestResult::TrFailedShouldPanic(ref mismatch) => {
+            Some(("should_panic", Some(mismatch.to_string())))
+        }
+        TestResult::TrTimedOut(_) => Some(("timeout", None)),
+        TestResult::TrOk
+        | TestResult::TrOkWithRetries(_)
+        | TestResult::TrIgnored
+        | TestResult::TrIgnoredMsg(_)
+        | TestResult::TrAllowedFail
+        | TestResult::TrBench(_) => None,
+    }
+}
+
+impl<T: Write> OutputFormatter for JUnitFormatter<T> {
+    fn write_run_start(&mut self, _test_count: usize) -> io::Result<()> {
+        self.write_message(r#"<?xml version="1.0" encoding="UTF-8"?>"#)
+    }
+
+    fn write_test_start(&mut self, _desc: &TestDesc) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_timeout(&mut self, _desc: &TestDesc) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_result(
+        &mut self,
+        desc: &TestDesc,
+        result: &TestResult,
+        exec_time: Option<Duration>,
+        _output: &TestOutput,
+    ) -> io::Result<()> {
+        let exec_time = exec_time.unwrap_or_default();
+        self.results.push((desc.clone(), result.clone(), exec_time));
+        Ok(())
+    }
+
+    fn write_run_finish(
+        &mut self,
+        state: &ConsoleTestState,
+    ) -> io::Result<bool> {
+        self.write_message("<testsuites>")?;
+        self.write_message(&format!(
+            "<testsuite name=\"test\" errors=\"0\" failures=\"{}\" \
+             tests=\"{}\" skipped=\"{}\">",
+            state.summary.failed,
+            self.results.len(),
+            state.summary.ignored
+        ))?;
+        for (desc, result, exec_time) in self.results.split_off(0) {
+            let (class, name) = class_and_name(&desc);
+            let secs = exec_time.as_secs() as f64
+                + f64::from(exec_time.subsec_nanos()) / 1e9;
+            let testcase = format!(
+                r#"<testcase classname="{}" name="{}" time="{}""#,
+                escape_xml(class),
+                escape_xml(name),
+                secs
+            );
+            match (failure(&result), result) {
+                (Some((ty, msg)), _) => {
+                    self.write_message(&format!("{}>", testcase))?;
+                    match msg {
+                        Some(msg) => self.write_message(&format!(
+                            r#"<failure type="{}" message="{}"/>"#,
+                            ty,
+                            escape_xml(&msg)
+                        ))?,
+                        None => self.write_message(&format!(
+                            r#"<failure type="{}"/>"#,
+                            ty
+                        ))?,
+                    }
+                    self.write_message("</testcase>")?;
+                }
+                (None, TestResult::TrIgnored)
+                | (None, TestResult::TrIgnoredMsg(_)) => {
+                    self.write_message(&format!("{}>", testcase))?;
+                    self.write_message("<skipped/>")?;
+                    self.write_message("</testcase>")?;
+                }
+                (None, _) => {
+                    self.write_message(&format!("{}/>", testcase))?;
+                }
+            }
+        }
+        self.write_message("</testsuite>")?;
+        self.write_message("</testsuites>")?;
+        self.out.flush()?;
+
+        Ok(state.success())
+    }
+}
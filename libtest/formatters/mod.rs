@@ -1,23 +1,125 @@
 use super::*;
 
+mod binary;
 mod json;
+mod junit;
 mod pretty;
+mod tap;
+mod tee;
 mod terse;
 
+pub(crate) use self::binary::BinaryFormatter;
+pub(crate) use self::json::EscapedString;
 pub(crate) use self::json::JsonFormatter;
+pub(crate) use self::junit::JUnitFormatter;
 pub(crate) use self::pretty::PrettyFormatter;
+pub(crate) use self::tap::TapFormatter;
+pub(crate) use self::tee::TeeFormatter;
 pub(crate) use self::terse::TerseFormatter;
 
-pub(crate) trait OutputFormatter {
+/// How the human-readable formatters show a run, resolved once from the
+/// options.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct DisplayConfig {
+    pub use_color: bool,
+    /// Number of columns to fill when aligning names
+    pub max_name_len: usize,
+    /// Whether tests may run concurrently, interleaving their results;
+    /// never with `--force-run-in-process`
+    pub is_multithreaded: bool,
+    /// Whether to append the relative standard deviation to benchmarks
+    pub show_bench_rsd: bool,
+    /// Whether to draw a histogram of each benchmark's samples
+    pub show_bench_histogram: bool,
+    /// Whether to append the iterations per second to benchmarks
+    pub show_bench_throughput: bool,
+    /// Whether `--progress` was given and stdout is a terminal
+    pub show_progress: bool,
+    /// With `--report-time`, the durations past which a test's time is
+    /// shown in yellow, and in red
+    pub report_time: Option<(Duration, Duration)>,
+    /// How long a test runs for before `write_timeout` is called for it
+    pub warn_timeout: Duration,
+    /// Whether to print the output of failing tests with their results,
+    /// rather than with the failures at the end
+    pub stream_failures: bool,
+}
+
+impl DisplayConfig {
+    /// The display `opts` asks for, aligning names to `max_name_len`
+    /// columns.
+    pub fn new(opts: &TestOpts, max_name_len: usize) -> Self {
+        Self {
+            use_color: use_color(opts),
+            max_name_len,
+            is_multithreaded: !opts.force_run_in_process
+                && resolve_concurrency(opts) > 1,
+            show_bench_rsd: opts.bench_rsd,
+            show_bench_histogram: opts.bench_histogram,
+            show_bench_throughput: opts.bench_show_throughput,
+            show_progress: opts.progress && stdout_isatty(),
+            report_time: if opts.report_time {
+                Some((opts.report_time_warn, opts.report_time_critical))
+            } else {
+                None
+            },
+            warn_timeout: opts.warn_timeout.unwrap_or_default(),
+            stream_failures: opts.stream_failures,
+        }
+    }
+}
+
+/// Plain output, with everything optional turned off.
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        Self {
+            use_color: false,
+            max_name_len: 0,
+            is_multithreaded: false,
+            show_bench_rsd: false,
+            show_bench_histogram: false,
+            show_bench_throughput: false,
+            show_progress: false,
+            report_time: None,
+            warn_timeout: Duration::from_secs(TEST_WARN_TIMEOUT_S),
+            stream_failures: false,
+        }
+    }
+}
+
+/// Reports the progress and results of a run, as driven by
+/// `run_tests_console_with_formatter`.
+pub trait OutputFormatter {
+    /// Called once before any test starts, with the number of tests that
+    /// will run.
     fn write_run_start(&mut self, test_count: usize) -> io::Result<()>;
+    /// Called when a test starts.
     fn write_test_start(&mut self, desc: &TestDesc) -> io::Result<()>;
+    /// Called when a test has been running for over a minute.
     fn write_timeout(&mut self, desc: &TestDesc) -> io::Result<()>;
+    /// Called periodically while waiting on concurrent tests when
+    /// `--progress` is enabled.
+    fn write_tick(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+    /// Called for each test that was filtered out, with `--report-skipped`.
+    fn write_skipped(
+        &mut self,
+        _desc: &TestDesc,
+        _reason: SkipReason,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+    /// Called when a test finishes, with how long it ran for (if it ran at
+    /// all) and its captured output.
     fn write_result(
         &mut self,
         desc: &TestDesc,
         result: &TestResult,
-        stdout: &[u8],
+        exec_time: Option<Duration>,
+        output: &TestOutput,
     ) -> io::Result<()>;
+    /// Called once after the last test, returning whether the run succeeded.
     fn write_run_finish(
         &mut self,
         state: &ConsoleTestState,
@@ -1,27 +1,40 @@
 use super::*;
 
+const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+
+/// Counters behind the `--progress` status line.
+#[derive(Default)]
+struct Progress {
+    total: usize,
+    running: usize,
+    done: usize,
+    failed: usize,
+    spinner: usize,
+    /// Length of the status line currently on screen, 0 if none is.
+    drawn_len: usize,
+    last_drawn: Option<Instant>,
+}
+
 pub(crate) struct PrettyFormatter<T> {
     out: OutputLocation<T>,
-    use_color: bool,
+    config: DisplayConfig,
 
-    /// Number of columns to fill when aligning names
-    max_name_len: usize,
-
-    is_multithreaded: bool,
+    /// Live status line, kept below the per-test output
+    progress: Option<Progress>,
 }
 
 impl<T: Write> PrettyFormatter<T> {
-    pub fn new(
-        out: OutputLocation<T>,
-        use_color: bool,
-        max_name_len: usize,
-        is_multithreaded: bool,
-    ) -> Self {
+    /// The `--progress` status line is only drawn for multithreaded runs,
+    /// where the results alone don't show what is still running.
+    pub fn new(out: OutputLocation<T>, config: DisplayConfig) -> Self {
         Self {
             out,
-            use_color,
-            max_name_len,
-            is_multithreaded,
+            config,
+            progress: if config.show_progress && config.is_multithreaded {
+                Some(Progress::default())
+            } else {
+                None
+            },
         }
     }
 
@@ -38,8 +51,26 @@ impl<T: Write> PrettyFormatter<T> {
         self.write_short_result("FAILED", term::color::RED)
     }
 
-    pub fn write_ignored(&mut self) -> io::Result<()> {
-        self.write_short_result("ignored", term::color::YELLOW)
+    pub fn write_timed_out(&mut self) -> io::Result<()> {
+        self.write_short_result("TIMED OUT", term::color::RED)
+    }
+
+    pub fn write_ignored(&mut self, message: Option<&str>) -> io::Result<()> {
+        match message {
+            Some(message) => self.write_short_result(
+                &format!("ignored, {}", message),
+                term::color::YELLOW,
+            ),
+            None => self.write_short_result("ignored", term::color::YELLOW),
+        }
+    }
+
+    pub fn write_flaky(&mut self, retries: u32) -> io::Result<()> {
+        let noun = if retries == 1 { "retry" } else { "retries" };
+        self.write_short_result(
+            &format!("ok (flaky, {} {})", retries, noun),
+            term::color::YELLOW,
+        )
     }
 
     pub fn write_allowed_fail(&mut self) -> io::Result<()> {
@@ -55,8 +86,7 @@ impl<T: Write> PrettyFormatter<T> {
         result: &str,
         color: term::color::Color,
     ) -> io::Result<()> {
-        self.write_pretty(result, color)?;
-        self.write_plain("\n")
+        self.write_pretty(result, color)
     }
 
     pub fn write_pretty(
@@ -66,11 +96,11 @@ impl<T: Write> PrettyFormatter<T> {
     ) -> io::Result<()> {
         match self.out {
             OutputLocation::Pretty(ref mut term) => {
-                if self.use_color {
+                if self.config.use_color {
                     term.fg(color)?;
                 }
                 term.write_all(word.as_bytes())?;
-                if self.use_color {
+                if self.config.use_color {
                     term.reset()?;
                 }
                 term.flush()
@@ -124,9 +154,9 @@ impl<T: Write> PrettyFormatter<T> {
         self.write_plain("\nfailures:\n")?;
         let mut failures = Vec::new();
         let mut fail_out = String::new();
-        for &(ref f, ref stdout) in &state.failures {
+        for &(ref f, ref stdout) in &state.summary.failures {
             failures.push(f.name.to_string());
-            if !stdout.is_empty() {
+            if !stdout.is_empty() && !self.config.stream_failures {
                 fail_out.push_str(&format!("---- {} stdout ----\n", f.name));
                 let output = String::from_utf8_lossy(stdout);
                 fail_out.push_str(&output);
@@ -146,9 +176,108 @@ impl<T: Write> PrettyFormatter<T> {
         Ok(())
     }
 
+    /// Redraws the status line in place. Unless `force` is set, this is a
+    /// no-op if the line was drawn less than `PROGRESS_REFRESH_MS` ago.
+    fn draw_progress(&mut self, force: bool) -> io::Result<()> {
+        let line = match self.progress {
+            Some(ref mut p) => {
+                let refresh = Duration::from_millis(PROGRESS_REFRESH_MS);
+                if let Some(last) = p.last_drawn {
+                    if !force && last.elapsed() < refresh {
+                        return Ok(());
+                    }
+                }
+                p.spinner = (p.spinner + 1) % SPINNER.len();
+                p.last_drawn = Some(Instant::now());
+                let line = format!(
+                    "{} [running {}, done {}/{}, {} failed]",
+                    SPINNER[p.spinner], p.running, p.done, p.total, p.failed
+                );
+                let pad = p.drawn_len.saturating_sub(line.len());
+                p.drawn_len = line.len();
+                format!("\r{}{}", line, " ".repeat(pad))
+            }
+            None => return Ok(()),
+        };
+        self.write_plain(line)
+    }
+
+    /// Erases the status line so regular output can take its place.
+    fn clear_progress(&mut self) -> io::Result<()> {
+        let len = match self.progress {
+            Some(ref mut p) => std::mem::replace(&mut p.drawn_len, 0),
+            None => 0,
+        };
+        if len == 0 {
+            return Ok(());
+        }
+        self.write_plain(format!("\r{}\r", " ".repeat(len)))
+    }
+
+    fn write_result_line(
+        &mut self,
+        desc: &TestDesc,
+        result: &TestResult,
+        exec_time: Option<Duration>,
+        allocations: Option<AllocationStats>,
+    ) -> io::Result<()> {
+        match *result {
+            TestResult::TrOk => self.write_ok(),
+            TestResult::TrOkWithRetries(n) => self.write_flaky(n),
+            TestResult::TrFailed
+            | TestResult::TrFailedErr
+            | TestResult::TrFailedMsg(_)
+            | TestResult::TrFailedShouldPanic(_) => self.write_failed(),
+            TestResult::TrTimedOut(_) => self.write_timed_out(),
+            TestResult::TrIgnored => self.write_ignored(desc.ignore_message),
+            TestResult::TrIgnoredMsg(ref msg) => self.write_ignored(Some(msg)),
+            TestResult::TrAllowedFail => self.write_allowed_fail(),
+            TestResult::TrBench(ref bs) => {
+                self.write_bench()?;
+                self.write_plain(&format!(": {}", fmt_bench_samples(bs)))?;
+                if self.config.show_bench_throughput {
+                    self.write_plain(fmt_bench_throughput(bs))?;
+                }
+                if self.config.show_bench_rsd {
+                    self.write_pretty(
+                        &fmt_bench_rsd(bs),
+                        bench_rsd_color(bs),
+                    )?;
+                }
+                self.write_plain("\n")?;
+                if self.config.show_bench_histogram {
+                    self.write_plain(fmt_bench_histogram(bs))?;
+                }
+                return Ok(());
+            }
+        }?;
+        if let Some(stats) = allocations {
+            self.write_plain(fmt_allocations(stats))?;
+        }
+        if let (Some((warn, critical)), Some(t)) =
+            (self.config.report_time, exec_time)
+        {
+            let time = format!(" <{}.{:03}s>", t.as_secs(), t.subsec_millis());
+            if t >= critical {
+                self.write_pretty(&time, term::color::RED)?;
+            } else if t >= warn {
+                self.write_pretty(&time, term::color::YELLOW)?;
+            } else {
+                self.write_plain(&time)?;
+            }
+        }
+        self.write_plain("\n")
+    }
+
     fn write_test_name(&mut self, desc: &TestDesc) -> io::Result<()> {
-        let name = desc.padded_name(self.max_name_len, desc.name.padding());
-        self.write_plain(&format!("test {} ... ", name))?;
+        let name =
+            desc.padded_name(self.config.max_name_len, desc.name.padding());
+        let bench = if desc.is_converted_bench {
+            " (bench)"
+        } else {
+            ""
+        };
+        self.write_plain(&format!("test {}{} ... ", name, bench))?;
 
         Ok(())
     }
@@ -157,7 +286,11 @@ impl<T: Write> PrettyFormatter<T> {
 impl<T: Write> OutputFormatter for PrettyFormatter<T> {
     fn write_run_start(&mut self, test_count: usize) -> io::Result<()> {
         let noun = if test_count == 1 { "test" } else { "tests" };
-        self.write_plain(&format!("\nrunning {} {}\n", test_count, noun))
+        self.write_plain(&format!("\nrunning {} {}\n", test_count, noun))?;
+        if let Some(ref mut p) = self.progress {
+            p.total = test_count;
+        }
+        Ok(())
     }
 
     fn write_test_start(&mut self, desc: &TestDesc) -> io::Result<()> {
@@ -165,57 +298,86 @@ impl<T: Write> OutputFormatter for PrettyFormatter<T> {
         // the test's name as the result will be mis-aligned.
         // When running the tests serially, we print the name here so
         // that the user can see which test hangs.
-        if !self.is_multithreaded {
+        if !self.config.is_multithreaded {
             self.write_test_name(desc)?;
         }
 
-        Ok(())
+        if let Some(ref mut p) = self.progress {
+            p.running += 1;
+        }
+        self.draw_progress(false)
+    }
+
+    fn write_tick(&mut self) -> io::Result<()> {
+        self.draw_progress(false)
     }
 
     fn write_result(
         &mut self,
         desc: &TestDesc,
         result: &TestResult,
-        _: &[u8],
+        exec_time: Option<Duration>,
+        output: &TestOutput,
     ) -> io::Result<()> {
-        if self.is_multithreaded {
+        self.clear_progress()?;
+        if self.config.is_multithreaded {
             self.write_test_name(desc)?;
         }
 
-        match *result {
-            TestResult::TrOk => self.write_ok(),
-            TestResult::TrFailed | TestResult::TrFailedMsg(_) => {
-                self.write_failed()
+        self.write_result_line(desc, result, exec_time, output.allocations)?;
+        if self.config.stream_failures {
+            match failure_output(result, output) {
+                Some(ref output) if !output.is_empty() => {
+                    self.write_plain(format!(
+                        "---- {} stdout ----\n",
+                        desc.name
+                    ))?;
+                    self.write_plain(String::from_utf8_lossy(output))?;
+                    self.write_plain("\n")?;
+                }
+                _ => {}
             }
-            TestResult::TrIgnored => self.write_ignored(),
-            TestResult::TrAllowedFail => self.write_allowed_fail(),
-            TestResult::TrBench(ref bs) => {
-                self.write_bench()?;
-                self.write_plain(&format!(": {}\n", fmt_bench_samples(bs)))
+        }
+
+        if let Some(ref mut p) = self.progress {
+            p.running = p.running.saturating_sub(1);
+            p.done += 1;
+            match *result {
+                TestResult::TrFailed
+                | TestResult::TrFailedErr
+                | TestResult::TrFailedMsg(_)
+                | TestResult::TrFailedShouldPanic(_)
+                | TestResult::TrTimedOut(_) => p.failed += 1,
+                _ => {}
             }
         }
+        self.draw_progress(true)
     }
 
     fn write_timeout(&mut self, desc: &TestDesc) -> io::Result<()> {
-        if self.is_multithreaded {
+        self.clear_progress()?;
+        if self.config.is_multithreaded {
             self.write_test_name(desc)?;
         }
 
         self.write_plain(&format!(
             "test {} has been running for over {} seconds\n",
-            desc.name, TEST_WARN_TIMEOUT_S
-        ))
+            desc.name,
+            self.config.warn_timeout.as_secs()
+        ))?;
+        self.draw_progress(true)
     }
 
     fn write_run_finish(
         &mut self,
         state: &ConsoleTestState,
     ) -> io::Result<bool> {
+        self.clear_progress()?;
         if state.options.display_output {
             self.write_successes(state)?;
         }
-        let success = state.failed == 0;
-        if !success {
+        let success = state.success();
+        if state.summary.failed > 0 {
             self.write_failures(state)?;
         }
 
@@ -228,22 +390,15 @@ impl<T: Write> OutputFormatter for PrettyFormatter<T> {
             self.write_pretty("FAILED", term::color::RED)?;
         }
 
-        let s = if state.allowed_fail > 0 {
-            format!(
-                ". {} passed; {} failed ({} allowed); {} ignored; {} measured; {} filtered out\n\n",
-                state.passed,
-                state.failed + state.allowed_fail,
-                state.allowed_fail,
-                state.ignored,
-                state.measured,
-                state.filtered_out
-            )
-        } else {
-            format!(
-                ". {} passed; {} failed; {} ignored; {} measured; {} filtered out\n\n",
-                state.passed, state.failed, state.ignored, state.measured, state.filtered_out
-            )
+        let pass_rate = match state.min_pass_rate {
+            Some(min) => format!(
+                "; pass rate {:.2}% (minimum {}%)",
+                state.pass_rate(),
+                min
+            ),
+            None => String::new(),
         };
+        let s = format!(". {}{}\n\n", state.result_counts(), pass_rate);
 
         self.write_plain(&s)?;
 
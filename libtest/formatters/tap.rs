@@ -0,0 +1,182 @@
+//! The `--format=tap` output, in version 13 of the Test Anything Protocol,
+//! for the many tools that consume TAP.
+
+use super::*;
+
+pub(crate) struct TapFormatter<T> {
+    out: OutputLocation<T>,
+    /// The number of the last test point written.
+    test_number: usize,
+}
+
+impl<T: Write> TapFormatter<T> {
+    pub fn new(out: OutputLocation<T>) -> Self {
+        Self {
+            out,
+            test_number: 0,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn output_location(&self) -> &OutputLocation<T> {
+        &self.out
+    }
+
+    /// Writes `s` as one line of the output.
+    fn write_message(&mut self, s: &str) -> io::Result<()> {
+        assert!(!s.contains('\n'));
+
+        self.out.write_all(s.as_bytes())?;
+        self.out.write_all(b"\n")
+    }
+
+    /// Writes the next test point, `ok N - name` or `not ok N - name`,
+    /// followed by `directive` if there is one.
+    fn write_test_point(
+        &mut self,
+        ok: bool,
+        desc: &TestDesc,
+        directive: Option<String>,
+    ) -> io::Result<()> {
+        self.test_number += 1;
+        // An unescaped `#` would start a directive.
+        let name = one_line(desc.name.as_slice()).replace('#', "\\#");
+        let status = if ok { "ok" } else { "not ok" };
+        let line = match directive {
+            Some(directive) => format!(
+                "{} {} - {} # {}",
+                status,
+                self.test_number,
+                name,
+                one_line(&directive)
+            ),
+            None => format!("{} {} - {}", status, self.test_number, name),
+        };
+        self.write_message(&line)
+    }
+
+    /// Writes the YAML diagnostics block of a failed test, with the reason
+    /// it failed if the result has one, and its captured output if any.
+    fn write_diagnostics(
+        &mut self,
+        message: Option<String>,
+        output: &[u8],
+    ) -> io::Result<()> {
+        if message.is_none() && output.is_empty() {
+            return Ok(());
+        }
+        self.write_message("  ---")?;
+        if let Some(message) = message {
+            self.write_message(&format!(
+                "  message: \"{}\"",
+                EscapedString(message)
+            ))?;
+        }
+        if !output.is_empty() {
+            self.write_message(&format!(
+                "  output: \"{}\"",
+                EscapedString(String::from_utf8_lossy(output))
+            ))?;
+        }
+        self.write_message("  ...")
+    }
+}
+
+/// Escapes the line breaks in `s`, which would end a test point early.
+fn one_line(s: &str) -> String {
+    s.replace('\r', "\\r").replace('\n', "\\n")
+}
+
+/// Why a failed test failed, for its diagnostics, or `None` if the result
+/// doesn't say.
+fn failure_message(result: &TestResult) -> Option<String> {
+    match *result {
+        TestResult::TrFailedMsg(ref msg) => Some(msg.clone()),
+        TestResult::TrFailedShouldPanic(ref mismatch) => {
+            Some(mismatch.to_string())
+        }
+        TestResult::TrTimedOut(timeout) => {
+            Some(format!("timed out after {:?}", timeout))
+        }
+        _ => None,
+    }
+}
+
+impl<T: Write> OutputFormatter for TapFormatter<T> {
+    fn write_run_start(&mut self, test_count: usize) -> io::Result<()> {
+        self.write_message("TAP version 13")?;
+        self.write_message(&format!("1..{}", test_count))
+    }
+
+    fn write_test_start(&mut self, _desc: &TestDesc) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_timeout(&mut self, desc: &TestDesc) -> io::Result<()> {
+        let name = one_line(desc.name.as_slice());
+        self.write_message(&format!("# {} is still running", name))
+    }
+
+    fn write_result(
+        &mut self,
+        desc: &TestDesc,
+        result: &TestResult,
+        _exec_time: Option<Duration>,
+        output: &TestOutput,
+    ) -> io::Result<()> {
+        match *result {
+            TestResult::TrOk => self.write_test_point(true, desc, None),
+            TestResult::TrOkWithRetries(n) => self.write_test_point(
+                true,
+                desc,
+                Some(format!("passed after {} retries", n)),
+            ),
+            TestResult::TrIgnored => {
+                let reason = desc.ignore_message.map(str::to_owned);
+                let directive = match reason {
+                    Some(reason) => format!("SKIP {}", reason),
+                    None => "SKIP".to_owned(),
+                };
+                self.write_test_point(true, desc, Some(directive))
+            }
+            TestResult::TrIgnoredMsg(ref reason) => self.write_test_point(
+                true,
+                desc,
+                Some(format!("SKIP {}", reason)),
+            ),
+            // A TODO test is one that is expected to fail, so its failure
+            // doesn't fail the run.
+            TestResult::TrAllowedFail => self.write_test_point(
+                false,
+                desc,
+                Some("TODO allowed to fail".to_owned()),
+            ),
+            TestResult::TrBench(ref bs) => self.write_test_point(
+                true,
+                desc,
+                Some(format!("bench: {}", fmt_bench_samples(bs))),
+            ),
+            TestResult::TrFailed
+            | TestResult::TrFailedErr
+            | TestResult::TrFailedMsg(_)
+            | TestResult::TrFailedShouldPanic(_)
+            | TestResult::TrTimedOut(_) => {
+                self.write_test_point(false, desc, None)?;
+                self.write_diagnostics(
+                    failure_message(result),
+                    &output.combined,
+                )
+            }
+        }
+    }
+
+    fn write_run_finish(
+        &mut self,
+        state: &ConsoleTestState,
+    ) -> io::Result<bool> {
+        self.write_message(&format!("# {}", state.result_counts()))?;
+        self.out.flush()?;
+
+        Ok(state.success())
+    }
+}
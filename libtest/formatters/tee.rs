@@ -0,0 +1,70 @@
+//! Reporting one run through two formatters at once.
+
+use super::*;
+
+/// Passes every event to `primary` and then to `secondary`. Only `primary`
+/// decides whether the run succeeded.
+pub(crate) struct TeeFormatter {
+    primary: Box<dyn OutputFormatter>,
+    secondary: Box<dyn OutputFormatter>,
+}
+
+impl TeeFormatter {
+    pub fn new(
+        primary: Box<dyn OutputFormatter>,
+        secondary: Box<dyn OutputFormatter>,
+    ) -> Self {
+        Self { primary, secondary }
+    }
+}
+
+impl OutputFormatter for TeeFormatter {
+    fn write_run_start(&mut self, test_count: usize) -> io::Result<()> {
+        self.primary.write_run_start(test_count)?;
+        self.secondary.write_run_start(test_count)
+    }
+
+    fn write_test_start(&mut self, desc: &TestDesc) -> io::Result<()> {
+        self.primary.write_test_start(desc)?;
+        self.secondary.write_test_start(desc)
+    }
+
+    fn write_timeout(&mut self, desc: &TestDesc) -> io::Result<()> {
+        self.primary.write_timeout(desc)?;
+        self.secondary.write_timeout(desc)
+    }
+
+    fn write_tick(&mut self) -> io::Result<()> {
+        self.primary.write_tick()?;
+        self.secondary.write_tick()
+    }
+
+    fn write_skipped(
+        &mut self,
+        desc: &TestDesc,
+        reason: SkipReason,
+    ) -> io::Result<()> {
+        self.primary.write_skipped(desc, reason)?;
+        self.secondary.write_skipped(desc, reason)
+    }
+
+    fn write_result(
+        &mut self,
+        desc: &TestDesc,
+        result: &TestResult,
+        exec_time: Option<Duration>,
+        output: &TestOutput,
+    ) -> io::Result<()> {
+        self.primary.write_result(desc, result, exec_time, output)?;
+        self.secondary.write_result(desc, result, exec_time, output)
+    }
+
+    fn write_run_finish(
+        &mut self,
+        state: &ConsoleTestState,
+    ) -> io::Result<bool> {
+        let success = self.primary.write_run_finish(state)?;
+        self.secondary.write_run_finish(state)?;
+        Ok(success)
+    }
+}
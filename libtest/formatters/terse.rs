@@ -2,32 +2,32 @@ use super::*;
 
 pub(crate) struct TerseFormatter<T> {
     out: OutputLocation<T>,
-    use_color: bool,
-    is_multithreaded: bool,
-    /// Number of columns to fill when aligning names
-    max_name_len: usize,
+    /// With `show_progress`, a `[done/total]` count is kept after the last
+    /// result on the line, erased before anything else is written
+    config: DisplayConfig,
+    /// Length of the count currently drawn, if any
+    progress_len: usize,
 
     test_count: usize,
     total_test_count: usize,
 }
 
 impl<T: Write> TerseFormatter<T> {
-    pub fn new(
-        out: OutputLocation<T>,
-        use_color: bool,
-        max_name_len: usize,
-        is_multithreaded: bool,
-    ) -> Self {
+    pub fn new(out: OutputLocation<T>, config: DisplayConfig) -> Self {
         Self {
             out,
-            use_color,
-            max_name_len,
-            is_multithreaded,
+            config,
+            progress_len: 0,
             test_count: 0,
             total_test_count: 0, // initialized later, when write_run_start is called
         }
     }
 
+    #[cfg(test)]
+    pub fn output_location(&self) -> &OutputLocation<T> {
+        &self.out
+    }
+
     pub fn write_ok(&mut self) -> io::Result<()> {
         self.write_short_result(".", term::color::GREEN)
     }
@@ -54,8 +54,9 @@ impl<T: Write> TerseFormatter<T> {
         color: term::color::Color,
     ) -> io::Result<()> {
         self.write_pretty(result, color)?;
-        if self.test_count % QUIET_MODE_MAX_COLUMN == QUIET_MODE_MAX_COLUMN - 1
-        {
+        let line_full = self.test_count % QUIET_MODE_MAX_COLUMN
+            == QUIET_MODE_MAX_COLUMN - 1;
+        if line_full {
             // we insert a new line every 100 dots in order to flush the
             // screen when dealing with line-buffered output (e.g., piping to
             // `stamp` in the rust CI).
@@ -68,21 +69,45 @@ impl<T: Write> TerseFormatter<T> {
         }
 
         self.test_count += 1;
+        if self.config.show_progress && !line_full {
+            self.draw_progress()?;
+        }
         Ok(())
     }
 
+    fn draw_progress(&mut self) -> io::Result<()> {
+        let count = format!("[{}/{}]", self.test_count, self.total_test_count);
+        self.out.write_all(count.as_bytes())?;
+        self.out.flush()?;
+        self.progress_len = count.len();
+        Ok(())
+    }
+
+    /// Erases the count by backing over it, so that the next result lands
+    /// right after the previous one.
+    fn clear_progress(&mut self) -> io::Result<()> {
+        let len = std::mem::replace(&mut self.progress_len, 0);
+        if len == 0 {
+            return Ok(());
+        }
+        let back = "\x08".repeat(len);
+        let erase = format!("{}{}{}", back, " ".repeat(len), back);
+        self.out.write_all(erase.as_bytes())
+    }
+
     pub fn write_pretty(
         &mut self,
         word: &str,
         color: term::color::Color,
     ) -> io::Result<()> {
+        self.clear_progress()?;
         match self.out {
             OutputLocation::Pretty(ref mut term) => {
-                if self.use_color {
+                if self.config.use_color {
                     term.fg(color)?;
                 }
                 term.write_all(word.as_bytes())?;
-                if self.use_color {
+                if self.config.use_color {
                     term.reset()?;
                 }
                 term.flush()
@@ -95,6 +120,7 @@ impl<T: Write> TerseFormatter<T> {
     }
 
     pub fn write_plain<S: AsRef<str>>(&mut self, s: S) -> io::Result<()> {
+        self.clear_progress()?;
         let s = s.as_ref();
         self.out.write_all(s.as_bytes())?;
         self.out.flush()
@@ -136,7 +162,7 @@ impl<T: Write> TerseFormatter<T> {
         self.write_plain("\nfailures:\n")?;
         let mut failures = Vec::new();
         let mut fail_out = String::new();
-        for &(ref f, ref stdout) in &state.failures {
+        for &(ref f, ref stdout) in &state.summary.failures {
             failures.push(f.name.to_string());
             if !stdout.is_empty() {
                 fail_out.push_str(&format!("---- {} stdout ----\n", f.name));
@@ -159,8 +185,14 @@ impl<T: Write> TerseFormatter<T> {
     }
 
     fn write_test_name(&mut self, desc: &TestDesc) -> io::Result<()> {
-        let name = desc.padded_name(self.max_name_len, desc.name.padding());
-        self.write_plain(&format!("test {} ... ", name))?;
+        let name =
+            desc.padded_name(self.config.max_name_len, desc.name.padding());
+        let bench = if desc.is_converted_bench {
+            " (bench)"
+        } else {
+            ""
+        };
+        self.write_plain(&format!("test {}{} ... ", name, bench))?;
 
         Ok(())
     }
@@ -178,7 +210,7 @@ impl<T: Write> OutputFormatter for TerseFormatter<T> {
         // in order to indicate benchmarks.
         // When running benchmarks, terse-mode should still print their name as if
         // it is the Pretty formatter.
-        if !self.is_multithreaded
+        if !self.config.is_multithreaded
             && desc.name.padding() == NamePadding::PadOnRight
         {
             self.write_test_name(desc)?;
@@ -191,21 +223,38 @@ impl<T: Write> OutputFormatter for TerseFormatter<T> {
         &mut self,
         desc: &TestDesc,
         result: &TestResult,
-        _: &[u8],
+        _: Option<Duration>,
+        _: &TestOutput,
     ) -> io::Result<()> {
         match *result {
-            TestResult::TrOk => self.write_ok(),
-            TestResult::TrFailed | TestResult::TrFailedMsg(_) => {
-                self.write_failed()
+            TestResult::TrOk | TestResult::TrOkWithRetries(_) => {
+                self.write_ok()
+            }
+            TestResult::TrFailed
+            | TestResult::TrFailedErr
+            | TestResult::TrFailedMsg(_)
+            | TestResult::TrFailedShouldPanic(_)
+            | TestResult::TrTimedOut(_) => self.write_failed(),
+            TestResult::TrIgnored | TestResult::TrIgnoredMsg(_) => {
+                self.write_ignored()
             }
-            TestResult::TrIgnored => self.write_ignored(),
             TestResult::TrAllowedFail => self.write_allowed_fail(),
             TestResult::TrBench(ref bs) => {
-                if self.is_multithreaded {
+                if self.config.is_multithreaded {
                     self.write_test_name(desc)?;
                 }
                 self.write_bench()?;
-                self.write_plain(&format!(": {}\n", fmt_bench_samples(bs)))
+                self.write_plain(&format!(": {}", fmt_bench_samples(bs)))?;
+                if self.config.show_bench_throughput {
+                    self.write_plain(fmt_bench_throughput(bs))?;
+                }
+                if self.config.show_bench_rsd {
+                    self.write_pretty(
+                        &fmt_bench_rsd(bs),
+                        bench_rsd_color(bs),
+                    )?;
+                }
+                self.write_plain("\n")
             }
         }
     }
@@ -213,7 +262,8 @@ impl<T: Write> OutputFormatter for TerseFormatter<T> {
     fn write_timeout(&mut self, desc: &TestDesc) -> io::Result<()> {
         self.write_plain(&format!(
             "test {} has been running for over {} seconds\n",
-            desc.name, TEST_WARN_TIMEOUT_S
+            desc.name,
+            self.config.warn_timeout.as_secs()
         ))
     }
 
@@ -224,8 +274,8 @@ impl<T: Write> OutputFormatter for TerseFormatter<T> {
         if state.options.display_output {
             self.write_outputs(state)?;
         }
-        let success = state.failed == 0;
-        if !success {
+        let success = state.success();
+        if state.summary.failed > 0 {
             self.write_failures(state)?;
         }
 
@@ -238,22 +288,15 @@ impl<T: Write> OutputFormatter for TerseFormatter<T> {
             self.write_pretty("FAILED", term::color::RED)?;
         }
 
-        let s = if state.allowed_fail > 0 {
-            format!(
-                ". {} passed; {} failed ({} allowed); {} ignored; {} measured; {} filtered out\n\n",
-                state.passed,
-                state.failed + state.allowed_fail,
-                state.allowed_fail,
-                state.ignored,
-                state.measured,
-                state.filtered_out
-            )
-        } else {
-            format!(
-                ". {} passed; {} failed; {} ignored; {} measured; {} filtered out\n\n",
-                state.passed, state.failed, state.ignored, state.measured, state.filtered_out
-            )
+        let pass_rate = match state.min_pass_rate {
+            Some(min) => format!(
+                "; pass rate {:.2}% (minimum {}%)",
+                state.pass_rate(),
+                min
+            ),
+            None => String::new(),
         };
+        let s = format!(". {}{}\n\n", state.result_counts(), pass_rate);
 
         self.write_plain(&s)?;
 
@@ -33,30 +33,46 @@ use std::{
     any::Any,
     borrow::Cow,
     boxed::FnBox,
+    cell::{Cell, RefCell},
     cmp,
-    collections::BTreeMap,
-    env, fmt,
-    fs::File,
+    collections::{BTreeMap, HashMap, HashSet},
+    env, error, fmt,
+    fs::{self, File},
     io::{self, prelude::*},
-    panic::{catch_unwind, AssertUnwindSafe},
-    path::PathBuf,
+    panic::{self, catch_unwind, AssertUnwindSafe},
+    path::{Path, PathBuf},
     process::{self, Termination},
+    ptr,
     sync::{
         mpsc::{channel, Sender},
-        Arc, Mutex,
+        Arc, Mutex, Once, PoisonError,
     },
     thread,
     time::{Duration, Instant},
 };
 
 const TEST_WARN_TIMEOUT_S: u64 = 60;
+const PROGRESS_REFRESH_MS: u64 = 100; // how often `--progress` redraws when idle
+const BENCH_HISTOGRAM_BUCKETS: usize = 10; // rows drawn by `--bench-histogram`
 const QUIET_MODE_MAX_COLUMN: usize = 100; // insert a '\n' after 100 tests in quiet mode
 
+#[cfg(feature = "track-allocations")]
+mod alloc_counter;
+#[cfg(unix)]
+mod fd_capture;
 mod formatters;
+pub mod plugin;
+#[cfg(all(unix, feature = "process-isolation"))]
+mod process_isolation;
 pub mod stats;
 
+#[cfg(feature = "track-allocations")]
+pub use crate::alloc_counter::CountingAllocator;
+pub use crate::formatters::OutputFormatter;
 use crate::formatters::{
-    JsonFormatter, OutputFormatter, PrettyFormatter, TerseFormatter,
+    BinaryFormatter, DisplayConfig, EscapedString, JUnitFormatter,
+    JsonFormatter, PrettyFormatter, TapFormatter, TeeFormatter,
+    TerseFormatter,
 };
 
 /// Whether to execute tests concurrently or not
@@ -179,7 +195,55 @@ impl fmt::Debug for TestFn {
 pub struct Bencher {
     mode: BenchMode,
     summary: Option<stats::Summary>,
+    samples: Vec<f64>,
+    /// How many samples each round of measurements takes.
+    sample_count: usize,
+    iterations: u64,
+    saturated: bool,
+    /// How many bytes a single iteration processes, used to report the
+    /// throughput in MB/s. Not a total for the whole benchmark: it can be
+    /// set once before calling `iter`.
     pub bytes: u64,
+    /// Extra metrics recorded by the benchmark with `record_metric`.
+    metrics: MetricMap,
+    /// What iterations are timed with; shared rather than boxed so that
+    /// `Bencher` stays `Clone`.
+    clock: Arc<dyn Clock + Send + Sync>,
+}
+
+/// A source of timestamps for benchmarks, counting in whatever ticks suit
+/// it, such as CPU cycles or retired instructions. The summaries and samples
+/// of a benchmark are in the ticks of its clock, so MB/s figures are only
+/// meaningful for clocks counting nanoseconds.
+pub trait Clock {
+    /// The current time in ticks since an arbitrary, fixed point.
+    fn now(&self) -> u64;
+}
+
+/// The default `Clock`, counting wall-clock nanoseconds.
+#[derive(Clone, Copy, Debug)]
+pub struct WallClock {
+    origin: Instant,
+}
+
+impl WallClock {
+    pub fn new() -> Self {
+        WallClock {
+            origin: Instant::now(),
+        }
+    }
+}
+
+impl Default for WallClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for WallClock {
+    fn now(&self) -> u64 {
+        ns_from_dur(self.origin.elapsed())
+    }
 }
 
 #[derive(Clone, PartialEq, Eq)]
@@ -193,6 +257,55 @@ pub enum ShouldPanic {
     No,
     Yes,
     YesWithMessage(&'static str),
+    /// Panics with a message matching this regular expression. Needs the
+    /// `regex` feature.
+    YesWithRegex(&'static str),
+}
+
+/// The kind of target a test was declared in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum TestType {
+    /// A `#[test]` in a library or binary crate.
+    UnitTest,
+    /// A `#[test]` in a crate under `tests/`.
+    IntegrationTest,
+    /// A code block in a doc comment.
+    DocTest,
+    /// A `#[bench]` function.
+    Benchmark,
+    /// Not known, e.g. for tests built by hand.
+    Unknown,
+}
+
+impl TestType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TestType::UnitTest => "unit",
+            TestType::IntegrationTest => "integration",
+            TestType::DocTest => "doc",
+            TestType::Benchmark => "bench",
+            TestType::Unknown => "unknown",
+        }
+    }
+
+    fn from_str(s: &str) -> Option<Self> {
+        [
+            TestType::UnitTest,
+            TestType::IntegrationTest,
+            TestType::DocTest,
+            TestType::Benchmark,
+            TestType::Unknown,
+        ]
+        .iter()
+        .cloned()
+        .find(|ty| ty.as_str() == s)
+    }
+}
+
+impl Default for TestType {
+    fn default() -> Self {
+        TestType::Unknown
+    }
 }
 
 // The definition of a single test. A test runner will run a list of
@@ -201,8 +314,20 @@ pub enum ShouldPanic {
 pub struct TestDesc {
     pub name: TestName,
     pub ignore: bool,
+    /// Why the test is ignored, from `#[ignore = "reason"]`.
+    pub ignore_message: Option<&'static str>,
     pub should_panic: ShouldPanic,
     pub allow_fail: bool,
+    pub test_type: TestType,
+    /// Don't warn when the test is still running after 60 seconds, for
+    /// tests that are expected to be slow.
+    pub no_timeout_warning: bool,
+    /// A benchmark that `convert_benchmarks_to_tests` turned into a test run
+    /// once, shown as `test foo (bench) ... ok`.
+    pub is_converted_bench: bool,
+    /// Categories the test belongs to, e.g. `"network"`, for selecting
+    /// tests with `--include-tag` and `--exclude-tag`.
+    pub tags: &'static [&'static str],
 }
 
 #[derive(Debug)]
@@ -223,10 +348,73 @@ impl Metric {
     }
 }
 
+/// How a metric moved from one run to the next, as classified by
+/// `MetricMap::compare_to_old`.
+#[derive(Clone, PartialEq, Debug, Copy)]
+pub enum MetricChange {
+    /// Moved the wanted way by more than the noise; the change as a
+    /// percentage of the old value.
+    Improved(f64),
+    /// Moved the unwanted way by more than the noise; the change as a
+    /// percentage of the old value.
+    Regressed(f64),
+    /// Moved by no more than the noise.
+    Unchanged,
+    /// Only in the new map.
+    Added,
+    /// Only in the old map.
+    Removed,
+}
+
+/// The change of every metric in either of two compared `MetricMap`s.
+pub type MetricDiff = BTreeMap<String, MetricChange>;
+
+/// Computes the process exit code of `test_main` from the final state of the
+/// run.
+pub type ExitCodeFn = fn(&ConsoleTestState) -> i32;
+
+/// Returns how many tests to run at once when `--test-threads` isn't given.
+pub type ConcurrencyFn = fn() -> usize;
+
+/// Called with a test's description just before or after it runs.
+pub type TestHookFn = fn(&TestDesc);
+
+/// Extracts a message from a panic payload of a type this crate doesn't
+/// know about, to be matched against `#[should_panic(expected = "...")]`.
+pub type PanicMessageFn = fn(&(dyn Any + Send)) -> Option<String>;
+
+/// Reads the allocation counters of the calling thread, for
+/// `--track-allocations`.
+pub type AllocationCounterFn = fn() -> AllocationStats;
+
+#[cfg(feature = "track-allocations")]
+const DEFAULT_ALLOCATION_COUNTER: Option<AllocationCounterFn> =
+    Some(CountingAllocator::thread_stats);
+#[cfg(not(feature = "track-allocations"))]
+const DEFAULT_ALLOCATION_COUNTER: Option<AllocationCounterFn> = None;
+
+/// The counter `--track-allocations` reads, if it was given and there is
+/// one to read.
+fn allocation_counter(opts: &TestOpts) -> Option<AllocationCounterFn> {
+    if opts.track_allocations {
+        opts.options
+            .allocation_counter
+            .or(DEFAULT_ALLOCATION_COUNTER)
+    } else {
+        None
+    }
+}
+
 /// In case we want to add other options as well, just add them in this struct.
-#[derive(Copy, Clone, Debug, Default)]
+#[derive(Copy, Clone, Default)]
 pub struct Options {
     display_output: bool,
+    exit_code_fn: Option<ExitCodeFn>,
+    concurrency_provider: Option<ConcurrencyFn>,
+    before_each: Option<TestHookFn>,
+    after_each: Option<TestHookFn>,
+    panic_message: Option<PanicMessageFn>,
+    allocation_counter: Option<AllocationCounterFn>,
 }
 
 impl Options {
@@ -238,8 +426,98 @@ impl Options {
         self.display_output = display_output;
         self
     }
+
+    /// Replaces the default exit code of `test_main`, which is 101 if any
+    /// test failed and 0 otherwise.
+    pub fn exit_code_fn(mut self, exit_code_fn: ExitCodeFn) -> Self {
+        self.exit_code_fn = Some(exit_code_fn);
+        self
+    }
+
+    /// Decides the number of test threads in place of `RUST_TEST_THREADS`
+    /// and the CPU count. `--test-threads` still takes precedence.
+    pub fn concurrency_provider(mut self, provider: ConcurrencyFn) -> Self {
+        self.concurrency_provider = Some(provider);
+        self
+    }
+
+    /// Runs `hook` on each test's thread right before the test, with its
+    /// output captured along with the test's, e.g. to set up thread-locals.
+    /// If the hook panics, the test fails without being run. Benchmarks
+    /// don't run hooks.
+    pub fn before_each(mut self, hook: TestHookFn) -> Self {
+        self.before_each = Some(hook);
+        self
+    }
+
+    /// Runs `hook` on each test's thread right after the test, whether it
+    /// passed or not, unless the `before_each` hook panicked. If the hook
+    /// panics, the test fails.
+    pub fn after_each(mut self, hook: TestHookFn) -> Self {
+        self.after_each = Some(hook);
+        self
+    }
+
+    /// Tries `panic_message` on the payloads of panics that aren't strings
+    /// or boxed errors, when checking the message of a `#[should_panic]`
+    /// test.
+    pub fn panic_message(mut self, panic_message: PanicMessageFn) -> Self {
+        self.panic_message = Some(panic_message);
+        self
+    }
+
+    /// Reads the allocation counters that `--track-allocations` takes a
+    /// snapshot of on each test's thread, before and after the test and
+    /// its hooks run. The counters may be maintained by a global allocator
+    /// such as `CountingAllocator`, or by the tests themselves.
+    pub fn allocation_counter(mut self, counter: AllocationCounterFn) -> Self {
+        self.allocation_counter = Some(counter);
+        self
+    }
 }
 
+// Function pointers taking a reference don't implement these traits, so
+// function pointers are compared and printed by address.
+impl fmt::Debug for Options {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Options")
+            .field("display_output", &self.display_output)
+            .field("exit_code_fn", &self.exit_code_fn.map(|f| f as usize))
+            .field(
+                "concurrency_provider",
+                &self.concurrency_provider.map(|f| f as usize),
+            )
+            .field("before_each", &self.before_each.map(|f| f as usize))
+            .field("after_each", &self.after_each.map(|f| f as usize))
+            .field("panic_message", &self.panic_message.map(|f| f as usize))
+            .field(
+                "allocation_counter",
+                &self.allocation_counter.map(|f| f as usize),
+            )
+            .finish()
+    }
+}
+
+impl PartialEq for Options {
+    fn eq(&self, other: &Self) -> bool {
+        self.display_output == other.display_output
+            && self.exit_code_fn.map(|f| f as usize)
+                == other.exit_code_fn.map(|f| f as usize)
+            && self.concurrency_provider.map(|f| f as usize)
+                == other.concurrency_provider.map(|f| f as usize)
+            && self.before_each.map(|f| f as usize)
+                == other.before_each.map(|f| f as usize)
+            && self.after_each.map(|f| f as usize)
+                == other.after_each.map(|f| f as usize)
+            && self.panic_message.map(|f| f as usize)
+                == other.panic_message.map(|f| f as usize)
+            && self.allocation_counter.map(|f| f as usize)
+                == other.allocation_counter.map(|f| f as usize)
+    }
+}
+
+impl Eq for Options {}
+
 // The default console test runner. It accepts the command line
 // arguments and a vector of test_descs.
 pub fn test_main(
@@ -247,28 +525,55 @@ pub fn test_main(
     tests: Vec<TestDescAndFn>,
     options: Options,
 ) {
+    let code = test_main_with_exit_code(args, tests, options);
+    if code != 0 {
+        process::exit(code);
+    }
+}
+
+/// Like `test_main`, but returns the exit code the process should end with
+/// instead of exiting, so that the caller can clean up or run more tests
+/// first.
+pub fn test_main_with_exit_code(
+    args: &[String],
+    tests: Vec<TestDescAndFn>,
+    options: Options,
+) -> i32 {
     let mut opts = match parse_opts(args) {
         Some(Ok(o)) => o,
         Some(Err(msg)) => {
             eprintln!("error: {}", msg);
-            process::exit(101);
+            return 101;
         }
-        None => return,
+        None => return 0,
     };
 
-    opts.options = options;
-    if opts.list {
+    // `--show-output` adds to what the caller asked for.
+    let display_output = options.display_output || opts.options.display_output;
+    opts.options = options.display_output(display_output);
+    if opts.track_allocations && allocation_counter(&opts).is_none() {
+        eprintln!(
+            "error: --track-allocations needs libtest to be built with the \
+             `track-allocations` feature, or a counter set with \
+             `Options::allocation_counter`"
+        );
+        return 101;
+    }
+    if opts.dump_config {
+        println!("{}", opts.dump_config());
+        0
+    } else if opts.list {
         if let Err(e) = list_tests_console(&opts, tests) {
             eprintln!("error: io error when listing tests: {:?}", e);
-            process::exit(101);
+            return 101;
         }
+        0
     } else {
-        match run_tests_console(&opts, tests) {
-            Ok(true) => {}
-            Ok(false) => process::exit(101),
+        match run_console(&opts, tests) {
+            Ok((_, code)) => code,
             Err(e) => {
                 eprintln!("error: io error when listing tests: {:?}", e);
-                process::exit(101);
+                101
             }
         }
     }
@@ -283,29 +588,171 @@ pub fn test_main(
 // rather than a &[].
 pub fn test_main_static(tests: &[&TestDescAndFn]) {
     let args = env::args().collect::<Vec<_>>();
-    let owned_tests = tests
-        .iter()
-        .map(|t| match t.testfn {
-            TestFn::StaticTestFn(f) => TestDescAndFn {
-                testfn: TestFn::StaticTestFn(f),
-                desc: t.desc.clone(),
-            },
-            TestFn::StaticBenchFn(f) => TestDescAndFn {
-                testfn: TestFn::StaticBenchFn(f),
-                desc: t.desc.clone(),
-            },
-            _ => panic!("non-static tests passed to test::test_main_static"),
-        })
-        .collect();
+    let owned_tests = tests.iter().map(|t| make_owned_test(t)).collect();
     test_main(&args, owned_tests, Options::new())
 }
 
+/// Copies a static test out of a borrowed test vector, for harnesses that
+/// collect their tests into a `&[&TestDescAndFn]` like `test_main_static`.
+///
+/// # Panics
+///
+/// Panics if the test is dynamic, since its function can't be copied.
+pub fn make_owned_test(test: &TestDescAndFn) -> TestDescAndFn {
+    let testfn = match test.testfn {
+        TestFn::StaticTestFn(f) => TestFn::StaticTestFn(f),
+        TestFn::StaticBenchFn(f) => TestFn::StaticBenchFn(f),
+        _ => {
+            panic!("non-static test `{}` can't be made owned", test.desc.name)
+        }
+    };
+    TestDescAndFn {
+        desc: test.desc.clone(),
+        testfn,
+    }
+}
+
+thread_local! {
+    /// Set by `assert_test_result` just before it panics, so that the
+    /// runner can tell a returned error apart from any other panic.
+    static RETURNED_ERR: Cell<bool> = Cell::new(false);
+
+    /// Where the test running on this thread last panicked, recorded by the
+    /// hook `PanicLocationHook` installs.
+    static PANIC_LOCATION: RefCell<Option<PanicLocation>> = RefCell::new(None);
+}
+
+/// How many `PanicLocationHook`s are alive, and how to put back the hook
+/// that was installed before the first of them.
+type PanicHookUsers = Mutex<(usize, Option<Box<dyn FnBox() + Send>>)>;
+
+fn panic_hook_users() -> &'static PanicHookUsers {
+    static INIT: Once = Once::new();
+    static mut USERS: *const PanicHookUsers = ptr::null();
+    unsafe {
+        INIT.call_once(|| {
+            USERS = Box::into_raw(Box::new(Mutex::new((0, None))));
+        });
+        &*USERS
+    }
+}
+
+/// Chains a panic hook that records each panic's location in
+/// `PANIC_LOCATION`, to report where failed tests panicked, for as long as
+/// it's alive. The previous hook still runs, so panics print as usual, and
+/// is put back once the last of any overlapping runs is done.
+struct PanicLocationHook;
+
+impl PanicLocationHook {
+    fn install() -> Self {
+        let mut users = panic_hook_users()
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        // A hook left in place by a run that ended in a panic is reused.
+        if users.1.is_none() {
+            let previous = Arc::new(panic::take_hook());
+            let chained = previous.clone();
+            panic::set_hook(Box::new(move |info| {
+                if let Some(location) = info.location() {
+                    let location = PanicLocation {
+                        file: location.file().to_owned(),
+                        line: location.line(),
+                        column: location.column(),
+                    };
+                    PANIC_LOCATION.with(|l| *l.borrow_mut() = Some(location));
+                }
+                chained(info);
+            }));
+            users.1 = Some(Box::new(move || {
+                // Dropping our hook drops its handle on `previous`.
+                drop(panic::take_hook());
+                match Arc::try_unwrap(previous) {
+                    Ok(previous) => panic::set_hook(previous),
+                    Err(previous) => {
+                        panic::set_hook(Box::new(move |info| previous(info)))
+                    }
+                }
+            }));
+        }
+        users.0 += 1;
+        PanicLocationHook
+    }
+}
+
+impl Drop for PanicLocationHook {
+    fn drop(&mut self) {
+        let mut users = panic_hook_users()
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        users.0 -= 1;
+        // The hook can't be changed while panicking, so it's left in place.
+        if users.0 == 0 && !thread::panicking() {
+            if let Some(restore) = users.1.take() {
+                restore();
+            }
+        }
+    }
+}
+
+/// Lines of context `source_snippet` shows on each side of the panic.
+const SNIPPET_CONTEXT: u32 = 2;
+
+/// Renders the lines of `file` around `line`, marking `line` itself, or
+/// `None` if the file can't be read (e.g. the tests run away from the
+/// sources) or is shorter than expected.
+fn source_snippet(file: &str, line: u32) -> Option<String> {
+    let source = fs::read_to_string(file).ok()?;
+    let first = line.saturating_sub(SNIPPET_CONTEXT).max(1);
+    let last = line + SNIPPET_CONTEXT;
+    let width = last.to_string().len();
+
+    let mut snippet = format!("note: panicked at {}:{}\n", file, line);
+    let mut found = false;
+    for (n, text) in (1..).zip(source.lines()) {
+        if n < first {
+            continue;
+        }
+        if n > last {
+            break;
+        }
+        found |= n == line;
+        let marker = if n == line { '>' } else { ' ' };
+        snippet.push_str(&format!(
+            "{} {:>w$} | {}\n",
+            marker,
+            n,
+            text,
+            w = width
+        ));
+    }
+    if found {
+        Some(snippet)
+    } else {
+        None
+    }
+}
+
+/// Stops the running test and reports it as ignored, with `reason` as the
+/// message, rather than as passed or failed. Meant for tests whose
+/// preconditions, e.g. some hardware or an environment variable, can only be
+/// checked at run time.
+///
+/// This unwinds the calling thread, so it must be called from the thread
+/// running the test, and it takes precedence over `#[should_panic]`.
+pub fn skip(reason: &str) -> ! {
+    panic::resume_unwind(Box::new(SkippedTest(reason.to_owned())))
+}
+
+/// The payload `skip` unwinds with.
+struct SkippedTest(String);
+
 /// Invoked when unit tests terminate. Should panic if the unit
 /// Tests is considered a failure. By default, invokes `report()`
 /// and checks for a `0` result.
 pub fn assert_test_result<T: Termination>(result: T) {
     let code = result.report();
     if code != 0 {
+        RETURNED_ERR.with(|r| r.set(true));
         panic!(
             "the test returned a termination value with a non-zero status code ({}) \
                 which indicates a failure (this most likely means your test returned \
@@ -315,10 +762,14 @@ pub fn assert_test_result<T: Termination>(result: T) {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum ColorConfig {
     AutoColor,
     AlwaysColor,
+    /// Like `AlwaysColor`, but with ANSI escape codes even where the
+    /// terminal would otherwise be driven through another API, such as the
+    /// Windows console.
+    AlwaysAnsiColor,
     NeverColor,
 }
 
@@ -327,6 +778,23 @@ pub enum OutputFormat {
     Pretty,
     Terse,
     Json,
+    Binary,
+    /// The Test Anything Protocol, version 13.
+    Tap,
+}
+
+/// The order in which the selected tests are started.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TestOrder {
+    /// By name.
+    Alphabetical,
+    /// By the duration recorded for each test in the `--timings` file,
+    /// shortest first.
+    FastestFirst,
+    /// By the duration recorded for each test in the `--timings` file,
+    /// longest first, so that the slow tests don't end up running alone at
+    /// the end of a parallel run.
+    SlowestFirst,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -336,41 +804,205 @@ pub enum RunIgnored {
     Only,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct TestOpts {
     pub list: bool,
-    pub filter: Option<String>,
+    pub list_runnable: bool,
+    /// Print the options as JSON, see `TestOpts::dump_config`, instead of
+    /// running or listing the tests.
+    pub dump_config: bool,
+    /// Run only the tests matching any of these; all of them if empty.
+    pub filters: Vec<String>,
     pub filter_exact: bool,
+    /// Match `filters` and `skip` as regular expressions. Needs the `regex`
+    /// feature.
+    pub filter_regex: bool,
+    /// Match `filters` and `skip` against whole leading path segments, so
+    /// that `base` selects `base` and `base::test` but not `database`.
+    pub filter_prefix: bool,
+    /// A file of exact test names, one per line, that are selected in
+    /// addition to those matching `filters`.
+    pub test_list_file: Option<PathBuf>,
+    /// Only run tests of this type.
+    pub test_kind: Option<TestType>,
     pub exclude_should_panic: bool,
     pub run_ignored: RunIgnored,
     pub run_tests: bool,
     pub bench_benchmarks: bool,
     pub logfile: Option<PathBuf>,
+    /// Size in bytes past which the logfile is rotated.
+    pub logfile_max_size: Option<u64>,
+    /// How many rotated logfiles to keep, as `PATH.1` (the newest) to
+    /// `PATH.N`.
+    pub logfile_keep: usize,
+    pub export_trace: Option<PathBuf>,
+    /// Also write a JUnit XML report of the run to this file.
+    pub junit_path: Option<PathBuf>,
     pub nocapture: bool,
+    /// Print the output of each failing test as soon as it fails, rather
+    /// than in the failures section at the end. Pretty format only.
+    pub stream_failures: bool,
+    pub capture_fds: bool,
+    /// Run each test in a forked child process, so that a crash or leftover
+    /// global state only affects that test. Needs the `process-isolation`
+    /// feature.
+    pub process_isolation: bool,
+    /// Run every test on the thread that calls `run_tests`, whatever the
+    /// number of test threads.
+    pub force_run_in_process: bool,
+    /// Show the source lines around where each failing test panicked.
+    pub backtrace_snippets: bool,
+    /// Count the allocations each test makes, with the counter set by
+    /// `Options::allocation_counter` or else `CountingAllocator`.
+    pub track_allocations: bool,
+    pub bench_rsd: bool,
+    /// Draw a histogram of each benchmark's samples, with the pretty format.
+    pub bench_histogram: bool,
+    /// Show each benchmark's iterations per second next to its time per
+    /// iteration.
+    pub bench_show_throughput: bool,
+    pub bench_pin_cpu: Option<usize>,
+    /// How many samples each round of benchmark measurements takes.
+    pub bench_samples: usize,
+    pub bench_save: Option<PathBuf>,
+    pub bench_compare: Option<PathBuf>,
+    pub bench_compare_md: bool,
+    pub bench_significance: bool,
+    /// Free-form annotation recorded with the benchmark results.
+    pub bench_note: Option<String>,
+    pub progress: bool,
     pub color: ColorConfig,
     pub format: OutputFormat,
+    /// How many tests to run at once, taking precedence over everything
+    /// else `resolve_concurrency` looks at.
     pub test_threads: Option<usize>,
+    pub chaos: Option<u64>,
+    /// Start the tests in a random order, shuffled with this seed.
+    pub shuffle: Option<u64>,
+    /// Fails tests that are still running after this long. Threads can't be
+    /// killed, so the test's thread is left running in the background and
+    /// its eventual result is ignored.
+    pub timeout: Option<Duration>,
+    /// Fails tests that pass, but take longer than this to do so.
+    pub max_test_time: Option<Duration>,
+    /// Warns about tests that are still running after this long, or never
+    /// if `None`.
+    pub warn_timeout: Option<Duration>,
+    /// Show how long each test took in the pretty output.
+    pub report_time: bool,
+    /// How long a test can take before `report_time` shows its time in
+    /// yellow.
+    pub report_time_warn: Duration,
+    /// How long a test can take before `report_time` shows its time in red.
+    pub report_time_critical: Duration,
     pub skip: Vec<String>,
+    /// Skip tests whose names are exactly one of these.
+    pub skip_exact: Vec<String>,
+    /// Only run tests with at least one of these tags, if there are any.
+    pub include_tags: Vec<String>,
+    /// Skip tests with any of these tags.
+    pub exclude_tags: Vec<String>,
+    pub shard: Option<(usize, usize)>,
+    pub order: TestOrder,
+    /// Keep the tests in the order they were passed in instead of sorting
+    /// them by name.
+    pub no_sort: bool,
+    /// An `--export-trace` file from a previous run, giving the durations
+    /// used by `TestOrder::FastestFirst` and `TestOrder::SlowestFirst`.
+    pub timings: Option<PathBuf>,
+    /// Start the tests missing from `timings` before the others rather than
+    /// after them.
+    pub untimed_first: bool,
+    /// Stop starting tests after the first failure.
+    pub fail_fast: bool,
+    pub fail_fast_on_new: bool,
+    pub known_failures: Option<PathBuf>,
+    /// Where the names of the tests that failed are written after each run.
+    pub failures_file: Option<PathBuf>,
+    /// Only run the tests listed in `failures_file` by the previous run.
+    pub last_failed: bool,
+    pub report_skipped: bool,
+    pub summary_by_module: bool,
+    pub retries: usize,
+    pub max_total_retries: Option<usize>,
+    /// Percentage of passed tests, out of those that passed or failed, at or
+    /// above which the run succeeds even if some tests failed.
+    pub min_pass_rate: Option<f64>,
+    /// Fail the run if no tests are left after filtering.
+    pub error_on_no_tests: bool,
     pub options: Options,
 }
 
 impl TestOpts {
-    #[cfg(test)]
     fn new() -> TestOpts {
         TestOpts {
             list: false,
-            filter: None,
+            list_runnable: false,
+            dump_config: false,
+            filters: Vec::new(),
             filter_exact: false,
+            filter_regex: false,
+            filter_prefix: false,
+            test_list_file: None,
+            test_kind: None,
             exclude_should_panic: false,
             run_ignored: RunIgnored::No,
             run_tests: false,
             bench_benchmarks: false,
             logfile: None,
+            logfile_max_size: None,
+            logfile_keep: 1,
+            export_trace: None,
+            junit_path: None,
             nocapture: false,
+            stream_failures: false,
+            capture_fds: false,
+            process_isolation: false,
+            force_run_in_process: false,
+            backtrace_snippets: false,
+            track_allocations: false,
+            bench_rsd: false,
+            bench_histogram: false,
+            bench_show_throughput: false,
+            bench_pin_cpu: None,
+            bench_samples: DEFAULT_BENCH_SAMPLES,
+            bench_save: None,
+            bench_compare: None,
+            bench_compare_md: false,
+            bench_significance: false,
+            bench_note: None,
+            progress: false,
             color: ColorConfig::AutoColor,
             format: OutputFormat::Pretty,
             test_threads: None,
+            chaos: None,
+            shuffle: None,
+            timeout: None,
+            max_test_time: None,
+            warn_timeout: Some(Duration::from_secs(TEST_WARN_TIMEOUT_S)),
+            report_time: false,
+            report_time_warn: Duration::from_millis(50),
+            report_time_critical: Duration::from_secs(1),
             skip: vec![],
+            skip_exact: vec![],
+            include_tags: vec![],
+            exclude_tags: vec![],
+            shard: None,
+            order: TestOrder::Alphabetical,
+            no_sort: false,
+            timings: None,
+            untimed_first: false,
+            fail_fast: false,
+            fail_fast_on_new: false,
+            known_failures: None,
+            failures_file: None,
+            last_failed: false,
+            report_skipped: false,
+            summary_by_module: false,
+            retries: 0,
+            max_total_retries: None,
+            min_pass_rate: None,
+            error_on_no_tests: false,
             options: Options::new(),
         }
     }
@@ -379,129 +1011,1176 @@ impl TestOpts {
 /// Result of parsing the options.
 pub type OptRes = Result<TestOpts, String>;
 
-fn optgroups() -> getopts::Options {
-    let mut opts = getopts::Options::new();
-    opts.optflag("", "include-ignored", "Run ignored and not ignored tests")
-        .optflag("", "ignored", "Run only ignored tests")
-        .optflag("", "exclude-should-panic", "Excludes tests marked as should_panic")
-        .optflag("", "test", "Run tests and not benchmarks")
-        .optflag("", "bench", "Run benchmarks instead of tests")
-        .optflag("", "list", "List all tests and benchmarks")
-        .optflag("h", "help", "Display this message (longer with --help)")
-        .optopt(
-            "",
-            "logfile",
-            "Write logs to the specified file instead \
-             of stdout",
-            "PATH",
-        )
-        .optflag(
-            "",
-            "nocapture",
-            "don't capture stdout/stderr of each \
-             task, allow printing directly",
-        )
-        .optopt(
-            "",
-            "test-threads",
-            "Number of threads used for running tests \
-             in parallel",
-            "n_threads",
-        )
-        .optmulti(
-            "",
-            "skip",
-            "Skip tests whose names contain FILTER (this flag can \
-             be used multiple times)",
-            "FILTER",
-        )
-        .optflag(
-            "q",
-            "quiet",
-            "Display one character per test instead of one line. \
-             Alias to --format=terse",
-        )
-        .optflag(
-            "",
-            "exact",
-            "Exactly match filters rather than by substring",
-        )
-        .optopt(
-            "",
-            "color",
-            "Configure coloring of output:
-            auto   = colorize if stdout is a tty and tests are run on serially (default);
-            always = always colorize output;
-            never  = never colorize output;",
-            "auto|always|never",
-        )
-        .optopt(
-            "",
-            "format",
-            "Configure formatting of output:
-            pretty = Print verbose output;
-            terse  = Display one character per test;
-            json   = Output a json document",
-            "pretty|terse|json",
-        )
-        .optopt(
-            "Z",
-            "",
-            "Enable nightly-only flags:
-            unstable-options = Allow use of experimental features",
-            "unstable-options",
-        );
-    opts
+/// The value of one option, as `TestOpts::encode` and
+/// `TestOpts::dump_config` write it.
+enum OptValue {
+    Bool(bool),
+    /// An integer, or a fraction for `min_pass_rate`
+    Number(String),
+    Text(String),
+    /// Every value of an option that can be given more than once
+    List(Vec<String>),
+    /// An optional setting that isn't set
+    Unset,
 }
 
-fn usage(binary: &str, options: &getopts::Options) {
-    let message = format!("Usage: {} [OPTIONS] [FILTER]", binary);
-    println!(
-        r#"{usage}
+impl OptValue {
+    fn number<T: ToString>(n: T) -> Self {
+        OptValue::Number(n.to_string())
+    }
 
-The FILTER string is tested against the name of all tests, and only those
-tests whose names contain the filter are run.
+    fn text(s: &str) -> Self {
+        OptValue::Text(s.to_owned())
+    }
 
-By default, all tests are run in parallel. This can be altered with the
---test-threads flag or the RUST_TEST_THREADS environment variable when running
-tests (set it to 1).
+    fn path(path: &Option<PathBuf>) -> Self {
+        match *path {
+            Some(ref path) => OptValue::Text(path.to_string_lossy().into()),
+            None => OptValue::Unset,
+        }
+    }
+}
 
-All tests have their standard output and standard error captured by default.
-This can be overridden with the --nocapture flag or setting RUST_TEST_NOCAPTURE
-environment variable to a value other than "0". Logging is not captured by default.
+impl TestOpts {
+    /// Encodes the fully resolved options as a single line of
+    /// `key=value` pairs separated by `;`, suitable for passing to a child
+    /// process through an environment variable. `TestOpts::decode` turns
+    /// the result back into the same options, without consulting the
+    /// command line or the environment again, except that durations are
+    /// rounded down to whole milliseconds, paths that aren't valid Unicode
+    /// are converted lossily, and of the caller's `Options` only
+    /// `display_output` is kept.
+    pub fn encode(&self) -> String {
+        let mut pairs = Vec::new();
+        for (key, value) in self.fields() {
+            match value {
+                OptValue::Bool(b) => pairs.push((key, b.to_string())),
+                OptValue::Number(s) | OptValue::Text(s) => {
+                    pairs.push((key, s))
+                }
+                OptValue::List(values) => {
+                    pairs.extend(values.into_iter().map(|v| (key, v)))
+                }
+                OptValue::Unset => {}
+            }
+        }
 
-Test Attributes:
+        let mut out = String::new();
+        for (key, value) in pairs {
+            if !out.is_empty() {
+                out.push(';');
+            }
+            out.push_str(key);
+            out.push('=');
+            for c in value.chars() {
+                match c {
+                    '%' => out.push_str("%25"),
+                    ';' => out.push_str("%3B"),
+                    '=' => out.push_str("%3D"),
+                    '\n' => out.push_str("%0A"),
+                    c => out.push(c),
+                }
+            }
+        }
+        out
+    }
 
-    #[test]        - Indicates a function is a test to be run. This function
-                     takes no arguments.
-    #[bench]       - Indicates a function is a benchmark to be run. This
-                     function takes one argument (test::Bencher).
-    #[should_panic] - This function (also labeled with #[test]) will only pass if
-                     the code causes a panic (an assertion failure or panic!)
-                     A message may be provided, which the failure string must
-                     contain: #[should_panic(expected = "foo")].
-    #[ignore]      - When applied to a function which is already attributed as a
-                     test, then the test runner will ignore these tests during
-                     normal test runs. Running with --ignored or --include-ignored will run
-                     these tests."#,
-        usage = options.usage(&message)
-    );
-}
+    /// Writes the options as a JSON object, for `--dump-config`, with the
+    /// keys and values `encode` uses. Flags are booleans, counts and
+    /// durations in milliseconds are numbers, options that can be given
+    /// more than once are arrays of strings and options that aren't set are
+    /// `null`. The object ends with the number of test threads and whether
+    /// color is used, as resolved from the options and the environment.
+    pub fn dump_config(&self) -> String {
+        let string = |s: &str| format!("\"{}\"", EscapedString(s));
+        let mut fields: Vec<(&str, String)> = Vec::new();
+        for (key, value) in self.fields() {
+            let json = match value {
+                OptValue::Bool(b) => b.to_string(),
+                OptValue::Number(n) => n,
+                OptValue::Text(s) => string(&s),
+                OptValue::List(values) => {
+                    let values: Vec<_> =
+                        values.iter().map(|v| string(v)).collect();
+                    format!("[{}]", values.join(", "))
+                }
+                OptValue::Unset => "null".to_owned(),
+            };
+            fields.push((key, json));
+        }
+        let threads = resolve_concurrency(self).to_string();
+        fields.push(("resolved_test_threads", threads));
+        fields.push(("resolved_color", use_color(self).to_string()));
 
-// FIXME: Copied from libsyntax until linkage errors are resolved. Issue #47566
-fn is_nightly() -> bool {
-    // Whether this is a feature-staged build, i.e., on the beta or stable channel
-    let disable_unstable_features =
-        option_env!("CFG_DISABLE_UNSTABLE_FEATURES").is_some();
-    // Whether we should enable unstable features for bootstrapping
-    let bootstrap = env::var("RUSTC_BOOTSTRAP").is_ok();
+        let lines: Vec<_> = fields
+            .iter()
+            .map(|&(key, ref json)| format!("  \"{}\": {}", key, json))
+            .collect();
+        format!("{{\n{}\n}}", lines.join(",\n"))
+    }
 
-    bootstrap || !disable_unstable_features
-}
+    /// Every option with its value, in the order `encode` writes them.
+    fn fields(&self) -> Vec<(&'static str, OptValue)> {
+        let TestOpts {
+            list,
+            list_runnable,
+            dump_config,
+            ref filters,
+            filter_exact,
+            filter_regex,
+            filter_prefix,
+            ref test_list_file,
+            test_kind,
+            exclude_should_panic,
+            run_ignored,
+            run_tests,
+            bench_benchmarks,
+            ref logfile,
+            logfile_max_size,
+            logfile_keep,
+            ref export_trace,
+            ref junit_path,
+            nocapture,
+            stream_failures,
+            capture_fds,
+            process_isolation,
+            force_run_in_process,
+            backtrace_snippets,
+            track_allocations,
+            bench_rsd,
+            bench_histogram,
+            bench_show_throughput,
+            bench_pin_cpu,
+            bench_samples,
+            ref bench_save,
+            ref bench_compare,
+            bench_compare_md,
+            bench_significance,
+            ref bench_note,
+            progress,
+            color,
+            format,
+            test_threads,
+            chaos,
+            shuffle,
+            timeout,
+            max_test_time,
+            warn_timeout,
+            report_time,
+            report_time_warn,
+            report_time_critical,
+            ref skip,
+            ref skip_exact,
+            ref include_tags,
+            ref exclude_tags,
+            shard,
+            order,
+            no_sort,
+            ref timings,
+            untimed_first,
+            fail_fast,
+            fail_fast_on_new,
+            ref known_failures,
+            ref failures_file,
+            last_failed,
+            report_skipped,
+            summary_by_module,
+            retries,
+            max_total_retries,
+            min_pass_rate,
+            error_on_no_tests,
+            // Function pointers can't cross into a child process; the exit
+            // code and the thread count are always decided by the parent,
+            // and the hooks, the panic message reader and the allocation
+            // counter are whatever the child passes to `test_main`.
+            options:
+                Options {
+                    display_output,
+                    exit_code_fn: _,
+                    concurrency_provider: _,
+                    before_each: _,
+                    after_each: _,
+                    panic_message: _,
+                    allocation_counter: _,
+                },
+        } = *self;
 
-// Parses command line arguments into test options
-pub fn parse_opts(args: &[String]) -> Option<OptRes> {
+        let mut fields = Vec::new();
+        let mut push = |key: &'static str, value: OptValue| {
+            fields.push((key, value));
+        };
+
+        push("list", OptValue::Bool(list));
+        push("list_runnable", OptValue::Bool(list_runnable));
+        push("dump_config", OptValue::Bool(dump_config));
+        push("filter", OptValue::List(filters.clone()));
+        push("filter_exact", OptValue::Bool(filter_exact));
+        push("filter_regex", OptValue::Bool(filter_regex));
+        push("filter_prefix", OptValue::Bool(filter_prefix));
+        push("test_list_file", OptValue::path(test_list_file));
+        push(
+            "test_kind",
+            test_kind.map_or(OptValue::Unset, |k| OptValue::text(k.as_str())),
+        );
+        push("exclude_should_panic", OptValue::Bool(exclude_should_panic));
+        push(
+            "run_ignored",
+            OptValue::text(match run_ignored {
+                RunIgnored::Yes => "yes",
+                RunIgnored::No => "no",
+                RunIgnored::Only => "only",
+            }),
+        );
+        push("run_tests", OptValue::Bool(run_tests));
+        push("bench_benchmarks", OptValue::Bool(bench_benchmarks));
+        push("logfile", OptValue::path(logfile));
+        push(
+            "logfile_max_size",
+            logfile_max_size.map_or(OptValue::Unset, OptValue::number),
+        );
+        push("logfile_keep", OptValue::number(logfile_keep));
+        push("export_trace", OptValue::path(export_trace));
+        push("junit_path", OptValue::path(junit_path));
+        push("nocapture", OptValue::Bool(nocapture));
+        push("stream_failures", OptValue::Bool(stream_failures));
+        push("capture_fds", OptValue::Bool(capture_fds));
+        push("process_isolation", OptValue::Bool(process_isolation));
+        push("force_run_in_process", OptValue::Bool(force_run_in_process));
+        push("backtrace_snippets", OptValue::Bool(backtrace_snippets));
+        push("track_allocations", OptValue::Bool(track_allocations));
+        push("bench_rsd", OptValue::Bool(bench_rsd));
+        push("bench_histogram", OptValue::Bool(bench_histogram));
+        push(
+            "bench_show_throughput",
+            OptValue::Bool(bench_show_throughput),
+        );
+        push(
+            "bench_pin_cpu",
+            bench_pin_cpu.map_or(OptValue::Unset, OptValue::number),
+        );
+        push("bench_samples", OptValue::number(bench_samples));
+        push("bench_save", OptValue::path(bench_save));
+        push("bench_compare", OptValue::path(bench_compare));
+        push("bench_compare_md", OptValue::Bool(bench_compare_md));
+        push("bench_significance", OptValue::Bool(bench_significance));
+        push(
+            "bench_note",
+            bench_note
+                .as_ref()
+                .map_or(OptValue::Unset, |n| OptValue::text(n)),
+        );
+        push("progress", OptValue::Bool(progress));
+        push(
+            "color",
+            OptValue::text(match color {
+                ColorConfig::AutoColor => "auto",
+                ColorConfig::AlwaysColor => "always",
+                ColorConfig::AlwaysAnsiColor => "always-ansi",
+                ColorConfig::NeverColor => "never",
+            }),
+        );
+        push(
+            "format",
+            OptValue::text(match format {
+                OutputFormat::Pretty => "pretty",
+                OutputFormat::Terse => "terse",
+                OutputFormat::Json => "json",
+                OutputFormat::Binary => "binary",
+                OutputFormat::Tap => "tap",
+            }),
+        );
+        push(
+            "test_threads",
+            test_threads.map_or(OptValue::Unset, OptValue::number),
+        );
+        push("chaos", chaos.map_or(OptValue::Unset, OptValue::number));
+        push("shuffle", shuffle.map_or(OptValue::Unset, OptValue::number));
+        let ms = |t: Duration| {
+            OptValue::number(t.as_secs() * 1000 + u64::from(t.subsec_millis()))
+        };
+        push("timeout_ms", timeout.map_or(OptValue::Unset, ms));
+        push(
+            "max_test_time_ms",
+            max_test_time.map_or(OptValue::Unset, ms),
+        );
+        // Zero stands for never warning, like for `--warn-timeout`.
+        push("warn_timeout_ms", ms(warn_timeout.unwrap_or_default()));
+        push("report_time", OptValue::Bool(report_time));
+        push("report_time_warn_ms", ms(report_time_warn));
+        push("report_time_critical_ms", ms(report_time_critical));
+        push("skip", OptValue::List(skip.clone()));
+        push("skip_exact", OptValue::List(skip_exact.clone()));
+        push("include_tag", OptValue::List(include_tags.clone()));
+        push("exclude_tag", OptValue::List(exclude_tags.clone()));
+        push(
+            "shard",
+            shard.map_or(OptValue::Unset, |(index, count)| {
+                OptValue::Text(format!("{}/{}", index, count))
+            }),
+        );
+        push(
+            "order",
+            OptValue::text(match order {
+                TestOrder::Alphabetical => "alphabetical",
+                TestOrder::FastestFirst => "fastest-first",
+                TestOrder::SlowestFirst => "slowest-first",
+            }),
+        );
+        push("no_sort", OptValue::Bool(no_sort));
+        push("timings", OptValue::path(timings));
+        push("untimed_first", OptValue::Bool(untimed_first));
+        push("fail_fast", OptValue::Bool(fail_fast));
+        push("fail_fast_on_new", OptValue::Bool(fail_fast_on_new));
+        push("known_failures", OptValue::path(known_failures));
+        push("failures_file", OptValue::path(failures_file));
+        push("last_failed", OptValue::Bool(last_failed));
+        push("report_skipped", OptValue::Bool(report_skipped));
+        push("summary_by_module", OptValue::Bool(summary_by_module));
+        push("retries", OptValue::number(retries));
+        push(
+            "max_total_retries",
+            max_total_retries.map_or(OptValue::Unset, OptValue::number),
+        );
+        push(
+            "min_pass_rate",
+            min_pass_rate.map_or(OptValue::Unset, OptValue::number),
+        );
+        push("error_on_no_tests", OptValue::Bool(error_on_no_tests));
+        push("display_output", OptValue::Bool(display_output));
+
+        fields
+    }
+
+    /// Decodes options previously produced by `TestOpts::encode`. Keys that
+    /// are missing keep their default values.
+    pub fn decode(encoded: &str) -> OptRes {
+        fn unescape(s: &str) -> Result<String, String> {
+            let mut out = String::new();
+            let mut chars = s.chars();
+            while let Some(c) = chars.next() {
+                if c != '%' {
+                    out.push(c);
+                    continue;
+                }
+                let hex: String = chars.by_ref().take(2).collect();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(b) if hex.len() == 2 => out.push(char::from(b)),
+                    _ => return Err(format!("invalid escape `%{}`", hex)),
+                }
+            }
+            Ok(out)
+        }
+
+        fn parse_bool(key: &str, value: &str) -> Result<bool, String> {
+            value.parse().map_err(|_| {
+                format!("expected true or false for `{}` (was {})", key, value)
+            })
+        }
+
+        fn parse_ms(key: &str, value: &str) -> Result<Duration, String> {
+            value.parse().map(Duration::from_millis).map_err(|_| {
+                format!("invalid value for `{}` (was {})", key, value)
+            })
+        }
+
+        let mut opts = TestOpts::new();
+
+        for pair in encoded.split(';').filter(|pair| !pair.is_empty()) {
+            let mut parts = pair.splitn(2, '=');
+            let key = parts.next().unwrap_or("");
+            let value = match parts.next() {
+                Some(value) => unescape(value)?,
+                None => return Err(format!("missing value for `{}`", key)),
+            };
+
+            match key {
+                "list" => opts.list = parse_bool(key, &value)?,
+                "list_runnable" => {
+                    opts.list_runnable = parse_bool(key, &value)?
+                }
+                "dump_config" => opts.dump_config = parse_bool(key, &value)?,
+                "filter" => opts.filters.push(value),
+                "filter_exact" => opts.filter_exact = parse_bool(key, &value)?,
+                "filter_regex" => opts.filter_regex = parse_bool(key, &value)?,
+                "filter_prefix" => {
+                    opts.filter_prefix = parse_bool(key, &value)?
+                }
+                "test_list_file" => {
+                    opts.test_list_file = Some(PathBuf::from(value))
+                }
+                "test_kind" => {
+                    opts.test_kind = match TestType::from_str(&value) {
+                        Some(kind) => Some(kind),
+                        None => {
+                            return Err(format!(
+                                "invalid value for `test_kind` (was {})",
+                                value
+                            ))
+                        }
+                    }
+                }
+                "exclude_should_panic" => {
+                    opts.exclude_should_panic = parse_bool(key, &value)?
+                }
+                "run_ignored" => {
+                    opts.run_ignored = match &*value {
+                        "yes" => RunIgnored::Yes,
+                        "no" => RunIgnored::No,
+                        "only" => RunIgnored::Only,
+                        v => {
+                            return Err(format!(
+                                "invalid value for `run_ignored` (was {})",
+                                v
+                            ))
+                        }
+                    }
+                }
+                "run_tests" => opts.run_tests = parse_bool(key, &value)?,
+                "bench_benchmarks" => {
+                    opts.bench_benchmarks = parse_bool(key, &value)?
+                }
+                "logfile" => opts.logfile = Some(PathBuf::from(value)),
+                "logfile_max_size" => {
+                    opts.logfile_max_size =
+                        Some(value.parse().map_err(|_| {
+                            format!(
+                            "invalid value for `logfile_max_size` (was {})",
+                            value
+                        )
+                        })?)
+                }
+                "logfile_keep" => {
+                    opts.logfile_keep = value.parse().map_err(|_| {
+                        format!(
+                            "invalid value for `logfile_keep` (was {})",
+                            value
+                        )
+                    })?
+                }
+                "export_trace" => {
+                    opts.export_trace = Some(PathBuf::from(value))
+                }
+                "junit_path" => opts.junit_path = Some(PathBuf::from(value)),
+                "nocapture" => opts.nocapture = parse_bool(key, &value)?,
+                "stream_failures" => {
+                    opts.stream_failures = parse_bool(key, &value)?
+                }
+                "capture_fds" => opts.capture_fds = parse_bool(key, &value)?,
+                "process_isolation" => {
+                    opts.process_isolation = parse_bool(key, &value)?
+                }
+                "force_run_in_process" => {
+                    opts.force_run_in_process = parse_bool(key, &value)?
+                }
+                "backtrace_snippets" => {
+                    opts.backtrace_snippets = parse_bool(key, &value)?
+                }
+                "track_allocations" => {
+                    opts.track_allocations = parse_bool(key, &value)?
+                }
+                "bench_rsd" => opts.bench_rsd = parse_bool(key, &value)?,
+                "bench_histogram" => {
+                    opts.bench_histogram = parse_bool(key, &value)?
+                }
+                "bench_show_throughput" => {
+                    opts.bench_show_throughput = parse_bool(key, &value)?
+                }
+                "bench_save" => opts.bench_save = Some(PathBuf::from(value)),
+                "bench_compare" => {
+                    opts.bench_compare = Some(PathBuf::from(value))
+                }
+                "bench_compare_md" => {
+                    opts.bench_compare_md = parse_bool(key, &value)?
+                }
+                "bench_significance" => {
+                    opts.bench_significance = parse_bool(key, &value)?
+                }
+                "bench_note" => opts.bench_note = Some(value),
+                "bench_pin_cpu" => {
+                    opts.bench_pin_cpu = Some(value.parse().map_err(|_| {
+                        format!(
+                            "invalid value for `bench_pin_cpu` (was {})",
+                            value
+                        )
+                    })?)
+                }
+                "bench_samples" => {
+                    opts.bench_samples = value.parse().map_err(|_| {
+                        format!(
+                            "invalid value for `bench_samples` (was {})",
+                            value
+                        )
+                    })?
+                }
+                "progress" => opts.progress = parse_bool(key, &value)?,
+                "color" => {
+                    opts.color = match &*value {
+                        "auto" => ColorConfig::AutoColor,
+                        "always" => ColorConfig::AlwaysColor,
+                        "always-ansi" => ColorConfig::AlwaysAnsiColor,
+                        "never" => ColorConfig::NeverColor,
+                        v => {
+                            return Err(format!(
+                                "invalid value for `color` (was {})",
+                                v
+                            ))
+                        }
+                    }
+                }
+                "format" => {
+                    opts.format = match &*value {
+                        "pretty" => OutputFormat::Pretty,
+                        "terse" => OutputFormat::Terse,
+                        "json" => OutputFormat::Json,
+                        "binary" => OutputFormat::Binary,
+                        "tap" => OutputFormat::Tap,
+                        v => {
+                            return Err(format!(
+                                "invalid value for `format` (was {})",
+                                v
+                            ))
+                        }
+                    }
+                }
+                "test_threads" => {
+                    opts.test_threads = Some(value.parse().map_err(|_| {
+                        format!(
+                            "invalid value for `test_threads` (was {})",
+                            value
+                        )
+                    })?)
+                }
+                "chaos" => {
+                    opts.chaos = Some(value.parse().map_err(|_| {
+                        format!("invalid value for `chaos` (was {})", value)
+                    })?)
+                }
+                "shuffle" => {
+                    opts.shuffle = Some(value.parse().map_err(|_| {
+                        format!("invalid value for `shuffle` (was {})", value)
+                    })?)
+                }
+                "timeout_ms" => opts.timeout = Some(parse_ms(key, &value)?),
+                "warn_timeout_ms" => {
+                    let t = parse_ms(key, &value)?;
+                    opts.warn_timeout = if t == Duration::default() {
+                        None
+                    } else {
+                        Some(t)
+                    };
+                }
+                "max_test_time_ms" => {
+                    opts.max_test_time = Some(parse_ms(key, &value)?)
+                }
+                "report_time" => opts.report_time = parse_bool(key, &value)?,
+                "report_time_warn_ms" => {
+                    opts.report_time_warn = parse_ms(key, &value)?
+                }
+                "report_time_critical_ms" => {
+                    opts.report_time_critical = parse_ms(key, &value)?
+                }
+                "skip" => opts.skip.push(value),
+                "skip_exact" => opts.skip_exact.push(value),
+                "include_tag" => opts.include_tags.push(value),
+                "exclude_tag" => opts.exclude_tags.push(value),
+                "shard" => {
+                    let mut parts = value.splitn(2, '/').map(str::parse);
+                    opts.shard = match (parts.next(), parts.next()) {
+                        (Some(Ok(index)), Some(Ok(count)))
+                            if index < count =>
+                        {
+                            Some((index, count))
+                        }
+                        _ => {
+                            return Err(format!(
+                                "invalid value for `shard` (was {})",
+                                value
+                            ));
+                        }
+                    }
+                }
+                "order" => {
+                    opts.order = match &*value {
+                        "alphabetical" => TestOrder::Alphabetical,
+                        "fastest-first" => TestOrder::FastestFirst,
+                        "slowest-first" => TestOrder::SlowestFirst,
+                        v => {
+                            return Err(format!(
+                                "invalid value for `order` (was {})",
+                                v
+                            ))
+                        }
+                    }
+                }
+                "no_sort" => opts.no_sort = parse_bool(key, &value)?,
+                "timings" => opts.timings = Some(PathBuf::from(value)),
+                "untimed_first" => {
+                    opts.untimed_first = parse_bool(key, &value)?
+                }
+                "fail_fast" => opts.fail_fast = parse_bool(key, &value)?,
+                "fail_fast_on_new" => {
+                    opts.fail_fast_on_new = parse_bool(key, &value)?
+                }
+                "known_failures" => {
+                    opts.known_failures = Some(PathBuf::from(value))
+                }
+                "failures_file" => {
+                    opts.failures_file = Some(PathBuf::from(value))
+                }
+                "last_failed" => opts.last_failed = parse_bool(key, &value)?,
+                "report_skipped" => {
+                    opts.report_skipped = parse_bool(key, &value)?
+                }
+                "summary_by_module" => {
+                    opts.summary_by_module = parse_bool(key, &value)?
+                }
+                "retries" => {
+                    opts.retries = value.parse().map_err(|_| {
+                        format!("invalid value for `retries` (was {})", value)
+                    })?
+                }
+                "max_total_retries" => {
+                    opts.max_total_retries =
+                        Some(value.parse().map_err(|_| {
+                            format!(
+                            "invalid value for `max_total_retries` (was {})",
+                            value
+                        )
+                        })?)
+                }
+                "min_pass_rate" => {
+                    opts.min_pass_rate = Some(value.parse().map_err(|_| {
+                        format!(
+                            "invalid value for `min_pass_rate` (was {})",
+                            value
+                        )
+                    })?)
+                }
+                "error_on_no_tests" => {
+                    opts.error_on_no_tests = parse_bool(key, &value)?
+                }
+                "display_output" => {
+                    opts.options.display_output = parse_bool(key, &value)?
+                }
+                _ => return Err(format!("unknown option `{}`", key)),
+            }
+        }
+
+        Ok(opts)
+    }
+}
+
+fn optgroups() -> getopts::Options {
+    let mut opts = getopts::Options::new();
+    opts.optflag("", "include-ignored", "Run ignored and not ignored tests")
+        .optflag("", "ignored", "Run only ignored tests")
+        .optflag("", "exclude-should-panic", "Excludes tests marked as should_panic")
+        .optflag("", "test", "Run tests and not benchmarks")
+        .optflag("", "bench", "Run benchmarks instead of tests")
+        .optflag("", "list", "List all tests and benchmarks")
+        .optflag(
+            "",
+            "list-runnable",
+            "List only the tests and benchmarks that would be run",
+        )
+        .optflag(
+            "",
+            "dump-config",
+            "Print the options, as resolved from the command line and the \
+             environment, as JSON and exit",
+        )
+        .optflag("h", "help", "Display this message (longer with --help)")
+        .optopt(
+            "",
+            "logfile",
+            "Write logs to the specified file instead \
+             of stdout. With --format=json the file gets the same JSON \
+             lines as stdout",
+            "PATH",
+        )
+        .optopt(
+            "",
+            "logfile-max-size",
+            "Rotate the --logfile once it would grow past BYTES, moving it \
+             to PATH.1",
+            "BYTES",
+        )
+        .optopt(
+            "",
+            "logfile-keep",
+            "How many rotated logfiles to keep (default 1)",
+            "N",
+        )
+        .optopt(
+            "",
+            "export-trace",
+            "Write one tab-separated `name, status, duration in \
+             nanoseconds` line per test to the specified file",
+            "PATH",
+        )
+        .optopt(
+            "",
+            "junit-file",
+            "Also write a JUnit XML report of the run to the specified \
+             file, whatever the --format",
+            "PATH",
+        )
+        .optflag(
+            "",
+            "nocapture",
+            "don't capture stdout/stderr of each \
+             task, allow printing directly",
+        )
+        .optflag(
+            "",
+            "no-capture-on-failure",
+            "Print the captured output of each failing test as soon as it \
+             fails, instead of with the failures at the end (pretty format \
+             only)",
+        )
+        .optflag(
+            "",
+            "show-output",
+            "Show captured stdout of successful tests",
+        )
+        .optflag(
+            "",
+            "capture-fds",
+            "Also capture output written directly to the stdout/stderr \
+             file descriptors, e.g. by C code (unix only, implies \
+             --test-threads=1)",
+        )
+        .optflag(
+            "",
+            "process-isolation",
+            "Run each test in a forked child process, so that a test that \
+             crashes fails on its own instead of ending the run (unix only, \
+             requires the `process-isolation` feature)",
+        )
+        .optflag(
+            "",
+            "force-run-in-process",
+            "Run every test on the main thread, one after the other, \
+             without spawning any threads",
+        )
+        .optflag(
+            "",
+            "backtrace-snippets",
+            "Show the source code around the line where each failing test \
+             panicked, when the source file can be found",
+        )
+        .optflag(
+            "",
+            "track-allocations",
+            "Report how many allocations each test makes. Needs a global \
+             allocator that counts them, such as CountingAllocator",
+        )
+        .optflag(
+            "",
+            "bench-rsd",
+            "Show the relative standard deviation of each benchmark, \
+             colored by how noisy the measurement is",
+        )
+        .optflag(
+            "",
+            "bench-histogram",
+            "Draw a histogram of the time per iteration of each \
+             benchmark's samples (pretty format only)",
+        )
+        .optflag(
+            "",
+            "bench-show-throughput",
+            "Show the iterations per second of each benchmark, computed \
+             from its median time per iteration",
+        )
+        .optopt(
+            "",
+            "bench-pin-cpu",
+            "Pin the thread running benchmarks to CPU N, so that the \
+             scheduler can't migrate it between cores mid-measurement \
+             (Linux only, ignored elsewhere)",
+            "N",
+        )
+        .optopt(
+            "",
+            "bench-samples",
+            "How many samples each round of benchmark measurements takes \
+             (default 50)",
+            "N",
+        )
+        .optopt(
+            "",
+            "bench-save",
+            "Save the median of each benchmark to PATH, for later use \
+             with --bench-compare",
+            "PATH",
+        )
+        .optopt(
+            "",
+            "bench-compare",
+            "Compare benchmarks against a baseline written by --bench-save",
+            "PATH",
+        )
+        .optflag(
+            "",
+            "bench-compare-md",
+            "After the run, print the --bench-compare comparison as a \
+             markdown table",
+        )
+        .optflag(
+            "",
+            "significance",
+            "After the run, test whether each benchmark differs \
+             significantly from the --bench-compare baseline",
+        )
+        .optopt(
+            "",
+            "bench-note",
+            "Annotate the benchmark results, e.g. with a commit hash, in \
+             the JSON output, the --bench-save file and the markdown table",
+            "TEXT",
+        )
+        .optflag(
+            "",
+            "progress",
+            "Keep a live status line of running and finished tests at the \
+             bottom of the output, or with --quiet a count after the last \
+             result (interactive terminals only)",
+        )
+        .optopt(
+            "",
+            "test-threads",
+            "Number of threads used for running tests \
+             in parallel",
+            "n_threads",
+        )
+        .optflagopt(
+            "",
+            "chaos",
+            "Sleep or yield for a random moment before starting each test, \
+             to vary how concurrent tests interleave. The seed is printed \
+             at the start of the run; pass it back to repeat the same \
+             delays",
+            "SEED",
+        )
+        .optflag(
+            "",
+            "shuffle",
+            "Run tests in a random order rather than alphabetically. The \
+             seed is printed at the start of the run",
+        )
+        .optopt(
+            "",
+            "shuffle-seed",
+            "Shuffle tests with the given seed, e.g. one printed by an \
+             earlier run with --shuffle (implies --shuffle)",
+            "SEED",
+        )
+        .optopt(
+            "",
+            "timeout",
+            "Fail tests that run for longer than SECS seconds. The test's \
+             thread can't be stopped and keeps running in the background",
+            "SECS",
+        )
+        .optopt(
+            "",
+            "max-test-time",
+            "Fail tests that pass, but take longer than SECS seconds to \
+             do so",
+            "SECS",
+        )
+        .optopt(
+            "",
+            "warn-timeout",
+            "Warn about tests that are still running after SECS seconds, \
+             or never if SECS is 0 (defaults to RUST_TEST_TIMEOUT, or 60)",
+            "SECS",
+        )
+        .optflag(
+            "",
+            "report-time",
+            "Show how long each test took, in yellow past \
+             --report-time-warn and in red past --report-time-critical",
+        )
+        .optopt(
+            "",
+            "report-time-warn",
+            "Duration past which --report-time shows a test's time in \
+             yellow (default 50)",
+            "MS",
+        )
+        .optopt(
+            "",
+            "report-time-critical",
+            "Duration past which --report-time shows a test's time in red \
+             (default 1000)",
+            "MS",
+        )
+        .optmulti(
+            "",
+            "skip",
+            "Skip tests whose names contain FILTER (this flag can \
+             be used multiple times)",
+            "FILTER",
+        )
+        .optmulti(
+            "",
+            "skip-exact",
+            "Skip the test named exactly NAME (this flag can be used \
+             multiple times)",
+            "NAME",
+        )
+        .optmulti(
+            "",
+            "include-tag",
+            "Only run tests tagged with TAG, or with any of the tags if \
+             this flag is used multiple times",
+            "TAG",
+        )
+        .optmulti(
+            "",
+            "exclude-tag",
+            "Skip tests tagged with TAG (this flag can be used multiple \
+             times)",
+            "TAG",
+        )
+        .optopt(
+            "",
+            "shard-index",
+            "Run only the tests in shard N (counting from 0) of the \
+             --shard-count shards; a test's shard is its position in \
+             the sorted list of selected tests, modulo the shard count",
+            "N",
+        )
+        .optopt(
+            "",
+            "shard-count",
+            "Split the selected tests into M shards (requires \
+             --shard-index)",
+            "M",
+        )
+        .optopt(
+            "",
+            "order",
+            "Configure the order in which tests are started:
+            alphabetical  = by name (default);
+            fastest-first = shortest duration in --timings first;
+            slowest-first = longest duration in --timings first",
+            "alphabetical|fastest-first|slowest-first",
+        )
+        .optopt(
+            "",
+            "timings",
+            "Read the duration of each test from an --export-trace file \
+             written by a previous run",
+            "PATH",
+        )
+        .optflag(
+            "",
+            "no-sort",
+            "Run and list the tests in the order they were declared rather \
+             than sorted by name",
+        )
+        .optflag(
+            "",
+            "untimed-first",
+            "With --order=fastest-first or slowest-first, start the tests \
+             missing from --timings first rather than last",
+        )
+        .optflag(
+            "",
+            "fail-fast",
+            "Stop scheduling tests after the first failure; tests that \
+             are already running are still waited for",
+        )
+        .optflag(
+            "",
+            "fail-fast-on-new",
+            "Stop scheduling tests after the first failure of a test \
+             that isn't listed in --known-failures",
+        )
+        .optopt(
+            "",
+            "known-failures",
+            "File listing the names of tests that are known to fail, one \
+             per line (requires --fail-fast-on-new)",
+            "PATH",
+        )
+        .optopt(
+            "",
+            "failures-file",
+            "Write the names of the tests that failed to PATH after the \
+             run, one per line (defaults to RUST_TEST_FAILURES_FILE)",
+            "PATH",
+        )
+        .optflag(
+            "",
+            "last-failed",
+            "Only run the tests that failed in the previous run, as \
+             recorded in the --failures-file",
+        )
+        .optflag(
+            "",
+            "report-skipped",
+            "Report every test that was not run, along with the reason \
+             (json and binary formats only)",
+        )
+        .optflag(
+            "",
+            "summary-by-module",
+            "After the run, print pass/fail counts for each top-level \
+             module",
+        )
+        .optopt(
+            "",
+            "retries",
+            "Run a failing test up to N more times before reporting it as \
+             failed; tests that time out are not retried",
+            "N",
+        )
+        .optopt(
+            "",
+            "max-total-retries",
+            "Stop retrying failed tests once N retries have been spent \
+             across the whole run",
+            "N",
+        )
+        .optopt(
+            "",
+            "min-pass-rate",
+            "Succeed if at least PCT percent of the tests that ran passed, \
+             instead of requiring that none failed",
+            "PCT",
+        )
+        .optflag(
+            "",
+            "fail-on-empty",
+            "Fail the run if no tests are left to run after filtering",
+        )
+        .optflag(
+            "q",
+            "quiet",
+            "Display one character per test instead of one line. \
+             Alias to --format=terse",
+        )
+        .optflag(
+            "",
+            "exact",
+            "Exactly match filters rather than by substring",
+        )
+        .optflag(
+            "",
+            "filter-regex",
+            "Match the filter and --skip filters as regular expressions \
+             (requires the `regex` feature)",
+        )
+        .optflag(
+            "",
+            "filter-prefix",
+            "Match the filter and --skip filters against leading `::` path \
+             segments, so that `base` matches `base::test` but not \
+             `database`",
+        )
+        .optopt(
+            "",
+            "test-list-file",
+            "Also run the tests named in the specified file, one exact \
+             name per line",
+            "PATH",
+        )
+        .optopt(
+            "",
+            "test-kind",
+            "Only run tests of the given kind",
+            "unit|integration|doc|bench|unknown",
+        )
+        .optopt(
+            "",
+            "color",
+            "Configure coloring of output:
+            auto        = colorize if stdout is a tty and tests are run on serially (default);
+            always      = always colorize output;
+            always-ansi = always colorize output with ANSI escape codes;
+            never       = never colorize output;
+            Without this option, CLICOLOR_FORCE=1 means always and a non-empty
+            NO_COLOR means never",
+            "auto|always|always-ansi|never",
+        )
+        .optopt(
+            "",
+            "format",
+            "Configure formatting of output:
+            pretty = Print verbose output;
+            terse  = Display one character per test;
+            json   = Output a json document;
+            binary = Output length-prefixed binary frames;
+            tap    = Output the Test Anything Protocol",
+            "pretty|terse|json|binary|tap",
+        )
+        .optopt(
+            "Z",
+            "",
+            "Enable nightly-only flags:
+            unstable-options = Allow use of experimental features",
+            "unstable-options",
+        );
+    opts
+}
+
+fn usage(binary: &str, options: &getopts::Options) {
+    let message = format!("Usage: {} [OPTIONS] [FILTERS...]", binary);
+    println!(
+        r#"{usage}
+
+The FILTER strings are tested against the name of all tests, and only those
+tests whose names contain any of the filters are run.
+
+By default, all tests are run in parallel, one thread per CPU. This can be
+altered with the --test-threads flag or the RUST_TEST_THREADS environment
+variable when running tests (set it to 1). The flag takes precedence over the
+environment variable.
+
+All tests have their standard output and standard error captured by default.
+This can be overridden with the --nocapture flag or setting RUST_TEST_NOCAPTURE
+environment variable to a value other than "0". Logging is not captured by default.
+Output written directly to the stdout/stderr file descriptors (e.g. by C code)
+is only captured with the unix-only --capture-fds flag, which runs tests serially.
+
+Test Attributes:
+
+    #[test]        - Indicates a function is a test to be run. This function
+                     takes no arguments.
+    #[bench]       - Indicates a function is a benchmark to be run. This
+                     function takes one argument (test::Bencher).
+    #[should_panic] - This function (also labeled with #[test]) will only pass if
+                     the code causes a panic (an assertion failure or panic!)
+                     A message may be provided, which the failure string must
+                     contain: #[should_panic(expected = "foo")].
+    #[ignore]      - When applied to a function which is already attributed as a
+                     test, then the test runner will ignore these tests during
+                     normal test runs. Running with --ignored or --include-ignored will run
+                     these tests."#,
+        usage = options.usage(&message)
+    );
+}
+
+// FIXME: Copied from libsyntax until linkage errors are resolved. Issue #47566
+fn is_nightly() -> bool {
+    // Whether this is a feature-staged build, i.e., on the beta or stable channel
+    let disable_unstable_features =
+        option_env!("CFG_DISABLE_UNSTABLE_FEATURES").is_some();
+    // Whether we should enable unstable features for bootstrapping
+    let bootstrap = env::var("RUSTC_BOOTSTRAP").is_ok();
+
+    bootstrap || !disable_unstable_features
+}
+
+// Parses command line arguments into test options
+pub fn parse_opts(args: &[String]) -> Option<OptRes> {
     let mut allow_unstable = false;
+    // Flags that are stable enough for embedders to opt out of the gate.
+    let stable_cli = cfg!(feature = "stable-cli");
     let opts = optgroups();
     let args = args.get(1..).unwrap_or(args);
     let matches = match opts.parse(args) {
@@ -509,1658 +2188,7832 @@ pub fn parse_opts(args: &[String]) -> Option<OptRes> {
         Err(f) => return Some(Err(f.to_string())),
     };
 
-    if let Some(opt) = matches.opt_str("Z") {
-        if !is_nightly() {
-            return Some(Err(
-                "the option `Z` is only accepted on the nightly compiler"
-                    .into(),
-            ));
+    if let Some(opt) = matches.opt_str("Z") {
+        if !is_nightly() {
+            return Some(Err(
+                "the option `Z` is only accepted on the nightly compiler"
+                    .into(),
+            ));
+        }
+
+        if let "unstable-options" = &*opt {
+            allow_unstable = true;
+        } else {
+            return Some(Err("Unrecognized option to `Z`".into()));
+        }
+    };
+
+    if matches.opt_present("h") {
+        usage(&args[0], &opts);
+        return None;
+    }
+
+    let filters = matches.free.clone();
+
+    let test_kind = match matches.opt_str("test-kind") {
+        Some(kind) => match TestType::from_str(&kind) {
+            Some(kind) => Some(kind),
+            None => {
+                return Some(Err(format!(
+                    "argument for --test-kind must be unit, integration, \
+                     doc, bench or unknown (was {})",
+                    kind
+                )));
+            }
+        },
+        None => None,
+    };
+
+    let exclude_should_panic = matches.opt_present("exclude-should-panic");
+    if !allow_unstable && !stable_cli && exclude_should_panic {
+        return Some(Err(
+            "The \"exclude-should-panic\" flag is only accepted on the nightly compiler".into(),
+        ));
+    }
+
+    let include_ignored = matches.opt_present("include-ignored");
+    if !allow_unstable && !stable_cli && include_ignored {
+        return Some(Err(
+            "The \"include-ignored\" flag is only accepted on the nightly compiler".into(),
+        ));
+    }
+
+    let run_ignored = match (include_ignored, matches.opt_present("ignored")) {
+        (true, true) => {
+            return Some(Err(
+                "the options --include-ignored and --ignored are mutually exclusive".into(),
+            ));
+        }
+        (true, false) => RunIgnored::Yes,
+        (false, true) => RunIgnored::Only,
+        (false, false) => RunIgnored::No,
+    };
+    let quiet = matches.opt_present("quiet");
+    let exact = matches.opt_present("exact");
+    let filter_regex = matches.opt_present("filter-regex");
+    if exact && filter_regex {
+        return Some(Err(
+            "the options --exact and --filter-regex are mutually exclusive"
+                .into(),
+        ));
+    }
+    let filter_prefix = matches.opt_present("filter-prefix");
+    if filter_prefix && (exact || filter_regex) {
+        return Some(Err(
+            "the option --filter-prefix can't be combined with --exact or \
+             --filter-regex"
+                .into(),
+        ));
+    }
+    let list_runnable = matches.opt_present("list-runnable");
+    let list = list_runnable || matches.opt_present("list");
+    let dump_config = matches.opt_present("dump-config");
+
+    let logfile = matches.opt_str("logfile");
+    let logfile = logfile.map(|s| PathBuf::from(&s));
+    let export_trace = matches.opt_str("export-trace").map(PathBuf::from);
+    let junit_path = matches.opt_str("junit-file").map(PathBuf::from);
+
+    let logfile_max_size = match matches.opt_str("logfile-max-size") {
+        Some(n_str) => match n_str.parse::<u64>() {
+            Ok(0) => {
+                return Some(Err(
+                    "argument for --logfile-max-size must not be 0"
+                        .to_string(),
+                ));
+            }
+            Ok(n) => Some(n),
+            Err(e) => {
+                return Some(Err(format!(
+                    "argument for --logfile-max-size must be a number \
+                     (error: {})",
+                    e
+                )));
+            }
+        },
+        None => None,
+    };
+    let logfile_keep = match matches.opt_str("logfile-keep") {
+        Some(n_str) => match n_str.parse::<usize>() {
+            Ok(n) => n,
+            Err(e) => {
+                return Some(Err(format!(
+                    "argument for --logfile-keep must be a number \
+                     (error: {})",
+                    e
+                )));
+            }
+        },
+        None => 1,
+    };
+    if logfile_max_size.is_some() && logfile.is_none() {
+        return Some(Err(
+            "the option --logfile-max-size requires --logfile".to_string()
+        ));
+    }
+    if matches.opt_present("logfile-keep") && logfile_max_size.is_none() {
+        return Some(Err(
+            "the option --logfile-keep requires --logfile-max-size"
+                .to_string(),
+        ));
+    }
+
+    let failures_file = matches
+        .opt_str("failures-file")
+        .or_else(|| env::var("RUST_TEST_FAILURES_FILE").ok())
+        .map(PathBuf::from);
+    let last_failed = matches.opt_present("last-failed");
+    if last_failed && failures_file.is_none() {
+        return Some(Err(
+            "the option --last-failed requires --failures-file \
+             or RUST_TEST_FAILURES_FILE"
+                .to_string(),
+        ));
+    }
+
+    let bench_benchmarks = matches.opt_present("bench");
+    let run_tests = !bench_benchmarks || matches.opt_present("test");
+
+    let mut nocapture = matches.opt_present("nocapture");
+    if !nocapture {
+        nocapture = match env::var("RUST_TEST_NOCAPTURE") {
+            Ok(val) => &val != "0",
+            Err(_) => false,
+        };
+    }
+
+    let test_threads = match matches.opt_str("test-threads") {
+        Some(n_str) => {
+            match parse_test_threads("argument for --test-threads", &n_str) {
+                Ok(n) => Some(n),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None => None,
+    };
+    // Only read when the thread count isn't given, but a bad value is an
+    // error up front rather than when the tests are about to run.
+    if test_threads.is_none() {
+        if let Ok(s) = env::var("RUST_TEST_THREADS") {
+            if let Err(e) = parse_test_threads("RUST_TEST_THREADS", &s) {
+                return Some(Err(e));
+            }
+        }
+    }
+
+    let retries = match matches.opt_str("retries") {
+        Some(n_str) => match n_str.parse::<usize>() {
+            Ok(n) => n,
+            Err(e) => {
+                return Some(Err(format!(
+                    "argument for --retries must be a number (error: {})",
+                    e
+                )));
+            }
+        },
+        None => 0,
+    };
+
+    let max_total_retries = match matches.opt_str("max-total-retries") {
+        Some(n_str) => match n_str.parse::<usize>() {
+            Ok(n) => Some(n),
+            Err(e) => {
+                return Some(Err(format!(
+                    "argument for --max-total-retries must be a number \
+                     (error: {})",
+                    e
+                )));
+            }
+        },
+        None => None,
+    };
+
+    let min_pass_rate = match matches.opt_str("min-pass-rate") {
+        Some(s) => match s.parse::<f64>() {
+            Ok(rate) if (0.0..=100.0).contains(&rate) => Some(rate),
+            _ => {
+                return Some(Err(format!(
+                    "argument for --min-pass-rate must be a percentage \
+                     between 0 and 100 (was {})",
+                    s
+                )));
+            }
+        },
+        None => None,
+    };
+
+    let bench_pin_cpu = match matches.opt_str("bench-pin-cpu") {
+        Some(n_str) => match n_str.parse::<usize>() {
+            Ok(n) => Some(n),
+            Err(e) => {
+                return Some(Err(format!(
+                    "argument for --bench-pin-cpu must be a number (error: {})",
+                    e
+                )));
+            }
+        },
+        None => None,
+    };
+    let bench_samples = match matches.opt_str("bench-samples") {
+        Some(n_str) => match n_str.parse::<usize>() {
+            Ok(0) => {
+                return Some(Err(
+                    "argument for --bench-samples must not be 0".to_string(),
+                ));
+            }
+            Ok(n) => n,
+            Err(e) => {
+                return Some(Err(format!(
+                    "argument for --bench-samples must be a number > 0 \
+                     (error: {})",
+                    e
+                )));
+            }
+        },
+        None => DEFAULT_BENCH_SAMPLES,
+    };
+
+    let backtrace_snippets = matches.opt_present("backtrace-snippets");
+    if backtrace_snippets && !allow_unstable {
+        return Some(Err(
+            "The \"backtrace-snippets\" flag is only accepted on the nightly compiler".into(),
+        ));
+    }
+
+    let capture_fds = matches.opt_present("capture-fds");
+    let test_threads = if capture_fds {
+        if !allow_unstable {
+            return Some(Err(
+                "The \"capture-fds\" flag is only accepted on the nightly compiler".into(),
+            ));
+        }
+        if !cfg!(unix) {
+            return Some(Err(
+                "the option --capture-fds is only supported on unix".into(),
+            ));
+        }
+        // The standard file descriptors are shared by the whole process, so
+        // output can only be attributed correctly if tests run one at a time.
+        match test_threads {
+            Some(n) if n > 1 => {
+                return Some(Err(
+                    "the option --capture-fds requires --test-threads=1"
+                        .into(),
+                ));
+            }
+            _ => Some(1),
+        }
+    } else {
+        test_threads
+    };
+
+    let process_isolation = matches.opt_present("process-isolation");
+    if process_isolation {
+        if !cfg!(unix) {
+            return Some(Err(
+                "the option --process-isolation is only supported on unix"
+                    .into(),
+            ));
+        }
+        if !cfg!(feature = "process-isolation") {
+            return Some(Err(
+                "the option --process-isolation requires libtest to be built \
+                 with the `process-isolation` feature"
+                    .into(),
+            ));
+        }
+    }
+
+    let shard = match (
+        matches.opt_str("shard-index"),
+        matches.opt_str("shard-count"),
+    ) {
+        (None, None) => None,
+        (Some(index), Some(count)) => {
+            let index = match index.parse::<usize>() {
+                Ok(n) => n,
+                Err(e) => {
+                    return Some(Err(format!(
+                        "argument for --shard-index must be a number (error: \
+                         {})",
+                        e
+                    )));
+                }
+            };
+            let count = match count.parse::<usize>() {
+                Ok(n) => n,
+                Err(e) => {
+                    return Some(Err(format!(
+                        "argument for --shard-count must be a number (error: \
+                         {})",
+                        e
+                    )));
+                }
+            };
+            if index >= count {
+                return Some(Err(format!(
+                    "--shard-index must be less than --shard-count (got {} \
+                     and {})",
+                    index, count
+                )));
+            }
+            Some((index, count))
+        }
+        _ => {
+            return Some(Err("the options --shard-index and --shard-count \
+                             must be used together"
+                .into()));
+        }
+    };
+
+    let order = match matches.opt_str("order").as_ref().map(|s| &**s) {
+        Some("alphabetical") | None => TestOrder::Alphabetical,
+        Some("fastest-first") => TestOrder::FastestFirst,
+        Some("slowest-first") => TestOrder::SlowestFirst,
+        Some(v) => {
+            return Some(Err(format!(
+                "argument for --order must be alphabetical, fastest-first \
+                 or slowest-first (was {})",
+                v
+            )));
+        }
+    };
+    let timings = matches.opt_str("timings").map(PathBuf::from);
+    if order != TestOrder::Alphabetical && timings.is_none() {
+        return Some(Err(format!(
+            "the option --order={} requires --timings",
+            matches.opt_str("order").unwrap_or_default()
+        )));
+    }
+
+    let chaos = if matches.opt_present("chaos") {
+        match matches.opt_str("chaos") {
+            Some(seed) => match seed.parse::<u64>() {
+                Ok(seed) => Some(seed),
+                Err(e) => {
+                    return Some(Err(format!(
+                        "argument for --chaos must be a number (error: {})",
+                        e
+                    )));
+                }
+            },
+            None => Some(Chaos::random_seed()),
+        }
+    } else {
+        None
+    };
+
+    let shuffle = match matches.opt_str("shuffle-seed") {
+        Some(seed) => match seed.parse::<u64>() {
+            Ok(seed) => Some(seed),
+            Err(e) => {
+                return Some(Err(format!(
+                    "argument for --shuffle-seed must be a number (error: {})",
+                    e
+                )));
+            }
+        },
+        None if matches.opt_present("shuffle") => Some(Chaos::random_seed()),
+        None => None,
+    };
+    if shuffle.is_some() && order != TestOrder::Alphabetical {
+        return Some(Err("the option --shuffle can't be used with \
+                         --order=fastest-first or slowest-first"
+            .into()));
+    }
+
+    let timeout = match matches.opt_str("timeout") {
+        Some(n_str) => match n_str.parse::<u64>() {
+            Ok(0) => {
+                return Some(Err(
+                    "argument for --timeout must not be 0".into()
+                ));
+            }
+            Ok(n) => Some(Duration::from_secs(n)),
+            Err(e) => {
+                return Some(Err(format!(
+                    "argument for --timeout must be a number (error: {})",
+                    e
+                )));
+            }
+        },
+        None => None,
+    };
+    // An abandoned test would leave the standard file descriptors
+    // redirected for the rest of the run.
+    if capture_fds && timeout.is_some() {
+        return Some(Err(
+            "the options --capture-fds and --timeout are mutually exclusive"
+                .into(),
+        ));
+    }
+
+    let fail_fast_on_new = matches.opt_present("fail-fast-on-new");
+    let known_failures = matches.opt_str("known-failures").map(PathBuf::from);
+    // The list is only read to decide whether a failure is new.
+    if known_failures.is_some() && !fail_fast_on_new {
+        return Some(Err(
+            "the option --known-failures requires --fail-fast-on-new".into(),
+        ));
+    }
+
+    let force_run_in_process = matches.opt_present("force-run-in-process");
+    if force_run_in_process && process_isolation {
+        return Some(Err(
+            "the options --force-run-in-process and --process-isolation are \
+             mutually exclusive"
+                .into(),
+        ));
+    }
+    // A test can only be given up on if it runs on its own thread.
+    if force_run_in_process && timeout.is_some() {
+        return Some(Err(
+            "the options --force-run-in-process and --timeout are mutually \
+             exclusive"
+                .into(),
+        ));
+    }
+
+    let warn_timeout = match matches.opt_str("warn-timeout") {
+        Some(s) => parse_warn_timeout("argument for --warn-timeout", &s),
+        None => match env::var("RUST_TEST_TIMEOUT") {
+            Ok(s) => parse_warn_timeout("RUST_TEST_TIMEOUT", &s),
+            Err(_) => Ok(Some(Duration::from_secs(TEST_WARN_TIMEOUT_S))),
+        },
+    };
+    let warn_timeout = match warn_timeout {
+        Ok(warn_timeout) => warn_timeout,
+        Err(e) => return Some(Err(e)),
+    };
+
+    let max_test_time = match matches.opt_str("max-test-time") {
+        Some(n_str) => match n_str.parse::<u64>() {
+            Ok(0) => {
+                return Some(Err(
+                    "argument for --max-test-time must not be 0".into(),
+                ));
+            }
+            Ok(n) => Some(Duration::from_secs(n)),
+            Err(e) => {
+                return Some(Err(format!(
+                    "argument for --max-test-time must be a number (error: \
+                     {})",
+                    e
+                )));
+            }
+        },
+        None => None,
+    };
+
+    let parse_ms = |flag: &str, default: Duration| {
+        let ms = match matches.opt_str(flag) {
+            Some(ms) => ms,
+            None => return Ok(default),
+        };
+        ms.parse().map(Duration::from_millis).map_err(|e| {
+            format!("argument for --{} must be a number (error: {})", flag, e)
+        })
+    };
+    let report_time_warn =
+        match parse_ms("report-time-warn", Duration::from_millis(50)) {
+            Ok(t) => t,
+            Err(e) => return Some(Err(e)),
+        };
+    let report_time_critical =
+        match parse_ms("report-time-critical", Duration::from_secs(1)) {
+            Ok(t) => t,
+            Err(e) => return Some(Err(e)),
+        };
+
+    let color = match matches.opt_str("color").as_ref().map(|s| &**s) {
+        None => color_from_env(
+            env::var("CLICOLOR_FORCE").ok().as_ref().map(|s| &**s),
+            env::var("NO_COLOR").ok().as_ref().map(|s| &**s),
+        ),
+        Some("auto") => ColorConfig::AutoColor,
+        Some("always") => ColorConfig::AlwaysColor,
+        Some("always-ansi") => ColorConfig::AlwaysAnsiColor,
+        Some("never") => ColorConfig::NeverColor,
+
+        Some(v) => {
+            return Some(Err(format!(
+                "argument for --color must be auto, always, always-ansi, or \
+                 never (was {})",
+                v
+            )));
+        }
+    };
+
+    let format = match matches.opt_str("format").as_ref().map(|s| &**s) {
+        None if quiet => OutputFormat::Terse,
+        Some("pretty") | None => OutputFormat::Pretty,
+        Some("terse") => OutputFormat::Terse,
+        Some("json") => {
+            if !allow_unstable && !stable_cli {
+                return Some(Err(
+                    "The \"json\" format is only accepted on the nightly compiler".into(),
+                ));
+            }
+            OutputFormat::Json
+        }
+        Some("binary") => {
+            if !allow_unstable {
+                return Some(Err(
+                    "The \"binary\" format is only accepted on the nightly compiler".into(),
+                ));
+            }
+            OutputFormat::Binary
+        }
+        Some("tap") => {
+            if !allow_unstable && !stable_cli {
+                return Some(Err(
+                    "The \"tap\" format is only accepted on the nightly compiler".into(),
+                ));
+            }
+            OutputFormat::Tap
+        }
+
+        Some(v) => {
+            return Some(Err(format!(
+                "argument for --format must be pretty, terse, json, binary, \
+                 or tap (was {})",
+                v
+            )));
+        }
+    };
+    // Reports printed as plain text would corrupt these formats.
+    let machine_format = match format {
+        OutputFormat::Json => Some("json"),
+        OutputFormat::Binary => Some("binary"),
+        OutputFormat::Tap => Some("tap"),
+        OutputFormat::Pretty | OutputFormat::Terse => None,
+    };
+
+    let summary_by_module = matches.opt_present("summary-by-module");
+    if let (true, Some(f)) = (summary_by_module, machine_format) {
+        return Some(Err(format!(
+            "the option --summary-by-module can't be used with --format={}",
+            f
+        )));
+    }
+
+    let stream_failures = matches.opt_present("no-capture-on-failure");
+    if stream_failures && format != OutputFormat::Pretty {
+        return Some(Err("the option --no-capture-on-failure only works \
+                         with --format=pretty"
+            .into()));
+    }
+
+    let bench_compare = matches.opt_str("bench-compare").map(PathBuf::from);
+    let bench_compare_md = matches.opt_present("bench-compare-md");
+    let bench_significance = matches.opt_present("significance");
+    for &(flag, present) in &[
+        ("bench-compare-md", bench_compare_md),
+        ("significance", bench_significance),
+    ] {
+        if !present {
+            continue;
+        }
+        if bench_compare.is_none() {
+            return Some(Err(format!(
+                "the option --{} requires --bench-compare",
+                flag
+            )));
+        }
+        if let Some(f) = machine_format {
+            return Some(Err(format!(
+                "the option --{} can't be used with --format={}",
+                flag, f
+            )));
+        }
+    }
+
+    let test_opts = TestOpts {
+        list,
+        list_runnable,
+        dump_config,
+        filters,
+        filter_exact: exact,
+        filter_regex,
+        filter_prefix,
+        test_list_file: matches.opt_str("test-list-file").map(PathBuf::from),
+        test_kind,
+        exclude_should_panic,
+        run_ignored,
+        run_tests,
+        bench_benchmarks,
+        logfile,
+        logfile_max_size,
+        logfile_keep,
+        export_trace,
+        junit_path,
+        nocapture,
+        stream_failures,
+        capture_fds,
+        process_isolation,
+        force_run_in_process,
+        backtrace_snippets,
+        track_allocations: matches.opt_present("track-allocations"),
+        bench_rsd: matches.opt_present("bench-rsd"),
+        bench_histogram: matches.opt_present("bench-histogram"),
+        bench_show_throughput: matches.opt_present("bench-show-throughput"),
+        bench_pin_cpu,
+        bench_samples,
+        bench_save: matches.opt_str("bench-save").map(PathBuf::from),
+        bench_compare,
+        bench_compare_md,
+        bench_significance,
+        bench_note: matches.opt_str("bench-note"),
+        progress: matches.opt_present("progress"),
+        color,
+        format,
+        test_threads,
+        chaos,
+        shuffle,
+        timeout,
+        max_test_time,
+        warn_timeout,
+        report_time: matches.opt_present("report-time"),
+        report_time_warn,
+        report_time_critical,
+        skip: matches.opt_strs("skip"),
+        skip_exact: matches.opt_strs("skip-exact"),
+        include_tags: matches.opt_strs("include-tag"),
+        exclude_tags: matches.opt_strs("exclude-tag"),
+        shard,
+        order,
+        no_sort: matches.opt_present("no-sort"),
+        timings,
+        untimed_first: matches.opt_present("untimed-first"),
+        fail_fast: matches.opt_present("fail-fast"),
+        fail_fast_on_new,
+        known_failures,
+        failures_file,
+        last_failed,
+        report_skipped: matches.opt_present("report-skipped"),
+        summary_by_module,
+        retries,
+        max_total_retries,
+        min_pass_rate,
+        error_on_no_tests: matches.opt_present("fail-on-empty"),
+        options: Options::new()
+            .display_output(matches.opt_present("show-output")),
+    };
+
+    // Report bad patterns now rather than when the tests are filtered
+    for pattern in test_opts.filters.iter().chain(&test_opts.skip) {
+        if let Err(e) = NameFilter::new(&test_opts, pattern) {
+            return Some(Err(e));
+        }
+    }
+
+    Some(Ok(test_opts))
+}
+
+#[derive(Clone, PartialEq)]
+pub struct BenchSamples {
+    ns_iter_summ: stats::Summary,
+    mb_s: usize,
+    /// Nanoseconds per iteration for each sample of the final measuring
+    /// round, after winsorizing
+    samples: Vec<f64>,
+    /// How many iterations each of `samples` was timed over.
+    iterations: u64,
+    /// Whether measuring stopped because the iteration count couldn't grow
+    /// any further, rather than because the results converged.
+    saturated: bool,
+    /// Extra metrics the benchmark recorded, keyed by their own names.
+    metrics: MetricMap,
+}
+
+impl BenchSamples {
+    /// The number of iterations each sample was timed over, or 0 if the
+    /// benchmark never called `iter`.
+    pub fn iterations(&self) -> u64 {
+        self.iterations
+    }
+
+    /// Nanoseconds per iteration for each sample the summary was computed
+    /// from, after winsorizing. Empty if the benchmark never called `iter`.
+    pub fn samples(&self) -> &[f64] {
+        &self.samples
+    }
+
+    /// The `pct`th percentile of `samples`, in nanoseconds per iteration,
+    /// or `None` if there are no samples. Winsorizing clamps the top 5% of
+    /// the samples, so percentiles past the 95th all read as the 95th.
+    pub fn percentile(&self, pct: f64) -> Option<f64> {
+        if self.samples.is_empty() {
+            None
+        } else {
+            Some(stats::Stats::percentile(&self.samples[..], pct))
+        }
+    }
+}
+
+/// A `#[should_panic(expected = "...")]` test whose panic message didn't
+/// contain the expected string, or a `#[should_panic(regex = "...")]` test
+/// whose message didn't match the expected regex.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PanicMismatch {
+    pub expected: String,
+    /// Whether `expected` is a regex rather than a string.
+    pub regex: bool,
+    /// The message the test actually panicked with, if the panic payload
+    /// was a string, a boxed error or something `Options::panic_message`
+    /// could read.
+    pub actual: Option<String>,
+}
+
+impl fmt::Display for PanicMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.regex {
+            write!(f, "Panic did not match expected regex '{}'", self.expected)
+        } else {
+            write!(
+                f,
+                "Panic did not include expected string '{}'",
+                self.expected
+            )
+        }
+    }
+}
+
+/// Where in the source a test panicked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PanicLocation {
+    pub file: String,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl fmt::Display for PanicLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub enum TestResult {
+    TrOk,
+    /// Passed after failing and being rerun this many times by `--retries`.
+    TrOkWithRetries(u32),
+    TrFailed,
+    /// The test returned a failing `Termination` value, e.g. an `Err`.
+    TrFailedErr,
+    TrFailedMsg(String),
+    TrFailedShouldPanic(PanicMismatch),
+    /// Still running after `--timeout`, and abandoned.
+    TrTimedOut(Duration),
+    TrIgnored,
+    /// Ignored at run time by calling `skip`, for the given reason.
+    TrIgnoredMsg(String),
+    TrAllowedFail,
+    TrBench(BenchSamples),
+}
+
+unsafe impl Send for TestResult {}
+
+enum OutputLocation<T> {
+    Pretty(Box<term::StdoutTerminal>),
+    Raw(T),
+}
+
+impl<T: Write> Write for OutputLocation<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            OutputLocation::Pretty(ref mut term) => term.write(buf),
+            OutputLocation::Raw(ref mut stdout) => stdout.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            OutputLocation::Pretty(ref mut term) => term.flush(),
+            OutputLocation::Raw(ref mut stdout) => stdout.flush(),
+        }
+    }
+}
+
+/// Results accumulated by the console runner over a whole run.
+pub struct ConsoleTestState {
+    log_out: Option<LogFile>,
+    trace_out: Option<io::BufWriter<File>>,
+    total: usize,
+    summary: RunSummary,
+    metrics: MetricMap,
+    not_failures: Vec<(TestDesc, Vec<u8>)>,
+    modules: Option<BTreeMap<String, ModuleCounts>>,
+    min_pass_rate: Option<f64>,
+    error_on_no_tests: bool,
+    failures_file: Option<PathBuf>,
+    /// How long the run took, once it has finished.
+    exec_time: Option<Duration>,
+    options: Options,
+}
+
+/// The outcome of a run: how many tests ended each way, and the output of
+/// the ones that failed.
+#[derive(Clone, Debug, Default)]
+pub struct RunSummary {
+    pub passed: usize,
+    /// How many of the passed tests needed retries.
+    pub flaky: usize,
+    pub failed: usize,
+    /// How many of the failed tests timed out.
+    pub timed_out: usize,
+    pub ignored: usize,
+    pub allowed_fail: usize,
+    pub filtered_out: FilterStats,
+    pub measured: usize,
+    /// Each failed test with its captured output, followed by a note on why
+    /// it failed when the result carries one and on where it panicked.
+    pub failures: Vec<(TestDesc, Vec<u8>)>,
+}
+
+impl RunSummary {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts the result of one test.
+    pub fn record(
+        &mut self,
+        test: &TestDesc,
+        result: &TestResult,
+        output: &TestOutput,
+    ) {
+        match *result {
+            TestResult::TrOk => self.passed += 1,
+            TestResult::TrOkWithRetries(_) => {
+                self.passed += 1;
+                self.flaky += 1;
+            }
+            TestResult::TrIgnored | TestResult::TrIgnoredMsg(_) => {
+                self.ignored += 1
+            }
+            TestResult::TrAllowedFail => self.allowed_fail += 1,
+            TestResult::TrBench(_) => self.measured += 1,
+            TestResult::TrTimedOut(_) => {
+                self.failed += 1;
+                self.timed_out += 1;
+            }
+            TestResult::TrFailed
+            | TestResult::TrFailedErr
+            | TestResult::TrFailedMsg(_)
+            | TestResult::TrFailedShouldPanic(_) => self.failed += 1,
+        }
+        if let Some(output) = failure_output(result, output) {
+            self.failures.push((test.clone(), output));
+        }
+    }
+}
+
+/// What is shown for a failed test: its captured output, followed by a
+/// note on why it failed when the result carries one and on where it
+/// panicked. `None` if the test didn't fail.
+fn failure_output(
+    result: &TestResult,
+    output: &TestOutput,
+) -> Option<Vec<u8>> {
+    let mut stdout = output.combined.clone();
+    match *result {
+        TestResult::TrOk
+        | TestResult::TrOkWithRetries(_)
+        | TestResult::TrIgnored
+        | TestResult::TrIgnoredMsg(_)
+        | TestResult::TrAllowedFail
+        | TestResult::TrBench(_) => return None,
+        TestResult::TrFailed | TestResult::TrFailedErr => {}
+        TestResult::TrFailedMsg(ref msg) => {
+            stdout.extend_from_slice(format!("note: {}", msg).as_bytes());
+        }
+        TestResult::TrTimedOut(timeout) => {
+            stdout.extend_from_slice(
+                format!("note: timed out after {:?}", timeout).as_bytes(),
+            );
+        }
+        TestResult::TrFailedShouldPanic(ref mismatch) => {
+            stdout.extend_from_slice(format!("note: {}", mismatch).as_bytes());
+            match mismatch.actual {
+                Some(ref actual) => stdout.extend_from_slice(
+                    format!("\nnote: panic message was '{}'", actual)
+                        .as_bytes(),
+                ),
+                None => stdout.extend_from_slice(
+                    b"\nnote: the panic payload was neither a string nor \
+                      a boxed error, see `Options::panic_message`",
+                ),
+            }
+        }
+    }
+    if let Some(ref location) = output.panic_location {
+        if stdout.len() > output.combined.len() {
+            stdout.push(b'\n');
+        }
+        stdout.extend_from_slice(
+            format!("note: panicked at {}", location).as_bytes(),
+        );
+    }
+    Some(stdout)
+}
+
+/// The `--logfile`, rotated to `PATH.1`, `PATH.2`, ... when a size limit is
+/// given.
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    written: u64,
+    max_size: Option<u64>,
+    keep: usize,
+}
+
+impl LogFile {
+    fn create(
+        path: &Path,
+        max_size: Option<u64>,
+        keep: usize,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            path: path.to_owned(),
+            file: File::create(path)?,
+            written: 0,
+            max_size,
+            keep,
+        })
+    }
+
+    /// Writes `msg` whole, rotating first if it would take the file past
+    /// the size limit. A single message larger than the limit still gets a
+    /// file of its own.
+    fn write_all(&mut self, msg: &[u8]) -> io::Result<()> {
+        if let Some(max_size) = self.max_size {
+            if self.written > 0 && self.written + msg.len() as u64 > max_size {
+                self.rotate()?;
+            }
+        }
+        self.file.write_all(msg)?;
+        self.written += msg.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |n: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{}", n));
+            PathBuf::from(path)
+        };
+        if self.keep == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            // The oldest file is overwritten by the rename below it.
+            for n in (1..self.keep).rev() {
+                let from = rotated(n);
+                if from.exists() {
+                    fs::rename(&from, rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated(1))?;
+            self.file = File::create(&self.path)?;
+        }
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for LogFile {
+    /// Writes `buf` whole, so a line written in one call is never split
+    /// across a rotation.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Per-module counters kept for `--summary-by-module`.
+#[derive(Clone, Copy, Default)]
+struct ModuleCounts {
+    passed: usize,
+    failed: usize,
+    allowed_fail: usize,
+    ignored: usize,
+    measured: usize,
+}
+
+impl ConsoleTestState {
+    pub fn new(opts: &TestOpts) -> io::Result<Self> {
+        let log_out = match opts.logfile {
+            Some(ref path) => Some(LogFile::create(
+                path,
+                opts.logfile_max_size,
+                opts.logfile_keep,
+            )?),
+            None => None,
+        };
+        let trace_out = match opts.export_trace {
+            Some(ref path) => Some(io::BufWriter::new(File::create(path)?)),
+            None => None,
+        };
+
+        Ok(Self {
+            log_out,
+            trace_out,
+            total: 0,
+            summary: RunSummary::new(),
+            metrics: MetricMap {
+                note: opts.bench_note.clone(),
+                ..MetricMap::new()
+            },
+            not_failures: Vec::new(),
+            modules: if opts.summary_by_module {
+                Some(BTreeMap::new())
+            } else {
+                None
+            },
+            min_pass_rate: opts.min_pass_rate,
+            error_on_no_tests: opts.error_on_no_tests,
+            failures_file: opts.failures_file.clone(),
+            exec_time: None,
+            options: opts.options,
+        })
+    }
+
+    /// Where the JSON formatter writes: stdout, and also the logfile if
+    /// there is one, which then gets nothing else.
+    fn json_output(&mut self) -> OutputLocation<Box<dyn Write>> {
+        OutputLocation::Raw(match self.log_out.take() {
+            Some(log) => Box::new(Tee(io::stdout(), log)),
+            None => Box::new(io::stdout()),
+        })
+    }
+
+    pub fn write_log<S: AsRef<str>>(&mut self, msg: S) -> io::Result<()> {
+        let msg = msg.as_ref();
+        match self.log_out {
+            None => Ok(()),
+            Some(ref mut o) => o.write_all(msg.as_bytes()),
+        }
+    }
+
+    /// Writes the names of the tests that failed to the `--failures-file`,
+    /// for `--last-failed` to pick up on the next run.
+    fn write_failures_file(&self) -> io::Result<()> {
+        let path = match self.failures_file {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let mut names = String::new();
+        for (test, _) in &self.summary.failures {
+            names.push_str(test.name.as_slice());
+            names.push('\n');
+        }
+        fs::write(path, names)
+    }
+
+    pub fn write_log_result(
+        &mut self,
+        test: &TestDesc,
+        result: &TestResult,
+    ) -> io::Result<()> {
+        self.write_log(format!(
+            "{} {}\n",
+            match *result {
+                TestResult::TrOk => "ok".to_owned(),
+                TestResult::TrOkWithRetries(n) => {
+                    format!("ok, flaky (retries: {})", n)
+                }
+                TestResult::TrFailed | TestResult::TrFailedErr => {
+                    "failed".to_owned()
+                }
+                TestResult::TrFailedMsg(ref msg) => format!("failed: {}", msg),
+                TestResult::TrFailedShouldPanic(ref mismatch) => {
+                    format!("failed: {}", mismatch)
+                }
+                TestResult::TrTimedOut(timeout) => {
+                    format!("timed out after {:?}", timeout)
+                }
+                TestResult::TrIgnored => "ignored".to_owned(),
+                TestResult::TrIgnoredMsg(ref msg) => {
+                    format!("ignored, {}", msg)
+                }
+                TestResult::TrAllowedFail => "failed (allowed)".to_owned(),
+                TestResult::TrBench(ref bs) => fmt_bench_samples(bs),
+            },
+            test.name
+        ))
+    }
+
+    /// Appends a line to the `--export-trace` file, made of three
+    /// tab-separated columns:
+    ///
+    /// 1. the test name, with `\`, tab, newline and carriage return
+    ///    escaped as `\\`, `\t`, `\n` and `\r`;
+    /// 2. the status: `ok`, `failed`, `timed_out`, `ignored`,
+    ///    `allowed_fail` or `bench`;
+    /// 3. the wall-clock duration in nanoseconds, or `-` if the test didn't
+    ///    run.
+    pub fn write_trace(
+        &mut self,
+        test: &TestDesc,
+        result: &TestResult,
+        exec_time: Option<Duration>,
+    ) -> io::Result<()> {
+        let out = match self.trace_out {
+            Some(ref mut o) => o,
+            None => return Ok(()),
+        };
+
+        let mut name = String::new();
+        for c in test.name.as_slice().chars() {
+            match c {
+                '\\' => name.push_str("\\\\"),
+                '\t' => name.push_str("\\t"),
+                '\n' => name.push_str("\\n"),
+                '\r' => name.push_str("\\r"),
+                c => name.push(c),
+            }
+        }
+        let status = match *result {
+            TestResult::TrOk | TestResult::TrOkWithRetries(_) => "ok",
+            TestResult::TrFailed
+            | TestResult::TrFailedErr
+            | TestResult::TrFailedMsg(_)
+            | TestResult::TrFailedShouldPanic(_) => "failed",
+            TestResult::TrTimedOut(_) => "timed_out",
+            TestResult::TrIgnored | TestResult::TrIgnoredMsg(_) => "ignored",
+            TestResult::TrAllowedFail => "allowed_fail",
+            TestResult::TrBench(_) => "bench",
+        };
+        match exec_time {
+            Some(t) => {
+                writeln!(out, "{}\t{}\t{}", name, status, ns_from_dur(t))
+            }
+            None => writeln!(out, "{}\t{}\t-", name, status),
+        }
+    }
+
+    /// Counts `result` towards the module `test` belongs to, i.e. the part
+    /// of its name before the first `::`. Tests at the crate root are
+    /// grouped under `(root)`.
+    fn record_module_result(&mut self, test: &TestDesc, result: &TestResult) {
+        let modules = match self.modules {
+            Some(ref mut modules) => modules,
+            None => return,
+        };
+        let name = test.name.as_slice();
+        let module = match name.find("::") {
+            Some(i) => &name[..i],
+            None => "(root)",
+        };
+        let counts = modules.entry(module.to_owned()).or_default();
+        match *result {
+            TestResult::TrOk | TestResult::TrOkWithRetries(_) => {
+                counts.passed += 1
+            }
+            TestResult::TrFailed
+            | TestResult::TrFailedErr
+            | TestResult::TrFailedMsg(_)
+            | TestResult::TrFailedShouldPanic(_)
+            | TestResult::TrTimedOut(_) => counts.failed += 1,
+            TestResult::TrAllowedFail => counts.allowed_fail += 1,
+            TestResult::TrIgnored | TestResult::TrIgnoredMsg(_) => {
+                counts.ignored += 1
+            }
+            TestResult::TrBench(_) => counts.measured += 1,
+        }
+    }
+
+    /// Renders the `--summary-by-module` table, one row per module followed
+    /// by the totals for the whole run, or `None` if the option is off.
+    fn module_summary(&self) -> Option<String> {
+        let modules = self.modules.as_ref()?;
+        let total = ModuleCounts {
+            passed: self.summary.passed,
+            failed: self.summary.failed,
+            allowed_fail: self.summary.allowed_fail,
+            ignored: self.summary.ignored,
+            measured: self.summary.measured,
+        };
+        let width = modules
+            .keys()
+            .map(String::len)
+            .chain(Some("module".len()))
+            .max()
+            .unwrap_or(0);
+
+        let mut output = format!(
+            "{:<w$}  passed  failed  allowed  ignored  measured\n",
+            "module",
+            w = width
+        );
+        let rows = modules.iter().map(|(m, c)| (&m[..], c));
+        for (module, c) in rows.chain(Some(("total", &total))) {
+            output.push_str(&format!(
+                "{:<w$}  {:>6}  {:>6}  {:>7}  {:>7}  {:>8}\n",
+                module,
+                c.passed,
+                c.failed,
+                c.allowed_fail,
+                c.ignored,
+                c.measured,
+                w = width
+            ));
+        }
+        Some(output)
+    }
+
+    /// The counts so far, along with the output of each failed test.
+    pub fn summary(&self) -> &RunSummary {
+        &self.summary
+    }
+
+    /// Number of tests that were selected to run.
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    pub fn passed(&self) -> usize {
+        self.summary.passed
+    }
+
+    /// Number of passed tests that only passed after being retried.
+    pub fn flaky(&self) -> usize {
+        self.summary.flaky
+    }
+
+    pub fn failed(&self) -> usize {
+        self.summary.failed
+    }
+
+    pub fn ignored(&self) -> usize {
+        self.summary.ignored
+    }
+
+    pub fn allowed_fail(&self) -> usize {
+        self.summary.allowed_fail
+    }
+
+    pub fn filtered_out(&self) -> usize {
+        self.summary.filtered_out.total()
+    }
+
+    pub fn measured(&self) -> usize {
+        self.summary.measured
+    }
+
+    /// Percentage of the tests that passed or failed which passed, 100 if
+    /// none did either.
+    pub fn pass_rate(&self) -> f64 {
+        let ran = self.summary.passed + self.summary.failed;
+        if ran == 0 {
+            100.0
+        } else {
+            self.summary.passed as f64 * 100.0 / ran as f64
+        }
+    }
+
+    /// Whether the run succeeded: no test failed, or with `--min-pass-rate`,
+    /// enough of them passed.
+    pub fn success(&self) -> bool {
+        if self.error_on_no_tests && self.total == 0 {
+            return false;
+        }
+        match self.min_pass_rate {
+            Some(min) => self.pass_rate() >= min,
+            None => self.summary.failed == 0,
+        }
+    }
+
+    /// The counts on the summary line, one category per test. The timed
+    /// out, allowed to fail and flaky categories are left out when empty;
+    /// the others are always there for tools that parse the line.
+    fn result_counts(&self) -> String {
+        let s = &self.summary;
+        let mut counts = vec![
+            format!("{} passed", s.passed - s.flaky),
+            format!("{} failed", s.failed - s.timed_out),
+        ];
+        let optional = [
+            (s.timed_out, "timed out"),
+            (s.allowed_fail, "allowed to fail"),
+            (s.flaky, "flaky"),
+        ];
+        for &(n, category) in &optional {
+            if n > 0 {
+                counts.push(format!("{} {}", n, category));
+            }
+        }
+        counts.push(format!("{} ignored", s.ignored));
+        counts.push(format!("{} measured", s.measured));
+        counts.push(s.filtered_out.to_string());
+        counts.join("; ")
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self.options.exit_code_fn {
+            Some(f) => f(self),
+            None if self.success() => 0,
+            None => 101,
+        }
+    }
+
+    fn current_test_count(&self) -> usize {
+        self.summary.passed
+            + self.summary.failed
+            + self.summary.ignored
+            + self.summary.measured
+            + self.summary.allowed_fail
+    }
+
+    /// Describes how the per-outcome counters disagree with the number of
+    /// tests that were scheduled, if they do. This points at a bookkeeping
+    /// bug in the harness rather than a test failure.
+    fn count_mismatch(&self) -> Option<String> {
+        let counted = self.current_test_count();
+        if counted == self.total {
+            return None;
+        }
+        let difference = if counted < self.total {
+            format!("{} missing", self.total - counted)
+        } else {
+            format!("{} extra", counted - self.total)
+        };
+        Some(format!(
+            "expected {} test results but counted {} ({} passed, {} failed, \
+             {} ignored, {} measured, {} allowed to fail), {}",
+            self.total,
+            counted,
+            self.summary.passed,
+            self.summary.failed,
+            self.summary.ignored,
+            self.summary.measured,
+            self.summary.allowed_fail,
+            difference
+        ))
+    }
+}
+
+// Format a number with thousands separators
+fn fmt_thousands_sep(n: usize, sep: char) -> String {
+    let digits = n.to_string();
+    let mut output = String::with_capacity(digits.len() * 4 / 3);
+    for (i, digit) in digits.chars().enumerate() {
+        // A separator goes before each digit that starts a group of three.
+        if i > 0 && i % 3 == digits.len() % 3 {
+            output.push(sep);
+        }
+        output.push(digit);
+    }
+    output
+}
+
+pub fn fmt_bench_samples(bs: &BenchSamples) -> String {
+    use std::fmt::Write;
+    let mut output = String::new();
+
+    let median = bs.ns_iter_summ.median as usize;
+    let deviation = (bs.ns_iter_summ.max - bs.ns_iter_summ.min) as usize;
+
+    output
+        .write_fmt(format_args!(
+            "{:>11} ns/iter (+/- {})",
+            fmt_thousands_sep(median, ','),
+            fmt_thousands_sep(deviation, ',')
+        ))
+        .unwrap();
+    if bs.mb_s != 0 {
+        output
+            .write_fmt(format_args!(" = {} MB/s", bs.mb_s))
+            .unwrap();
+    }
+    if bs.saturated {
+        output.push_str(" (iteration count saturated)");
+    }
+    output
+}
+
+/// Renders the benchmarks in `current` as a GitHub-flavored markdown table
+/// against the medians in `baseline`. The trend arrow points the way the
+/// time per iteration moved, so `↑` is a slowdown.
+pub fn fmt_bench_comparison_md(
+    baseline: &MetricMap,
+    current: &MetricMap,
+) -> String {
+    let ns = |value: f64| {
+        format!("{} ns/iter", fmt_thousands_sep(value as usize, ','))
+    };
+
+    let mut output = String::new();
+    for &(label, map) in &[("Baseline", baseline), ("Current", current)] {
+        if let Some(ref note) = map.note {
+            output.push_str(&format!("{}: {}\n\n", label, note));
+        }
+    }
+    output.push_str(
+        "| Name | Baseline | Current | Change% | Trend |\n\
+         |:-----|---------:|--------:|--------:|:-----:|\n",
+    );
+    for (name, cur) in &current.metrics {
+        let (base, change, trend) = match baseline.metrics.get(name) {
+            Some(base) if base.value > 0.0 => {
+                let change = (cur.value - base.value) / base.value * 100.0;
+                let trend = if cur.value > base.value {
+                    "↑"
+                } else if cur.value < base.value {
+                    "↓"
+                } else {
+                    "="
+                };
+                (ns(base.value), format!("{:+.2}%", change), trend)
+            }
+            Some(base) => (ns(base.value), "-".to_owned(), ""),
+            None => ("-".to_owned(), "-".to_owned(), ""),
+        };
+        output.push_str(&format!(
+            "| {} | {} | {} | {} | {} |\n",
+            name.replace('|', "\\|"),
+            base,
+            ns(cur.value),
+            change,
+            trend
+        ));
+    }
+    output
+}
+
+/// Largest p-value at which `fmt_bench_significance` calls a change
+/// significant.
+const SIGNIFICANCE_LEVEL: f64 = 0.05;
+
+/// Classifies each benchmark in `current` as a significant regression, a
+/// significant improvement or no significant change, by running a
+/// Mann-Whitney U test between its samples and those in `baseline`.
+pub fn fmt_bench_significance(
+    baseline: &MetricMap,
+    current: &MetricMap,
+) -> String {
+    let mut output = format!(
+        "significance against baseline (Mann-Whitney U, alpha = {}):\n",
+        SIGNIFICANCE_LEVEL
+    );
+    for (name, cur) in &current.metrics {
+        let verdict = match (
+            baseline.metrics.get(name),
+            baseline.samples.get(name),
+            current.samples.get(name),
+        ) {
+            (Some(base), Some(base_samples), Some(cur_samples)) => {
+                let p = stats::mann_whitney_u(base_samples, cur_samples);
+                let class = if p >= SIGNIFICANCE_LEVEL {
+                    "no significant change"
+                } else if cur.value > base.value {
+                    "significant regression"
+                } else {
+                    "significant improvement"
+                };
+                format!("p = {:.4}, {}", p, class)
+            }
+            _ => "no samples to compare".to_owned(),
+        };
+        output.push_str(&format!("    {}: {}\n", name, verdict));
+    }
+    output
+}
+
+/// Relative standard deviation (coefficient of variation) of a benchmark's
+/// samples, as a percentage of their mean.
+fn bench_rsd(bs: &BenchSamples) -> f64 {
+    bs.ns_iter_summ.std_dev_pct
+}
+
+pub fn fmt_bench_rsd(bs: &BenchSamples) -> String {
+    let rsd = bench_rsd(bs);
+    // A benchmark that never called `iter` has a mean of zero.
+    if rsd.is_finite() {
+        format!(" (rsd {:.2}%)", rsd)
+    } else {
+        String::new()
+    }
+}
+
+/// The allocations of a test, as shown after its result with
+/// `--track-allocations`.
+pub fn fmt_allocations(stats: AllocationStats) -> String {
+    format!(
+        " ({} allocations, {} bytes)",
+        fmt_thousands_sep(stats.allocations as usize, ','),
+        fmt_thousands_sep(stats.bytes as usize, ',')
+    )
+}
+
+/// Iterations per second at a benchmark's median time per iteration, or
+/// `None` if an iteration took no measurable time.
+fn bench_throughput(bs: &BenchSamples) -> Option<f64> {
+    if bs.ns_iter_summ.median > 0.0 {
+        Some(1_000_000_000.0 / bs.ns_iter_summ.median)
+    } else {
+        None
+    }
+}
+
+pub fn fmt_bench_throughput(bs: &BenchSamples) -> String {
+    match bench_throughput(bs) {
+        Some(ips) => format!(
+            " ({} iters/sec)",
+            fmt_thousands_sep(ips.round() as usize, ',')
+        ),
+        None => String::new(),
+    }
+}
+
+/// An ASCII histogram of a benchmark's samples, in nanoseconds per
+/// iteration, one line per bucket.
+pub fn fmt_bench_histogram(bs: &BenchSamples) -> String {
+    stats::histogram(&bs.samples, BENCH_HISTOGRAM_BUCKETS)
+}
+
+/// Color used to flag how trustworthy a benchmark measurement is.
+fn bench_rsd_color(bs: &BenchSamples) -> term::color::Color {
+    let rsd = bench_rsd(bs);
+    if rsd < 2.0 {
+        term::color::GREEN
+    } else if rsd < 5.0 {
+        term::color::YELLOW
+    } else {
+        term::color::RED
+    }
+}
+
+// List the tests to console, and optionally to logfile. Filters are honored,
+// and tests that would be ignored are marked as such (or left out entirely
+// with `--list-runnable`).
+pub fn list_tests_console(
+    opts: &TestOpts,
+    tests: Vec<TestDescAndFn>,
+) -> io::Result<()> {
+    fn plural(count: u32, s: &str) -> String {
+        match count {
+            1 => format!("{} {}", 1, s),
+            n => format!("{} {}s", n, s),
+        }
+    }
+
+    let mut output = match stdout_terminal(opts) {
+        None => OutputLocation::Raw(io::stdout()),
+        Some(t) => OutputLocation::Pretty(t),
+    };
+
+    let quiet = opts.format == OutputFormat::Terse;
+    let mut st = ConsoleTestState::new(opts)?;
+    // Tools listing tests as JSON get one object per line and nothing else.
+    let mut json = if opts.format == OutputFormat::Json {
+        Some(JsonFormatter::new(st.json_output(), false, false, false))
+    } else {
+        None
+    };
+
+    let mut ntest = 0;
+    let mut nbench = 0;
+
+    for test in filter_tests_with_reasons(&opts, tests)?.0 {
+        let TestDescAndFn { desc, testfn } = test;
+
+        if desc.ignore && opts.list_runnable {
+            continue;
+        }
+
+        let fntype = match testfn {
+            TestFn::StaticTestFn(..) | TestFn::DynTestFn(..) => {
+                ntest += 1;
+                "test"
+            }
+            TestFn::StaticBenchFn(..) | TestFn::DynBenchFn(..) => {
+                nbench += 1;
+                "benchmark"
+            }
+        };
+
+        if let Some(ref mut json) = json {
+            json.write_list_entry(&desc, fntype)?;
+        } else {
+            let ignored = if desc.ignore { " (ignored)" } else { "" };
+            writeln!(output, "{}: {}{}", desc.name, fntype, ignored)?;
+        }
+        st.write_log(format!("{} {}\n", fntype, desc.name))?;
+    }
+
+    if !quiet && json.is_none() {
+        if ntest != 0 || nbench != 0 {
+            writeln!(output)?;
+        }
+
+        writeln!(
+            output,
+            "{}, {}",
+            plural(ntest, "test"),
+            plural(nbench, "benchmark")
+        )?;
+    }
+
+    Ok(())
+}
+
+// A simple console test runner
+pub fn run_tests_console(
+    opts: &TestOpts,
+    tests: Vec<TestDescAndFn>,
+) -> io::Result<bool> {
+    run_console(opts, tests).map(|(success, _)| success)
+}
+
+/// Runs the tests without writing anything, returning what happened for
+/// callers that want to report the results themselves.
+pub fn run_tests_summary(
+    opts: &TestOpts,
+    tests: Vec<TestDescAndFn>,
+) -> io::Result<RunSummary> {
+    let mut summary = RunSummary::new();
+    run_tests(opts, tests, |event| {
+        match event {
+            TestEvent::TeFilteredOut(filtered_out) => {
+                summary.filtered_out = filtered_out
+            }
+            TestEvent::TeResult(test, result, _, output) => {
+                summary.record(&test, &result, &output)
+            }
+            _ => {}
+        }
+        Ok(())
+    })?;
+    Ok(summary)
+}
+
+/// Runs the tests like `run_tests_console`, also returning the exit code the
+/// process should end with.
+fn run_console(
+    opts: &TestOpts,
+    tests: Vec<TestDescAndFn>,
+) -> io::Result<(bool, i32)> {
+    fn len_if_padded(t: &TestDescAndFn) -> usize {
+        match t.testfn.padding() {
+            NamePadding::PadNone => 0,
+            NamePadding::PadOnRight => t.desc.name.as_slice().len(),
+        }
+    }
+
+    let output = match stdout_terminal(opts) {
+        None => OutputLocation::Raw(io::stdout()),
+        Some(t) => OutputLocation::Pretty(t),
+    };
+
+    let max_name_len = tests
+        .iter()
+        .max_by_key(|t| len_if_padded(*t))
+        .map_or(0, |t| t.desc.name.as_slice().len());
+
+    let mut st = ConsoleTestState::new(opts)?;
+    let out: Box<dyn OutputFormatter> = match opts.format {
+        OutputFormat::Pretty => Box::new(PrettyFormatter::new(
+            output,
+            DisplayConfig::new(opts, max_name_len),
+        )),
+        OutputFormat::Terse => Box::new(TerseFormatter::new(
+            output,
+            DisplayConfig::new(opts, max_name_len),
+        )),
+        OutputFormat::Json => Box::new(JsonFormatter::new(
+            st.json_output(),
+            opts.report_skipped,
+            opts.options.display_output,
+            opts.bench_show_throughput,
+        )),
+        OutputFormat::Binary => Box::new(BinaryFormatter::new(output)),
+        OutputFormat::Tap => Box::new(TapFormatter::new(output)),
+    };
+    run_console_with(opts, tests, out, st)
+}
+
+/// Runs the tests like `run_tests_console`, but reports them through `out`
+/// instead of one of the built-in formatters.
+pub fn run_tests_console_with_formatter(
+    opts: &TestOpts,
+    tests: Vec<TestDescAndFn>,
+    out: Box<dyn OutputFormatter>,
+) -> io::Result<bool> {
+    let st = ConsoleTestState::new(opts)?;
+    run_console_with(opts, tests, out, st).map(|(success, _)| success)
+}
+
+fn run_console_with(
+    opts: &TestOpts,
+    tests: Vec<TestDescAndFn>,
+    mut out: Box<dyn OutputFormatter>,
+    mut st: ConsoleTestState,
+) -> io::Result<(bool, i32)> {
+    fn callback(
+        event: &TestEvent,
+        st: &mut ConsoleTestState,
+        out: &mut dyn OutputFormatter,
+    ) -> io::Result<()> {
+        match (*event).clone() {
+            TestEvent::TeFiltered(ref filtered_tests) => {
+                st.total = filtered_tests.len();
+                out.write_run_start(filtered_tests.len())
+            }
+            TestEvent::TeFilteredOut(filtered_out) => {
+                st.summary.filtered_out = filtered_out;
+                Ok(())
+            }
+            TestEvent::TeWait(ref test) => out.write_test_start(test),
+            TestEvent::TeTimeout(ref test) => out.write_timeout(test),
+            TestEvent::TeTick => out.write_tick(),
+            TestEvent::TeSkipped(ref test, reason) => {
+                out.write_skipped(test, reason)
+            }
+            TestEvent::TeResult(test, result, exec_time, output) => {
+                st.write_log_result(&test, &result)?;
+                st.write_trace(&test, &result, exec_time)?;
+                st.record_module_result(&test, &result);
+                out.write_result(&test, &result, exec_time, &output)?;
+                st.summary.record(&test, &result, &output);
+                match result {
+                    TestResult::TrOk | TestResult::TrOkWithRetries(_) => {
+                        st.not_failures.push((test, output.combined));
+                    }
+                    TestResult::TrBench(bs) => {
+                        st.metrics.insert_metric(
+                            test.name.as_slice(),
+                            bs.ns_iter_summ.median,
+                            bs.ns_iter_summ.max - bs.ns_iter_summ.min,
+                        );
+                        st.metrics
+                            .insert_samples(test.name.as_slice(), bs.samples);
+                        for (name, m) in &bs.metrics.metrics {
+                            st.metrics.insert_metric(
+                                &format!("{}::{}", test.name, name),
+                                m.value,
+                                m.noise,
+                            );
+                        }
+                    }
+                    _ => {}
+                }
+                Ok(())
+            }
+        }
+    }
+
+    if let Some(ref path) = opts.junit_path {
+        let junit =
+            JUnitFormatter::new(OutputLocation::Raw(File::create(path)?));
+        out = Box::new(TeeFormatter::new(out, Box::new(junit)));
+    }
+
+    // Read the baseline up front, so a bad path fails before anything runs.
+    let baseline = match opts.bench_compare {
+        Some(ref path) => Some(MetricMap::load(path)?),
+        None => None,
+    };
+
+    if let Some(seed) = opts.chaos {
+        writeln!(io::stderr(), "note: running with --chaos={}", seed)?;
+    }
+    if let Some(seed) = opts.shuffle {
+        let note = format!("note: running with --shuffle-seed={}\n", seed);
+        // Keep machine-readable output parseable.
+        match opts.format {
+            OutputFormat::Pretty | OutputFormat::Terse => {
+                io::stdout().write_all(note.as_bytes())?
+            }
+            OutputFormat::Json | OutputFormat::Binary | OutputFormat::Tap => {
+                io::stderr().write_all(note.as_bytes())?
+            }
+        }
+    }
+
+    let start = Instant::now();
+    let stopped_early =
+        run_tests_inner(opts, tests, |x| callback(&x, &mut st, &mut *out))?;
+    st.exec_time = Some(start.elapsed());
+
+    // Stopping early on a failure leaves tests unrun on purpose.
+    if !stopped_early {
+        if let Some(mismatch) = st.count_mismatch() {
+            writeln!(io::stderr(), "warning: {}", mismatch)?;
+        }
+    }
+
+    if let Some(ref mut trace_out) = st.trace_out {
+        trace_out.flush()?;
+    }
+    if let Some(ref path) = opts.bench_save {
+        st.metrics.save(path)?;
+    }
+
+    let success = out.write_run_finish(&st)?;
+    st.write_failures_file()?;
+    if opts.error_on_no_tests && st.total == 0 {
+        writeln!(io::stderr(), "error: no tests matched filter")?;
+    }
+
+    if let Some(summary) = st.module_summary() {
+        io::stdout().write_all(summary.as_bytes())?;
+    }
+
+    if let Some(ref baseline) = baseline {
+        if opts.bench_compare_md {
+            let table = fmt_bench_comparison_md(baseline, &st.metrics);
+            io::stdout().write_all(table.as_bytes())?;
+        }
+        if opts.bench_significance {
+            let report = fmt_bench_significance(baseline, &st.metrics);
+            io::stdout().write_all(report.as_bytes())?;
+        }
+    }
+
+    Ok((success, st.exit_code()))
+}
+
+#[test]
+fn should_sort_failures_before_printing_them() {
+    let test_a = TestDesc {
+        name: TestName::StaticTestName("a"),
+        ignore: false,
+        ignore_message: None,
+        should_panic: ShouldPanic::No,
+        allow_fail: false,
+        test_type: TestType::Unknown,
+        no_timeout_warning: false,
+        is_converted_bench: false,
+        tags: &[],
+    };
+
+    let test_b = TestDesc {
+        name: TestName::StaticTestName("b"),
+        ignore: false,
+        ignore_message: None,
+        should_panic: ShouldPanic::No,
+        allow_fail: false,
+        test_type: TestType::Unknown,
+        no_timeout_warning: false,
+        is_converted_bench: false,
+        tags: &[],
+    };
+
+    let mut out = PrettyFormatter::new(
+        OutputLocation::Raw(Vec::new()),
+        DisplayConfig {
+            max_name_len: 10,
+            ..DisplayConfig::default()
+        },
+    );
+
+    let st = ConsoleTestState {
+        log_out: None,
+        trace_out: None,
+        total: 0,
+        summary: RunSummary {
+            failures: vec![(test_b, Vec::new()), (test_a, Vec::new())],
+            ..RunSummary::new()
+        },
+        metrics: MetricMap::new(),
+        options: Options::new(),
+        not_failures: Vec::new(),
+        modules: None,
+        min_pass_rate: None,
+        error_on_no_tests: false,
+        failures_file: None,
+        exec_time: None,
+    };
+
+    out.write_failures(&st).unwrap();
+    let s = match out.output_location() {
+        &OutputLocation::Raw(ref m) => String::from_utf8_lossy(&m[..]),
+        &OutputLocation::Pretty(_) => unreachable!(),
+    };
+
+    let apos = s.find("a").unwrap();
+    let bpos = s.find("b").unwrap();
+    assert!(apos < bpos);
+}
+
+fn use_color(opts: &TestOpts) -> bool {
+    match opts.color {
+        ColorConfig::AutoColor => !opts.nocapture && stdout_isatty(),
+        ColorConfig::AlwaysColor | ColorConfig::AlwaysAnsiColor => true,
+        ColorConfig::NeverColor => false,
+    }
+}
+
+/// The color configuration when `--color` isn't given, from the values of
+/// the `CLICOLOR_FORCE` and `NO_COLOR` environment variables. Forcing color
+/// wins over disabling it.
+fn color_from_env(
+    clicolor_force: Option<&str>,
+    no_color: Option<&str>,
+) -> ColorConfig {
+    match (clicolor_force, no_color) {
+        (Some(force), _) if force != "0" => ColorConfig::AlwaysColor,
+        (_, Some(no_color)) if !no_color.is_empty() => ColorConfig::NeverColor,
+        _ => ColorConfig::AutoColor,
+    }
+}
+
+/// The terminal to write pretty output to, if stdout is one or
+/// `--color=always-ansi` asks to treat it as one.
+fn stdout_terminal(opts: &TestOpts) -> Option<Box<term::StdoutTerminal>> {
+    if opts.color != ColorConfig::AlwaysAnsiColor {
+        return term::stdout();
+    }
+    // The basic 8 colors, as `term` sets them up for ANSI terminals it
+    // has no terminfo entry for.
+    let mut strings = HashMap::new();
+    strings.insert("sgr0", b"\x1B[0m".to_vec());
+    strings.insert("bold", b"\x1B[1m".to_vec());
+    strings.insert("setaf", b"\x1B[3%p1%dm".to_vec());
+    strings.insert("setab", b"\x1B[4%p1%dm".to_vec());
+    let mut numbers = HashMap::new();
+    numbers.insert("colors", 8);
+    let info = term::terminfo::TermInfo {
+        names: vec!["ansi".to_owned()],
+        bools: HashMap::new(),
+        numbers,
+        strings,
+    };
+    Some(Box::new(term::TerminfoTerminal::new_with_terminfo(
+        io::stdout(),
+        info,
+    )))
+}
+
+#[cfg(any(
+    target_os = "cloudabi",
+    target_os = "redox",
+    all(target_arch = "wasm32", not(target_os = "emscripten")),
+    all(target_vendor = "fortanix", target_env = "sgx")
+))]
+fn stdout_isatty() -> bool {
+    // FIXME: Implement isatty on Redox and SGX
+    false
+}
+#[cfg(any(unix, target_os = "fuchsia"))]
+fn stdout_isatty() -> bool {
+    unsafe { libc::isatty(libc::STDOUT_FILENO) != 0 }
+}
+#[cfg(windows)]
+fn stdout_isatty() -> bool {
+    type DWORD = u32;
+    type BOOL = i32;
+    type HANDLE = *mut u8;
+    type LPDWORD = *mut u32;
+    const STD_OUTPUT_HANDLE: DWORD = -11i32 as DWORD;
+    extern "system" {
+        fn GetStdHandle(which: DWORD) -> HANDLE;
+        fn GetConsoleMode(hConsoleHandle: HANDLE, lpMode: LPDWORD) -> BOOL;
+    }
+    unsafe {
+        let handle = GetStdHandle(STD_OUTPUT_HANDLE);
+        let mut out = 0;
+        GetConsoleMode(handle, &mut out) != 0
+    }
+}
+
+#[allow(clippy::large_enum_variant)] // FIXME
+#[derive(Clone)]
+pub enum TestEvent {
+    TeFiltered(Vec<TestDesc>),
+    TeWait(TestDesc),
+    TeResult(TestDesc, TestResult, Option<Duration>, TestOutput),
+    TeTimeout(TestDesc),
+    TeFilteredOut(FilterStats),
+    /// Sent periodically while waiting on concurrent tests when
+    /// `--progress` is enabled.
+    TeTick,
+    /// A test that was filtered out before the run, with `--report-skipped`.
+    TeSkipped(TestDesc, SkipReason),
+}
+
+/// A finished test, how long it ran for (if it ran at all), and its captured
+/// output.
+pub type MonitorMsg = (TestDesc, TestResult, Option<Duration>, TestOutput);
+
+/// Output captured while a test ran.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TestOutput {
+    /// Everything the test wrote to either stream, in order.
+    pub combined: Vec<u8>,
+    /// What the test wrote to standard output.
+    pub stdout: Vec<u8>,
+    /// What the test wrote to standard error, including panic messages.
+    pub stderr: Vec<u8>,
+    /// The allocations the test made, with `--track-allocations`.
+    pub allocations: Option<AllocationStats>,
+    /// Where the test panicked, if it failed by panicking.
+    pub panic_location: Option<PanicLocation>,
+}
+
+/// Counts of allocations made by a thread, or by a test.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AllocationStats {
+    /// How many times memory was allocated or reallocated.
+    pub allocations: u64,
+    /// The total size of those allocations, in bytes.
+    pub bytes: u64,
+}
+
+impl AllocationStats {
+    /// The allocations made since the counters read `earlier`.
+    fn since(self, earlier: AllocationStats) -> AllocationStats {
+        AllocationStats {
+            allocations: self.allocations.wrapping_sub(earlier.allocations),
+            bytes: self.bytes.wrapping_sub(earlier.bytes),
+        }
+    }
+}
+
+/// Capture buffers for a test's output, shared with the writers that fill
+/// them.
+#[derive(Clone, Default)]
+struct Capture {
+    combined: Arc<Mutex<Vec<u8>>>,
+    stdout: Arc<Mutex<Vec<u8>>>,
+    stderr: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Capture {
+    /// A writer for standard output.
+    fn stdout(&self) -> Tee {
+        Tee(Sink(self.combined.clone()), Sink(self.stdout.clone()))
+    }
+
+    /// A writer for standard error.
+    fn stderr(&self) -> Tee {
+        Tee(Sink(self.combined.clone()), Sink(self.stderr.clone()))
+    }
+
+    /// Takes the output captured so far, leaving the buffers empty.
+    fn take(&self) -> TestOutput {
+        TestOutput {
+            combined: Sink::take(&self.combined),
+            stdout: Sink::take(&self.stdout),
+            stderr: Sink::take(&self.stderr),
+            allocations: None,
+            panic_location: None,
+        }
+    }
+}
+
+/// Writes everything to both of its sinks.
+struct Tee<A = Sink, B = Sink>(A, B);
+
+impl<A: Write, B: Write> Write for Tee<A, B> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.0.write_all(data)?;
+        self.1.write_all(data)?;
+        Ok(data.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()?;
+        self.1.flush()
+    }
+}
+
+/// Capture buffer for a test's output.
+///
+/// A test may leave threads running that still hold a `Sink` after it
+/// returns, and those keep writing into the buffer until they exit. Once
+/// the test's output has been taken, later writes are discarded with the
+/// buffer. A thread that panics while writing poisons the lock, but the
+/// bytes in the buffer stay intact, so the poison is ignored rather than
+/// spreading the panic into the harness.
+struct Sink(Arc<Mutex<Vec<u8>>>);
+
+impl Sink {
+    /// Takes the output captured so far, leaving the buffer empty.
+    fn take(data: &Mutex<Vec<u8>>) -> Vec<u8> {
+        let mut buf = data.lock().unwrap_or_else(PoisonError::into_inner);
+        buf.split_off(0)
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let mut buf = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        Write::write(&mut *buf, data)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reruns of failed tests. Each test may be retried up to `--retries` times,
+/// and `--max-total-retries` caps the number of reruns across the whole run.
+///
+/// Only static test functions can be run again; dynamic ones are consumed by
+/// their first run and are always reported as-is.
+struct RetryBudget {
+    per_test: usize,
+    remaining: Option<usize>,
+    tests: HashMap<TestDesc, (fn(), usize)>,
+}
+
+impl RetryBudget {
+    fn new(opts: &TestOpts) -> RetryBudget {
+        RetryBudget {
+            per_test: opts.retries,
+            remaining: opts.max_total_retries,
+            tests: HashMap::new(),
+        }
+    }
+
+    /// Remembers how to rerun `test` should it fail.
+    fn track(&mut self, test: &TestDescAndFn) {
+        if self.per_test == 0 {
+            return;
+        }
+        if let TestFn::StaticTestFn(f) = test.testfn {
+            self.tests.insert(test.desc.clone(), (f, 0));
+        }
+    }
+
+    /// Returns the test to run again if `result` is a failure that still has
+    /// retries left, charging the attempt to the budget. Timeouts aren't
+    /// retried: the abandoned attempt may still be running, and would be
+    /// mistaken for the rerun.
+    fn retry(
+        &mut self,
+        desc: &TestDesc,
+        result: &TestResult,
+    ) -> Option<TestDescAndFn> {
+        match *result {
+            TestResult::TrFailed
+            | TestResult::TrFailedErr
+            | TestResult::TrFailedMsg(_)
+            | TestResult::TrFailedShouldPanic(_) => {}
+            _ => return None,
+        }
+        if self.remaining == Some(0) {
+            return None;
+        }
+        let (f, ref mut attempts) = *self.tests.get_mut(desc)?;
+        if *attempts >= self.per_test {
+            return None;
+        }
+        *attempts += 1;
+        if let Some(ref mut remaining) = self.remaining {
+            *remaining -= 1;
+        }
+        Some(TestDescAndFn {
+            desc: desc.clone(),
+            testfn: TestFn::StaticTestFn(f),
+        })
+    }
+
+    /// Marks a pass that took retries as such.
+    fn finish(&self, desc: &TestDesc, result: TestResult) -> TestResult {
+        match (result, self.tests.get(desc)) {
+            (TestResult::TrOk, Some(&(_, attempts))) if attempts > 0 => {
+                TestResult::TrOkWithRetries(attempts as u32)
+            }
+            (result, _) => result,
+        }
+    }
+}
+
+/// Upper bound of the delay `--chaos` may insert before starting a test.
+const CHAOS_MAX_DELAY_US: u64 = 1000;
+
+/// Seeded source of the random delays inserted by `--chaos`, and of the
+/// order picked by `--shuffle`. The same seed always produces the same
+/// sequence of delays, or the same order.
+struct Chaos {
+    state: u64,
+}
+
+impl Chaos {
+    fn new(seed: u64) -> Self {
+        const MIX: u64 = 0x9E37_79B9_7F4A_7C15;
+        // Xorshift never leaves an all-zero state, so the one seed that
+        // would start there starts like seed 0 instead.
+        let state = seed ^ MIX;
+        Chaos {
+            state: if state == 0 { MIX } else { state },
+        }
+    }
+
+    /// Picks a seed for a run that didn't ask for a particular one.
+    fn random_seed() -> u64 {
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        now.as_secs()
+            ^ u64::from(now.subsec_nanos())
+            ^ u64::from(process::id())
+    }
+
+    /// xorshift64*
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Does nothing, yields, or sleeps for up to `CHAOS_MAX_DELAY_US`, with
+    /// equal odds.
+    fn jitter(&mut self) {
+        let r = self.next();
+        match r % 3 {
+            0 => {}
+            1 => thread::yield_now(),
+            _ => thread::sleep(Duration::from_micros(
+                (r / 3) % CHAOS_MAX_DELAY_US,
+            )),
+        }
+    }
+
+    /// Fisher-Yates shuffle.
+    fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let j = (self.next() % (i as u64 + 1)) as usize;
+            items.swap(i, j);
+        }
+    }
+}
+
+/// When to warn that `desc` has been running for longer than `after`, if
+/// ever.
+fn warn_timeout(desc: &TestDesc, after: Option<Duration>) -> Option<Instant> {
+    match after {
+        Some(after) if !desc.no_timeout_warning => {
+            Some(Instant::now() + after)
+        }
+        _ => None,
+    }
+}
+
+pub fn run_tests<F>(
+    opts: &TestOpts,
+    tests: Vec<TestDescAndFn>,
+    callback: F,
+) -> io::Result<()>
+where
+    F: FnMut(TestEvent) -> io::Result<()>,
+{
+    run_tests_inner(opts, tests, callback).map(|_| ())
+}
+
+/// Like `run_tests`, but returns whether the run stopped early because a
+/// test failed, leaving other tests unrun.
+fn run_tests_inner<F>(
+    opts: &TestOpts,
+    tests: Vec<TestDescAndFn>,
+    mut callback: F,
+) -> io::Result<bool>
+where
+    F: FnMut(TestEvent) -> io::Result<()>,
+{
+    use std::collections::{self, HashMap};
+    use std::hash::BuildHasherDefault;
+    use std::sync::mpsc::{Receiver, RecvTimeoutError};
+    // Use a deterministic hasher
+    type TestMap = HashMap<
+        TestDesc,
+        Instant,
+        BuildHasherDefault<collections::hash_map::DefaultHasher>,
+    >;
+    fn get_timed_out_tests(running_tests: &mut TestMap) -> Vec<TestDesc> {
+        let now = Instant::now();
+        let timed_out = running_tests
+            .iter()
+            .filter_map(|(desc, timeout)| {
+                if now >= *timeout {
+                    Some(desc.clone())
+                } else {
+                    None
+                }
+            })
+            .collect();
+        for test in &timed_out {
+            running_tests.remove(test);
+        }
+        timed_out
+    };
+
+    fn timed_out(desc: &TestDesc, timeout: Duration) -> MonitorMsg {
+        (
+            desc.clone(),
+            TestResult::TrTimedOut(timeout),
+            Some(timeout),
+            TestOutput::default(),
+        )
+    }
+
+    // Tests that passed, but took longer than `--max-test-time`, fail.
+    fn check_time_budget(
+        result: TestResult,
+        exec_time: Option<Duration>,
+        budget: Option<Duration>,
+    ) -> TestResult {
+        match (result, exec_time, budget) {
+            (TestResult::TrOk, Some(took), Some(budget)) if took > budget => {
+                TestResult::TrFailedMsg(format!(
+                    "test passed but exceeded time budget of {:?} (took {:?})",
+                    budget, took
+                ))
+            }
+            (result, _, _) => result,
+        }
+    }
+
+    // Late results of tests that were reported as timed out are dropped.
+    fn take_abandoned(abandoned: &mut Vec<TestDesc>, desc: &TestDesc) -> bool {
+        match abandoned.iter().position(|d| d == desc) {
+            Some(i) => {
+                abandoned.swap_remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Waits for the result of `desc`, the only test being waited for.
+    fn recv_result(
+        rx: &Receiver<MonitorMsg>,
+        desc: &TestDesc,
+        timeout: Option<Duration>,
+        abandoned: &mut Vec<TestDesc>,
+    ) -> MonitorMsg {
+        let deadline = timeout.map(|t| (t, Instant::now() + t));
+        loop {
+            let msg = match deadline {
+                Some((t, deadline)) => {
+                    let now = Instant::now();
+                    let left = if deadline > now {
+                        deadline - now
+                    } else {
+                        Duration::new(0, 0)
+                    };
+                    match rx.recv_timeout(left) {
+                        Ok(msg) => msg,
+                        Err(_) => {
+                            abandoned.push(desc.clone());
+                            return timed_out(desc, t);
+                        }
+                    }
+                }
+                None => rx.recv().unwrap(),
+            };
+            if !take_abandoned(abandoned, &msg.0) {
+                return msg;
+            }
+        }
+    }
+
+    fn calc_timeout(running_tests: &TestMap) -> Option<Duration> {
+        running_tests.values().min().map(|next_timeout| {
+            let now = Instant::now();
+            if *next_timeout >= now {
+                *next_timeout - now
+            } else {
+                Duration::new(0, 0)
+            }
+        })
+    };
+
+    if opts.process_isolation
+        && !cfg!(all(unix, feature = "process-isolation"))
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            PROCESS_ISOLATION_UNSUPPORTED,
+        ));
+    }
+    let _panic_hook = PanicLocationHook::install();
+
+    let (mut filtered_tests, skipped) =
+        filter_tests_with_reasons(opts, tests)?;
+    if !opts.bench_benchmarks {
+        filtered_tests = convert_benchmarks_to_tests(filtered_tests);
+    }
+
+    let filtered_tests = {
+        let mut filtered_tests = filtered_tests;
+        for test in &mut filtered_tests {
+            test.desc.name =
+                test.desc.name.with_padding(test.testfn.padding());
+        }
+
+        filtered_tests
+    };
+
+    callback(TestEvent::TeFilteredOut(FilterStats::new(&skipped)))?;
+
+    let filtered_descs =
+        filtered_tests.iter().map(|t| t.desc.clone()).collect();
+
+    callback(TestEvent::TeFiltered(filtered_descs))?;
+
+    if opts.report_skipped {
+        for (desc, reason) in skipped {
+            callback(TestEvent::TeSkipped(desc, reason))?;
+        }
+    }
+
+    let (filtered_tests, filtered_benchs): (Vec<_>, _) =
+        filtered_tests.into_iter().partition(|e| match e.testfn {
+            TestFn::StaticTestFn(_) | TestFn::DynTestFn(_) => true,
+            _ => false,
+        });
+
+    let mut filtered_tests = filtered_tests;
+    if let Some(seed) = opts.shuffle {
+        Chaos::new(seed).shuffle(&mut filtered_tests);
+    } else if opts.order != TestOrder::Alphabetical {
+        let timings = match opts.timings {
+            Some(ref path) => read_timings(path)?,
+            None => HashMap::new(),
+        };
+        let slowest_first = opts.order == TestOrder::SlowestFirst;
+        // The sort is stable, so ties and the tests without a duration keep
+        // their alphabetical order. `!ns` puts the longest durations first.
+        filtered_tests.sort_by_key(|t| {
+            match timings.get(t.desc.name.as_slice()) {
+                Some(&ns) if slowest_first => (opts.untimed_first, !ns),
+                Some(&ns) => (opts.untimed_first, ns),
+                None => (!opts.untimed_first, 0),
+            }
+        });
+    }
+
+    let concurrency = resolve_concurrency(opts);
+
+    let known_failures = match opts.known_failures {
+        Some(ref path) if opts.fail_fast_on_new => read_test_names(path)?,
+        _ => HashSet::new(),
+    };
+    let stops_the_run = |desc: &TestDesc, result: &TestResult| {
+        let failed = match *result {
+            TestResult::TrFailed
+            | TestResult::TrFailedErr
+            | TestResult::TrFailedMsg(_)
+            | TestResult::TrFailedShouldPanic(_)
+            | TestResult::TrTimedOut(_) => true,
+            _ => false,
+        };
+        let is_new = !known_failures.contains(desc.name.as_slice());
+        failed && (opts.fail_fast || (opts.fail_fast_on_new && is_new))
+    };
+    // Set once a test fails in a way that should abort the run; tests that
+    // are already running are still waited for.
+    let mut stop_scheduling = false;
+    let mut retries = RetryBudget::new(opts);
+    let mut chaos = opts.chaos.map(Chaos::new);
+
+    let mut remaining = filtered_tests;
+    remaining.reverse();
+    let mut pending = 0;
+
+    let (tx, rx) = channel::<MonitorMsg>();
+
+    let mut running_tests: TestMap = HashMap::default();
+    // When each running test fails for exceeding `--timeout`.
+    let mut deadlines: TestMap = HashMap::default();
+    let mut abandoned = Vec::new();
+
+    let tick = if opts.progress {
+        Some(Duration::from_millis(PROGRESS_REFRESH_MS))
+    } else {
+        None
+    };
+
+    if concurrency == 1 {
+        // A test can only be given up on if it runs on its own thread.
+        let concurrent = if opts.timeout.is_some() {
+            Concurrent::Yes
+        } else {
+            Concurrent::No
+        };
+        while !stop_scheduling && !remaining.is_empty() {
+            let test = remaining.pop().unwrap();
+            let desc = test.desc.clone();
+            retries.track(&test);
+            callback(TestEvent::TeWait(test.desc.clone()))?;
+            run_test(opts, !opts.run_tests, test, tx.clone(), concurrent);
+            let mut msg =
+                recv_result(&rx, &desc, opts.timeout, &mut abandoned);
+            while let Some(test) = retries.retry(&msg.0, &msg.1) {
+                run_test(opts, false, test, tx.clone(), concurrent);
+                msg = recv_result(&rx, &desc, opts.timeout, &mut abandoned);
+            }
+            let (test, result, exec_time, stdout) = msg;
+            let result =
+                check_time_budget(result, exec_time, opts.max_test_time);
+            let result = retries.finish(&test, result);
+            stop_scheduling = stops_the_run(&test, &result);
+            callback(TestEvent::TeResult(test, result, exec_time, stdout))?;
+        }
+    } else {
+        while pending > 0 || (!stop_scheduling && !remaining.is_empty()) {
+            while !stop_scheduling
+                && pending < concurrency
+                && !remaining.is_empty()
+            {
+                let test = remaining.pop().unwrap();
+                if let Some(timeout) =
+                    warn_timeout(&test.desc, opts.warn_timeout)
+                {
+                    running_tests.insert(test.desc.clone(), timeout);
+                }
+                if let Some(t) = opts.timeout {
+                    deadlines.insert(test.desc.clone(), Instant::now() + t);
+                }
+                retries.track(&test);
+                callback(TestEvent::TeWait(test.desc.clone()))?; //here no pad
+                if let Some(ref mut chaos) = chaos {
+                    chaos.jitter();
+                }
+                run_test(
+                    opts,
+                    !opts.run_tests,
+                    test,
+                    tx.clone(),
+                    Concurrent::Yes,
+                );
+                pending += 1;
+            }
+
+            let mut finished = Vec::new();
+            loop {
+                let timeout = [
+                    calc_timeout(&running_tests),
+                    calc_timeout(&deadlines),
+                    tick,
+                ]
+                .iter()
+                .filter_map(|t| *t)
+                .min();
+                let res = if let Some(timeout) = timeout {
+                    let res = rx.recv_timeout(timeout);
+                    for test in get_timed_out_tests(&mut running_tests) {
+                        callback(TestEvent::TeTimeout(test))?;
+                    }
+                    for desc in get_timed_out_tests(&mut deadlines) {
+                        finished.push(timed_out(&desc, opts.timeout.unwrap()));
+                        abandoned.push(desc);
+                    }
+                    res
+                } else {
+                    rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+                };
+                match res {
+                    Ok(msg) => {
+                        if !take_abandoned(&mut abandoned, &msg.0) {
+                            finished.push(msg);
+                        }
+                        break;
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        if !finished.is_empty() {
+                            break;
+                        }
+                        if tick.is_some() {
+                            callback(TestEvent::TeTick)?;
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => {
+                        panic!("test result channel disconnected")
+                    }
+                }
+            }
+
+            for (desc, result, exec_time, stdout) in finished {
+                if let Some(test) = retries.retry(&desc, &result) {
+                    // The rerun takes over the slot of the failed attempt.
+                    if let Some(t) = opts.timeout {
+                        deadlines.insert(desc, Instant::now() + t);
+                    }
+                    run_test(opts, false, test, tx.clone(), Concurrent::Yes);
+                    continue;
+                }
+                let result =
+                    check_time_budget(result, exec_time, opts.max_test_time);
+                let result = retries.finish(&desc, result);
+                running_tests.remove(&desc);
+                deadlines.remove(&desc);
+                if stops_the_run(&desc, &result) {
+                    stop_scheduling = true;
+                }
+
+                callback(TestEvent::TeResult(
+                    desc, result, exec_time, stdout,
+                ))?;
+                pending -= 1;
+            }
+        }
+    }
+
+    let stopped_early = stop_scheduling
+        && (!remaining.is_empty()
+            || (opts.bench_benchmarks && !filtered_benchs.is_empty()));
+    if opts.bench_benchmarks && !stop_scheduling {
+        // All benchmarks run at the end, in serial.
+        let _pin = opts.bench_pin_cpu.and_then(CpuPin::new);
+        for b in filtered_benchs {
+            callback(TestEvent::TeWait(b.desc.clone()))?;
+            run_test(opts, false, b, tx.clone(), Concurrent::No);
+            let (test, result, exec_time, stdout) = rx.recv().unwrap();
+            callback(TestEvent::TeResult(test, result, exec_time, stdout))?;
+        }
+    }
+    Ok(stopped_early)
+}
+
+/// Keeps the current thread on a single CPU while alive, restoring its
+/// previous affinity when dropped.
+#[cfg(target_os = "linux")]
+struct CpuPin(libc::cpu_set_t);
+
+#[cfg(target_os = "linux")]
+impl CpuPin {
+    /// Returns `None`, leaving the affinity untouched, if `cpu` doesn't
+    /// exist or isn't available to this process.
+    fn new(cpu: usize) -> Option<CpuPin> {
+        use std::mem;
+
+        if cpu >= libc::CPU_SETSIZE as usize {
+            return None;
+        }
+        let size = mem::size_of::<libc::cpu_set_t>();
+        unsafe {
+            let mut saved = mem::zeroed();
+            if libc::sched_getaffinity(0, size, &mut saved) != 0 {
+                return None;
+            }
+            let mut set = mem::zeroed();
+            libc::CPU_ZERO(&mut set);
+            libc::CPU_SET(cpu, &mut set);
+            if libc::sched_setaffinity(0, size, &set) != 0 {
+                return None;
+            }
+            Some(CpuPin(saved))
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for CpuPin {
+    fn drop(&mut self) {
+        let size = std::mem::size_of::<libc::cpu_set_t>();
+        unsafe {
+            libc::sched_setaffinity(0, size, &self.0);
+        }
+    }
+}
+
+/// Thread affinity is only supported on Linux; elsewhere pinning is a no-op.
+#[cfg(not(target_os = "linux"))]
+struct CpuPin;
+
+#[cfg(not(target_os = "linux"))]
+impl CpuPin {
+    fn new(_cpu: usize) -> Option<CpuPin> {
+        None
+    }
+}
+
+/// How many tests to run at once, from the first of these that is set:
+///
+/// 1. `--test-threads`, i.e. `TestOpts::test_threads`,
+/// 2. the embedder's `Options::concurrency_provider`,
+/// 3. the `RUST_TEST_THREADS` environment variable,
+/// 4. the number of CPUs.
+///
+/// Never less than 1.
+fn resolve_concurrency(opts: &TestOpts) -> usize {
+    match env::var("RUST_TEST_THREADS") {
+        Ok(s) => resolve_concurrency_with(opts, Some(&s), cpus),
+        Err(..) => resolve_concurrency_with(opts, None, cpus),
+    }
+}
+
+/// `resolve_concurrency`, with the value of `RUST_TEST_THREADS` and the
+/// CPU count passed in.
+fn resolve_concurrency_with(
+    opts: &TestOpts,
+    env_threads: Option<&str>,
+    cpus: fn() -> usize,
+) -> usize {
+    let n = match (opts.test_threads, opts.options.concurrency_provider) {
+        (Some(n), _) => n,
+        (None, Some(provider)) => provider(),
+        // `parse_opts` reports bad values; tests run without it still get a
+        // predictable thread count.
+        (None, None) => match env_threads {
+            Some(s) => parse_test_threads("RUST_TEST_THREADS", s).unwrap_or(1),
+            None => cpus(),
+        },
+    };
+    cmp::max(n, 1)
+}
+
+/// The number of CPUs available to run tests on.
+#[allow(deprecated)]
+fn cpus() -> usize {
+    #[cfg(windows)]
+    #[allow(nonstandard_style)]
+    fn num_cpus() -> usize {
+        #[repr(C)]
+        struct SYSTEM_INFO {
+            wProcessorArchitecture: u16,
+            wReserved: u16,
+            dwPageSize: u32,
+            lpMinimumApplicationAddress: *mut u8,
+            lpMaximumApplicationAddress: *mut u8,
+            dwActiveProcessorMask: *mut u8,
+            dwNumberOfProcessors: u32,
+            dwProcessorType: u32,
+            dwAllocationGranularity: u32,
+            wProcessorLevel: u16,
+            wProcessorRevision: u16,
+        }
+        extern "system" {
+            fn GetSystemInfo(info: *mut SYSTEM_INFO) -> i32;
+        }
+        unsafe {
+            let mut sysinfo = std::mem::zeroed();
+            GetSystemInfo(&mut sysinfo);
+            sysinfo.dwNumberOfProcessors as usize
+        }
+    }
+
+    #[cfg(target_os = "redox")]
+    fn num_cpus() -> usize {
+        // FIXME: Implement num_cpus on Redox
+        1
+    }
+
+    #[cfg(any(
+        all(target_arch = "wasm32", not(target_os = "emscripten")),
+        all(target_vendor = "fortanix", target_env = "sgx")
+    ))]
+    fn num_cpus() -> usize {
+        1
+    }
+
+    #[cfg(any(
+        target_os = "android",
+        target_os = "cloudabi",
+        target_os = "emscripten",
+        target_os = "fuchsia",
+        target_os = "ios",
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "solaris"
+    ))]
+    fn num_cpus() -> usize {
+        unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) as usize }
+    }
+
+    #[cfg(any(
+        target_os = "freebsd",
+        target_os = "dragonfly",
+        target_os = "bitrig",
+        target_os = "netbsd"
+    ))]
+    fn num_cpus() -> usize {
+        use std::ptr;
+
+        let mut cpus: libc::c_uint = 0;
+        let mut cpus_size = std::mem::size_of_val(&cpus);
+
+        unsafe {
+            cpus = libc::sysconf(libc::_SC_NPROCESSORS_ONLN) as libc::c_uint;
+        }
+        if cpus < 1 {
+            let mut mib = [libc::CTL_HW, libc::HW_NCPU, 0, 0];
+            unsafe {
+                libc::sysctl(
+                    mib.as_mut_ptr(),
+                    2,
+                    &mut cpus as *mut _ as *mut _,
+                    &mut cpus_size as *mut _ as *mut _,
+                    ptr::null_mut(),
+                    0,
+                );
+            }
+            if cpus < 1 {
+                cpus = 1;
+            }
+        }
+        cpus as usize
+    }
+
+    #[cfg(target_os = "openbsd")]
+    fn num_cpus() -> usize {
+        use std::ptr;
+
+        let mut cpus: libc::c_uint = 0;
+        let mut cpus_size = std::mem::size_of_val(&cpus);
+        let mut mib = [libc::CTL_HW, libc::HW_NCPU, 0, 0];
+
+        unsafe {
+            libc::sysctl(
+                mib.as_mut_ptr(),
+                2,
+                &mut cpus as *mut _ as *mut _,
+                &mut cpus_size as *mut _ as *mut _,
+                ptr::null_mut(),
+                0,
+            );
+        }
+        if cpus < 1 {
+            cpus = 1;
+        }
+        cpus as usize
+    }
+
+    #[cfg(target_os = "haiku")]
+    fn num_cpus() -> usize {
+        // FIXME: implement
+        1
+    }
+
+    #[cfg(target_os = "l4re")]
+    fn num_cpus() -> usize {
+        // FIXME: implement
+        1
+    }
+
+    num_cpus()
+}
+
+/// Parses a thread count from `--test-threads` or `RUST_TEST_THREADS`,
+/// `source` naming which one in the error.
+fn parse_test_threads(source: &str, value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(0) => Err(format!("{} must not be 0", source)),
+        Ok(n) => Ok(n),
+        Err(e) => {
+            Err(format!("{} must be a number > 0 (error: {})", source, e))
+        }
+    }
+}
+
+/// Parses a number of seconds from `--warn-timeout` or `RUST_TEST_TIMEOUT`,
+/// `source` naming which one in the error. Zero turns the warning off.
+fn parse_warn_timeout(
+    source: &str,
+    value: &str,
+) -> Result<Option<Duration>, String> {
+    match value.parse::<u64>() {
+        Ok(0) => Ok(None),
+        Ok(n) => Ok(Some(Duration::from_secs(n))),
+        Err(e) => Err(format!("{} must be a number (error: {})", source, e)),
+    }
+}
+
+/// Reads a file of test names, one per line. Blank lines and lines starting
+/// with `#` are skipped.
+fn read_test_names(path: &Path) -> io::Result<HashSet<String>> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect())
+}
+
+/// Reads the duration of each test from an `--export-trace` file, in
+/// nanoseconds. Lines for tests that didn't run are skipped, and the last
+/// remaining line wins for tests listed more than once.
+fn read_timings(path: &Path) -> io::Result<HashMap<String, u64>> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+
+    let mut timings = HashMap::new();
+    for line in contents.lines().filter(|line| !line.is_empty()) {
+        let mut fields = line.rsplitn(3, '\t');
+        let (ns, name) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(ns), Some(_status), Some(name)) => (ns, name),
+            _ => return Err(malformed_trace_line(path, line)),
+        };
+        let mut unescaped = String::new();
+        let mut chars = name.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                unescaped.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('\\') => unescaped.push('\\'),
+                Some('t') => unescaped.push('\t'),
+                Some('n') => unescaped.push('\n'),
+                Some('r') => unescaped.push('\r'),
+                _ => return Err(malformed_trace_line(path, line)),
+            }
+        }
+        if ns == "-" {
+            continue;
+        }
+        match ns.parse() {
+            Ok(ns) => timings.insert(unescaped, ns),
+            Err(_) => return Err(malformed_trace_line(path, line)),
+        };
+    }
+    Ok(timings)
+}
+
+fn malformed_trace_line(path: &Path, line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: malformed trace line `{}`", path.display(), line),
+    )
+}
+
+/// A test filter or `--skip` filter, compiled once for matching against
+/// every test name.
+enum NameFilter<'a> {
+    Substring(&'a str),
+    Exact(&'a str),
+    Prefix(&'a str),
+    #[cfg(feature = "regex")]
+    Regex(regex::Regex),
+}
+
+impl<'a> NameFilter<'a> {
+    fn new(opts: &TestOpts, pattern: &'a str) -> Result<Self, String> {
+        if opts.filter_regex {
+            Self::regex(pattern)
+        } else if opts.filter_exact {
+            Ok(NameFilter::Exact(pattern))
+        } else if opts.filter_prefix {
+            Ok(NameFilter::Prefix(pattern))
+        } else {
+            Ok(NameFilter::Substring(pattern))
+        }
+    }
+
+    #[cfg(feature = "regex")]
+    fn regex(pattern: &str) -> Result<Self, String> {
+        match regex::Regex::new(pattern) {
+            Ok(re) => Ok(NameFilter::Regex(re)),
+            Err(e) => {
+                Err(format!("invalid filter regex `{}`: {}", pattern, e))
+            }
+        }
+    }
+
+    #[cfg(not(feature = "regex"))]
+    fn regex(_: &str) -> Result<Self, String> {
+        Err(
+            "the option --filter-regex requires libtest to be built with \
+             the `regex` feature"
+                .into(),
+        )
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match *self {
+            NameFilter::Substring(filter) => name.contains(filter),
+            NameFilter::Exact(filter) => name == filter,
+            NameFilter::Prefix(filter) => {
+                name.starts_with(filter)
+                    && (name.len() == filter.len()
+                        || filter.ends_with("::")
+                        || name[filter.len()..].starts_with("::"))
+            }
+            #[cfg(feature = "regex")]
+            NameFilter::Regex(ref re) => re.is_match(name),
+        }
+    }
+}
+
+/// Why a test in the binary didn't run.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Marked `#[ignore]`, and ignored tests weren't requested.
+    Ignored,
+    /// Didn't match the test filter or the tags to include, had a tag to
+    /// exclude, or wasn't ignored under `--ignored`.
+    Filtered,
+    /// Matched one of the `--skip` filters, or named by `--skip-exact`.
+    SkipFlag,
+    /// A `#[should_panic]` test, dropped by `--exclude-should-panic`.
+    ExcludedShouldPanic,
+    /// Assigned to another shard than the one given by `--shard-index`.
+    Shard,
+}
+
+impl SkipReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SkipReason::Ignored => "ignored",
+            SkipReason::Filtered => "filtered",
+            SkipReason::SkipFlag => "skip_flag",
+            SkipReason::ExcludedShouldPanic => "excluded_should_panic",
+            SkipReason::Shard => "shard",
+        }
+    }
+}
+
+/// How many tests were left out of a run, by what left them out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FilterStats {
+    /// Didn't match the test filter, or weren't ignored under `--ignored`.
+    pub by_filter: usize,
+    /// Matched one of the `--skip` filters.
+    pub by_skip: usize,
+    /// `#[should_panic]` tests dropped by `--exclude-should-panic`.
+    pub by_should_panic: usize,
+    /// Assigned to another shard than the one given by `--shard-index`.
+    pub by_shard: usize,
+}
+
+impl FilterStats {
+    /// Tallies the tests dropped by `filter_tests_with_reasons`.
+    pub fn new(skipped: &[(TestDesc, SkipReason)]) -> Self {
+        let mut stats = Self::default();
+        for &(_, reason) in skipped {
+            match reason {
+                SkipReason::Ignored | SkipReason::Filtered => {
+                    stats.by_filter += 1
+                }
+                SkipReason::SkipFlag => stats.by_skip += 1,
+                SkipReason::ExcludedShouldPanic => stats.by_should_panic += 1,
+                SkipReason::Shard => stats.by_shard += 1,
+            }
+        }
+        stats
+    }
+
+    pub fn total(&self) -> usize {
+        self.by_filter + self.by_skip + self.by_should_panic + self.by_shard
+    }
+}
+
+/// Writes `N filtered out`, followed by a breakdown when anything other than
+/// the test filter contributed to `N`.
+impl fmt::Display for FilterStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} filtered out", self.total())?;
+        if self.total() == self.by_filter {
+            return Ok(());
+        }
+        let parts = [
+            (self.by_filter, "by filter"),
+            (self.by_skip, "by --skip"),
+            (self.by_should_panic, "by --exclude-should-panic"),
+            (self.by_shard, "in other shards"),
+        ];
+        let breakdown = parts
+            .iter()
+            .filter(|&&(n, _)| n > 0)
+            .map(|&(n, what)| format!("{} {}", n, what))
+            .collect::<Vec<_>>();
+        write!(f, " ({})", breakdown.join(", "))
+    }
+}
+
+/// # Panics
+///
+/// Panics if `opts.test_list_file`, or `opts.failures_file` under
+/// `opts.last_failed`, is set and can't be read, or if a filter or
+/// `opts.shard` is invalid; use `filter_tests_with_reasons` to handle the
+/// error instead.
+pub fn filter_tests(
+    opts: &TestOpts,
+    tests: Vec<TestDescAndFn>,
+) -> Vec<TestDescAndFn> {
+    match filter_tests_with_reasons(opts, tests) {
+        Ok((filtered, _)) => filtered,
+        Err(e) => panic!("couldn't filter the tests: {}", e),
+    }
+}
+
+/// Like `filter_tests`, but also returns the tests that were dropped, sorted
+/// by name, along with the filter that dropped them.
+///
+/// Ignored tests aren't dropped here: they're still run, and reported as
+/// ignored. Fails if the test list file or the failures file can't be read,
+/// or if `opts.shard` isn't a valid shard.
+pub fn filter_tests_with_reasons(
+    opts: &TestOpts,
+    tests: Vec<TestDescAndFn>,
+) -> io::Result<(Vec<TestDescAndFn>, Vec<(TestDesc, SkipReason)>)> {
+    if let Some((index, count)) = opts.shard {
+        if index >= count {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "the shard index must be less than the shard count (got \
+                     {} and {})",
+                    index, count
+                ),
+            ));
+        }
+    }
+    let listed = match opts.test_list_file {
+        Some(ref path) => Some(read_test_names(path)?),
+        None => None,
+    };
+    let last_failed = match opts.failures_file {
+        Some(ref path) if opts.last_failed => match read_test_names(path) {
+            Ok(names) => Some(names),
+            // No run has recorded its failures yet, so run everything.
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        },
+        _ => None,
+    };
+    let mut filtered = tests;
+    let mut skipped = Vec::new();
+    let mut drop_unless =
+        |tests: &mut Vec<TestDescAndFn>,
+         reason: SkipReason,
+         keep: &dyn Fn(&TestDescAndFn) -> bool| {
+            tests.retain(|test| {
+                let kept = keep(test);
+                if !kept {
+                    skipped.push((test.desc.clone(), reason));
+                }
+                kept
+            });
+        };
+    let name_filter = |pattern| {
+        NameFilter::new(opts, pattern)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    };
+    let filters = opts
+        .filters
+        .iter()
+        .map(|f| name_filter(f))
+        .collect::<io::Result<Vec<_>>>()?;
+    let skip_filters = opts
+        .skip
+        .iter()
+        .map(|sf| name_filter(sf))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    // Remove tests that don't match any of the test filters, unless they're
+    // listed in the test list file
+    if !filters.is_empty() || listed.is_some() {
+        drop_unless(&mut filtered, SkipReason::Filtered, &|test| {
+            let in_list = match listed {
+                Some(ref names) => names.contains(test.desc.name.as_slice()),
+                None => false,
+            };
+            let in_filter =
+                filters.iter().any(|f| f.matches(test.desc.name.as_slice()));
+            in_list || in_filter
+        });
+    }
+
+    // Remove tests without any of the included tags, or with an excluded one
+    if !opts.include_tags.is_empty() || !opts.exclude_tags.is_empty() {
+        drop_unless(&mut filtered, SkipReason::Filtered, &|test| {
+            let tagged = |tags: &[String]| {
+                test.desc
+                    .tags
+                    .iter()
+                    .any(|t| tags.iter().any(|tag| tag == t))
+            };
+            (opts.include_tags.is_empty() || tagged(&opts.include_tags))
+                && !tagged(&opts.exclude_tags)
+        });
+    }
+
+    // Remove tests that passed last time
+    if let Some(ref names) = last_failed {
+        drop_unless(&mut filtered, SkipReason::Filtered, &|test| {
+            names.contains(test.desc.name.as_slice())
+        });
+    }
+
+    // Remove tests of other kinds than the one requested
+    if let Some(kind) = opts.test_kind {
+        drop_unless(&mut filtered, SkipReason::Filtered, &|test| {
+            test.desc.test_type == kind
+        });
+    }
+
+    // Skip tests that match any of the skip filters, or are named exactly
+    // by `--skip-exact`
+    drop_unless(&mut filtered, SkipReason::SkipFlag, &|test| {
+        let name = test.desc.name.as_slice();
+        !skip_filters.iter().any(|sf| sf.matches(name))
+            && !opts.skip_exact.iter().any(|s| s == name)
+    });
+
+    // Excludes #[should_panic] tests
+    if opts.exclude_should_panic {
+        drop_unless(&mut filtered, SkipReason::ExcludedShouldPanic, &|test| {
+            test.desc.should_panic == ShouldPanic::No
+        });
+    }
+
+    // maybe unignore tests
+    match opts.run_ignored {
+        RunIgnored::Yes => {
+            filtered
+                .iter_mut()
+                .for_each(|test| test.desc.ignore = false);
+        }
+        RunIgnored::Only => {
+            drop_unless(&mut filtered, SkipReason::Filtered, &|test| {
+                test.desc.ignore
+            });
+            filtered
+                .iter_mut()
+                .for_each(|test| test.desc.ignore = false);
+        }
+        RunIgnored::No => {}
+    }
+
+    // Sort the tests alphabetically
+    if !opts.no_sort {
+        filtered.sort_by(|t1, t2| {
+            t1.desc.name.as_slice().cmp(t2.desc.name.as_slice())
+        });
+    }
+
+    // Keep every `count`th test, so shards are disjoint and stable as long
+    // as every worker selects the same tests
+    if let Some((index, count)) = opts.shard {
+        let position = Cell::new(0);
+        drop_unless(&mut filtered, SkipReason::Shard, &|_| {
+            let i = position.get();
+            position.set(i + 1);
+            i % count == index
+        });
+    }
+
+    skipped.sort_by(|(d1, _), (d2, _)| {
+        d1.name.as_slice().cmp(d2.name.as_slice())
+    });
+
+    Ok((filtered, skipped))
+}
+
+pub fn convert_benchmarks_to_tests(
+    tests: Vec<TestDescAndFn>,
+) -> Vec<TestDescAndFn> {
+    // convert benchmarks to tests, if we're not benchmarking them
+    tests
+        .into_iter()
+        .map(|x| {
+            let mut desc = x.desc;
+            if let TestFn::StaticBenchFn(_) | TestFn::DynBenchFn(_) = x.testfn
+            {
+                desc.is_converted_bench = true;
+            }
+            let testfn = match x.testfn {
+                TestFn::DynBenchFn(bench) => {
+                    TestFn::DynTestFn(Box::new(move || {
+                        bench::run_once(|b| {
+                            __rust_begin_short_backtrace(|| bench.run(b))
+                        })
+                    }))
+                }
+                TestFn::StaticBenchFn(benchfn) => {
+                    TestFn::DynTestFn(Box::new(move || {
+                        bench::run_once(|b| {
+                            __rust_begin_short_backtrace(|| benchfn(b))
+                        })
+                    }))
+                }
+                f => f,
+            };
+            TestDescAndFn { desc, testfn }
+        })
+        .collect()
+}
+
+pub fn run_test(
+    opts: &TestOpts,
+    force_ignore: bool,
+    test: TestDescAndFn,
+    monitor_ch: Sender<MonitorMsg>,
+    concurrency: Concurrent,
+) {
+    /// The options a test runs with, copied out of `TestOpts` so the test
+    /// doesn't borrow them from another thread.
+    #[derive(Clone, Copy)]
+    struct RunSettings {
+        nocapture: bool,
+        capture_fds: bool,
+        backtrace_snippets: bool,
+        process_isolation: bool,
+        timeout: Option<Duration>,
+        options: Options,
+        allocation_counter: Option<AllocationCounterFn>,
+    }
+
+    fn run_test_inner(
+        desc: TestDesc,
+        monitor_ch: Sender<MonitorMsg>,
+        settings: RunSettings,
+        testfn: Box<dyn FnBox() + Send>,
+        concurrency: Concurrent,
+    ) {
+        let RunSettings {
+            nocapture,
+            capture_fds,
+            backtrace_snippets,
+            process_isolation,
+            timeout,
+            options,
+            allocation_counter,
+        } = settings;
+
+        // Buffers for capturing standard I/O
+        let capture = Capture::default();
+
+        let parent_desc = desc.clone();
+        let runtest = move |monitor_ch: Sender<MonitorMsg>| {
+            let oldio = if nocapture {
+                None
+            } else {
+                Some((
+                    io::set_print(Some(Box::new(capture.stdout()))),
+                    io::set_panic(Some(Box::new(capture.stderr()))),
+                ))
+            };
+
+            let allocations_before =
+                allocation_counter.map(|count| (count, count()));
+            let start = Instant::now();
+            let (result, hook_failure) = {
+                let _fd_capture = if capture_fds && !nocapture {
+                    start_fd_capture(&capture)
+                } else {
+                    None
+                };
+                run_with_hooks(&desc, options, testfn)
+            };
+            let exec_time = start.elapsed();
+            let allocations = allocations_before
+                .map(|(count, before)| count().since(before));
+
+            if let Some((printio, panicio)) = oldio {
+                io::set_print(printio);
+                io::set_panic(panicio);
+            };
+
+            let returned_err = RETURNED_ERR.with(|r| r.replace(false));
+            let panic_location =
+                PANIC_LOCATION.with(|l| l.borrow_mut().take());
+            let test_result =
+                match (hook_failure, calc_result(&desc, result, options)) {
+                    (Some(msg), _) => TestResult::TrFailedMsg(msg),
+                    (None, TestResult::TrFailed) if returned_err => {
+                        TestResult::TrFailedErr
+                    }
+                    (None, test_result) => test_result,
+                };
+            let mut output = capture.take();
+            output.allocations = allocations;
+            // A returned error panics inside this crate, which is of no
+            // interest to the test's author.
+            output.panic_location = match test_result {
+                TestResult::TrFailed | TestResult::TrFailedShouldPanic(_) => {
+                    panic_location
+                }
+                _ => None,
+            };
+            if backtrace_snippets && test_result == TestResult::TrFailed {
+                if let Some(ref location) = output.panic_location {
+                    let snippet =
+                        source_snippet(&location.file, location.line);
+                    if let Some(snippet) = snippet {
+                        output.combined.extend_from_slice(snippet.as_bytes());
+                        output.stderr.extend_from_slice(snippet.as_bytes());
+                    }
+                }
+            }
+            monitor_ch
+                .send((desc.clone(), test_result, Some(exec_time), output))
+                .unwrap();
+        };
+
+        if process_isolation {
+            run_in_child(
+                parent_desc,
+                monitor_ch,
+                concurrency,
+                timeout,
+                runtest,
+            );
+            return;
+        }
+
+        // If the platform is single-threaded we're just going to run
+        // the test synchronously, regardless of the concurrency
+        // level.
+        let supports_threads =
+            !cfg!(any(target_os = "emscripten", target_arch = "wasm32"));
+        if concurrency == Concurrent::Yes && supports_threads {
+            let name = parent_desc.name.as_slice().to_owned();
+            let cfg = thread::Builder::new().name(name);
+            cfg.spawn(move || runtest(monitor_ch)).unwrap();
+        } else {
+            runtest(monitor_ch);
+        }
+    }
+
+    let TestDescAndFn { desc, testfn } = test;
+
+    let concurrency = if opts.force_run_in_process {
+        Concurrent::No
+    } else {
+        concurrency
+    };
+
+    let ignore_because_panic_abort = cfg!(target_arch = "wasm32")
+        && !cfg!(target_os = "emscripten")
+        && desc.should_panic != ShouldPanic::No;
+
+    if force_ignore || desc.ignore || ignore_because_panic_abort {
+        monitor_ch
+            .send((desc, TestResult::TrIgnored, None, TestOutput::default()))
+            .unwrap();
+        return;
+    }
+
+    let settings = RunSettings {
+        nocapture: opts.nocapture,
+        capture_fds: opts.capture_fds,
+        backtrace_snippets: opts.backtrace_snippets,
+        process_isolation: opts.process_isolation,
+        timeout: opts.timeout,
+        options: opts.options,
+        allocation_counter: allocation_counter(opts),
+    };
+
+    match testfn {
+        TestFn::DynBenchFn(bencher) => {
+            crate::bench::benchmark(
+                desc,
+                &monitor_ch,
+                opts.nocapture,
+                opts.bench_samples,
+                |harness| bencher.run(harness),
+            );
+        }
+        TestFn::StaticBenchFn(benchfn) => {
+            crate::bench::benchmark(
+                desc,
+                &monitor_ch,
+                opts.nocapture,
+                opts.bench_samples,
+                |harness| (benchfn)(harness),
+            );
+        }
+        TestFn::DynTestFn(f) => {
+            let cb = move || __rust_begin_short_backtrace(f);
+            run_test_inner(
+                desc,
+                monitor_ch,
+                settings,
+                Box::new(cb),
+                concurrency,
+            )
+        }
+        TestFn::StaticTestFn(f) => run_test_inner(
+            desc,
+            monitor_ch,
+            settings,
+            Box::new(move || __rust_begin_short_backtrace(f)),
+            concurrency,
+        ),
+    }
+}
+
+/// Runs `runtest` in a forked child for `--process-isolation`. The child is
+/// forked right away, on the thread scheduling the tests, and waited for on
+/// a thread of its own unless `concurrency` is `No`. A child still running
+/// after `timeout` is killed, since the runner gives up on it then.
+#[cfg(all(unix, feature = "process-isolation"))]
+fn run_in_child<F>(
+    desc: TestDesc,
+    monitor_ch: Sender<MonitorMsg>,
+    concurrency: Concurrent,
+    timeout: Option<Duration>,
+    runtest: F,
+) where
+    F: FnOnce(Sender<MonitorMsg>),
+{
+    let child = process_isolation::Child::fork(|| {
+        let (tx, rx) = channel();
+        runtest(tx);
+        rx.recv().unwrap()
+    });
+    let deadline = timeout.map(|t| Instant::now() + t);
+    let wait = move || {
+        let msg = match child {
+            Ok(child) => child.wait(desc, deadline),
+            Err(e) => {
+                let msg = format!("couldn't fork the test process: {}", e);
+                (
+                    desc,
+                    TestResult::TrFailedMsg(msg),
+                    None,
+                    TestOutput::default(),
+                )
+            }
+        };
+        monitor_ch.send(msg).unwrap();
+    };
+    if concurrency == Concurrent::Yes {
+        thread::spawn(wait);
+    } else {
+        wait();
+    }
+}
+
+#[cfg(not(all(unix, feature = "process-isolation")))]
+fn run_in_child<F>(
+    desc: TestDesc,
+    monitor_ch: Sender<MonitorMsg>,
+    _: Concurrent,
+    _: Option<Duration>,
+    _: F,
+) where
+    F: FnOnce(Sender<MonitorMsg>),
+{
+    let msg = PROCESS_ISOLATION_UNSUPPORTED.to_owned();
+    let result = TestResult::TrFailedMsg(msg);
+    monitor_ch
+        .send((desc, result, None, TestOutput::default()))
+        .unwrap();
+}
+
+const PROCESS_ISOLATION_UNSUPPORTED: &str =
+    "process isolation requires libtest to be built with the \
+     `process-isolation` feature, on unix";
+
+#[cfg(unix)]
+fn start_fd_capture(capture: &Capture) -> Option<fd_capture::FdCapture> {
+    // If the descriptors can't be redirected the test still runs, its raw
+    // output just isn't captured.
+    fd_capture::FdCapture::start(
+        Box::new(capture.stdout()),
+        Box::new(capture.stderr()),
+    )
+    .ok()
+}
+
+#[cfg(not(unix))]
+fn start_fd_capture(_: &Capture) -> Option<()> {
+    None
+}
+
+/// Runs `testfn` between the `before_each` and `after_each` hooks of
+/// `options`. Returns the outcome of the test and, if a hook panicked, why
+/// the test should fail regardless.
+fn run_with_hooks(
+    desc: &TestDesc,
+    options: Options,
+    testfn: Box<dyn FnBox() + Send>,
+) -> (Result<(), Box<dyn Any + Send>>, Option<String>) {
+    if let Some(before_each) = options.before_each {
+        if catch_unwind(AssertUnwindSafe(|| before_each(desc))).is_err() {
+            let msg = "the before_each hook panicked".to_owned();
+            return (Ok(()), Some(msg));
+        }
+    }
+    let result = catch_unwind(AssertUnwindSafe(testfn));
+    let hook_failure = match options.after_each {
+        Some(after_each) => {
+            match catch_unwind(AssertUnwindSafe(|| after_each(desc))) {
+                Ok(()) => None,
+                Err(_) => Some("the after_each hook panicked".to_owned()),
+            }
+        }
+        None => None,
+    };
+    (result, hook_failure)
+}
+
+/// Fixed frame used to clean the backtrace with `RUST_BACKTRACE=1`.
+#[inline(never)]
+fn __rust_begin_short_backtrace<F: FnOnce()>(f: F) {
+    f()
+}
+
+fn calc_result(
+    desc: &TestDesc,
+    task_result: Result<(), Box<dyn Any + Send>>,
+    options: Options,
+) -> TestResult {
+    if let Err(ref err) = task_result {
+        if let Some(SkippedTest(reason)) = err.downcast_ref() {
+            return TestResult::TrIgnoredMsg(reason.clone());
+        }
+    }
+    match (&desc.should_panic, task_result) {
+        (&ShouldPanic::No, Ok(())) | (&ShouldPanic::Yes, Err(_)) => {
+            TestResult::TrOk
+        }
+        (&ShouldPanic::YesWithMessage(expected), Err(ref err))
+        | (&ShouldPanic::YesWithRegex(expected), Err(ref err)) => {
+            let regex =
+                desc.should_panic == ShouldPanic::YesWithRegex(expected);
+            let actual = panic_message(&**err, options);
+            let matched = if regex {
+                match regex_is_match(expected, &actual) {
+                    Ok(matched) => matched,
+                    Err(msg) => return TestResult::TrFailedMsg(msg),
+                }
+            } else {
+                match actual {
+                    Some(ref actual) => actual.contains(expected),
+                    None => false,
+                }
+            };
+            if matched {
+                TestResult::TrOk
+            } else if desc.allow_fail {
+                TestResult::TrAllowedFail
+            } else {
+                TestResult::TrFailedShouldPanic(PanicMismatch {
+                    expected: expected.to_owned(),
+                    regex,
+                    actual,
+                })
+            }
+        }
+        _ if desc.allow_fail => TestResult::TrAllowedFail,
+        _ => TestResult::TrFailed,
+    }
+}
+
+/// The message a test panicked with: the payload itself if it's a string,
+/// the `Display` of a boxed error, or else whatever `Options::panic_message`
+/// makes of it.
+fn panic_message(
+    payload: &(dyn Any + Send),
+    options: Options,
+) -> Option<String> {
+    if let Some(s) = payload.downcast_ref::<String>() {
+        Some(s.clone())
+    } else if let Some(s) = payload.downcast_ref::<&'static str>() {
+        Some((*s).to_owned())
+    } else if let Some(e) =
+        payload.downcast_ref::<Box<dyn error::Error + Send + Sync>>()
+    {
+        Some(e.to_string())
+    } else if let Some(e) =
+        payload.downcast_ref::<Box<dyn error::Error + Send>>()
+    {
+        Some(e.to_string())
+    } else {
+        options.panic_message.and_then(|f| f(payload))
+    }
+}
+
+/// Whether the panic message `actual` matches the `should_panic` regex
+/// `pattern`, or an error if the pattern doesn't compile.
+#[cfg(feature = "regex")]
+fn regex_is_match(
+    pattern: &str,
+    actual: &Option<String>,
+) -> Result<bool, String> {
+    match regex::Regex::new(pattern) {
+        Ok(re) => match *actual {
+            Some(ref actual) => Ok(re.is_match(actual)),
+            None => Ok(false),
+        },
+        Err(e) => {
+            Err(format!("invalid should_panic regex `{}`: {}", pattern, e))
+        }
+    }
+}
+
+#[cfg(not(feature = "regex"))]
+fn regex_is_match(_: &str, _: &Option<String>) -> Result<bool, String> {
+    Err(
+        "#[should_panic(regex = ...)] requires libtest to be built with the \
+         `regex` feature"
+            .to_string(),
+    )
+}
+
+/// Failure message for a test whose process died of a stack overflow.
+const STACK_OVERFLOW_MSG: &str = "test overflowed its stack";
+
+/// Turns the exit status of a child process that ran the single test `desc`
+/// into a result, for harnesses that run each test in its own process. The
+/// child is expected to exit successfully if and only if the test passed.
+///
+/// A child killed by a crash rather than a failed assertion is reported
+/// with a message saying why, and a stack overflow is told apart from
+/// other crashes: on Unix, the standard library prints a "has overflowed
+/// its stack" message to `stderr` and raises `SIGABRT` when a thread hits
+/// its guard page, while on Windows the process exits with
+/// `STATUS_STACK_OVERFLOW`.
+pub fn child_exit_result(
+    desc: &TestDesc,
+    status: process::ExitStatus,
+    stderr: &[u8],
+) -> TestResult {
+    if status.success() {
+        return TestResult::TrOk;
+    }
+    if desc.allow_fail {
+        return TestResult::TrAllowedFail;
+    }
+    match crash_message(status, stderr) {
+        Some(msg) => TestResult::TrFailedMsg(msg),
+        None => TestResult::TrFailed,
+    }
+}
+
+#[cfg(unix)]
+fn crash_message(
+    status: process::ExitStatus,
+    stderr: &[u8],
+) -> Option<String> {
+    use std::os::unix::process::ExitStatusExt;
+
+    let signal = status.signal()?;
+    let overflowed =
+        String::from_utf8_lossy(stderr).contains("has overflowed its stack");
+    if signal == libc::SIGABRT && overflowed {
+        Some(STACK_OVERFLOW_MSG.to_owned())
+    } else {
+        Some(format!("process aborted with signal {}", signal))
+    }
+}
+
+#[cfg(windows)]
+fn crash_message(
+    status: process::ExitStatus,
+    _stderr: &[u8],
+) -> Option<String> {
+    const STATUS_STACK_OVERFLOW: u32 = 0xC000_00FD;
+    match status.code() {
+        Some(code) if code as u32 == STATUS_STACK_OVERFLOW => {
+            Some(STACK_OVERFLOW_MSG.to_owned())
+        }
+        _ => None,
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn crash_message(
+    _status: process::ExitStatus,
+    _stderr: &[u8],
+) -> Option<String> {
+    None
+}
+
+/// Start of the line holding the note in a saved `MetricMap`.
+const NOTE_PREFIX: &str = "# note: ";
+
+#[derive(Clone, PartialEq, Default)]
+pub struct MetricMap {
+    metrics: BTreeMap<String, Metric>,
+    /// Raw measurements behind some of the metrics, for significance tests.
+    samples: BTreeMap<String, Vec<f64>>,
+    /// The `--bench-note` the metrics were recorded with.
+    note: Option<String>,
+}
+
+impl MetricMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Insert a named `value` (+/- `noise`) metric into the map. The value
+    /// must be non-negative. The `noise` indicates the uncertainty of the
+    /// metric, which doubles as the "noise range" of acceptable
+    /// pairwise-regressions on this named value, when comparing from one
+    /// metric to the next using `compare_to_old`.
+    ///
+    /// If `noise` is positive, then it means this metric is of a value
+    /// you want to see grow smaller, so a change larger than `noise` in the
+    /// positive direction represents a regression.
+    ///
+    /// If `noise` is negative, then it means this metric is of a value
+    /// you want to see grow larger, so a change larger than `noise` in the
+    /// negative direction represents a regression.
+    pub fn insert_metric(&mut self, name: &str, value: f64, noise: f64) {
+        let m = Metric { value, noise };
+        self.metrics.insert(name.to_owned(), m);
+    }
+
+    /// Attaches the raw measurements a metric was computed from.
+    pub fn insert_samples(&mut self, name: &str, samples: Vec<f64>) {
+        self.samples.insert(name.to_owned(), samples);
+    }
+
+    /// Attaches a free-form note, such as the commit being measured.
+    pub fn set_note(&mut self, note: &str) {
+        self.note = Some(note.to_owned());
+    }
+
+    /// Writes the map to `path` as one `name<TAB>value<TAB>noise<TAB>samples`
+    /// line per metric, which `load` reads back. `samples` is a
+    /// comma-separated list, empty if the metric has none.
+    ///
+    /// The note, if any, comes first on a `# note: ` line. Tabs and line
+    /// breaks in it are written as spaces, so it can't be mistaken for a
+    /// metric.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut out = io::BufWriter::new(File::create(path)?);
+        if let Some(ref note) = self.note {
+            let note: String = note
+                .chars()
+                .map(|c| if c.is_control() { ' ' } else { c })
+                .collect();
+            writeln!(out, "{}{}", NOTE_PREFIX, note)?;
+        }
+        for (name, m) in &self.metrics {
+            let samples = match self.samples.get(name) {
+                Some(samples) => samples
+                    .iter()
+                    .map(f64::to_string)
+                    .collect::<Vec<_>>()
+                    .join(","),
+                None => String::new(),
+            };
+            writeln!(out, "{}\t{}\t{}\t{}", name, m.value, m.noise, samples)?;
+        }
+        out.flush()
+    }
+
+    pub fn load(path: &Path) -> io::Result<MetricMap> {
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+
+        let mut map = MetricMap::new();
+        for line in contents.lines() {
+            if line.starts_with(NOTE_PREFIX) && !line.contains('\t') {
+                map.set_note(&line[NOTE_PREFIX.len()..]);
+                continue;
+            }
+            // Split from the right, so names may themselves contain tabs.
+            let mut fields = line.rsplitn(4, '\t');
+            let parsed = match (
+                fields.next(),
+                fields.next(),
+                fields.next(),
+                fields.next(),
+            ) {
+                (Some(samples), Some(noise), Some(value), Some(name)) => {
+                    let samples = samples
+                        .split(',')
+                        .filter(|s| !s.is_empty())
+                        .map(str::parse)
+                        .collect::<Result<Vec<f64>, _>>();
+                    match (value.parse(), noise.parse(), samples) {
+                        (Ok(value), Ok(noise), Ok(samples)) => {
+                            Some((name, value, noise, samples))
+                        }
+                        _ => None,
+                    }
+                }
+                _ => None,
+            };
+            match parsed {
+                Some((name, value, noise, samples)) => {
+                    map.insert_metric(name, value, noise);
+                    if !samples.is_empty() {
+                        map.insert_samples(name, samples);
+                    }
+                }
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "{}: malformed metric line `{}`",
+                            path.display(),
+                            line
+                        ),
+                    ));
+                }
+            }
+        }
+        Ok(map)
+    }
+
+    /// Classifies how each metric changed since `old`, following the
+    /// `noise` semantics described at `insert_metric`. A change is noise if
+    /// it is within the larger of the two noises, or within `noise_pct`
+    /// percent of the old value if that is given.
+    pub fn compare_to_old(
+        &self,
+        old: &MetricMap,
+        noise_pct: Option<f64>,
+    ) -> MetricDiff {
+        let mut diff = MetricDiff::new();
+        for (name, vold) in &old.metrics {
+            let change = match self.metrics.get(name) {
+                None => MetricChange::Removed,
+                Some(v) => {
+                    let delta = v.value - vold.value;
+                    let noise = match noise_pct {
+                        None => vold.noise.abs().max(v.noise.abs()),
+                        Some(pct) => vold.value * pct / 100.0,
+                    };
+                    if delta.abs() <= noise {
+                        MetricChange::Unchanged
+                    } else {
+                        let pct =
+                            delta.abs() / vold.value.max(f64::EPSILON) * 100.0;
+                        // A negative noise means the metric should grow.
+                        if (delta < 0.0) == (vold.noise < 0.0) {
+                            MetricChange::Regressed(pct)
+                        } else {
+                            MetricChange::Improved(pct)
+                        }
+                    }
+                }
+            };
+            diff.insert(name.clone(), change);
+        }
+        for name in self.metrics.keys() {
+            if !diff.contains_key(name) {
+                diff.insert(name.clone(), MetricChange::Added);
+            }
+        }
+        diff
+    }
+
+    pub fn fmt_metrics(&self) -> String {
+        let v = self
+            .metrics
+            .iter()
+            .map(|(k, v)| format!("{}: {} (+/- {})", *k, v.value, v.noise))
+            .collect::<Vec<_>>();
+        v.join(", ")
+    }
+}
+
+// Benchmarking
+
+impl Bencher {
+    /// Callback for benchmark functions to run in their body.
+    pub fn iter<T, F>(&mut self, inner: F)
+    where
+        F: FnMut() -> T,
+    {
+        self.iter_for(DEFAULT_BENCH_TIME, inner)
+    }
+
+    /// Like `iter`, but measures for up to `time` rather than 3 seconds, and
+    /// won't settle on a result before a round of measurements has taken a
+    /// thirtieth of it. Longer times help benchmarks with a lot of noise.
+    pub fn iter_for<T, F>(&mut self, time: Duration, mut inner: F)
+    where
+        F: FnMut() -> T,
+    {
+        if self.mode == BenchMode::Single {
+            let ns = ns_iter_inner(&*self.clock, &mut inner, 1);
+            self.summary = Some(stats::Summary::new(&[ns as f64]));
+            return;
+        }
+
+        let (summary, samples, iterations, saturated) = iter_until_converged(
+            &*self.clock,
+            &mut inner,
+            time,
+            self.sample_count,
+        );
+        self.summary = Some(summary);
+        self.samples = samples;
+        self.iterations = iterations;
+        self.saturated = saturated;
+    }
+
+    /// Sets `bytes`, the number of bytes processed by each iteration.
+    pub fn bytes_per_iter(&mut self, bytes: u64) {
+        self.bytes = bytes;
+    }
+
+    /// Times the iterations of later calls to `iter` with `clock` instead
+    /// of the wall clock.
+    pub fn set_clock<C>(&mut self, clock: C)
+    where
+        C: Clock + Send + Sync + 'static,
+    {
+        self.clock = Arc::new(clock);
+    }
+
+    /// Records a named metric alongside the timing, such as a count of
+    /// allocations. See `MetricMap::insert_metric` for the meaning of
+    /// `noise`. The console runner stores it as `<benchmark>::<name>` in
+    /// the run's metrics, e.g. for `--bench-save`.
+    pub fn record_metric(&mut self, name: &str, value: f64, noise: f64) {
+        self.metrics.insert_metric(name, value, noise);
+    }
+
+    pub fn bench<F>(&mut self, mut f: F) -> Option<stats::Summary>
+    where
+        F: FnMut(&mut Self),
+    {
+        f(self);
+        self.summary
+    }
+}
+
+/// An identity function that the optimizer has to assume can use `x` in any
+/// way, so that benchmarked code isn't optimized away.
+pub fn black_box<T>(x: T) -> T {
+    test::black_box(x)
+}
+
+fn ns_from_dur(dur: Duration) -> u64 {
+    dur.as_secs() * 1_000_000_000 + u64::from(dur.subsec_nanos())
+}
+
+fn ns_iter_inner<T, F>(clock: &dyn Clock, inner: &mut F, k: u64) -> u64
+where
+    F: FnMut() -> T,
+{
+    let start = clock.now();
+    for _ in 0..k {
+        black_box(inner());
+    }
+    clock.now().saturating_sub(start)
+}
+
+pub fn iter<T, F>(inner: &mut F) -> stats::Summary
+where
+    F: FnMut() -> T,
+{
+    iter_until_converged(
+        &WallClock::new(),
+        inner,
+        DEFAULT_BENCH_TIME,
+        DEFAULT_BENCH_SAMPLES,
+    )
+    .0
+}
+
+/// Longest a benchmark is measured for, unless it asks for another time
+/// with `Bencher::iter_for`.
+const DEFAULT_BENCH_TIME: Duration = Duration::from_secs(3);
+
+/// How spread out, as the median absolute deviation in percent of the
+/// median, the first samples of a benchmark may be for it to stop there.
+const FAST_PATH_MAX_DEV_PCT: f64 = 0.25;
+
+/// How many samples each round of measurements takes, unless changed with
+/// `--bench-samples`.
+const DEFAULT_BENCH_SAMPLES: usize = 50;
+
+/// Benchmarks `inner` with `clock` for up to `max_time` of wall-clock time,
+/// taking `sample_count` samples per round of measurements, also returning
+/// the samples the summary was computed from, the number of iterations each
+/// sample was timed over and whether measuring had to stop early because
+/// the iteration count would have overflowed.
+fn iter_until_converged<T, F>(
+    clock: &dyn Clock,
+    inner: &mut F,
+    max_time: Duration,
+    sample_count: usize,
+) -> (stats::Summary, Vec<f64>, u64, bool)
+where
+    F: FnMut() -> T,
+{
+    // Initial bench run to get ballpark figure.
+    let ns_single = ns_iter_inner(clock, inner, 1);
+
+    // Try to estimate iter count for 1ms falling back to 1m
+    // iterations if first run took < 1ns.
+    let ns_target_total = 1_000_000; // 1ms
+    let mut n = ns_target_total / cmp::max(1, ns_single);
+
+    // if the first run took more than 1ms we don't want to just
+    // be left doing 0 iterations on every loop. The unfortunate
+    // side effect of not being able to do as many runs is
+    // automatically handled by the statistical analysis below
+    // (i.e., larger error bars).
+    n = cmp::max(1, n);
+
+    let mut total_run = Duration::new(0, 0);
+    let samples: &mut [f64] = &mut vec![0.0_f64; sample_count];
+    let mut first_round = true;
+    loop {
+        let loop_start = Instant::now();
+
+        for p in &mut *samples {
+            *p = ns_iter_inner(clock, inner, n) as f64 / n as f64;
         }
 
-        if let "unstable-options" = &*opt {
-            allow_unstable = true;
-        } else {
-            return Some(Err("Unrecognized option to `Z`".into()));
+        stats::winsorize(samples, 5.0);
+        let summ = stats::Summary::new(samples);
+
+        // Cheap closures are often stable from their first samples on. If
+        // so, and they've run for 10ms (by default), stop there rather than
+        // measuring again with five times the iterations.
+        if first_round
+            && loop_start.elapsed() > max_time / 300
+            && summ.median_abs_dev_pct < FAST_PATH_MAX_DEV_PCT
+        {
+            return (summ, samples.to_vec(), n, false);
+        }
+        first_round = false;
+
+        for p in &mut *samples {
+            let ns = ns_iter_inner(clock, inner, 5 * n);
+            *p = ns as f64 / (5 * n) as f64;
+        }
+
+        stats::winsorize(samples, 5.0);
+        let summ5 = stats::Summary::new(samples);
+
+        let loop_run = loop_start.elapsed();
+
+        // If we've run for 100ms (by default) and seem to have converged to
+        // a stable median.
+        if loop_run > max_time / 30
+            && summ.median_abs_dev_pct < 1.0
+            && summ.median - summ5.median < summ5.median_abs_dev
+        {
+            return (summ5, samples.to_vec(), 5 * n, false);
+        }
+
+        total_run += loop_run;
+        // Longest we ever run for is 3s by default.
+        if total_run > max_time {
+            return (summ5, samples.to_vec(), 5 * n, false);
         }
+
+        // If we overflow here just return the results so far. We check a
+        // multiplier of 10 because we're about to multiply by 2 and the
+        // next iteration of the loop will also multiply by 5 (to calculate
+        // the summ5 result)
+        n = if n.checked_mul(10).is_some() {
+            n * 2
+        } else {
+            return (summ5, samples.to_vec(), 5 * n, true);
+        };
+    }
+}
+
+pub mod bench {
+    use super::{
+        BenchMode, BenchSamples, Bencher, Capture, MetricMap, MonitorMsg,
+        Sender, TestDesc, TestResult, WallClock, DEFAULT_BENCH_SAMPLES,
     };
+    use crate::stats;
+    use std::cmp;
+    use std::io;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::sync::Arc;
+    use std::time::Instant;
 
-    if matches.opt_present("h") {
-        usage(&args[0], &opts);
-        return None;
+    pub fn benchmark<F>(
+        desc: TestDesc,
+        monitor_ch: &Sender<MonitorMsg>,
+        nocapture: bool,
+        sample_count: usize,
+        f: F,
+    ) where
+        F: FnMut(&mut Bencher),
+    {
+        let mut bs = Bencher {
+            mode: BenchMode::Auto,
+            summary: None,
+            samples: Vec::new(),
+            sample_count,
+            iterations: 0,
+            saturated: false,
+            bytes: 0,
+            metrics: MetricMap::new(),
+            clock: Arc::new(WallClock::new()),
+        };
+
+        let capture = Capture::default();
+
+        let oldio = if nocapture {
+            None
+        } else {
+            Some((
+                io::set_print(Some(Box::new(capture.stdout()))),
+                io::set_panic(Some(Box::new(capture.stderr()))),
+            ))
+        };
+
+        let start = Instant::now();
+        let result = catch_unwind(AssertUnwindSafe(|| bs.bench(f)));
+        let exec_time = start.elapsed();
+
+        if let Some((printio, panicio)) = oldio {
+            io::set_print(printio);
+            io::set_panic(panicio);
+        };
+
+        let test_result = match result {
+            //bs.bench(f) {
+            Ok(Some(ns_iter_summ)) => {
+                // Bytes per nanosecond are thousands of MB/s, since `bytes`
+                // and the median are both per iteration.
+                let ns_iter = cmp::max(ns_iter_summ.median as u64, 1);
+                let mb_s = bs.bytes * 1000 / ns_iter;
+
+                let bs = BenchSamples {
+                    ns_iter_summ,
+                    mb_s: mb_s as usize,
+                    samples: bs.samples,
+                    iterations: bs.iterations,
+                    saturated: bs.saturated,
+                    metrics: bs.metrics,
+                };
+                TestResult::TrBench(bs)
+            }
+            Ok(None) => {
+                // iter not called, so no data.
+                // FIXME: error in this case?
+                let samples: &mut [f64] = &mut [0.0_f64; 1];
+                let bs = BenchSamples {
+                    ns_iter_summ: stats::Summary::new(samples),
+                    mb_s: 0,
+                    samples: Vec::new(),
+                    iterations: 0,
+                    saturated: false,
+                    metrics: bs.metrics,
+                };
+                TestResult::TrBench(bs)
+            }
+            Err(_) => TestResult::TrFailed,
+        };
+
+        let output = capture.take();
+        monitor_ch
+            .send((desc, test_result, Some(exec_time), output))
+            .unwrap();
     }
 
-    let filter = if matches.free.is_empty() {
-        None
-    } else {
-        Some(matches.free[0].clone())
+    pub fn run_once<F>(f: F)
+    where
+        F: FnMut(&mut Bencher),
+    {
+        bench_once(f);
+    }
+
+    /// Runs `f` with `iter` timing a single iteration, as when benchmarks
+    /// are run as tests. Returns the summary of that one measurement, or
+    /// `None` if `f` never called `iter`.
+    pub fn bench_once<F>(f: F) -> Option<stats::Summary>
+    where
+        F: FnMut(&mut Bencher),
+    {
+        new_bencher(BenchMode::Single).bench(f)
+    }
+
+    /// Benchmarks `f` the way `#[bench]` functions are, without reporting
+    /// the result anywhere. Returns the summary of the measurements, or
+    /// `None` if `f` never called `iter`.
+    pub fn bench_auto<F>(f: F) -> Option<stats::Summary>
+    where
+        F: FnMut(&mut Bencher),
+    {
+        new_bencher(BenchMode::Auto).bench(f)
+    }
+
+    fn new_bencher(mode: BenchMode) -> Bencher {
+        Bencher {
+            mode,
+            summary: None,
+            samples: Vec::new(),
+            sample_count: DEFAULT_BENCH_SAMPLES,
+            iterations: 0,
+            saturated: false,
+            bytes: 0,
+            metrics: MetricMap::new(),
+            clock: Arc::new(WallClock::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        assert_test_result, bench, bench_rsd_color, child_exit_result,
+        color_from_env, convert_benchmarks_to_tests, filter_tests,
+        filter_tests_with_reasons, fmt_allocations, fmt_bench_comparison_md,
+        fmt_bench_rsd, fmt_bench_samples, fmt_bench_significance,
+        fmt_bench_throughput, fmt_thousands_sep, make_owned_test, parse_opts,
+        parse_test_threads, plugin, resolve_concurrency,
+        resolve_concurrency_with, run_test, run_tests, run_tests_console,
+        run_tests_console_with_formatter, run_tests_inner, run_tests_summary,
+        source_snippet, stats, test_main_with_exit_code, use_color,
+        warn_timeout, AllocationStats, BenchSamples, Bencher, BinaryFormatter,
+        Chaos, Clock, ColorConfig, Concurrent, ConsoleTestState,
+        DisplayConfig, FilterStats, JUnitFormatter, JsonFormatter,
+        MetricChange, MetricMap, Options, OutputFormat, OutputFormatter,
+        OutputLocation, PanicMismatch, PrettyFormatter, RunIgnored,
+        RunSummary, ShouldPanic, Sink, SkipReason, TapFormatter,
+        TerseFormatter, TestDesc, TestDescAndFn, TestEvent, TestFn, TestName,
+        TestOpts, TestOrder, TestOutput, TestResult, TestType,
+        DEFAULT_BENCH_SAMPLES,
     };
+    use std::any::Any;
+    use std::cell::Cell;
+    use std::io::{self, Write};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::channel;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use std::{cmp, env, error, fs, process, thread};
 
-    let exclude_should_panic = matches.opt_present("exclude-should-panic");
-    if !allow_unstable && exclude_should_panic {
-        return Some(Err(
-            "The \"exclude-should-panic\" flag is only accepted on the nightly compiler".into(),
-        ));
+    fn one_ignored_one_unignored_test() -> Vec<TestDescAndFn> {
+        vec![
+            TestDescAndFn {
+                desc: TestDesc {
+                    name: TestName::StaticTestName("1"),
+                    ignore: true,
+                    ignore_message: None,
+                    should_panic: ShouldPanic::No,
+                    allow_fail: false,
+                    test_type: TestType::Unknown,
+                    no_timeout_warning: false,
+                    is_converted_bench: false,
+                    tags: &[],
+                },
+                testfn: TestFn::DynTestFn(Box::new(move || {})),
+            },
+            TestDescAndFn {
+                desc: TestDesc {
+                    name: TestName::StaticTestName("2"),
+                    ignore: false,
+                    ignore_message: None,
+                    should_panic: ShouldPanic::No,
+                    allow_fail: false,
+                    test_type: TestType::Unknown,
+                    no_timeout_warning: false,
+                    is_converted_bench: false,
+                    tags: &[],
+                },
+                testfn: TestFn::DynTestFn(Box::new(move || {})),
+            },
+        ]
+    }
+
+    /// A test named `name`, with everything else left at its default.
+    fn test_desc(name: &'static str) -> TestDesc {
+        TestDesc {
+            name: TestName::StaticTestName(name),
+            ignore: false,
+            ignore_message: None,
+            should_panic: ShouldPanic::No,
+            allow_fail: false,
+            test_type: TestType::Unknown,
+            no_timeout_warning: false,
+            is_converted_bench: false,
+            tags: &[],
+        }
+    }
+
+    /// What a formatter writing into a buffer has written so far.
+    fn written(out: &OutputLocation<Vec<u8>>) -> String {
+        match *out {
+            OutputLocation::Raw(ref m) => {
+                String::from_utf8_lossy(m).into_owned()
+            }
+            OutputLocation::Pretty(_) => unreachable!(),
+        }
+    }
+
+    /// A `JsonFormatter` writing into a buffer, with everything optional
+    /// turned off.
+    fn json_formatter() -> JsonFormatter<Vec<u8>> {
+        JsonFormatter::new(
+            OutputLocation::Raw(Vec::new()),
+            false,
+            false,
+            false,
+        )
+    }
+
+    /// The state of a run of `total` tests that ended with `summary`.
+    fn console_state(total: usize, summary: RunSummary) -> ConsoleTestState {
+        ConsoleTestState {
+            log_out: None,
+            trace_out: None,
+            total,
+            summary,
+            metrics: MetricMap::new(),
+            options: Options::new(),
+            not_failures: Vec::new(),
+            modules: None,
+            min_pass_rate: None,
+            error_on_no_tests: false,
+            failures_file: None,
+            exec_time: None,
+        }
+    }
+
+    #[test]
+    pub fn do_not_run_ignored_tests() {
+        fn f() {
+            panic!();
+        }
+        let desc = TestDescAndFn {
+            desc: TestDesc {
+                name: TestName::StaticTestName("whatever"),
+                ignore: true,
+                ignore_message: None,
+                should_panic: ShouldPanic::No,
+                allow_fail: false,
+                test_type: TestType::Unknown,
+                no_timeout_warning: false,
+                is_converted_bench: false,
+                tags: &[],
+            },
+            testfn: TestFn::DynTestFn(Box::new(f)),
+        };
+        let (tx, rx) = channel();
+        run_test(&TestOpts::new(), false, desc, tx, Concurrent::No);
+        let (_, res, _, _) = rx.recv().unwrap();
+        assert!(res != TestResult::TrOk);
+    }
+
+    #[test]
+    pub fn ignored_tests_result_in_ignored() {
+        fn f() {}
+        let desc = TestDescAndFn {
+            desc: TestDesc {
+                name: TestName::StaticTestName("whatever"),
+                ignore: true,
+                ignore_message: None,
+                should_panic: ShouldPanic::No,
+                allow_fail: false,
+                test_type: TestType::Unknown,
+                no_timeout_warning: false,
+                is_converted_bench: false,
+                tags: &[],
+            },
+            testfn: TestFn::DynTestFn(Box::new(f)),
+        };
+        let (tx, rx) = channel();
+        run_test(&TestOpts::new(), false, desc, tx, Concurrent::No);
+        let (_, res, _, _) = rx.recv().unwrap();
+        assert!(res == TestResult::TrIgnored);
     }
 
-    let include_ignored = matches.opt_present("include-ignored");
-    if !allow_unstable && include_ignored {
-        return Some(Err(
-            "The \"include-ignored\" flag is only accepted on the nightly compiler".into(),
-        ));
+    #[test]
+    pub fn skipped_at_run_time_results_in_ignored_with_reason() {
+        fn f() {
+            crate::skip("no GPU");
+        }
+        let desc = TestDescAndFn {
+            desc: TestDesc {
+                name: TestName::StaticTestName("whatever"),
+                ignore: false,
+                ignore_message: None,
+                should_panic: ShouldPanic::Yes,
+                allow_fail: false,
+                test_type: TestType::Unknown,
+                no_timeout_warning: false,
+                is_converted_bench: false,
+                tags: &[],
+            },
+            testfn: TestFn::DynTestFn(Box::new(f)),
+        };
+        let (tx, rx) = channel();
+        run_test(&TestOpts::new(), false, desc, tx, Concurrent::No);
+        let (_, res, _, _) = rx.recv().unwrap();
+        assert!(res == TestResult::TrIgnoredMsg("no GPU".to_owned()));
     }
 
-    let run_ignored = match (include_ignored, matches.opt_present("ignored")) {
-        (true, true) => {
-            return Some(Err(
-                "the options --include-ignored and --ignored are mutually exclusive".into(),
-            ));
+    #[test]
+    fn test_should_panic() {
+        fn f() {
+            panic!();
         }
-        (true, false) => RunIgnored::Yes,
-        (false, true) => RunIgnored::Only,
-        (false, false) => RunIgnored::No,
-    };
-    let quiet = matches.opt_present("quiet");
-    let exact = matches.opt_present("exact");
-    let list = matches.opt_present("list");
-
-    let logfile = matches.opt_str("logfile");
-    let logfile = logfile.map(|s| PathBuf::from(&s));
-
-    let bench_benchmarks = matches.opt_present("bench");
-    let run_tests = !bench_benchmarks || matches.opt_present("test");
+        let desc = TestDescAndFn {
+            desc: TestDesc {
+                name: TestName::StaticTestName("whatever"),
+                ignore: false,
+                ignore_message: None,
+                should_panic: ShouldPanic::Yes,
+                allow_fail: false,
+                test_type: TestType::Unknown,
+                no_timeout_warning: false,
+                is_converted_bench: false,
+                tags: &[],
+            },
+            testfn: TestFn::DynTestFn(Box::new(f)),
+        };
+        let (tx, rx) = channel();
+        run_test(&TestOpts::new(), false, desc, tx, Concurrent::No);
+        let (_, res, _, _) = rx.recv().unwrap();
+        assert!(res == TestResult::TrOk);
+    }
 
-    let mut nocapture = matches.opt_present("nocapture");
-    if !nocapture {
-        nocapture = match env::var("RUST_TEST_NOCAPTURE") {
-            Ok(val) => &val != "0",
-            Err(_) => false,
+    #[test]
+    fn test_should_panic_good_message() {
+        fn f() {
+            panic!("an error message");
+        }
+        let desc = TestDescAndFn {
+            desc: TestDesc {
+                name: TestName::StaticTestName("whatever"),
+                ignore: false,
+                ignore_message: None,
+                should_panic: ShouldPanic::YesWithMessage("error message"),
+                allow_fail: false,
+                test_type: TestType::Unknown,
+                no_timeout_warning: false,
+                is_converted_bench: false,
+                tags: &[],
+            },
+            testfn: TestFn::DynTestFn(Box::new(f)),
         };
+        let (tx, rx) = channel();
+        run_test(&TestOpts::new(), false, desc, tx, Concurrent::No);
+        let (_, res, _, _) = rx.recv().unwrap();
+        assert!(res == TestResult::TrOk);
     }
 
-    let test_threads = match matches.opt_str("test-threads") {
-        Some(n_str) => match n_str.parse::<usize>() {
-            Ok(0) => {
-                return Some(Err(
-                    "argument for --test-threads must not be 0".to_string()
-                ))
-            }
-            Ok(n) => Some(n),
-            Err(e) => {
-                return Some(Err(format!(
-                    "argument for --test-threads must be a number > 0 \
-                     (error: {})",
-                    e
-                )));
-            }
-        },
-        None => None,
-    };
+    #[test]
+    fn test_should_panic_bad_message() {
+        fn f() {
+            panic!("an error message");
+        }
+        let expected = "foobar";
+        let failed_msg = "Panic did not include expected string";
+        let desc = TestDescAndFn {
+            desc: TestDesc {
+                name: TestName::StaticTestName("whatever"),
+                ignore: false,
+                ignore_message: None,
+                should_panic: ShouldPanic::YesWithMessage(expected),
+                allow_fail: false,
+                test_type: TestType::Unknown,
+                no_timeout_warning: false,
+                is_converted_bench: false,
+                tags: &[],
+            },
+            testfn: TestFn::DynTestFn(Box::new(f)),
+        };
+        let (tx, rx) = channel();
+        run_test(&TestOpts::new(), false, desc, tx, Concurrent::No);
+        let (_, res, _, _) = rx.recv().unwrap();
+        let mismatch = PanicMismatch {
+            expected: expected.to_string(),
+            regex: false,
+            actual: Some("an error message".to_string()),
+        };
+        assert_eq!(
+            mismatch.to_string(),
+            format!("{} '{}'", failed_msg, expected)
+        );
+        assert!(res == TestResult::TrFailedShouldPanic(mismatch));
+    }
 
-    let color = match matches.opt_str("color").as_ref().map(|s| &**s) {
-        Some("auto") | None => ColorConfig::AutoColor,
-        Some("always") => ColorConfig::AlwaysColor,
-        Some("never") => ColorConfig::NeverColor,
+    #[test]
+    fn test_should_panic_reads_non_string_payloads() {
+        struct Oops(u32);
 
-        Some(v) => {
-            return Some(Err(format!(
-                "argument for --color must be auto, always, or never (was \
-                 {})",
-                v
-            )));
+        fn boxed_error() {
+            let e: Box<dyn error::Error + Send + Sync> = "bad input".into();
+            panic::resume_unwind(Box::new(e));
         }
-    };
-
-    let format = match matches.opt_str("format").as_ref().map(|s| &**s) {
-        None if quiet => OutputFormat::Terse,
-        Some("pretty") | None => OutputFormat::Pretty,
-        Some("terse") => OutputFormat::Terse,
-        Some("json") => {
-            if !allow_unstable {
-                return Some(Err(
-                    "The \"json\" format is only accepted on the nightly compiler".into(),
-                ));
-            }
-            OutputFormat::Json
+        fn oops() {
+            panic::resume_unwind(Box::new(Oops(7)));
         }
-
-        Some(v) => {
-            return Some(Err(format!(
-                "argument for --format must be pretty, terse, or json (was \
-                 {})",
-                v
-            )));
+        fn oops_message(payload: &(dyn Any + Send)) -> Option<String> {
+            payload
+                .downcast_ref::<Oops>()
+                .map(|o| format!("oops #{}", o.0))
         }
-    };
 
-    let test_opts = TestOpts {
-        list,
-        filter,
-        filter_exact: exact,
-        exclude_should_panic,
-        run_ignored,
-        run_tests,
-        bench_benchmarks,
-        logfile,
-        nocapture,
-        color,
-        format,
-        test_threads,
-        skip: matches.opt_strs("skip"),
-        options: Options::new(),
-    };
+        let run = |options, expected, f| {
+            let mut opts = TestOpts::new();
+            opts.options = options;
+            let desc = TestDescAndFn {
+                desc: TestDesc {
+                    name: TestName::StaticTestName("whatever"),
+                    ignore: false,
+                    ignore_message: None,
+                    should_panic: ShouldPanic::YesWithMessage(expected),
+                    allow_fail: false,
+                    test_type: TestType::Unknown,
+                    no_timeout_warning: false,
+                    is_converted_bench: false,
+                    tags: &[],
+                },
+                testfn: TestFn::StaticTestFn(f),
+            };
+            let (tx, rx) = channel();
+            run_test(&opts, false, desc, tx, Concurrent::No);
+            rx.recv().unwrap().1
+        };
 
-    Some(Ok(test_opts))
-}
+        let res = run(Options::new(), "bad input", boxed_error);
+        assert!(res == TestResult::TrOk);
 
-#[derive(Clone, PartialEq)]
-pub struct BenchSamples {
-    ns_iter_summ: stats::Summary,
-    mb_s: usize,
-}
+        let res = run(Options::new(), "oops #7", oops);
+        assert!(
+            res == TestResult::TrFailedShouldPanic(PanicMismatch {
+                expected: "oops #7".to_string(),
+                regex: false,
+                actual: None,
+            })
+        );
 
-#[derive(Clone, PartialEq)]
-pub enum TestResult {
-    TrOk,
-    TrFailed,
-    TrFailedMsg(String),
-    TrIgnored,
-    TrAllowedFail,
-    TrBench(BenchSamples),
-}
+        let options = Options::new().panic_message(oops_message);
+        assert!(run(options, "oops #7", oops) == TestResult::TrOk);
+    }
 
-unsafe impl Send for TestResult {}
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_should_panic_regex() {
+        fn f() {
+            panic!("bad pointer 0x7ffd5e8c");
+        }
+        let run = |should_panic| {
+            let desc = TestDescAndFn {
+                desc: TestDesc {
+                    name: TestName::StaticTestName("whatever"),
+                    ignore: false,
+                    ignore_message: None,
+                    should_panic,
+                    allow_fail: false,
+                    test_type: TestType::Unknown,
+                    no_timeout_warning: false,
+                    is_converted_bench: false,
+                    tags: &[],
+                },
+                testfn: TestFn::DynTestFn(Box::new(f)),
+            };
+            let (tx, rx) = channel();
+            run_test(&TestOpts::new(), false, desc, tx, Concurrent::No);
+            rx.recv().unwrap().1
+        };
 
-enum OutputLocation<T> {
-    Pretty(Box<term::StdoutTerminal>),
-    Raw(T),
-}
+        let res = run(ShouldPanic::YesWithRegex(r"pointer 0x[0-9a-f]+$"));
+        assert!(res == TestResult::TrOk);
 
-impl<T: Write> Write for OutputLocation<T> {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        match *self {
-            OutputLocation::Pretty(ref mut term) => term.write(buf),
-            OutputLocation::Raw(ref mut stdout) => stdout.write(buf),
+        let res = run(ShouldPanic::YesWithRegex(r"^pointer"));
+        let mismatch = PanicMismatch {
+            expected: "^pointer".to_string(),
+            regex: true,
+            actual: Some("bad pointer 0x7ffd5e8c".to_string()),
+        };
+        assert_eq!(
+            mismatch.to_string(),
+            "Panic did not match expected regex '^pointer'"
+        );
+        assert!(res == TestResult::TrFailedShouldPanic(mismatch));
+
+        match run(ShouldPanic::YesWithRegex("(")) {
+            TestResult::TrFailedMsg(msg) => {
+                assert!(msg.starts_with("invalid should_panic regex `(`"))
+            }
+            _ => panic!("an invalid regex should fail the test"),
         }
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        match *self {
-            OutputLocation::Pretty(ref mut term) => term.flush(),
-            OutputLocation::Raw(ref mut stdout) => stdout.flush(),
-        }
+    #[test]
+    fn test_should_panic_but_succeeds() {
+        fn f() {}
+        let desc = TestDescAndFn {
+            desc: TestDesc {
+                name: TestName::StaticTestName("whatever"),
+                ignore: false,
+                ignore_message: None,
+                should_panic: ShouldPanic::Yes,
+                allow_fail: false,
+                test_type: TestType::Unknown,
+                no_timeout_warning: false,
+                is_converted_bench: false,
+                tags: &[],
+            },
+            testfn: TestFn::DynTestFn(Box::new(f)),
+        };
+        let (tx, rx) = channel();
+        run_test(&TestOpts::new(), false, desc, tx, Concurrent::No);
+        let (_, res, _, _) = rx.recv().unwrap();
+        assert!(res == TestResult::TrFailed);
     }
-}
 
-struct ConsoleTestState {
-    log_out: Option<File>,
-    total: usize,
-    passed: usize,
-    failed: usize,
-    ignored: usize,
-    allowed_fail: usize,
-    filtered_out: usize,
-    measured: usize,
-    metrics: MetricMap,
-    failures: Vec<(TestDesc, Vec<u8>)>,
-    not_failures: Vec<(TestDesc, Vec<u8>)>,
-    options: Options,
-}
+    #[test]
+    fn returned_err_is_reported_as_error() {
+        fn f() {
+            assert_test_result(Err::<(), _>("nope"));
+        }
+        let desc = TestDescAndFn {
+            desc: TestDesc {
+                name: TestName::StaticTestName("whatever"),
+                ignore: false,
+                ignore_message: None,
+                should_panic: ShouldPanic::No,
+                allow_fail: false,
+                test_type: TestType::Unknown,
+                no_timeout_warning: false,
+                is_converted_bench: false,
+                tags: &[],
+            },
+            testfn: TestFn::StaticTestFn(f),
+        };
+        let (tx, rx) = channel();
+        run_test(&TestOpts::new(), false, desc, tx, Concurrent::No);
+        let (desc, res, _, _) = rx.recv().unwrap();
+        assert!(res == TestResult::TrFailedErr);
+
+        let mut out = JsonFormatter::new(
+            OutputLocation::Raw(Vec::new()),
+            false,
+            false,
+            false,
+        );
+        out.write_result(&desc, &res, None, &TestOutput::default())
+            .unwrap();
+        out.write_result(
+            &desc,
+            &TestResult::TrFailed,
+            None,
+            &TestOutput::default(),
+        )
+        .unwrap();
+        let s = match *out.output_location() {
+            OutputLocation::Raw(ref m) => String::from_utf8_lossy(&m[..]),
+            OutputLocation::Pretty(_) => unreachable!(),
+        };
+        let kinds = s
+            .lines()
+            .map(|l| l.contains(r#""failure_kind": "error""#))
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, vec![true, false]);
+        assert!(s.contains(r#""failure_kind": "panic""#));
+    }
 
-impl ConsoleTestState {
-    pub fn new(opts: &TestOpts) -> io::Result<Self> {
-        let log_out = match opts.logfile {
-            Some(ref path) => Some(File::create(path)?),
-            None => None,
+    #[test]
+    fn binary_format_frames() {
+        let desc = TestDesc {
+            name: TestName::StaticTestName("t"),
+            ignore: false,
+            ignore_message: None,
+            should_panic: ShouldPanic::No,
+            allow_fail: false,
+            test_type: TestType::Unknown,
+            no_timeout_warning: false,
+            is_converted_bench: false,
+            tags: &[],
+        };
+        let mut out = BinaryFormatter::new(OutputLocation::Raw(Vec::new()));
+        out.write_run_start(2).unwrap();
+        out.write_result(
+            &desc,
+            &TestResult::TrOk,
+            None,
+            &TestOutput::default(),
+        )
+        .unwrap();
+        let output = TestOutput {
+            combined: b"out".to_vec(),
+            ..TestOutput::default()
+        };
+        out.write_result(&desc, &TestResult::TrFailed, None, &output)
+            .unwrap();
+        let bytes = match *out.output_location() {
+            OutputLocation::Raw(ref m) => m.clone(),
+            OutputLocation::Pretty(_) => unreachable!(),
         };
 
-        Ok(Self {
-            log_out,
-            total: 0,
-            passed: 0,
-            failed: 0,
-            ignored: 0,
-            allowed_fail: 0,
-            filtered_out: 0,
-            measured: 0,
-            metrics: MetricMap::new(),
-            failures: Vec::new(),
-            not_failures: Vec::new(),
-            options: opts.options,
-        })
+        let mut frames = Vec::new();
+        let mut rest = &bytes[..];
+        while !rest.is_empty() {
+            let len = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]);
+            let (frame, tail) = rest[4..].split_at(len as usize);
+            frames.push(frame.to_vec());
+            rest = tail;
+        }
+        assert_eq!(
+            frames,
+            vec![
+                vec![0, 4, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0],
+                vec![2, 1, 0, 0, 0, b't', 0],
+                vec![
+                    2, 1, 0, 0, 0, b't', 1, 0, 0, 0, 0, 0, 3, 0, 0, 0, b'o',
+                    b'u', b't',
+                ],
+            ]
+        );
     }
 
-    pub fn write_log<S: AsRef<str>>(&mut self, msg: S) -> io::Result<()> {
-        let msg = msg.as_ref();
-        match self.log_out {
-            None => Ok(()),
-            Some(ref mut o) => o.write_all(msg.as_bytes()),
+    #[test]
+    fn terse_progress_count_follows_the_last_result() {
+        let test = test_desc("a");
+        let mut terse = TerseFormatter::new(
+            OutputLocation::Raw(Vec::new()),
+            DisplayConfig {
+                max_name_len: 1,
+                show_progress: true,
+                ..DisplayConfig::default()
+            },
+        );
+        terse.write_run_start(3).unwrap();
+        for _ in 0..2 {
+            terse
+                .write_result(
+                    &test,
+                    &TestResult::TrOk,
+                    None,
+                    &TestOutput::default(),
+                )
+                .unwrap();
         }
+        assert_eq!(
+            written(terse.output_location()),
+            "\nrunning 3 tests\n.[1/3]\x08\x08\x08\x08\x08     \
+             \x08\x08\x08\x08\x08.[2/3]"
+        );
     }
 
-    pub fn write_log_result(
-        &mut self,
-        test: &TestDesc,
-        result: &TestResult,
-    ) -> io::Result<()> {
-        self.write_log(format!(
-            "{} {}\n",
-            match *result {
-                TestResult::TrOk => "ok".to_owned(),
-                TestResult::TrFailed => "failed".to_owned(),
-                TestResult::TrFailedMsg(ref msg) => format!("failed: {}", msg),
-                TestResult::TrIgnored => "ignored".to_owned(),
-                TestResult::TrAllowedFail => "failed (allowed)".to_owned(),
-                TestResult::TrBench(ref bs) => fmt_bench_samples(bs),
+    #[test]
+    fn ignored_tests_show_their_reason() {
+        let test = TestDesc {
+            ignore: true,
+            ignore_message: Some("takes \"minutes\""),
+            ..test_desc("slow")
+        };
+
+        let mut pretty = PrettyFormatter::new(
+            OutputLocation::Raw(Vec::new()),
+            DisplayConfig {
+                max_name_len: 4,
+                is_multithreaded: true,
+                ..DisplayConfig::default()
             },
-            test.name
-        ))
+        );
+        pretty
+            .write_result(
+                &test,
+                &TestResult::TrIgnored,
+                None,
+                &TestOutput::default(),
+            )
+            .unwrap();
+        assert_eq!(
+            written(pretty.output_location()),
+            "test slow ... ignored, takes \"minutes\"\n"
+        );
+
+        let mut json = json_formatter();
+        json.write_result(
+            &test,
+            &TestResult::TrIgnored,
+            None,
+            &TestOutput::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            written(json.output_location()),
+            "{ \"type\": \"test\", \"name\": \"slow\", \
+             \"event\": \"ignored\", \"reason\": \"takes \\\"minutes\\\"\" }\n"
+        );
     }
 
-    fn current_test_count(&self) -> usize {
-        self.passed
-            + self.failed
-            + self.ignored
-            + self.measured
-            + self.allowed_fail
+    #[test]
+    fn report_time_appends_each_tests_duration() {
+        let test = test_desc("foo");
+
+        let mut pretty = PrettyFormatter::new(
+            OutputLocation::Raw(Vec::new()),
+            DisplayConfig {
+                max_name_len: 3,
+                is_multithreaded: true,
+                report_time: Some((
+                    Duration::from_millis(50),
+                    Duration::from_secs(1),
+                )),
+                ..DisplayConfig::default()
+            },
+        );
+        let exec_time = Some(Duration::from_millis(3));
+        pretty
+            .write_result(
+                &test,
+                &TestResult::TrOk,
+                exec_time,
+                &TestOutput::default(),
+            )
+            .unwrap();
+        pretty
+            .write_result(
+                &test,
+                &TestResult::TrIgnored,
+                None,
+                &TestOutput::default(),
+            )
+            .unwrap();
+        assert_eq!(
+            written(pretty.output_location()),
+            "test foo ... ok <0.003s>\ntest foo ... ignored\n"
+        );
     }
-}
 
-// Format a number with thousands separators
-fn fmt_thousands_sep(mut n: usize, sep: char) -> String {
-    use std::fmt::Write;
-    let mut output = String::new();
-    let mut trailing = false;
-    for &pow in &[9, 6, 3, 0] {
-        let base = 10_usize.pow(pow);
-        if pow == 0 || trailing || n / base != 0 {
-            if trailing {
-                output.write_fmt(format_args!("{:03}", n / base)).unwrap();
-            } else {
-                output.write_fmt(format_args!("{}", n / base)).unwrap();
-            }
-            if pow != 0 {
-                output.push(sep);
-            }
-            trailing = true;
-        }
-        n %= base;
+    #[test]
+    fn timed_out_tests_are_counted_separately() {
+        let test = test_desc("hangs");
+        let timed_out = TestResult::TrTimedOut(Duration::from_secs(2));
+
+        let mut summary = RunSummary::new();
+        summary.record(&test, &timed_out, &TestOutput::default());
+        summary.record(&test, &TestResult::TrFailed, &TestOutput::default());
+        assert_eq!((summary.failed, summary.timed_out), (2, 1));
+
+        let st = console_state(2, summary);
+        let mut pretty = PrettyFormatter::new(
+            OutputLocation::Raw(Vec::new()),
+            DisplayConfig {
+                max_name_len: 5,
+                ..DisplayConfig::default()
+            },
+        );
+        pretty
+            .write_result(&test, &timed_out, None, &TestOutput::default())
+            .unwrap();
+        assert!(!pretty.write_run_finish(&st).unwrap());
+        let s = written(pretty.output_location());
+        assert!(s.starts_with("TIMED OUT\n"));
+        assert!(s.contains("note: timed out after 2s"));
+        assert!(s.contains(". 0 passed; 1 failed; 1 timed out; 0 ignored;"));
+
+        let mut json = json_formatter();
+        json.write_result(&test, &timed_out, None, &TestOutput::default())
+            .unwrap();
+        assert_eq!(
+            written(json.output_location()),
+            "{ \"type\": \"test\", \"name\": \"hangs\", \
+             \"event\": \"failed\", \"failure_kind\": \"timeout\", \
+             \"message\": \"timed out after 2s\" }\n"
+        );
     }
 
-    output
-}
+    #[test]
+    fn no_capture_on_failure_prints_output_with_the_result() {
+        let test = test_desc("noisy");
+        let result = TestResult::TrFailedMsg("boom".to_owned());
+        let output = TestOutput {
+            combined: b"hello\n".to_vec(),
+            ..TestOutput::default()
+        };
 
-pub fn fmt_bench_samples(bs: &BenchSamples) -> String {
-    use std::fmt::Write;
-    let mut output = String::new();
+        let mut summary = RunSummary::new();
+        summary.record(&test, &result, &output);
+        let st = console_state(1, summary);
+        let mut pretty = PrettyFormatter::new(
+            OutputLocation::Raw(Vec::new()),
+            DisplayConfig {
+                max_name_len: 5,
+                stream_failures: true,
+                ..DisplayConfig::default()
+            },
+        );
+        pretty.write_result(&test, &result, None, &output).unwrap();
+        assert!(!pretty.write_run_finish(&st).unwrap());
+        let s = written(pretty.output_location());
+        assert!(s.starts_with(
+            "FAILED\n---- noisy stdout ----\nhello\nnote: boom\n"
+        ));
+        assert_eq!(s.matches("hello").count(), 1);
+        assert!(s.contains("failures:\n    noisy\n"));
 
-    let median = bs.ns_iter_summ.median as usize;
-    let deviation = (bs.ns_iter_summ.max - bs.ns_iter_summ.min) as usize;
+        let args = vec![
+            "progname".to_owned(),
+            "--no-capture-on-failure".to_owned(),
+            "--format=json".to_owned(),
+            "-Zunstable-options".to_owned(),
+        ];
+        assert!(parse_opts(&args).unwrap().is_err());
+    }
 
-    output
-        .write_fmt(format_args!(
-            "{:>11} ns/iter (+/- {})",
-            fmt_thousands_sep(median, ','),
-            fmt_thousands_sep(deviation, ',')
-        ))
+    #[test]
+    fn converted_benchmarks_are_marked_in_the_output() {
+        fn bench(_: &mut Bencher) {}
+        fn test() {}
+
+        let tests = vec![
+            TestDescAndFn {
+                desc: test_desc("b"),
+                testfn: TestFn::StaticBenchFn(bench),
+            },
+            TestDescAndFn {
+                desc: test_desc("t"),
+                testfn: TestFn::StaticTestFn(test),
+            },
+        ];
+        let tests = convert_benchmarks_to_tests(tests);
+        assert!(tests[0].desc.is_converted_bench);
+        assert!(!tests[1].desc.is_converted_bench);
+
+        let mut pretty = PrettyFormatter::new(
+            OutputLocation::Raw(Vec::new()),
+            DisplayConfig {
+                max_name_len: 1,
+                ..DisplayConfig::default()
+            },
+        );
+        for t in &tests {
+            pretty.write_test_start(&t.desc).unwrap();
+            pretty
+                .write_result(
+                    &t.desc,
+                    &TestResult::TrOk,
+                    None,
+                    &TestOutput::default(),
+                )
+                .unwrap();
+        }
+        assert_eq!(
+            written(pretty.output_location()),
+            "test b (bench) ... ok\ntest t ... ok\n"
+        );
+    }
+
+    #[test]
+    fn json_results_carry_exec_time() {
+        let test = test_desc("timed");
+
+        let mut json = json_formatter();
+        let exec_time = Some(Duration::from_millis(1500));
+        json.write_result(
+            &test,
+            &TestResult::TrOk,
+            exec_time,
+            &TestOutput::default(),
+        )
         .unwrap();
-    if bs.mb_s != 0 {
-        output
-            .write_fmt(format_args!(" = {} MB/s", bs.mb_s))
-            .unwrap();
+        json.write_result(
+            &test,
+            &TestResult::TrOkWithRetries(1),
+            exec_time,
+            &TestOutput::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            written(json.output_location()),
+            "{ \"type\": \"test\", \"name\": \"timed\", \
+             \"event\": \"ok\", \"exec_time\": 1.5 }\n\
+             { \"type\": \"test\", \"name\": \"timed\", \
+             \"event\": \"ok\", \"retries\": 1, \"exec_time\": 1.5 }\n"
+        );
     }
-    output
-}
 
-// List the tests to console, and optionally to logfile. Filters are honored.
-pub fn list_tests_console(
-    opts: &TestOpts,
-    tests: Vec<TestDescAndFn>,
-) -> io::Result<()> {
-    fn plural(count: u32, s: &str) -> String {
-        match count {
-            1 => format!("{} {}", 1, s),
-            n => format!("{} {}s", n, s),
+    #[test]
+    fn json_results_carry_captured_output() {
+        let test = test_desc("chatty");
+        let output = TestOutput {
+            combined: b"log line\n".to_vec(),
+            stdout: b"log line\n".to_vec(),
+            ..TestOutput::default()
+        };
+        let failed = TestResult::TrFailedMsg("bad".to_owned());
+
+        for &display_output in &[false, true] {
+            let mut json = JsonFormatter::new(
+                OutputLocation::Raw(Vec::new()),
+                false,
+                display_output,
+                false,
+            );
+            json.write_result(&test, &failed, None, &output).unwrap();
+            json.write_result(&test, &TestResult::TrOk, None, &output)
+                .unwrap();
+            let passed = if display_output {
+                ", \"stdout\": \"log line\\n\""
+            } else {
+                ""
+            };
+            assert_eq!(
+                written(json.output_location()),
+                format!(
+                    "{{ \"type\": \"test\", \"name\": \"chatty\", \
+                     \"event\": \"failed\", \"message\": \"bad\", \
+                     \"stdout\": \"log line\\n\" }}\n\
+                     {{ \"type\": \"test\", \"name\": \"chatty\", \
+                     \"event\": \"ok\"{} }}\n",
+                    passed
+                )
+            );
         }
     }
 
-    let mut output = match term::stdout() {
-        None => OutputLocation::Raw(io::stdout()),
-        Some(t) => OutputLocation::Pretty(t),
-    };
+    #[test]
+    fn json_suite_summary_carries_exec_time() {
+        let mut st = ConsoleTestState::new(&TestOpts::new()).unwrap();
+        st.total = 3;
+        st.summary.passed = 2;
+        st.summary.ignored = 1;
+        st.exec_time = Some(Duration::from_millis(250));
+
+        let mut json = json_formatter();
+        assert!(json.write_run_finish(&st).unwrap());
+        assert_eq!(
+            written(json.output_location()),
+            "{ \"type\": \"suite\", \"event\": \"ok\", \"passed\": 2, \
+             \"flaky\": 0, \"failed\": 0, \"timed_out\": 0, \
+             \"allowed_fail\": 0, \"ignored\": 1, \"measured\": 0, \
+             \"filtered_out\": 0, \"filtered_out_by\": { \"filter\": 0, \
+             \"skip\": 0, \"should_panic\": 0, \"shard\": 0 }, \
+             \"exec_time\": 0.25 }\n"
+        );
+    }
 
-    let quiet = opts.format == OutputFormat::Terse;
-    let mut st = ConsoleTestState::new(opts)?;
+    #[test]
+    fn junit_escapes_names_and_messages() {
+        let test = test_desc("m::<a> & 'b'");
+        let st = ConsoleTestState::new(&TestOpts::new()).unwrap();
+
+        let mut junit = JUnitFormatter::new(OutputLocation::Raw(Vec::new()));
+        let msg = "expected <foo> & \"bar\"\n\tgot\x07 nothing".to_owned();
+        junit
+            .write_result(
+                &test,
+                &TestResult::TrFailedMsg(msg),
+                Some(Duration::from_millis(5)),
+                &TestOutput::default(),
+            )
+            .unwrap();
+        junit.write_run_finish(&st).unwrap();
+        let report = written(junit.output_location());
+        let lines: Vec<_> = report.lines().collect();
+        assert_eq!(
+            lines[2],
+            "<testcase classname=\"m\" name=\"&lt;a&gt; &amp; &apos;b&apos;\" \
+             time=\"0.005\">"
+        );
+        assert_eq!(
+            lines[3],
+            "<failure type=\"failure\" message=\"expected &lt;foo&gt; \
+             &amp; &quot;bar&quot;&#10;&#9;got\u{fffd} nothing\"/>"
+        );
+    }
 
-    let mut ntest = 0;
-    let mut nbench = 0;
+    #[test]
+    fn tap_reports_each_test_point() {
+        let desc = |name, ignore_message| TestDesc {
+            ignore_message,
+            ..test_desc(name)
+        };
+        let st = ConsoleTestState::new(&TestOpts::new()).unwrap();
+        let failed_output = TestOutput {
+            combined: b"line 1\n\"line 2\"\n".to_vec(),
+            ..TestOutput::default()
+        };
 
-    for test in filter_tests(&opts, tests) {
-        let TestDescAndFn {
-            desc: TestDesc { name, .. },
-            testfn,
-        } = test;
+        let mut tap = TapFormatter::new(OutputLocation::Raw(Vec::new()));
+        tap.write_run_start(4).unwrap();
+        tap.write_result(
+            &desc("a", None),
+            &TestResult::TrOk,
+            None,
+            &TestOutput::default(),
+        )
+        .unwrap();
+        tap.write_result(
+            &desc("b#1", Some("slow")),
+            &TestResult::TrIgnored,
+            None,
+            &TestOutput::default(),
+        )
+        .unwrap();
+        tap.write_result(
+            &desc("c", None),
+            &TestResult::TrFailedMsg("boom".to_owned()),
+            None,
+            &failed_output,
+        )
+        .unwrap();
+        tap.write_timeout(&desc("d\ne", None)).unwrap();
+        tap.write_result(
+            &desc("d\ne", None),
+            &TestResult::TrIgnoredMsg("two\nlines".to_owned()),
+            None,
+            &TestOutput::default(),
+        )
+        .unwrap();
+        assert!(tap.write_run_finish(&st).unwrap());
+        assert_eq!(
+            written(tap.output_location()),
+            "TAP version 13\n\
+             1..4\n\
+             ok 1 - a\n\
+             ok 2 - b\\#1 # SKIP slow\n\
+             not ok 3 - c\n  \
+             ---\n  \
+             message: \"boom\"\n  \
+             output: \"line 1\\n\\\"line 2\\\"\\n\"\n  \
+             ...\n\
+             # d\\ne is still running\n\
+             ok 4 - d\\ne # SKIP two\\nlines\n\
+             # 0 passed; 0 failed; 0 ignored; 0 measured; 0 filtered out\n"
+        );
 
-        let fntype = match testfn {
-            TestFn::StaticTestFn(..) | TestFn::DynTestFn(..) => {
-                ntest += 1;
-                "test"
-            }
-            TestFn::StaticBenchFn(..) | TestFn::DynBenchFn(..) => {
-                nbench += 1;
-                "benchmark"
-            }
+        let args = vec!["progname".to_owned(), "--format=tap".to_owned()];
+        assert_eq!(
+            parse_opts(&args).unwrap().is_ok(),
+            cfg!(feature = "stable-cli")
+        );
+        let args = vec![
+            "progname".to_owned(),
+            "-Zunstable-options".to_owned(),
+            "--format=tap".to_owned(),
+        ];
+        let opts = parse_opts(&args).unwrap().unwrap();
+        assert_eq!(opts.format, OutputFormat::Tap);
+    }
+
+    #[test]
+    fn json_list_entries() {
+        let test = TestDesc {
+            ignore: true,
+            should_panic: ShouldPanic::YesWithMessage("boom"),
+            ..test_desc("m::\"quoted\"")
         };
 
-        writeln!(output, "{}: {}", name, fntype)?;
-        st.write_log(format!("{} {}\n", fntype, name))?;
+        let mut json = json_formatter();
+        json.write_list_entry(&test, "test").unwrap();
+        assert_eq!(
+            written(json.output_location()),
+            "{ \"name\": \"m::\\\"quoted\\\"\", \"kind\": \"test\", \
+             \"ignore\": true, \"should_panic\": true }\n"
+        );
     }
 
-    if !quiet {
-        if ntest != 0 || nbench != 0 {
-            writeln!(output)?;
-        }
+    #[test]
+    fn progress_line_stays_below_results() {
+        let test = test_desc("a");
+
+        let mut out = PrettyFormatter::new(
+            OutputLocation::Raw(Vec::new()),
+            DisplayConfig {
+                max_name_len: 1,
+                is_multithreaded: true,
+                show_progress: true,
+                ..DisplayConfig::default()
+            },
+        );
+        out.write_run_start(2).unwrap();
+        out.write_test_start(&test).unwrap();
+        out.write_result(
+            &test,
+            &TestResult::TrFailed,
+            None,
+            &TestOutput::default(),
+        )
+        .unwrap();
+        let s = written(out.output_location());
 
-        writeln!(
-            output,
-            "{}, {}",
-            plural(ntest, "test"),
-            plural(nbench, "benchmark")
-        )?;
+        let start = "/ [running 1, done 0/2, 0 failed]";
+        let clear = format!("\r{}\r", " ".repeat(start.len()));
+        let expected = format!(
+            "\nrunning 2 tests\n\r{}{}test a ... FAILED\n\r- [running 0, done 1/2, 1 failed]",
+            start, clear
+        );
+        assert_eq!(s, expected);
     }
 
-    Ok(())
-}
+    #[test]
+    fn capture_survives_a_panicking_writer() {
+        let data = Arc::new(Mutex::new(Vec::new()));
+        let mut sink = Sink(data.clone());
+        sink.write_all(b"before ").unwrap();
+
+        // A thread left behind by a test panics while holding the lock.
+        let leftover = Sink(data.clone());
+        thread::spawn(move || {
+            let _buf = leftover.0.lock().unwrap();
+            panic!("detached thread panicked");
+        })
+        .join()
+        .unwrap_err();
+        assert!(data.is_poisoned());
+
+        sink.write_all(b"after").unwrap();
+        assert_eq!(Sink::take(&data), b"before after");
+        Sink(data.clone()).write_all(b"late").unwrap();
+        assert_eq!(Sink::take(&data), b"late");
+    }
 
-// A simple console test runner
-pub fn run_tests_console(
-    opts: &TestOpts,
-    tests: Vec<TestDescAndFn>,
-) -> io::Result<bool> {
-    fn callback(
-        event: &TestEvent,
-        st: &mut ConsoleTestState,
-        out: &mut dyn OutputFormatter,
-    ) -> io::Result<()> {
-        match (*event).clone() {
-            TestEvent::TeFiltered(ref filtered_tests) => {
-                st.total = filtered_tests.len();
-                out.write_run_start(filtered_tests.len())
-            }
-            TestEvent::TeFilteredOut(filtered_out) => {
-                st.filtered_out = filtered_out;
-                Ok(())
-            }
-            TestEvent::TeWait(ref test) => out.write_test_start(test),
-            TestEvent::TeTimeout(ref test) => out.write_timeout(test),
-            TestEvent::TeResult(test, result, stdout) => {
-                st.write_log_result(&test, &result)?;
-                out.write_result(&test, &result, &*stdout)?;
-                match result {
-                    TestResult::TrOk => {
-                        st.passed += 1;
-                        st.not_failures.push((test, stdout));
-                    }
-                    TestResult::TrIgnored => st.ignored += 1,
-                    TestResult::TrAllowedFail => st.allowed_fail += 1,
-                    TestResult::TrBench(bs) => {
-                        st.metrics.insert_metric(
-                            test.name.as_slice(),
-                            bs.ns_iter_summ.median,
-                            bs.ns_iter_summ.max - bs.ns_iter_summ.min,
-                        );
-                        st.measured += 1
-                    }
-                    TestResult::TrFailed => {
-                        st.failed += 1;
-                        st.failures.push((test, stdout));
-                    }
-                    TestResult::TrFailedMsg(msg) => {
-                        st.failed += 1;
-                        let mut stdout = stdout;
-                        stdout.extend_from_slice(
-                            format!("note: {}", msg).as_bytes(),
-                        );
-                        st.failures.push((test, stdout));
-                    }
-                }
-                Ok(())
-            }
-        }
+    #[test]
+    fn json_lines_are_flushed_as_they_are_written() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let buffered = io::BufWriter::new(Sink(written.clone()));
+        let mut out = JsonFormatter::new(
+            OutputLocation::Raw(buffered),
+            false,
+            false,
+            false,
+        );
+        let desc = |name| TestDesc {
+            name: TestName::StaticTestName(name),
+            ignore: false,
+            ignore_message: None,
+            should_panic: ShouldPanic::No,
+            allow_fail: false,
+            test_type: TestType::Unknown,
+            no_timeout_warning: false,
+            is_converted_bench: false,
+            tags: &[],
+        };
+
+        // The run dies after two events; everything before that must already
+        // be out, one complete object per line.
+        let run = panic::catch_unwind(AssertUnwindSafe(|| {
+            out.write_run_start(3).unwrap();
+            out.write_result(
+                &desc("a"),
+                &TestResult::TrOk,
+                None,
+                &TestOutput::default(),
+            )
+            .unwrap();
+            panic!("harness crashed");
+        }));
+        assert!(run.is_err());
+
+        let written = String::from_utf8(Sink::take(&written)).unwrap();
+        assert!(written.ends_with('\n'));
+        let lines = written.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines
+            .iter()
+            .all(|l| l.starts_with("{ ") && l.ends_with(" }")));
+        assert!(lines[1].contains(r#""name": "a", "event": "ok""#));
     }
 
-    fn len_if_padded(t: &TestDescAndFn) -> usize {
-        match t.testfn.padding() {
-            NamePadding::PadNone => 0,
-            NamePadding::PadOnRight => t.desc.name.as_slice().len(),
-        }
+    #[test]
+    fn parse_ignored_flag() {
+        let args = vec![
+            "progname".to_string(),
+            "filter".to_string(),
+            "--ignored".to_string(),
+        ];
+        let opts = parse_opts(&args).unwrap().unwrap();
+        assert_eq!(opts.run_ignored, RunIgnored::Only);
     }
 
-    let output = match term::stdout() {
-        None => OutputLocation::Raw(io::stdout()),
-        Some(t) => OutputLocation::Pretty(t),
-    };
-
-    let max_name_len = tests
-        .iter()
-        .max_by_key(|t| len_if_padded(*t))
-        .map_or(0, |t| t.desc.name.as_slice().len());
-
-    let is_multithreaded =
-        opts.test_threads.unwrap_or_else(get_concurrency) > 1;
+    #[test]
+    fn parse_include_ignored_flag() {
+        let args = vec![
+            "progname".to_string(),
+            "filter".to_string(),
+            "-Zunstable-options".to_string(),
+            "--include-ignored".to_string(),
+        ];
+        let opts = parse_opts(&args).unwrap().unwrap();
+        assert_eq!(opts.run_ignored, RunIgnored::Yes);
+    }
 
-    let mut out: Box<dyn OutputFormatter> = match opts.format {
-        OutputFormat::Pretty => Box::new(PrettyFormatter::new(
-            output,
-            use_color(opts),
-            max_name_len,
-            is_multithreaded,
-        )),
-        OutputFormat::Terse => Box::new(TerseFormatter::new(
-            output,
-            use_color(opts),
-            max_name_len,
-            is_multithreaded,
-        )),
-        OutputFormat::Json => Box::new(JsonFormatter::new(output)),
-    };
-    let mut st = ConsoleTestState::new(opts)?;
+    #[test]
+    fn parse_capture_fds_flag() {
+        let args = vec!["progname".to_string(), "--capture-fds".to_string()];
+        assert!(parse_opts(&args).unwrap().is_err());
 
-    run_tests(opts, tests, |x| callback(&x, &mut st, &mut *out))?;
+        let args = vec![
+            "progname".to_string(),
+            "-Zunstable-options".to_string(),
+            "--capture-fds".to_string(),
+            "--test-threads=2".to_string(),
+        ];
+        assert!(parse_opts(&args).unwrap().is_err());
 
-    assert!(st.current_test_count() == st.total);
+        let args = vec![
+            "progname".to_string(),
+            "-Zunstable-options".to_string(),
+            "--capture-fds".to_string(),
+            "--timeout=10".to_string(),
+        ];
+        assert!(parse_opts(&args).unwrap().is_err());
+    }
 
-    out.write_run_finish(&st)
-}
+    #[test]
+    fn chaos_is_reproducible_from_its_seed() {
+        let args = vec!["progname".to_string(), "--chaos=42".to_string()];
+        let opts = parse_opts(&args).unwrap().unwrap();
+        assert_eq!(opts.chaos, Some(42));
 
-#[test]
-fn should_sort_failures_before_printing_them() {
-    let test_a = TestDesc {
-        name: TestName::StaticTestName("a"),
-        ignore: false,
-        should_panic: ShouldPanic::No,
-        allow_fail: false,
-    };
+        let args = vec!["progname".to_string(), "--chaos".to_string()];
+        let opts = parse_opts(&args).unwrap().unwrap();
+        assert!(opts.chaos.is_some());
 
-    let test_b = TestDesc {
-        name: TestName::StaticTestName("b"),
-        ignore: false,
-        should_panic: ShouldPanic::No,
-        allow_fail: false,
-    };
+        let draws = |seed| {
+            let mut chaos = Chaos::new(seed);
+            (0..8).map(|_| chaos.next()).collect::<Vec<_>>()
+        };
+        assert_eq!(draws(42), draws(42));
+        assert_ne!(draws(42), draws(43));
+        assert!(draws(0).iter().all(|&r| r != 0));
+        assert!(draws(0x9E37_79B9_7F4A_7C15).iter().all(|&r| r != 0));
+    }
 
-    let mut out = PrettyFormatter::new(
-        OutputLocation::Raw(Vec::new()),
-        false,
-        10,
-        false,
-    );
+    #[test]
+    fn shuffle_is_reproducible_from_its_seed() {
+        let args = vec!["progname".to_string(), "--shuffle".to_string()];
+        assert!(parse_opts(&args).unwrap().unwrap().shuffle.is_some());
+        let args =
+            vec!["progname".to_string(), "--shuffle-seed=42".to_string()];
+        assert_eq!(parse_opts(&args).unwrap().unwrap().shuffle, Some(42));
+
+        let order = |seed| {
+            let mut items = (0..16).collect::<Vec<_>>();
+            Chaos::new(seed).shuffle(&mut items);
+            items
+        };
+        assert_eq!(order(42), order(42));
+        assert_ne!(order(42), order(43));
+        let mut sorted = order(42);
+        sorted.sort();
+        assert_eq!(sorted, (0..16).collect::<Vec<_>>());
+    }
 
-    let st = ConsoleTestState {
-        log_out: None,
-        total: 0,
-        passed: 0,
-        failed: 0,
-        ignored: 0,
-        allowed_fail: 0,
-        filtered_out: 0,
-        measured: 0,
-        metrics: MetricMap::new(),
-        failures: vec![(test_b, Vec::new()), (test_a, Vec::new())],
-        options: Options::new(),
-        not_failures: Vec::new(),
-    };
+    #[test]
+    fn parse_bench_samples() {
+        let args = vec!["progname".to_string(), "--bench".to_string()];
+        let opts = parse_opts(&args).unwrap().unwrap();
+        assert_eq!(opts.bench_samples, 50);
 
-    out.write_failures(&st).unwrap();
-    let s = match out.output_location() {
-        &OutputLocation::Raw(ref m) => String::from_utf8_lossy(&m[..]),
-        &OutputLocation::Pretty(_) => unreachable!(),
-    };
+        for bad in &["--bench-samples=0", "--bench-samples=x"] {
+            let args = vec!["progname".to_string(), bad.to_string()];
+            assert!(parse_opts(&args).unwrap().is_err());
+        }
 
-    let apos = s.find("a").unwrap();
-    let bpos = s.find("b").unwrap();
-    assert!(apos < bpos);
-}
+        let args =
+            vec!["progname".to_string(), "--bench-samples=7".to_string()];
+        let opts = parse_opts(&args).unwrap().unwrap();
+        assert_eq!(opts.bench_samples, 7);
+        assert_eq!(TestOpts::decode(&opts.encode()), Ok(opts));
 
-fn use_color(opts: &TestOpts) -> bool {
-    match opts.color {
-        ColorConfig::AutoColor => !opts.nocapture && stdout_isatty(),
-        ColorConfig::AlwaysColor => true,
-        ColorConfig::NeverColor => false,
+        let (tx, rx) = channel();
+        let desc = TestDesc {
+            name: TestName::StaticTestName("few_samples"),
+            ignore: false,
+            ignore_message: None,
+            should_panic: ShouldPanic::No,
+            allow_fail: false,
+            test_type: TestType::Unknown,
+            no_timeout_warning: false,
+            is_converted_bench: false,
+            tags: &[],
+        };
+        crate::bench::benchmark(desc, &tx, true, 7, |b: &mut Bencher| {
+            b.iter_for(Duration::from_millis(30), || {})
+        });
+        match rx.recv().unwrap().1 {
+            TestResult::TrBench(ref bs) => assert_eq!(bs.samples().len(), 7),
+            _ => panic!("expected a benchmark result"),
+        }
     }
-}
 
-#[cfg(any(
-    target_os = "cloudabi",
-    target_os = "redox",
-    all(target_arch = "wasm32", not(target_os = "emscripten")),
-    all(target_vendor = "fortanix", target_env = "sgx")
-))]
-fn stdout_isatty() -> bool {
-    // FIXME: Implement isatty on Redox and SGX
-    false
-}
-#[cfg(any(unix, target_os = "fuchsia"))]
-fn stdout_isatty() -> bool {
-    unsafe { libc::isatty(libc::STDOUT_FILENO) != 0 }
-}
-#[cfg(windows)]
-fn stdout_isatty() -> bool {
-    type DWORD = u32;
-    type BOOL = i32;
-    type HANDLE = *mut u8;
-    type LPDWORD = *mut u32;
-    const STD_OUTPUT_HANDLE: DWORD = -11i32 as DWORD;
-    extern "system" {
-        fn GetStdHandle(which: DWORD) -> HANDLE;
-        fn GetConsoleMode(hConsoleHandle: HANDLE, lpMode: LPDWORD) -> BOOL;
+    #[test]
+    fn stable_cli_feature_lifts_the_nightly_gate() {
+        for flag in &[
+            "--include-ignored",
+            "--exclude-should-panic",
+            "--format=json",
+            "--format=tap",
+        ] {
+            let args = vec!["progname".to_string(), flag.to_string()];
+            assert_eq!(
+                parse_opts(&args).unwrap().is_ok(),
+                cfg!(feature = "stable-cli"),
+                "{}",
+                flag
+            );
+        }
+        let args = vec!["progname".to_string(), "--format=binary".to_string()];
+        assert!(parse_opts(&args).unwrap().is_err());
     }
-    unsafe {
-        let handle = GetStdHandle(STD_OUTPUT_HANDLE);
-        let mut out = 0;
-        GetConsoleMode(handle, &mut out) != 0
+
+    #[test]
+    fn parse_bench_pin_cpu() {
+        let args =
+            vec!["progname".to_string(), "--bench-pin-cpu=x".to_string()];
+        assert!(parse_opts(&args).unwrap().is_err());
+
+        let args =
+            vec!["progname".to_string(), "--bench-pin-cpu=3".to_string()];
+        let opts = parse_opts(&args).unwrap().unwrap();
+        assert_eq!(opts.bench_pin_cpu, Some(3));
+        assert_eq!(TestOpts::decode(&opts.encode()), Ok(opts));
     }
-}
 
-#[allow(clippy::large_enum_variant)] // FIXME
-#[derive(Clone)]
-pub enum TestEvent {
-    TeFiltered(Vec<TestDesc>),
-    TeWait(TestDesc),
-    TeResult(TestDesc, TestResult, Vec<u8>),
-    TeTimeout(TestDesc),
-    TeFilteredOut(usize),
-}
+    #[cfg(unix)]
+    #[test]
+    fn parse_capture_fds_forces_one_thread() {
+        let args = vec![
+            "progname".to_string(),
+            "-Zunstable-options".to_string(),
+            "--capture-fds".to_string(),
+        ];
+        let opts = parse_opts(&args).unwrap().unwrap();
+        assert!(opts.capture_fds);
+        assert_eq!(opts.test_threads, Some(1));
+    }
 
-pub type MonitorMsg = (TestDesc, TestResult, Vec<u8>);
+    /// Held by tests that redirect fds 1 and 2 of the whole process, so that
+    /// the harness running tests in parallel doesn't interleave them, and by
+    /// tests that fork, so that no child inherits them redirected.
+    #[cfg(unix)]
+    fn lock_std_fds() -> std::sync::MutexGuard<'static, ()> {
+        use std::ptr;
+        use std::sync::{Once, PoisonError};
 
-struct Sink(Arc<Mutex<Vec<u8>>>);
-impl Write for Sink {
-    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
-        Write::write(&mut *self.0.lock().unwrap(), data)
+        static INIT: Once = Once::new();
+        static mut LOCK: *const Mutex<()> = ptr::null();
+        unsafe {
+            INIT.call_once(|| {
+                LOCK = Box::into_raw(Box::new(Mutex::new(())));
+            });
+            (*LOCK).lock().unwrap_or_else(PoisonError::into_inner)
+        }
     }
-    fn flush(&mut self) -> io::Result<()> {
-        Ok(())
+
+    #[cfg(unix)]
+    #[test]
+    fn capture_fds_collects_raw_writes() {
+        fn f() {
+            let msg = b"written to fd 1\n";
+            unsafe {
+                libc::write(
+                    libc::STDOUT_FILENO,
+                    msg.as_ptr() as *const libc::c_void,
+                    msg.len(),
+                );
+            }
+        }
+        let desc = TestDescAndFn {
+            desc: TestDesc {
+                name: TestName::StaticTestName("whatever"),
+                ignore: false,
+                ignore_message: None,
+                should_panic: ShouldPanic::No,
+                allow_fail: false,
+                test_type: TestType::Unknown,
+                no_timeout_warning: false,
+                is_converted_bench: false,
+                tags: &[],
+            },
+            testfn: TestFn::DynTestFn(Box::new(f)),
+        };
+        let _fds = lock_std_fds();
+        let mut opts = TestOpts::new();
+        opts.capture_fds = true;
+        let (tx, rx) = channel();
+        run_test(&opts, false, desc, tx, Concurrent::No);
+        let (_, res, _, output) = rx.recv().unwrap();
+        assert!(res == TestResult::TrOk);
+        assert!(String::from_utf8_lossy(&output.combined)
+            .contains("written to fd 1"));
     }
-}
 
-pub fn run_tests<F>(
-    opts: &TestOpts,
-    tests: Vec<TestDescAndFn>,
-    mut callback: F,
-) -> io::Result<()>
-where
-    F: FnMut(TestEvent) -> io::Result<()>,
-{
-    use std::collections::{self, HashMap};
-    use std::hash::BuildHasherDefault;
-    use std::sync::mpsc::RecvTimeoutError;
-    // Use a deterministic hasher
-    type TestMap = HashMap<
-        TestDesc,
-        Instant,
-        BuildHasherDefault<collections::hash_map::DefaultHasher>,
-    >;
-    fn get_timed_out_tests(running_tests: &mut TestMap) -> Vec<TestDesc> {
-        let now = Instant::now();
-        let timed_out = running_tests
-            .iter()
-            .filter_map(|(desc, timeout)| {
-                if now >= *timeout {
-                    Some(desc.clone())
-                } else {
-                    None
-                }
-            })
-            .collect();
-        for test in &timed_out {
-            running_tests.remove(test);
+    #[cfg(unix)]
+    #[test]
+    fn capture_fds_does_not_wait_for_background_processes() {
+        // The child inherits the redirected descriptors, so the pipes stay
+        // open for as long as it runs.
+        let child = Arc::new(Mutex::new(None));
+        let spawned = child.clone();
+        let f = move || {
+            let sleep = process::Command::new("sleep").arg("60").spawn();
+            *spawned.lock().unwrap() = sleep.ok();
+        };
+        let desc = TestDescAndFn {
+            desc: TestDesc {
+                name: TestName::StaticTestName("background"),
+                ignore: false,
+                ignore_message: None,
+                should_panic: ShouldPanic::No,
+                allow_fail: false,
+                test_type: TestType::Unknown,
+                no_timeout_warning: false,
+                is_converted_bench: false,
+                tags: &[],
+            },
+            testfn: TestFn::DynTestFn(Box::new(f)),
+        };
+        let _fds = lock_std_fds();
+        let mut opts = TestOpts::new();
+        opts.capture_fds = true;
+        let (tx, rx) = channel();
+        let start = Instant::now();
+        run_test(&opts, false, desc, tx, Concurrent::No);
+        let (_, res, _, _) = rx.recv().unwrap();
+        let took = start.elapsed();
+        if let Some(mut sleep) = child.lock().unwrap().take() {
+            let _ = sleep.kill();
+            let _ = sleep.wait();
         }
-        timed_out
-    };
+        assert!(res == TestResult::TrOk);
+        assert!(took < Duration::from_secs(10));
+    }
 
-    fn calc_timeout(running_tests: &TestMap) -> Option<Duration> {
-        running_tests.values().min().map(|next_timeout| {
-            let now = Instant::now();
-            if *next_timeout >= now {
-                *next_timeout - now
-            } else {
-                Duration::new(0, 0)
+    #[cfg(all(unix, feature = "process-isolation"))]
+    #[test]
+    fn process_isolation_reports_crashes_as_failures() {
+        fn crash() {
+            unsafe { libc::abort() }
+        }
+        fn wrong_panic() {
+            let msg = b"written by the child\n";
+            unsafe {
+                libc::write(
+                    libc::STDOUT_FILENO,
+                    msg.as_ptr() as *const libc::c_void,
+                    msg.len(),
+                );
             }
-        })
-    };
-
-    let tests_len = tests.len();
+            panic!("something else")
+        }
+        let test = |name, should_panic, f: fn()| TestDescAndFn {
+            desc: TestDesc {
+                name: TestName::StaticTestName(name),
+                ignore: false,
+                ignore_message: None,
+                should_panic,
+                allow_fail: false,
+                test_type: TestType::Unknown,
+                no_timeout_warning: false,
+                is_converted_bench: false,
+                tags: &[],
+            },
+            testfn: TestFn::StaticTestFn(f),
+        };
+        let _fds = lock_std_fds();
+        let mut opts = TestOpts::new();
+        opts.process_isolation = true;
+        opts.capture_fds = true;
+        let (tx, rx) = channel();
 
-    let mut filtered_tests = filter_tests(opts, tests);
-    if !opts.bench_benchmarks {
-        filtered_tests = convert_benchmarks_to_tests(filtered_tests);
+        run_test(
+            &opts,
+            false,
+            test("crash", ShouldPanic::No, crash),
+            tx.clone(),
+            Concurrent::No,
+        );
+        let (_, res, _, _) = rx.recv().unwrap();
+        let msg = format!("process aborted with signal {}", libc::SIGABRT);
+        assert!(res == TestResult::TrFailedMsg(msg));
+
+        let should_panic = ShouldPanic::YesWithMessage("expected");
+        run_test(
+            &opts,
+            false,
+            test("wrong_panic", should_panic, wrong_panic),
+            tx,
+            Concurrent::Yes,
+        );
+        let (desc, res, exec_time, output) = rx.recv().unwrap();
+        assert_eq!(desc.name.as_slice(), "wrong_panic");
+        assert!(
+            res == TestResult::TrFailedShouldPanic(PanicMismatch {
+                expected: "expected".to_owned(),
+                regex: false,
+                actual: Some("something else".to_owned()),
+            })
+        );
+        assert!(exec_time.is_some());
+        assert!(String::from_utf8_lossy(&output.stdout)
+            .contains("written by the child"));
     }
 
-    let filtered_tests = {
-        let mut filtered_tests = filtered_tests;
-        for test in &mut filtered_tests {
-            test.desc.name =
-                test.desc.name.with_padding(test.testfn.padding());
+    #[cfg(all(unix, feature = "process-isolation"))]
+    #[test]
+    fn process_isolation_returns_the_childs_stderr() {
+        fn write_stderr() {
+            let msg = b"written to stderr\n";
+            unsafe {
+                libc::write(
+                    libc::STDERR_FILENO,
+                    msg.as_ptr() as *const libc::c_void,
+                    msg.len(),
+                );
+            }
         }
+        let _fds = lock_std_fds();
+        let mut opts = TestOpts::new();
+        opts.process_isolation = true;
+        let (tx, rx) = channel();
 
-        filtered_tests
-    };
-
-    let filtered_out = tests_len - filtered_tests.len();
-    callback(TestEvent::TeFilteredOut(filtered_out))?;
-
-    let filtered_descs =
-        filtered_tests.iter().map(|t| t.desc.clone()).collect();
+        run_test(
+            &opts,
+            false,
+            TestDescAndFn {
+                desc: TestDesc {
+                    name: TestName::StaticTestName("write_stderr"),
+                    ignore: false,
+                    ignore_message: None,
+                    should_panic: ShouldPanic::No,
+                    allow_fail: false,
+                    test_type: TestType::Unknown,
+                    no_timeout_warning: false,
+                    is_converted_bench: false,
+                    tags: &[],
+                },
+                testfn: TestFn::StaticTestFn(write_stderr),
+            },
+            tx,
+            Concurrent::No,
+        );
+        let (_, res, _, output) = rx.recv().unwrap();
+        assert!(res == TestResult::TrOk);
+        assert_eq!(output.stderr, b"written to stderr\n");
+        assert_eq!(output.combined, b"written to stderr\n");
+    }
 
-    callback(TestEvent::TeFiltered(filtered_descs))?;
+    #[cfg(all(unix, feature = "process-isolation"))]
+    #[test]
+    fn process_isolation_reports_stack_overflows() {
+        fn recurse(depth: u64) -> u64 {
+            let frame = crate::black_box([depth; 64]);
+            if frame[0] != frame[1] {
+                return 0;
+            }
+            recurse(depth + 1) + frame[1]
+        }
+        fn overflow() {
+            crate::black_box(recurse(0));
+        }
+        let desc = TestDescAndFn {
+            desc: TestDesc {
+                name: TestName::StaticTestName("overflow"),
+                ignore: false,
+                ignore_message: None,
+                should_panic: ShouldPanic::No,
+                allow_fail: false,
+                test_type: TestType::Unknown,
+                no_timeout_warning: false,
+                is_converted_bench: false,
+                tags: &[],
+            },
+            testfn: TestFn::StaticTestFn(overflow),
+        };
+        let _fds = lock_std_fds();
+        let mut opts = TestOpts::new();
+        opts.process_isolation = true;
+        let (tx, rx) = channel();
 
-    let (filtered_tests, filtered_benchs): (Vec<_>, _) =
-        filtered_tests.into_iter().partition(|e| match e.testfn {
-            TestFn::StaticTestFn(_) | TestFn::DynTestFn(_) => true,
-            _ => false,
-        });
+        run_test(&opts, false, desc, tx, Concurrent::No);
+        let (_, res, _, output) = rx.recv().unwrap();
+        let msg = "test overflowed its stack".to_owned();
+        assert!(res == TestResult::TrFailedMsg(msg));
+        assert!(String::from_utf8_lossy(&output.stderr)
+            .contains("has overflowed its stack"));
+    }
 
-    let concurrency = opts.test_threads.unwrap_or_else(get_concurrency);
+    #[cfg(all(unix, feature = "process-isolation"))]
+    #[test]
+    fn process_isolation_kills_children_that_time_out() {
+        fn hang() {
+            thread::sleep(Duration::from_secs(60));
+        }
+        let desc = TestDescAndFn {
+            desc: TestDesc {
+                name: TestName::StaticTestName("hang"),
+                ignore: false,
+                ignore_message: None,
+                should_panic: ShouldPanic::No,
+                allow_fail: false,
+                test_type: TestType::Unknown,
+                no_timeout_warning: false,
+                is_converted_bench: false,
+                tags: &[],
+            },
+            testfn: TestFn::StaticTestFn(hang),
+        };
+        let _fds = lock_std_fds();
+        let mut opts = TestOpts::new();
+        opts.process_isolation = true;
+        opts.timeout = Some(Duration::from_millis(50));
+        let (tx, rx) = channel();
 
-    let mut remaining = filtered_tests;
-    remaining.reverse();
-    let mut pending = 0;
+        run_test(&opts, false, desc, tx, Concurrent::Yes);
+        let (_, res, _, _) = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        let msg = format!("process aborted with signal {}", libc::SIGKILL);
+        assert!(res == TestResult::TrFailedMsg(msg));
+    }
 
-    let (tx, rx) = channel::<MonitorMsg>();
+    #[cfg(not(feature = "process-isolation"))]
+    #[test]
+    fn process_isolation_requires_its_feature() {
+        let args =
+            vec!["progname".to_string(), "--process-isolation".to_string()];
+        let err = parse_opts(&args).unwrap().err().unwrap();
+        assert!(err.contains("process-isolation"));
 
-    let mut running_tests: TestMap = HashMap::default();
+        let mut opts = TestOpts::new();
+        opts.process_isolation = true;
+        let err = run_tests(&opts, Vec::new(), |_| Ok(())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
 
-    if concurrency == 1 {
-        while !remaining.is_empty() {
-            let test = remaining.pop().unwrap();
-            callback(TestEvent::TeWait(test.desc.clone()))?;
-            run_test(opts, !opts.run_tests, test, tx.clone(), Concurrent::No);
-            let (test, result, stdout) = rx.recv().unwrap();
-            callback(TestEvent::TeResult(test, result, stdout))?;
+    #[test]
+    fn make_owned_test_copies_static_tests() {
+        fn f() {}
+        let test = TestDescAndFn {
+            desc: TestDesc {
+                name: TestName::StaticTestName("static"),
+                ignore: false,
+                ignore_message: None,
+                should_panic: ShouldPanic::No,
+                allow_fail: false,
+                test_type: TestType::Unknown,
+                no_timeout_warning: false,
+                is_converted_bench: false,
+                tags: &[],
+            },
+            testfn: TestFn::StaticTestFn(f),
+        };
+        let owned = make_owned_test(&test);
+        assert_eq!(owned.desc, test.desc);
+        match owned.testfn {
+            TestFn::StaticTestFn(_) => {}
+            _ => panic!("expected a static test function"),
         }
-    } else {
-        while pending > 0 || !remaining.is_empty() {
-            while pending < concurrency && !remaining.is_empty() {
-                let test = remaining.pop().unwrap();
-                let timeout =
-                    Instant::now() + Duration::from_secs(TEST_WARN_TIMEOUT_S);
-                running_tests.insert(test.desc.clone(), timeout);
-                callback(TestEvent::TeWait(test.desc.clone()))?; //here no pad
-                run_test(
-                    opts,
-                    !opts.run_tests,
-                    test,
-                    tx.clone(),
-                    Concurrent::Yes,
-                );
-                pending += 1;
-            }
 
-            let mut res;
-            loop {
-                if let Some(timeout) = calc_timeout(&running_tests) {
-                    res = rx.recv_timeout(timeout);
-                    for test in get_timed_out_tests(&mut running_tests) {
-                        callback(TestEvent::TeTimeout(test))?;
-                    }
-                    if res != Err(RecvTimeoutError::Timeout) {
-                        break;
-                    }
-                } else {
-                    res =
-                        rx.recv().map_err(|_| RecvTimeoutError::Disconnected);
-                    break;
-                }
-            }
+        let dynamic = TestDescAndFn {
+            desc: TestDesc {
+                name: TestName::StaticTestName("dynamic"),
+                ..test.desc
+            },
+            testfn: TestFn::DynTestFn(Box::new(|| {})),
+        };
+        let err = panic::catch_unwind(AssertUnwindSafe(|| {
+            make_owned_test(&dynamic);
+        }))
+        .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<String>().unwrap(),
+            "non-static test `dynamic` can't be made owned"
+        );
+    }
 
-            let (desc, result, stdout) = res.unwrap();
-            running_tests.remove(&desc);
+    #[test]
+    fn force_run_in_process_runs_tests_on_the_calling_thread() {
+        let args = vec![
+            "progname".to_string(),
+            "--force-run-in-process".to_string(),
+            "--test-threads=4".to_string(),
+        ];
+        let mut opts = parse_opts(&args).unwrap().unwrap();
+        assert!(opts.force_run_in_process);
+        opts.run_tests = true;
 
-            callback(TestEvent::TeResult(desc, result, stdout))?;
-            pending -= 1;
-        }
+        let threads = Arc::new(Mutex::new(Vec::new()));
+        let tests = (0..3)
+            .map(|i| {
+                let threads = threads.clone();
+                TestDescAndFn {
+                    desc: TestDesc {
+                        name: TestName::DynTestName(format!("t{}", i)),
+                        ignore: false,
+                        ignore_message: None,
+                        should_panic: ShouldPanic::No,
+                        allow_fail: false,
+                        test_type: TestType::Unknown,
+                        no_timeout_warning: false,
+                        is_converted_bench: false,
+                        tags: &[],
+                    },
+                    testfn: TestFn::DynTestFn(Box::new(move || {
+                        threads.lock().unwrap().push(thread::current().id())
+                    })),
+                }
+            })
+            .collect();
+        run_tests(&opts, tests, |_| Ok(())).unwrap();
+        let threads = threads.lock().unwrap();
+        assert_eq!(threads.len(), 3);
+        assert!(threads.iter().all(|&id| id == thread::current().id()));
+        assert!(!DisplayConfig::new(&opts, 0).is_multithreaded);
+
+        let mut args = args;
+        args.push("--timeout=5".to_string());
+        assert!(parse_opts(&args).unwrap().is_err());
     }
 
-    if opts.bench_benchmarks {
-        // All benchmarks run at the end, in serial.
-        for b in filtered_benchs {
-            callback(TestEvent::TeWait(b.desc.clone()))?;
-            run_test(opts, false, b, tx.clone(), Concurrent::No);
-            let (test, result, stdout) = rx.recv().unwrap();
-            callback(TestEvent::TeResult(test, result, stdout))?;
+    #[test]
+    fn track_allocations_reports_what_each_test_allocated() {
+        thread_local! {
+            static COUNTS: Cell<AllocationStats> = Cell::default();
         }
-    }
-    Ok(())
-}
-
-#[allow(deprecated)]
-fn get_concurrency() -> usize {
-    #[cfg(windows)]
-    #[allow(nonstandard_style)]
-    fn num_cpus() -> usize {
-        #[repr(C)]
-        struct SYSTEM_INFO {
-            wProcessorArchitecture: u16,
-            wReserved: u16,
-            dwPageSize: u32,
-            lpMinimumApplicationAddress: *mut u8,
-            lpMaximumApplicationAddress: *mut u8,
-            dwActiveProcessorMask: *mut u8,
-            dwNumberOfProcessors: u32,
-            dwProcessorType: u32,
-            dwAllocationGranularity: u32,
-            wProcessorLevel: u16,
-            wProcessorRevision: u16,
+        fn counter() -> AllocationStats {
+            COUNTS.with(Cell::get)
         }
-        extern "system" {
-            fn GetSystemInfo(info: *mut SYSTEM_INFO) -> i32;
+        fn allocate() {
+            COUNTS.with(|counts| {
+                counts.set(AllocationStats {
+                    allocations: counts.get().allocations + 2,
+                    bytes: counts.get().bytes + 1500,
+                })
+            });
         }
-        unsafe {
-            let mut sysinfo = std::mem::zeroed();
-            GetSystemInfo(&mut sysinfo);
-            sysinfo.dwNumberOfProcessors as usize
+
+        let desc = TestDesc {
+            name: TestName::StaticTestName("allocates"),
+            ignore: false,
+            ignore_message: None,
+            should_panic: ShouldPanic::No,
+            allow_fail: false,
+            test_type: TestType::Unknown,
+            no_timeout_warning: false,
+            is_converted_bench: false,
+            tags: &[],
+        };
+        let mut opts = TestOpts::new();
+        opts.options = Options::new().allocation_counter(counter);
+        for &track in &[false, true] {
+            opts.track_allocations = track;
+            let (tx, rx) = channel();
+            let test = TestDescAndFn {
+                desc: desc.clone(),
+                testfn: TestFn::StaticTestFn(allocate),
+            };
+            run_test(&opts, false, test, tx, Concurrent::Yes);
+            let (_, result, _, output) = rx.recv().unwrap();
+            assert!(result == TestResult::TrOk);
+            let stats = AllocationStats {
+                allocations: 2,
+                bytes: 1500,
+            };
+            assert_eq!(
+                output.allocations,
+                if track { Some(stats) } else { None }
+            );
         }
+        assert_eq!(
+            fmt_allocations(AllocationStats {
+                allocations: 2,
+                bytes: 1500
+            }),
+            " (2 allocations, 1,500 bytes)"
+        );
     }
 
-    #[cfg(target_os = "redox")]
-    fn num_cpus() -> usize {
-        // FIXME: Implement num_cpus on Redox
-        1
+    #[cfg(unix)]
+    #[test]
+    fn capture_keeps_stdout_and_stderr_apart() {
+        fn f() {
+            for &(fd, msg) in &[
+                (libc::STDOUT_FILENO, "to stdout\n"),
+                (libc::STDERR_FILENO, "to stderr\n"),
+            ] {
+                unsafe {
+                    libc::write(
+                        fd,
+                        msg.as_ptr() as *const libc::c_void,
+                        msg.len(),
+                    );
+                }
+            }
+        }
+        let desc = TestDescAndFn {
+            desc: TestDesc {
+                name: TestName::StaticTestName("whatever"),
+                ignore: false,
+                ignore_message: None,
+                should_panic: ShouldPanic::No,
+                allow_fail: false,
+                test_type: TestType::Unknown,
+                no_timeout_warning: false,
+                is_converted_bench: false,
+                tags: &[],
+            },
+            testfn: TestFn::DynTestFn(Box::new(f)),
+        };
+        let _fds = lock_std_fds();
+        let mut opts = TestOpts::new();
+        opts.capture_fds = true;
+        let (tx, rx) = channel();
+        run_test(&opts, false, desc, tx, Concurrent::No);
+        let (_, _, _, output) = rx.recv().unwrap();
+        assert_eq!(output.stdout, b"to stdout\n");
+        assert_eq!(output.stderr, b"to stderr\n");
+        assert_eq!(output.combined.len(), 20);
     }
 
-    #[cfg(any(
-        all(target_arch = "wasm32", not(target_os = "emscripten")),
-        all(target_vendor = "fortanix", target_env = "sgx")
-    ))]
-    fn num_cpus() -> usize {
-        1
-    }
+    #[test]
+    fn fail_fast_on_new_skips_known_failures() {
+        fn fail() {
+            panic!();
+        }
+        fn pass() {}
 
-    #[cfg(any(
-        target_os = "android",
-        target_os = "cloudabi",
-        target_os = "emscripten",
-        target_os = "fuchsia",
-        target_os = "ios",
-        target_os = "linux",
-        target_os = "macos",
-        target_os = "solaris"
-    ))]
-    fn num_cpus() -> usize {
-        unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) as usize }
-    }
+        let path = env::temp_dir()
+            .join(format!("libtest-known-failures-{}", process::id()));
+        fs::write(&path, "# known reds\na\n").unwrap();
 
-    #[cfg(any(
-        target_os = "freebsd",
-        target_os = "dragonfly",
-        target_os = "bitrig",
-        target_os = "netbsd"
-    ))]
-    fn num_cpus() -> usize {
-        use std::ptr;
+        let mut opts = TestOpts::new();
+        opts.run_tests = true;
+        opts.test_threads = Some(1);
+        opts.fail_fast_on_new = true;
+        opts.known_failures = Some(path.clone());
 
-        let mut cpus: libc::c_uint = 0;
-        let mut cpus_size = std::mem::size_of_val(&cpus);
+        let tests = vec![("a", fail as fn()), ("b", fail), ("c", pass)]
+            .into_iter()
+            .map(|(name, f)| TestDescAndFn {
+                desc: TestDesc {
+                    name: TestName::StaticTestName(name),
+                    ignore: false,
+                    ignore_message: None,
+                    should_panic: ShouldPanic::No,
+                    allow_fail: false,
+                    test_type: TestType::Unknown,
+                    no_timeout_warning: false,
+                    is_converted_bench: false,
+                    tags: &[],
+                },
+                testfn: TestFn::StaticTestFn(f),
+            })
+            .collect();
 
-        unsafe {
-            cpus = libc::sysconf(libc::_SC_NPROCESSORS_ONLN) as libc::c_uint;
-        }
-        if cpus < 1 {
-            let mut mib = [libc::CTL_HW, libc::HW_NCPU, 0, 0];
-            unsafe {
-                libc::sysctl(
-                    mib.as_mut_ptr(),
-                    2,
-                    &mut cpus as *mut _ as *mut _,
-                    &mut cpus_size as *mut _ as *mut _,
-                    ptr::null_mut(),
-                    0,
-                );
+        let mut ran = Vec::new();
+        run_tests(&opts, tests, |event| {
+            if let TestEvent::TeResult(desc, ..) = event {
+                ran.push(desc.name.to_string());
             }
-            if cpus < 1 {
-                cpus = 1;
-            }
-        }
-        cpus as usize
-    }
+            Ok(())
+        })
+        .unwrap();
+        fs::remove_file(&path).unwrap();
 
-    #[cfg(target_os = "openbsd")]
-    fn num_cpus() -> usize {
-        use std::ptr;
+        assert_eq!(ran, vec!["a", "b"]);
 
-        let mut cpus: libc::c_uint = 0;
-        let mut cpus_size = std::mem::size_of_val(&cpus);
-        let mut mib = [libc::CTL_HW, libc::HW_NCPU, 0, 0];
+        let args =
+            vec!["progname".to_string(), "--known-failures=known".to_string()];
+        assert!(parse_opts(&args).unwrap().is_err());
+    }
 
-        unsafe {
-            libc::sysctl(
-                mib.as_mut_ptr(),
-                2,
-                &mut cpus as *mut _ as *mut _,
-                &mut cpus_size as *mut _ as *mut _,
-                ptr::null_mut(),
-                0,
-            );
-        }
-        if cpus < 1 {
-            cpus = 1;
+    #[test]
+    fn fail_fast_stops_after_the_first_failure() {
+        fn fail() {
+            panic!();
         }
-        cpus as usize
-    }
+        fn pass() {}
 
-    #[cfg(target_os = "haiku")]
-    fn num_cpus() -> usize {
-        // FIXME: implement
-        1
-    }
+        for &threads in &[1, 2] {
+            let mut opts = TestOpts::new();
+            opts.run_tests = true;
+            opts.test_threads = Some(threads);
+            opts.fail_fast = true;
 
-    #[cfg(target_os = "l4re")]
-    fn num_cpus() -> usize {
-        // FIXME: implement
-        1
-    }
+            let tests = vec![("a", pass as fn()), ("b", fail), ("c", pass)]
+                .into_iter()
+                .map(|(name, f)| TestDescAndFn {
+                    desc: TestDesc {
+                        name: TestName::StaticTestName(name),
+                        ignore: false,
+                        ignore_message: None,
+                        should_panic: ShouldPanic::No,
+                        allow_fail: false,
+                        test_type: TestType::Unknown,
+                        no_timeout_warning: false,
+                        is_converted_bench: false,
+                        tags: &[],
+                    },
+                    testfn: TestFn::StaticTestFn(f),
+                })
+                .collect();
 
-    match env::var("RUST_TEST_THREADS") {
-        Ok(s) => {
-            let opt_n: Option<usize> = s.parse().ok();
-            match opt_n {
-                Some(n) if n > 0 => n,
-                _ => panic!(
-                    "RUST_TEST_THREADS is `{}`, should be a positive integer.",
-                    s
-                ),
+            let mut ran = Vec::new();
+            let stopped_early = run_tests_inner(&opts, tests, |event| {
+                if let TestEvent::TeResult(desc, ..) = event {
+                    ran.push(desc.name.to_string());
+                }
+                Ok(())
+            })
+            .unwrap();
+
+            // With two threads, "c" may start before "b" has failed.
+            assert!(ran.contains(&"b".to_owned()));
+            assert_eq!(stopped_early, ran.len() < 3);
+            if threads == 1 {
+                assert_eq!(ran, vec!["a", "b"]);
             }
         }
-        Err(..) => num_cpus(),
     }
-}
-
-pub fn filter_tests(
-    opts: &TestOpts,
-    tests: Vec<TestDescAndFn>,
-) -> Vec<TestDescAndFn> {
-    let mut filtered = tests;
-    let matches_filter = |test: &TestDescAndFn, filter: &str| {
-        let test_name = test.desc.name.as_slice();
 
-        if opts.filter_exact {
-            test_name == filter
-        } else {
-            test_name.contains(filter)
+    #[test]
+    fn retries_share_a_total_budget() {
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        fn fails_twice() {
+            assert!(RUNS.fetch_add(1, Ordering::SeqCst) >= 2);
         }
-    };
 
-    // Remove tests that don't match the test filter
-    if let Some(ref filter) = opts.filter {
-        filtered.retain(|test| matches_filter(test, filter));
-    }
+        let run = |max_total_retries| {
+            RUNS.store(0, Ordering::SeqCst);
+            let mut opts = TestOpts::new();
+            opts.run_tests = true;
+            opts.test_threads = Some(1);
+            opts.retries = 3;
+            opts.max_total_retries = max_total_retries;
+            let test = TestDescAndFn {
+                desc: TestDesc {
+                    name: TestName::StaticTestName("flaky"),
+                    ignore: false,
+                    ignore_message: None,
+                    should_panic: ShouldPanic::No,
+                    allow_fail: false,
+                    test_type: TestType::Unknown,
+                    no_timeout_warning: false,
+                    is_converted_bench: false,
+                    tags: &[],
+                },
+                testfn: TestFn::StaticTestFn(fails_twice),
+            };
 
-    // Skip tests that match any of the skip filters
-    filtered
-        .retain(|test| !opts.skip.iter().any(|sf| matches_filter(test, sf)));
+            let mut results = Vec::new();
+            run_tests(&opts, vec![test], |event| {
+                if let TestEvent::TeResult(_, result, ..) = event {
+                    results.push(result);
+                }
+                Ok(())
+            })
+            .unwrap();
+            (results, RUNS.load(Ordering::SeqCst))
+        };
 
-    // Excludes #[should_panic] tests
-    if opts.exclude_should_panic {
-        filtered.retain(|test| test.desc.should_panic == ShouldPanic::No);
+        assert!(run(Some(1)) == (vec![TestResult::TrFailed], 2));
+        assert!(run(None) == (vec![TestResult::TrOkWithRetries(2)], 3));
     }
 
-    // maybe unignore tests
-    match opts.run_ignored {
-        RunIgnored::Yes => {
-            filtered
-                .iter_mut()
-                .for_each(|test| test.desc.ignore = false);
-        }
-        RunIgnored::Only => {
-            filtered.retain(|test| test.desc.ignore);
-            filtered
-                .iter_mut()
-                .for_each(|test| test.desc.ignore = false);
-        }
-        RunIgnored::No => {}
-    }
+    #[test]
+    fn custom_formatter_receives_events() {
+        struct Recorder(Arc<Mutex<Vec<String>>>);
 
-    // Sort the tests alphabetically
-    filtered.sort_by(|t1, t2| {
-        t1.desc.name.as_slice().cmp(t2.desc.name.as_slice())
-    });
+        impl OutputFormatter for Recorder {
+            fn write_run_start(&mut self, n: usize) -> io::Result<()> {
+                self.0.lock().unwrap().push(format!("start {}", n));
+                Ok(())
+            }
+            fn write_test_start(&mut self, _: &TestDesc) -> io::Result<()> {
+                Ok(())
+            }
+            fn write_timeout(&mut self, _: &TestDesc) -> io::Result<()> {
+                Ok(())
+            }
+            fn write_result(
+                &mut self,
+                desc: &TestDesc,
+                _: &TestResult,
+                _: Option<Duration>,
+                _: &TestOutput,
+            ) -> io::Result<()> {
+                self.0.lock().unwrap().push(desc.name.to_string());
+                Ok(())
+            }
+            fn write_run_finish(
+                &mut self,
+                state: &ConsoleTestState,
+            ) -> io::Result<bool> {
+                let failed = state.summary().failures.len();
+                self.0.lock().unwrap().push(format!("failed {}", failed));
+                Ok(failed == 0)
+            }
+        }
 
-    filtered
-}
+        let mut opts = TestOpts::new();
+        opts.run_tests = true;
+        let tests = vec![TestDescAndFn {
+            desc: TestDesc {
+                name: TestName::StaticTestName("only"),
+                ignore: false,
+                ignore_message: None,
+                should_panic: ShouldPanic::No,
+                allow_fail: false,
+                test_type: TestType::Unknown,
+                no_timeout_warning: false,
+                is_converted_bench: false,
+                tags: &[],
+            },
+            testfn: TestFn::DynTestFn(Box::new(|| {})),
+        }];
 
-pub fn convert_benchmarks_to_tests(
-    tests: Vec<TestDescAndFn>,
-) -> Vec<TestDescAndFn> {
-    // convert benchmarks to tests, if we're not benchmarking them
-    tests
-        .into_iter()
-        .map(|x| {
-            let testfn = match x.testfn {
-                TestFn::DynBenchFn(bench) => {
-                    TestFn::DynTestFn(Box::new(move || {
-                        bench::run_once(|b| {
-                            __rust_begin_short_backtrace(|| bench.run(b))
-                        })
-                    }))
-                }
-                TestFn::StaticBenchFn(benchfn) => {
-                    TestFn::DynTestFn(Box::new(move || {
-                        bench::run_once(|b| {
-                            __rust_begin_short_backtrace(|| benchfn(b))
-                        })
-                    }))
-                }
-                f => f,
-            };
-            TestDescAndFn {
-                desc: x.desc,
-                testfn,
-            }
-        })
-        .collect()
-}
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let out = Box::new(Recorder(events.clone()));
+        assert!(run_tests_console_with_formatter(&opts, tests, out).unwrap());
+        assert_eq!(*events.lock().unwrap(), ["start 1", "only", "failed 0"]);
+    }
 
-pub fn run_test(
-    opts: &TestOpts,
-    force_ignore: bool,
-    test: TestDescAndFn,
-    monitor_ch: Sender<MonitorMsg>,
-    concurrency: Concurrent,
-) {
-    fn run_test_inner(
-        desc: TestDesc,
-        monitor_ch: Sender<MonitorMsg>,
-        nocapture: bool,
-        testfn: Box<dyn FnBox() + Send>,
-        concurrency: Concurrent,
-    ) {
-        // Buffer for capturing standard I/O
-        let data = Arc::new(Mutex::new(Vec::new()));
-        let data2 = data.clone();
+    #[test]
+    fn run_tests_summary_counts_results() {
+        fn pass() {}
+        fn fail() {
+            panic!();
+        }
 
-        let name = desc.name.clone();
-        let runtest = move || {
-            let oldio = if nocapture {
-                None
-            } else {
-                Some((
-                    io::set_print(Some(Box::new(Sink(data2.clone())))),
-                    io::set_panic(Some(Box::new(Sink(data2)))),
-                ))
-            };
+        let test = |name, testfn, ignore| TestDescAndFn {
+            desc: TestDesc {
+                name: TestName::StaticTestName(name),
+                ignore,
+                ignore_message: None,
+                should_panic: ShouldPanic::No,
+                allow_fail: false,
+                test_type: TestType::Unknown,
+                no_timeout_warning: false,
+                is_converted_bench: false,
+                tags: &[],
+            },
+            testfn: TestFn::StaticTestFn(testfn),
+        };
+        let mut opts = TestOpts::new();
+        opts.run_tests = true;
+        opts.filters = vec!["s".to_owned()];
+        let tests = vec![
+            test("passes", pass, false),
+            test("fails", fail, false),
+            test("skipped", pass, true),
+            test("other", pass, false),
+        ];
 
-            let result = catch_unwind(AssertUnwindSafe(testfn));
+        let summary = run_tests_summary(&opts, tests).unwrap();
+        assert_eq!(
+            (
+                summary.passed,
+                summary.failed,
+                summary.ignored,
+                summary.filtered_out.total()
+            ),
+            (1, 1, 1, 1)
+        );
+        assert_eq!(summary.failures.len(), 1);
+        assert_eq!(summary.failures[0].0.name.as_slice(), "fails");
+    }
 
-            if let Some((printio, panicio)) = oldio {
-                io::set_print(printio);
-                io::set_panic(panicio);
+    #[test]
+    fn timeout_fails_hung_tests() {
+        for &threads in &[1, 2] {
+            let mut opts = TestOpts::new();
+            opts.run_tests = true;
+            opts.test_threads = Some(threads);
+            opts.timeout = Some(Duration::from_millis(50));
+            let test = |name, hang| TestDescAndFn {
+                desc: TestDesc {
+                    name: TestName::StaticTestName(name),
+                    ignore: false,
+                    ignore_message: None,
+                    should_panic: ShouldPanic::No,
+                    allow_fail: false,
+                    test_type: TestType::Unknown,
+                    no_timeout_warning: false,
+                    is_converted_bench: false,
+                    tags: &[],
+                },
+                testfn: TestFn::DynTestFn(Box::new(move || {
+                    if hang {
+                        thread::sleep(Duration::from_secs(60));
+                    }
+                })),
             };
 
-            let test_result = calc_result(&desc, result);
-            let stdout = data.lock().unwrap().to_vec();
-            monitor_ch
-                .send((desc.clone(), test_result, stdout))
-                .unwrap();
-        };
-
-        // If the platform is single-threaded we're just going to run
-        // the test synchronously, regardless of the concurrency
-        // level.
-        let supports_threads =
-            !cfg!(any(target_os = "emscripten", target_arch = "wasm32"));
-        if concurrency == Concurrent::Yes && supports_threads {
-            let cfg = thread::Builder::new().name(name.as_slice().to_owned());
-            cfg.spawn(runtest).unwrap();
-        } else {
-            runtest();
+            let mut results = Vec::new();
+            let tests = vec![test("hangs", true), test("passes", false)];
+            run_tests(&opts, tests, |event| {
+                if let TestEvent::TeResult(desc, result, ..) = event {
+                    results.push((desc.name.to_string(), result));
+                }
+                Ok(())
+            })
+            .unwrap();
+            results.sort_by(|a, b| a.0.cmp(&b.0));
+
+            let timed_out = TestResult::TrTimedOut(Duration::from_millis(50));
+            assert!(
+                results
+                    == vec![
+                        ("hangs".to_owned(), timed_out),
+                        ("passes".to_owned(), TestResult::TrOk),
+                    ]
+            );
         }
     }
 
-    let TestDescAndFn { desc, testfn } = test;
+    #[test]
+    fn timed_out_tests_are_not_retried() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
 
-    let ignore_because_panic_abort = cfg!(target_arch = "wasm32")
-        && !cfg!(target_os = "emscripten")
-        && desc.should_panic != ShouldPanic::No;
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        fn hangs_once() {
+            if CALLS.fetch_add(1, Ordering::SeqCst) == 0 {
+                thread::sleep(Duration::from_secs(60));
+            }
+        }
 
-    if force_ignore || desc.ignore || ignore_because_panic_abort {
-        monitor_ch
-            .send((desc, TestResult::TrIgnored, Vec::new()))
+        for &threads in &[1, 2] {
+            CALLS.store(0, Ordering::SeqCst);
+            let mut opts = TestOpts::new();
+            opts.run_tests = true;
+            opts.test_threads = Some(threads);
+            opts.timeout = Some(Duration::from_millis(50));
+            opts.retries = 2;
+            let tests = vec![TestDescAndFn {
+                desc: TestDesc {
+                    name: TestName::StaticTestName("hangs_once"),
+                    ignore: false,
+                    ignore_message: None,
+                    should_panic: ShouldPanic::No,
+                    allow_fail: false,
+                    test_type: TestType::Unknown,
+                    no_timeout_warning: false,
+                    is_converted_bench: false,
+                    tags: &[],
+                },
+                testfn: TestFn::StaticTestFn(hangs_once),
+            }];
+
+            let mut results = Vec::new();
+            run_tests(&opts, tests, |event| {
+                if let TestEvent::TeResult(_, result, ..) = event {
+                    results.push(result);
+                }
+                Ok(())
+            })
             .unwrap();
-        return;
+            let timed_out = TestResult::TrTimedOut(Duration::from_millis(50));
+            assert!(results == vec![timed_out]);
+            assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+        }
     }
 
-    match testfn {
-        TestFn::DynBenchFn(bencher) => {
-            crate::bench::benchmark(
-                desc,
-                &monitor_ch,
-                opts.nocapture,
-                |harness| bencher.run(harness),
-            );
-        }
-        TestFn::StaticBenchFn(benchfn) => {
-            crate::bench::benchmark(
-                desc,
-                &monitor_ch,
-                opts.nocapture,
-                |harness| (benchfn)(harness),
-            );
-        }
-        TestFn::DynTestFn(f) => {
-            let cb = move || __rust_begin_short_backtrace(f);
-            run_test_inner(
-                desc,
-                monitor_ch,
-                opts.nocapture,
-                Box::new(cb),
-                concurrency,
-            )
+    #[test]
+    fn max_test_time_fails_slow_passing_tests() {
+        let mut opts = TestOpts::new();
+        opts.run_tests = true;
+        opts.max_test_time = Some(Duration::from_millis(20));
+        let test = |name, slow, fails: bool| TestDescAndFn {
+            desc: TestDesc {
+                name: TestName::StaticTestName(name),
+                ignore: false,
+                ignore_message: None,
+                should_panic: ShouldPanic::No,
+                allow_fail: false,
+                test_type: TestType::Unknown,
+                no_timeout_warning: false,
+                is_converted_bench: false,
+                tags: &[],
+            },
+            testfn: TestFn::DynTestFn(Box::new(move || {
+                if slow {
+                    thread::sleep(Duration::from_millis(50));
+                }
+                assert!(!fails);
+            })),
+        };
+
+        let mut results = Vec::new();
+        let tests = vec![
+            test("fast", false, false),
+            test("slow", true, false),
+            test("slow_failure", true, true),
+        ];
+        run_tests(&opts, tests, |event| {
+            if let TestEvent::TeResult(desc, result, ..) = event {
+                results.push((desc.name.to_string(), result));
+            }
+            Ok(())
+        })
+        .unwrap();
+        results.sort_by(|a, b| a.0.cmp(&b.0));
+
+        assert!(results[0] == ("fast".to_owned(), TestResult::TrOk));
+        match results[1].1 {
+            TestResult::TrFailedMsg(ref msg) => assert!(
+                msg.starts_with(
+                    "test passed but exceeded time budget of 20ms (took "
+                ),
+                "{}",
+                msg
+            ),
+            _ => panic!("slow test didn't fail"),
         }
-        TestFn::StaticTestFn(f) => run_test_inner(
-            desc,
-            monitor_ch,
-            opts.nocapture,
-            Box::new(move || __rust_begin_short_backtrace(f)),
-            concurrency,
-        ),
+        assert!(results[2].1 == TestResult::TrFailed);
     }
-}
 
-/// Fixed frame used to clean the backtrace with `RUST_BACKTRACE=1`.
-#[inline(never)]
-fn __rust_begin_short_backtrace<F: FnOnce()>(f: F) {
-    f()
-}
+    #[test]
+    fn logfile_rotates_past_max_size() {
+        let dir = env::temp_dir()
+            .join(format!("libtest-logfile-rotation-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("log");
+        let args = vec![
+            "progname".to_string(),
+            format!("--logfile={}", path.display()),
+            "--logfile-max-size=8".to_string(),
+            "--logfile-keep=2".to_string(),
+        ];
+        let opts = parse_opts(&args).unwrap().unwrap();
 
-fn calc_result(
-    desc: &TestDesc,
-    task_result: Result<(), Box<dyn Any + Send>>,
-) -> TestResult {
-    match (&desc.should_panic, task_result) {
-        (&ShouldPanic::No, Ok(())) | (&ShouldPanic::Yes, Err(_)) => {
-            TestResult::TrOk
-        }
-        (&ShouldPanic::YesWithMessage(msg), Err(ref err)) => {
-            if err
-                .downcast_ref::<String>()
-                .map(|e| &**e)
-                .or_else(|| err.downcast_ref::<&'static str>().cloned())
-                .map_or(false, |e| e.contains(msg))
-            {
-                TestResult::TrOk
-            } else if desc.allow_fail {
-                TestResult::TrAllowedFail
-            } else {
-                TestResult::TrFailedMsg(format!(
-                    "Panic did not include expected string '{}'",
-                    msg
-                ))
-            }
+        let mut st = ConsoleTestState::new(&opts).unwrap();
+        for line in &["aaaa\n", "bbbb\n", "cccc\n", "dddd\n"] {
+            st.write_log(line).unwrap();
         }
-        _ if desc.allow_fail => TestResult::TrAllowedFail,
-        _ => TestResult::TrFailed,
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("log"), "dddd\n");
+        assert_eq!(read("log.1"), "cccc\n");
+        assert_eq!(read("log.2"), "bbbb\n");
+        assert!(!dir.join("log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+
+        let args =
+            vec!["progname".to_string(), "--logfile-max-size=10".to_string()];
+        assert!(parse_opts(&args).unwrap().is_err());
     }
-}
-
-#[derive(Clone, PartialEq, Default)]
-pub struct MetricMap(BTreeMap<String, Metric>);
 
-impl MetricMap {
-    pub fn new() -> Self {
-        Self::default()
+    #[test]
+    fn json_logfile_gets_the_json_lines() {
+        let path = env::temp_dir()
+            .join(format!("libtest-json-log-{}", process::id()));
+        let mut opts = TestOpts::new();
+        opts.run_tests = true;
+        opts.format = OutputFormat::Json;
+        opts.logfile = Some(path.clone());
+        let tests = vec![TestDescAndFn {
+            desc: TestDesc {
+                name: TestName::StaticTestName("logged"),
+                ignore: false,
+                ignore_message: None,
+                should_panic: ShouldPanic::No,
+                allow_fail: false,
+                test_type: TestType::Unknown,
+                no_timeout_warning: false,
+                is_converted_bench: false,
+                tags: &[],
+            },
+            testfn: TestFn::DynTestFn(Box::new(|| {})),
+        }];
+        assert!(run_tests_console(&opts, tests).unwrap());
+
+        let log = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(log
+            .lines()
+            .all(|l| l.starts_with("{ ") && l.ends_with(" }")));
+        assert!(log
+            .contains(r#"{ "type": "test", "name": "logged", "event": "ok""#));
+        assert!(log.contains(r#"{ "type": "suite", "event": "ok""#));
     }
 
-    /// Insert a named `value` (+/- `noise`) metric into the map. The value
-    /// must be non-negative. The `noise` indicates the uncertainty of the
-    /// metric, which doubles as the "noise range" of acceptable
-    /// pairwise-regressions on this named value, when comparing from one
-    /// metric to the next using `compare_to_old`.
-    ///
-    /// If `noise` is positive, then it means this metric is of a value
-    /// you want to see grow smaller, so a change larger than `noise` in the
-    /// positive direction represents a regression.
-    ///
-    /// If `noise` is negative, then it means this metric is of a value
-    /// you want to see grow larger, so a change larger than `noise` in the
-    /// negative direction represents a regression.
-    pub fn insert_metric(&mut self, name: &str, value: f64, noise: f64) {
-        let m = Metric { value, noise };
-        self.0.insert(name.to_owned(), m);
+    #[test]
+    fn last_failed_reruns_only_the_recorded_failures() {
+        fn fail() {
+            panic!();
+        }
+        fn pass() {}
+
+        let path = env::temp_dir()
+            .join(format!("libtest-failures-{}", process::id()));
+        let tests = || {
+            vec![("a", fail as fn()), ("b", pass), ("c", fail)]
+                .into_iter()
+                .map(|(name, f)| TestDescAndFn {
+                    desc: TestDesc {
+                        name: TestName::StaticTestName(name),
+                        ignore: false,
+                        ignore_message: None,
+                        should_panic: ShouldPanic::No,
+                        allow_fail: false,
+                        test_type: TestType::Unknown,
+                        no_timeout_warning: false,
+                        is_converted_bench: false,
+                        tags: &[],
+                    },
+                    testfn: TestFn::StaticTestFn(f),
+                })
+                .collect::<Vec<_>>()
+        };
+        let names = |opts: &TestOpts| {
+            filter_tests(opts, tests())
+                .into_iter()
+                .map(|t| t.desc.name.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let mut opts = TestOpts::new();
+        opts.run_tests = true;
+        opts.test_threads = Some(1);
+        opts.failures_file = Some(path.clone());
+        opts.last_failed = true;
+        let _ = fs::remove_file(&path);
+        assert_eq!(names(&opts), vec!["a", "b", "c"]);
+
+        assert!(!run_tests_console(&opts, tests()).unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "a\nc\n");
+        assert_eq!(names(&opts), vec!["a", "c"]);
+        fs::remove_file(&path).unwrap();
+
+        let args = vec!["progname".to_string(), "--last-failed".to_string()];
+        if env::var_os("RUST_TEST_FAILURES_FILE").is_none() {
+            assert!(parse_opts(&args).unwrap().is_err());
+        }
     }
 
-    pub fn fmt_metrics(&self) -> String {
-        let v = self
-            .0
-            .iter()
-            .map(|(k, v)| format!("{}: {} (+/- {})", *k, v.value, v.noise))
-            .collect::<Vec<_>>();
-        v.join(", ")
+    #[test]
+    fn source_snippet_marks_the_panicking_line() {
+        let path = env::temp_dir()
+            .join(format!("libtest-snippet-{}.rs", process::id()));
+        fs::write(&path, "fn a() {}\n\nfn b() {\n    panic!();\n}\n").unwrap();
+        let file = path.to_str().unwrap();
+        assert_eq!(
+            source_snippet(file, 4).unwrap(),
+            format!(
+                "note: panicked at {}:4\n  \
+                 2 | \n  \
+                 3 | fn b() {{\n\
+                 > 4 |     panic!();\n  \
+                 5 | }}\n",
+                file
+            )
+        );
+        assert_eq!(source_snippet(file, 40), None);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(source_snippet(file, 4), None);
     }
-}
 
-// Benchmarking
+    #[test]
+    fn failures_report_where_the_test_panicked() {
+        #[rustfmt::skip]
+        fn fail() { panic!("boom") }
+        let line = line!() - 1;
 
-impl Bencher {
-    /// Callback for benchmark functions to run in their body.
-    pub fn iter<T, F>(&mut self, mut inner: F)
-    where
-        F: FnMut() -> T,
-    {
-        if self.mode == BenchMode::Single {
-            ns_iter_inner(&mut inner, 1);
-            return;
-        }
+        let tests = || {
+            vec![TestDescAndFn {
+                desc: TestDesc {
+                    name: TestName::StaticTestName("fails"),
+                    ignore: false,
+                    ignore_message: None,
+                    should_panic: ShouldPanic::No,
+                    allow_fail: false,
+                    test_type: TestType::Unknown,
+                    no_timeout_warning: false,
+                    is_converted_bench: false,
+                    tags: &[],
+                },
+                testfn: TestFn::StaticTestFn(fail),
+            }]
+        };
+        let mut opts = TestOpts::new();
+        opts.run_tests = true;
 
-        self.summary = Some(iter(&mut inner));
+        let mut location = None;
+        run_tests(&opts, tests(), |event| {
+            if let TestEvent::TeResult(_, _, _, output) = event {
+                location = output.panic_location;
+            }
+            Ok(())
+        })
+        .unwrap();
+        let location = location.unwrap();
+        assert_eq!((&*location.file, location.line), (file!(), line));
+        assert_eq!(location.column, 21);
+
+        let summary = run_tests_summary(&opts, tests()).unwrap();
+        let failure = String::from_utf8_lossy(&summary.failures[0].1);
+        assert!(failure.ends_with(&format!(
+            "note: panicked at {}:{}:21",
+            file!(),
+            line
+        )));
     }
 
-    pub fn bench<F>(&mut self, mut f: F) -> Option<stats::Summary>
-    where
-        F: FnMut(&mut Self),
-    {
-        f(self);
-        self.summary
+    #[test]
+    fn encoded_opts_round_trip() {
+        let mut opts = TestOpts::new();
+        opts.filters = vec!["a;b=c%d\ne".to_string(), "f".to_string()];
+        opts.run_ignored = RunIgnored::Only;
+        opts.logfile = Some(PathBuf::from("/tmp/log"));
+        opts.color = ColorConfig::NeverColor;
+        opts.format = OutputFormat::Terse;
+        opts.test_threads = Some(3);
+        opts.timeout = Some(Duration::from_millis(1500));
+        opts.warn_timeout = None;
+        opts.skip = vec!["x".to_string(), "y".to_string()];
+        opts.skip_exact = vec!["z".to_string()];
+        opts.include_tags = vec!["net".to_string(), "gpu".to_string()];
+        opts.exclude_tags = vec!["slow".to_string()];
+        opts.failures_file = Some(PathBuf::from("/tmp/failures"));
+        opts.last_failed = true;
+        opts.shard = Some((2, 5));
+        opts.options = Options::new().display_output(true);
+
+        let encoded = opts.encode();
+        assert!(!encoded.contains('\n'));
+        assert_eq!(TestOpts::decode(&encoded), Ok(opts));
+
+        assert_eq!(TestOpts::decode(""), Ok(TestOpts::new()));
+        assert!(TestOpts::decode("bogus=1").is_err());
+        assert!(TestOpts::decode("list=maybe").is_err());
     }
-}
 
-fn ns_from_dur(dur: Duration) -> u64 {
-    dur.as_secs() * 1_000_000_000 + u64::from(dur.subsec_nanos())
-}
+    #[test]
+    fn color_flag_wins_over_clicolor_force_over_no_color() {
+        assert_eq!(color_from_env(None, None), ColorConfig::AutoColor);
+        assert_eq!(color_from_env(None, Some("")), ColorConfig::AutoColor);
+        assert_eq!(color_from_env(None, Some("1")), ColorConfig::NeverColor);
+        assert_eq!(color_from_env(Some("0"), None), ColorConfig::AutoColor);
+        assert_eq!(
+            color_from_env(Some("0"), Some("1")),
+            ColorConfig::NeverColor
+        );
+        assert_eq!(
+            color_from_env(Some("1"), Some("1")),
+            ColorConfig::AlwaysColor
+        );
 
-fn ns_iter_inner<T, F>(inner: &mut F, k: u64) -> u64
-where
-    F: FnMut() -> T,
-{
-    let start = Instant::now();
-    for _ in 0..k {
-        test::black_box(inner());
+        let args = vec!["progname".to_string(), "--color=always-ansi".into()];
+        let opts = parse_opts(&args).unwrap().unwrap();
+        assert_eq!(opts.color, ColorConfig::AlwaysAnsiColor);
+        assert!(use_color(&opts));
+        assert_eq!(TestOpts::decode(&opts.encode()), Ok(opts));
     }
-    ns_from_dur(start.elapsed())
-}
 
-pub fn iter<T, F>(inner: &mut F) -> stats::Summary
-where
-    F: FnMut() -> T,
-{
-    // Initial bench run to get ballpark figure.
-    let ns_single = ns_iter_inner(inner, 1);
+    #[test]
+    fn parse_show_output_displays_output_of_passing_tests() {
+        let args = vec!["progname".to_string(), "--show-output".to_string()];
+        let opts = parse_opts(&args).unwrap().unwrap();
+        assert!(opts.options.display_output);
 
-    // Try to estimate iter count for 1ms falling back to 1m
-    // iterations if first run took < 1ns.
-    let ns_target_total = 1_000_000; // 1ms
-    let mut n = ns_target_total / cmp::max(1, ns_single);
+        let args = vec!["progname".to_string()];
+        let opts = parse_opts(&args).unwrap().unwrap();
+        assert!(!opts.options.display_output);
+    }
 
-    // if the first run took more than 1ms we don't want to just
-    // be left doing 0 iterations on every loop. The unfortunate
-    // side effect of not being able to do as many runs is
-    // automatically handled by the statistical analysis below
-    // (i.e., larger error bars).
-    n = cmp::max(1, n);
+    #[test]
+    fn plugin_load_reports_missing_library() {
+        let path = env::temp_dir().join("libtest-no-such-plugin.so");
+        let err = unsafe { plugin::load_tests(&path, "p") }.err().unwrap();
+        assert!(!err.to_string().is_empty());
+    }
 
-    let mut total_run = Duration::new(0, 0);
-    let samples: &mut [f64] = &mut [0.0_f64; 50];
-    loop {
-        let loop_start = Instant::now();
+    #[test]
+    fn count_mismatch_is_described() {
+        let mut st = ConsoleTestState::new(&TestOpts::new()).unwrap();
+        st.total = 4;
+        st.summary.passed = 2;
+        st.summary.failed = 1;
+        assert_eq!(
+            st.count_mismatch().unwrap(),
+            "expected 4 test results but counted 3 (2 passed, 1 failed, \
+             0 ignored, 0 measured, 0 allowed to fail), 1 missing"
+        );
 
-        for p in &mut *samples {
-            *p = ns_iter_inner(inner, n) as f64 / n as f64;
+        st.summary.ignored = 1;
+        assert_eq!(st.count_mismatch(), None);
+    }
+
+    #[test]
+    fn module_summary_groups_by_first_segment() {
+        let mut opts = TestOpts::new();
+        let st = ConsoleTestState::new(&opts).unwrap();
+        assert!(st.module_summary().is_none());
+
+        opts.summary_by_module = true;
+        let mut st = ConsoleTestState::new(&opts).unwrap();
+        let desc = |name| TestDesc {
+            name: TestName::StaticTestName(name),
+            ignore: false,
+            ignore_message: None,
+            should_panic: ShouldPanic::No,
+            allow_fail: false,
+            test_type: TestType::Unknown,
+            no_timeout_warning: false,
+            is_converted_bench: false,
+            tags: &[],
+        };
+        for &(name, ref result) in &[
+            ("net::tcp::connect", TestResult::TrOk),
+            ("net::udp::bind", TestResult::TrFailed),
+            ("fs::read", TestResult::TrOk),
+            ("fs::write", TestResult::TrIgnored),
+            ("smoke", TestResult::TrAllowedFail),
+        ] {
+            st.record_module_result(&desc(name), result);
         }
+        st.summary.passed = 2;
+        st.summary.failed = 1;
+        st.summary.ignored = 1;
+        st.summary.allowed_fail = 1;
+
+        assert_eq!(
+            st.module_summary().unwrap(),
+            "module  passed  failed  allowed  ignored  measured\n\
+             (root)       0       0        1        0         0\n\
+             fs           1       0        0        1         0\n\
+             net          1       1        0        0         0\n\
+             total        2       1        1        1         0\n"
+        );
+    }
 
-        stats::winsorize(samples, 5.0);
-        let summ = stats::Summary::new(samples);
+    #[test]
+    fn exit_code_fn_overrides_default() {
+        fn capped_failures(st: &ConsoleTestState) -> i32 {
+            cmp::min(st.failed(), 100) as i32
+        }
 
-        for p in &mut *samples {
-            let ns = ns_iter_inner(inner, 5 * n);
-            *p = ns as f64 / (5 * n) as f64;
+        let mut opts = TestOpts::new();
+        let mut st = ConsoleTestState::new(&opts).unwrap();
+        assert_eq!(st.exit_code(), 0);
+        st.summary.failed = 3;
+        assert_eq!(st.exit_code(), 101);
+
+        opts.options = Options::new().exit_code_fn(capped_failures);
+        let mut st = ConsoleTestState::new(&opts).unwrap();
+        assert_eq!(st.exit_code(), 0);
+        st.summary.failed = 3;
+        assert_eq!(st.exit_code(), 3);
+        st.summary.failed = 250;
+        assert_eq!(st.exit_code(), 100);
+    }
+
+    #[test]
+    fn test_main_with_exit_code_returns_instead_of_exiting() {
+        fn seven(_: &ConsoleTestState) -> i32 {
+            7
         }
 
-        stats::winsorize(samples, 5.0);
-        let summ5 = stats::Summary::new(samples);
+        let args = vec!["progname".to_string(), "--no-such-flag".to_string()];
+        assert_eq!(
+            test_main_with_exit_code(&args, vec![], Options::new()),
+            101
+        );
 
-        let loop_run = loop_start.elapsed();
+        let args = vec!["progname".to_string(), "nothing".to_string()];
+        let options = Options::new().exit_code_fn(seven);
+        assert_eq!(test_main_with_exit_code(&args, vec![], options), 7);
+    }
 
-        // If we've run for 100ms and seem to have converged to a
-        // stable median.
-        if loop_run > Duration::from_millis(100)
-            && summ.median_abs_dev_pct < 1.0
-            && summ.median - summ5.median < summ5.median_abs_dev
-        {
-            return summ5;
+    #[test]
+    fn hooks_run_around_each_test_on_its_thread() {
+        use std::cell::RefCell;
+        thread_local! {
+            static FIXTURE: RefCell<Option<String>> = RefCell::new(None);
         }
+        static TORN_DOWN: AtomicUsize = AtomicUsize::new(0);
 
-        total_run += loop_run;
-        // Longest we ever run for is 3s.
-        if total_run > Duration::from_secs(3) {
-            return summ5;
+        fn set_up(desc: &TestDesc) {
+            if desc.name.as_slice() == "broken_fixture" {
+                panic!("no fixture for this one");
+            }
+            FIXTURE.with(|f| *f.borrow_mut() = Some(desc.name.to_string()));
+        }
+        fn tear_down(_: &TestDesc) {
+            FIXTURE.with(|f| f.borrow_mut().take()).unwrap();
+            TORN_DOWN.fetch_add(1, Ordering::SeqCst);
+        }
+        fn uses_fixture() {
+            let name = FIXTURE.with(|f| f.borrow().clone());
+            assert_eq!(name, Some("uses_fixture".to_owned()));
         }
 
-        // If we overflow here just return the results so far. We check a
-        // multiplier of 10 because we're about to multiply by 2 and the
-        // next iteration of the loop will also multiply by 5 (to calculate
-        // the summ5 result)
-        n = if n.checked_mul(10).is_some() {
-            n * 2
-        } else {
-            return summ5;
-        };
+        let mut opts = TestOpts::new();
+        opts.options =
+            Options::new().before_each(set_up).after_each(tear_down);
+        let mut results = Vec::new();
+        for &name in &["uses_fixture", "broken_fixture"] {
+            let test = TestDescAndFn {
+                desc: TestDesc {
+                    name: TestName::StaticTestName(name),
+                    ignore: false,
+                    ignore_message: None,
+                    should_panic: ShouldPanic::No,
+                    allow_fail: false,
+                    test_type: TestType::Unknown,
+                    no_timeout_warning: false,
+                    is_converted_bench: false,
+                    tags: &[],
+                },
+                testfn: TestFn::StaticTestFn(uses_fixture),
+            };
+            let (tx, rx) = channel();
+            run_test(&opts, false, test, tx, Concurrent::Yes);
+            results.push(rx.recv().unwrap().1);
+        }
+        assert!(results[0] == TestResult::TrOk);
+        assert!(
+            results[1]
+                == TestResult::TrFailedMsg(
+                    "the before_each hook panicked".to_owned()
+                )
+        );
+        assert_eq!(TORN_DOWN.load(Ordering::SeqCst), 1);
     }
-}
 
-pub mod bench {
-    use super::{
-        BenchMode, BenchSamples, Bencher, MonitorMsg, Sender, Sink, TestDesc,
-        TestResult,
-    };
-    use crate::stats;
-    use std::cmp;
-    use std::io;
-    use std::panic::{catch_unwind, AssertUnwindSafe};
-    use std::sync::{Arc, Mutex};
+    #[test]
+    fn test_threads_flag_and_env_var_are_validated_alike() {
+        for &source in &["argument for --test-threads", "RUST_TEST_THREADS"] {
+            assert_eq!(parse_test_threads(source, "4"), Ok(4));
+            assert_eq!(
+                parse_test_threads(source, "0"),
+                Err(format!("{} must not be 0", source))
+            );
+            assert!(parse_test_threads(source, "many")
+                .unwrap_err()
+                .starts_with(&format!("{} must be a number > 0", source)));
+        }
 
-    pub fn benchmark<F>(
-        desc: TestDesc,
-        monitor_ch: &Sender<MonitorMsg>,
-        nocapture: bool,
-        f: F,
-    ) where
-        F: FnMut(&mut Bencher),
-    {
-        let mut bs = Bencher {
-            mode: BenchMode::Auto,
-            summary: None,
-            bytes: 0,
+        let args = vec!["progname".to_string(), "--test-threads=0".into()];
+        assert_eq!(
+            parse_opts(&args).unwrap().err().unwrap(),
+            "argument for --test-threads must not be 0"
+        );
+    }
+
+    #[test]
+    fn concurrency_provider_defers_to_test_threads() {
+        fn three() -> usize {
+            3
+        }
+
+        let mut opts = TestOpts::new();
+        opts.options = Options::new().concurrency_provider(three);
+        assert_eq!(resolve_concurrency(&opts), 3);
+        opts.test_threads = Some(2);
+        assert_eq!(resolve_concurrency(&opts), 2);
+    }
+
+    #[test]
+    fn concurrency_precedence_is_flag_then_env_then_cpus() {
+        fn eight() -> usize {
+            8
+        }
+        fn none() -> usize {
+            0
+        }
+        let resolve = |test_threads, env_threads, cpus: fn() -> usize| {
+            let mut opts = TestOpts::new();
+            opts.test_threads = test_threads;
+            resolve_concurrency_with(&opts, env_threads, cpus)
         };
 
-        let data = Arc::new(Mutex::new(Vec::new()));
-        let data2 = data.clone();
+        assert_eq!(resolve(Some(2), Some("4"), eight), 2);
+        assert_eq!(resolve(Some(2), None, eight), 2);
+        assert_eq!(resolve(None, Some("4"), eight), 4);
+        assert_eq!(resolve(None, None, eight), 8);
 
-        let oldio = if nocapture {
-            None
-        } else {
-            Some((
-                io::set_print(Some(Box::new(Sink(data2.clone())))),
-                io::set_panic(Some(Box::new(Sink(data2)))),
-            ))
-        };
+        // Values `parse_opts` would have rejected.
+        assert_eq!(resolve(Some(0), Some("4"), eight), 1);
+        assert_eq!(resolve(None, Some("0"), eight), 1);
+        assert_eq!(resolve(None, Some("many"), eight), 1);
+        assert_eq!(resolve(None, None, none), 1);
+    }
 
-        let result = catch_unwind(AssertUnwindSafe(|| bs.bench(f)));
+    #[test]
+    fn min_pass_rate_tolerates_some_failures() {
+        let args =
+            vec!["progname".to_string(), "--min-pass-rate=90".to_string()];
+        let opts = parse_opts(&args).unwrap().unwrap();
+        let mut st = ConsoleTestState::new(&opts).unwrap();
+        assert_eq!(st.pass_rate(), 100.0);
+        st.summary.passed = 9;
+        st.summary.failed = 1;
+        assert!(st.success());
+        assert_eq!(st.exit_code(), 0);
+        st.summary.failed = 2;
+        assert!(!st.success());
+        assert_eq!(st.exit_code(), 101);
+
+        for bad in &["--min-pass-rate=101", "--min-pass-rate=NaN"] {
+            let args = vec!["progname".to_string(), bad.to_string()];
+            assert!(parse_opts(&args).unwrap().is_err());
+        }
+    }
 
-        if let Some((printio, panicio)) = oldio {
-            io::set_print(printio);
-            io::set_panic(panicio);
+    #[test]
+    fn slow_tests_can_opt_out_of_the_timeout_warning() {
+        let mut desc = TestDesc {
+            name: TestName::StaticTestName("slow"),
+            ignore: false,
+            ignore_message: None,
+            should_panic: ShouldPanic::No,
+            allow_fail: false,
+            test_type: TestType::Unknown,
+            no_timeout_warning: false,
+            is_converted_bench: false,
+            tags: &[],
         };
+        let after = Some(Duration::from_secs(60));
+        let warn_at = warn_timeout(&desc, after).unwrap();
+        assert!(warn_at > Instant::now() + Duration::from_secs(59));
+        assert_eq!(warn_timeout(&desc, None), None);
+        desc.no_timeout_warning = true;
+        assert_eq!(warn_timeout(&desc, after), None);
+    }
 
-        let test_result = match result {
-            //bs.bench(f) {
-            Ok(Some(ns_iter_summ)) => {
-                let ns_iter = cmp::max(ns_iter_summ.median as u64, 1);
-                let mb_s = bs.bytes * 1000 / ns_iter;
-
-                let bs = BenchSamples {
-                    ns_iter_summ,
-                    mb_s: mb_s as usize,
-                };
-                TestResult::TrBench(bs)
-            }
-            Ok(None) => {
-                // iter not called, so no data.
-                // FIXME: error in this case?
-                let samples: &mut [f64] = &mut [0.0_f64; 1];
-                let bs = BenchSamples {
-                    ns_iter_summ: stats::Summary::new(samples),
-                    mb_s: 0,
-                };
-                TestResult::TrBench(bs)
-            }
-            Err(_) => TestResult::TrFailed,
+    #[test]
+    fn warn_timeout_flag_sets_or_disables_the_warning() {
+        let warn_timeout = |value: &str| {
+            let args = vec![
+                "progname".to_string(),
+                "--warn-timeout".to_string(),
+                value.to_string(),
+            ];
+            parse_opts(&args).unwrap().map(|opts| opts.warn_timeout)
         };
+        assert_eq!(warn_timeout("300"), Ok(Some(Duration::from_secs(300))));
+        assert_eq!(warn_timeout("0"), Ok(None));
+        assert!(warn_timeout("soon").is_err());
+    }
+
+    #[test]
+    fn summary_line_counts_each_category_once() {
+        let mut st = ConsoleTestState::new(&TestOpts::new()).unwrap();
+        st.summary.passed = 5;
+        st.summary.failed = 3;
+        st.summary.ignored = 1;
+        assert_eq!(
+            st.result_counts(),
+            "5 passed; 3 failed; 1 ignored; 0 measured; 0 filtered out"
+        );
 
-        let stdout = data.lock().unwrap().to_vec();
-        monitor_ch.send((desc, test_result, stdout)).unwrap();
+        st.summary.flaky = 2;
+        st.summary.timed_out = 1;
+        st.summary.allowed_fail = 4;
+        assert_eq!(
+            st.result_counts(),
+            "3 passed; 2 failed; 1 timed out; 4 allowed to fail; 2 flaky; \
+             1 ignored; 0 measured; 0 filtered out"
+        );
     }
 
-    pub fn run_once<F>(f: F)
-    where
-        F: FnMut(&mut Bencher),
-    {
-        let mut bs = Bencher {
-            mode: BenchMode::Single,
-            summary: None,
-            bytes: 0,
+    #[test]
+    fn fail_on_empty_fails_runs_without_tests() {
+        let args = vec!["progname".to_string(), "nonexistent".to_string()];
+        let opts = parse_opts(&args).unwrap().unwrap();
+        assert!(!opts.error_on_no_tests);
+        assert!(ConsoleTestState::new(&opts).unwrap().success());
+
+        let args = vec![
+            "progname".to_string(),
+            "--fail-on-empty".to_string(),
+            "nonexistent".to_string(),
+        ];
+        let opts = parse_opts(&args).unwrap().unwrap();
+        assert_eq!(TestOpts::decode(&opts.encode()), Ok(opts.clone()));
+        let mut st = ConsoleTestState::new(&opts).unwrap();
+        assert!(!st.success());
+        assert_eq!(st.exit_code(), 101);
+        st.total = 1;
+        st.summary.passed = 1;
+        assert!(st.success());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn child_exit_result_detects_stack_overflow() {
+        use std::os::unix::process::ExitStatusExt;
+        use std::process::ExitStatus;
+
+        let desc = TestDesc {
+            name: TestName::StaticTestName("recurse"),
+            ignore: false,
+            ignore_message: None,
+            should_panic: ShouldPanic::No,
+            allow_fail: false,
+            test_type: TestType::Unknown,
+            no_timeout_warning: false,
+            is_converted_bench: false,
+            tags: &[],
         };
-        bs.bench(f);
+        let overflow = b"\nthread 'recurse' has overflowed its stack\n\
+                         fatal runtime error: stack overflow\n";
+        let aborted = ExitStatus::from_raw(libc::SIGABRT);
+
+        assert!(
+            child_exit_result(&desc, ExitStatus::from_raw(0), b"")
+                == TestResult::TrOk
+        );
+        assert!(
+            child_exit_result(&desc, ExitStatus::from_raw(101 << 8), b"")
+                == TestResult::TrFailed
+        );
+        assert!(
+            child_exit_result(&desc, aborted, overflow)
+                == TestResult::TrFailedMsg(
+                    "test overflowed its stack".to_owned()
+                )
+        );
+        assert!(
+            child_exit_result(&desc, aborted, b"")
+                == TestResult::TrFailedMsg(format!(
+                    "process aborted with signal {}",
+                    libc::SIGABRT
+                ))
+        );
+        assert!(
+            child_exit_result(
+                &desc,
+                ExitStatus::from_raw(libc::SIGSEGV),
+                overflow
+            ) == TestResult::TrFailedMsg(format!(
+                "process aborted with signal {}",
+                libc::SIGSEGV
+            ))
+        );
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::{
-        bench, filter_tests, parse_opts, run_test, Bencher, Concurrent,
-        MetricMap, RunIgnored, ShouldPanic, TestDesc, TestDescAndFn, TestFn,
-        TestName, TestOpts, TestResult,
-    };
-    use std::sync::mpsc::channel;
+    #[test]
+    fn export_trace_lines() {
+        let path = env::temp_dir()
+            .join(format!("libtest-export-trace-{}", process::id()));
+        let mut opts = TestOpts::new();
+        opts.export_trace = Some(path.clone());
 
-    fn one_ignored_one_unignored_test() -> Vec<TestDescAndFn> {
-        vec![
-            TestDescAndFn {
-                desc: TestDesc {
-                    name: TestName::StaticTestName("1"),
-                    ignore: true,
-                    should_panic: ShouldPanic::No,
-                    allow_fail: false,
-                },
-                testfn: TestFn::DynTestFn(Box::new(move || {})),
-            },
-            TestDescAndFn {
+        let desc = |name| TestDesc {
+            name: TestName::StaticTestName(name),
+            ignore: false,
+            ignore_message: None,
+            should_panic: ShouldPanic::No,
+            allow_fail: false,
+            test_type: TestType::Unknown,
+            no_timeout_warning: false,
+            is_converted_bench: false,
+            tags: &[],
+        };
+        let mut st = ConsoleTestState::new(&opts).unwrap();
+        st.write_trace(
+            &desc("a::b"),
+            &TestResult::TrOk,
+            Some(Duration::from_millis(3)),
+        )
+        .unwrap();
+        st.write_trace(&desc("tab\there"), &TestResult::TrIgnored, None)
+            .unwrap();
+        st.write_trace(
+            &desc("c"),
+            &TestResult::TrFailedMsg("boom".to_string()),
+            Some(Duration::new(1, 5)),
+        )
+        .unwrap();
+        drop(st);
+
+        let trace = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            trace,
+            "a::b\tok\t3000000\ntab\\there\tignored\t-\nc\tfailed\t1000000005\n"
+        );
+    }
+
+    #[test]
+    fn junit_file_reports_the_run_alongside_the_console() {
+        fn fail() {
+            panic!();
+        }
+        fn pass() {}
+
+        let path =
+            env::temp_dir().join(format!("libtest-junit-{}", process::id()));
+        let tests = vec![("m::a", pass as fn(), false), ("m::b", fail, false)]
+            .into_iter()
+            .chain(Some(("c", pass as fn(), true)))
+            .map(|(name, f, ignore)| TestDescAndFn {
                 desc: TestDesc {
-                    name: TestName::StaticTestName("2"),
-                    ignore: false,
+                    name: TestName::StaticTestName(name),
+                    ignore,
+                    ignore_message: None,
                     should_panic: ShouldPanic::No,
                     allow_fail: false,
+                    test_type: TestType::Unknown,
+                    no_timeout_warning: false,
+                    is_converted_bench: false,
+                    tags: &[],
                 },
-                testfn: TestFn::DynTestFn(Box::new(move || {})),
-            },
+                testfn: TestFn::StaticTestFn(f),
+            })
+            .collect();
+
+        let mut opts = TestOpts::new();
+        opts.run_tests = true;
+        opts.test_threads = Some(1);
+        opts.junit_path = Some(path.clone());
+        assert!(!run_tests_console(&opts, tests).unwrap());
+
+        let report = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<_> = report.lines().collect();
+        assert_eq!(lines.len(), 12);
+        assert_eq!(lines[0], r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+        assert_eq!(
+            lines[2],
+            r#"<testsuite name="test" errors="0" failures="1" tests="3" skipped="1">"#
+        );
+        assert!(
+            lines[3].starts_with(r#"<testcase classname="(root)" name="c""#)
+        );
+        assert_eq!(lines[4], "<skipped/>");
+        assert!(lines[6].starts_with(r#"<testcase classname="m" name="a""#));
+        assert!(lines[6].ends_with("/>"));
+        assert!(lines[7].starts_with(r#"<testcase classname="m" name="b""#));
+        assert_eq!(lines[8], r#"<failure type="panic"/>"#);
+        assert_eq!(lines[11], "</testsuites>");
+    }
+
+    #[test]
+    fn dump_config_prints_the_resolved_options_as_json() {
+        let args: Vec<_> = [
+            "progname",
+            "--dump-config",
+            "--test-threads=3",
+            "--color=never",
+            "--logfile=a\"b",
+            "foo",
+            "bar",
         ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        let opts = parse_opts(&args).unwrap().unwrap();
+        assert!(opts.dump_config);
+
+        let json = opts.dump_config();
+        let lines: Vec<_> = json.lines().collect();
+        assert_eq!(lines[0], "{");
+        assert_eq!(lines[1], r#"  "list": false,"#);
+        assert!(lines.contains(&r#"  "filter": ["foo", "bar"],"#));
+        assert!(lines.contains(&r#"  "skip": [],"#));
+        assert!(lines.contains(&r#"  "logfile": "a\"b","#));
+        assert!(lines.contains(&r#"  "test_threads": 3,"#));
+        assert!(lines.contains(&r#"  "timeout_ms": null,"#));
+        assert!(lines.contains(&r#"  "report_time_critical_ms": 1000,"#));
+        assert_eq!(lines[lines.len() - 3], r#"  "resolved_test_threads": 3,"#);
+        assert_eq!(lines[lines.len() - 2], r#"  "resolved_color": false"#);
+        assert_eq!(lines[lines.len() - 1], "}");
+
+        assert_eq!(
+            test_main_with_exit_code(&args, Vec::new(), Options::new()),
+            0
+        );
     }
 
     #[test]
-    pub fn do_not_run_ignored_tests() {
-        fn f() {
-            panic!();
-        }
-        let desc = TestDescAndFn {
-            desc: TestDesc {
-                name: TestName::StaticTestName("whatever"),
-                ignore: true,
-                should_panic: ShouldPanic::No,
-                allow_fail: false,
-            },
-            testfn: TestFn::DynTestFn(Box::new(f)),
-        };
-        let (tx, rx) = channel();
-        run_test(&TestOpts::new(), false, desc, tx, Concurrent::No);
-        let (_, res, _) = rx.recv().unwrap();
-        assert!(res != TestResult::TrOk);
+    fn parse_list_runnable_flag() {
+        let args = vec!["progname".to_string(), "--list-runnable".to_string()];
+        let opts = parse_opts(&args).unwrap().unwrap();
+        assert!(opts.list);
+        assert!(opts.list_runnable);
     }
 
     #[test]
-    pub fn ignored_tests_result_in_ignored() {
-        fn f() {}
-        let desc = TestDescAndFn {
-            desc: TestDesc {
-                name: TestName::StaticTestName("whatever"),
-                ignore: true,
-                should_panic: ShouldPanic::No,
-                allow_fail: false,
-            },
-            testfn: TestFn::DynTestFn(Box::new(f)),
-        };
-        let (tx, rx) = channel();
-        run_test(&TestOpts::new(), false, desc, tx, Concurrent::No);
-        let (_, res, _) = rx.recv().unwrap();
-        assert!(res == TestResult::TrIgnored);
+    pub fn filter_for_ignored_option() {
+        // When we run ignored tests the test filter should filter out all the
+        // unignored tests and flip the ignore flag on the rest to false
+
+        let mut opts = TestOpts::new();
+        opts.run_tests = true;
+        opts.run_ignored = RunIgnored::Only;
+
+        let tests = one_ignored_one_unignored_test();
+        let filtered = filter_tests(&opts, tests);
+
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0].desc.name.to_string(), "1");
+        assert!(!filtered[0].desc.ignore);
     }
 
     #[test]
-    fn test_should_panic() {
-        fn f() {
-            panic!();
-        }
-        let desc = TestDescAndFn {
+    pub fn run_include_ignored_option() {
+        // When we "--include-ignored" tests, the ignore flag should be set to false on
+        // all tests and no test filtered out
+
+        let mut opts = TestOpts::new();
+        opts.run_tests = true;
+        opts.run_ignored = RunIgnored::Yes;
+
+        let tests = one_ignored_one_unignored_test();
+        let filtered = filter_tests(&opts, tests);
+
+        assert_eq!(filtered.len(), 2);
+        assert!(!filtered[0].desc.ignore);
+        assert!(!filtered[1].desc.ignore);
+    }
+
+    #[test]
+    pub fn exclude_should_panic_option() {
+        let mut opts = TestOpts::new();
+        opts.run_tests = true;
+        opts.exclude_should_panic = true;
+
+        let mut tests = one_ignored_one_unignored_test();
+        tests.push(TestDescAndFn {
             desc: TestDesc {
-                name: TestName::StaticTestName("whatever"),
+                name: TestName::StaticTestName("3"),
                 ignore: false,
+                ignore_message: None,
                 should_panic: ShouldPanic::Yes,
                 allow_fail: false,
+                test_type: TestType::Unknown,
+                no_timeout_warning: false,
+                is_converted_bench: false,
+                tags: &[],
             },
-            testfn: TestFn::DynTestFn(Box::new(f)),
+            testfn: TestFn::DynTestFn(Box::new(move || {})),
+        });
+
+        let filtered = filter_tests(&opts, tests);
+
+        assert_eq!(filtered.len(), 2);
+        assert!(filtered
+            .iter()
+            .all(|test| test.desc.should_panic == ShouldPanic::No));
+    }
+
+    #[test]
+    pub fn shards_are_disjoint_and_complete() {
+        let names = ["e", "c", "a", "d", "b"];
+        let shard = |index| {
+            let mut opts = TestOpts::new();
+            opts.shard = Some((index, 2));
+            let tests = names
+                .iter()
+                .map(|&name| TestDescAndFn {
+                    desc: TestDesc {
+                        name: TestName::StaticTestName(name),
+                        ignore: false,
+                        ignore_message: None,
+                        should_panic: ShouldPanic::No,
+                        allow_fail: false,
+                        test_type: TestType::Unknown,
+                        no_timeout_warning: false,
+                        is_converted_bench: false,
+                        tags: &[],
+                    },
+                    testfn: TestFn::DynTestFn(Box::new(move || {})),
+                })
+                .collect();
+            let (kept, skipped) =
+                filter_tests_with_reasons(&opts, tests).unwrap();
+            assert!(skipped.iter().all(|&(_, r)| r == SkipReason::Shard));
+            kept.iter()
+                .map(|t| t.desc.name.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(shard(0), vec!["a", "c", "e"]);
+        assert_eq!(shard(1), vec!["b", "d"]);
+
+        let parse = |index: &str, count: &str| {
+            let args = vec![
+                "progname".to_string(),
+                format!("--shard-index={}", index),
+                format!("--shard-count={}", count),
+            ];
+            parse_opts(&args).unwrap().map(|opts| opts.shard)
+        };
+        assert_eq!(parse("1", "3"), Ok(Some((1, 3))));
+        assert!(parse("3", "3").is_err());
+        assert!(parse("0", "0").is_err());
+
+        assert!(TestOpts::decode("shard=0/0").is_err());
+        assert!(TestOpts::decode("shard=2/2").is_err());
+        let mut opts = TestOpts::new();
+        opts.shard = Some((0, 0));
+        let err = filter_tests_with_reasons(&opts, Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn fastest_first_orders_by_recorded_duration() {
+        let path =
+            env::temp_dir().join(format!("libtest-timings-{}", process::id()));
+        fs::write(
+            &path,
+            "slow\tok\t900\nfast\tok\t5\nmedium\tfailed\t40\n\
+             never\tignored\t-\n",
+        )
+        .unwrap();
+
+        let started = |order, untimed_first| {
+            let mut opts = TestOpts::new();
+            opts.order = order;
+            opts.timings = Some(path.clone());
+            opts.untimed_first = untimed_first;
+            opts.test_threads = Some(1);
+            let tests = ["slow", "new", "medium", "never", "fast"]
+                .iter()
+                .map(|&name| TestDescAndFn {
+                    desc: TestDesc {
+                        name: TestName::StaticTestName(name),
+                        ignore: false,
+                        ignore_message: None,
+                        should_panic: ShouldPanic::No,
+                        allow_fail: false,
+                        test_type: TestType::Unknown,
+                        no_timeout_warning: false,
+                        is_converted_bench: false,
+                        tags: &[],
+                    },
+                    testfn: TestFn::DynTestFn(Box::new(move || {})),
+                })
+                .collect();
+            let mut started = Vec::new();
+            run_tests(&opts, tests, |event| {
+                if let TestEvent::TeWait(desc) = event {
+                    started.push(desc.name.to_string());
+                }
+                Ok(())
+            })
+            .unwrap();
+            started
         };
-        let (tx, rx) = channel();
-        run_test(&TestOpts::new(), false, desc, tx, Concurrent::No);
-        let (_, res, _) = rx.recv().unwrap();
-        assert!(res == TestResult::TrOk);
+        let last = started(TestOrder::FastestFirst, false);
+        let first = started(TestOrder::FastestFirst, true);
+        let slowest = started(TestOrder::SlowestFirst, false);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(last, vec!["fast", "medium", "slow", "never", "new"]);
+        assert_eq!(first, vec!["never", "new", "fast", "medium", "slow"]);
+        assert_eq!(slowest, vec!["slow", "medium", "fast", "never", "new"]);
+
+        let args =
+            vec!["progname".to_string(), "--order=fastest-first".into()];
+        assert!(parse_opts(&args).unwrap().is_err());
+        let args = vec![
+            "progname".to_string(),
+            "--order=slowest-first".into(),
+            "--timings=trace".into(),
+        ];
+        let opts = parse_opts(&args).unwrap().unwrap();
+        assert_eq!(opts.order, TestOrder::SlowestFirst);
     }
 
     #[test]
-    fn test_should_panic_good_message() {
-        fn f() {
-            panic!("an error message");
-        }
-        let desc = TestDescAndFn {
+    pub fn skipped_tests_carry_a_reason() {
+        let mut opts = TestOpts::new();
+        opts.filters = vec!["a::".to_string()];
+        opts.skip = vec!["skip_me".to_string()];
+        opts.exclude_should_panic = true;
+        opts.report_skipped = true;
+
+        let tests = vec![
+            ("a::one", true, ShouldPanic::No),
+            ("a::two", false, ShouldPanic::No),
+            ("a::three", false, ShouldPanic::Yes),
+            ("a::skip_me", false, ShouldPanic::No),
+            ("b::other", false, ShouldPanic::No),
+        ]
+        .into_iter()
+        .map(|(name, ignore, should_panic)| TestDescAndFn {
             desc: TestDesc {
-                name: TestName::StaticTestName("whatever"),
-                ignore: false,
-                should_panic: ShouldPanic::YesWithMessage("error message"),
+                name: TestName::StaticTestName(name),
+                ignore,
+                ignore_message: None,
+                should_panic,
                 allow_fail: false,
+                test_type: TestType::Unknown,
+                no_timeout_warning: false,
+                is_converted_bench: false,
+                tags: &[],
             },
-            testfn: TestFn::DynTestFn(Box::new(f)),
-        };
-        let (tx, rx) = channel();
-        run_test(&TestOpts::new(), false, desc, tx, Concurrent::No);
-        let (_, res, _) = rx.recv().unwrap();
-        assert!(res == TestResult::TrOk);
+            testfn: TestFn::DynTestFn(Box::new(move || {})),
+        })
+        .collect();
+
+        let mut skipped = Vec::new();
+        let mut stats = FilterStats::default();
+        run_tests(&opts, tests, |event| {
+            match event {
+                TestEvent::TeSkipped(desc, reason) => {
+                    skipped.push((desc.name.to_string(), reason))
+                }
+                TestEvent::TeFilteredOut(s) => stats = s,
+                _ => {}
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            skipped,
+            vec![
+                ("a::skip_me".to_string(), SkipReason::SkipFlag),
+                ("a::three".to_string(), SkipReason::ExcludedShouldPanic),
+                ("b::other".to_string(), SkipReason::Filtered),
+            ]
+        );
+        assert_eq!(
+            stats.to_string(),
+            "3 filtered out (1 by filter, 1 by --skip, \
+             1 by --exclude-should-panic)"
+        );
+        stats.by_skip = 0;
+        stats.by_should_panic = 0;
+        assert_eq!(stats.to_string(), "1 filtered out");
     }
 
     #[test]
-    fn test_should_panic_bad_message() {
-        fn f() {
-            panic!("an error message");
+    pub fn exact_filter_match() {
+        fn tests() -> Vec<TestDescAndFn> {
+            vec!["base", "base::test", "base::test1", "base::test2"]
+                .into_iter()
+                .map(|name| TestDescAndFn {
+                    desc: TestDesc {
+                        name: TestName::StaticTestName(name),
+                        ignore: false,
+                        ignore_message: None,
+                        should_panic: ShouldPanic::No,
+                        allow_fail: false,
+                        test_type: TestType::Unknown,
+                        no_timeout_warning: false,
+                        is_converted_bench: false,
+                        tags: &[],
+                    },
+                    testfn: TestFn::DynTestFn(Box::new(move || {})),
+                })
+                .collect()
         }
-        let expected = "foobar";
-        let failed_msg = "Panic did not include expected string";
-        let desc = TestDescAndFn {
-            desc: TestDesc {
-                name: TestName::StaticTestName("whatever"),
-                ignore: false,
-                should_panic: ShouldPanic::YesWithMessage(expected),
-                allow_fail: false,
+
+        let substr = filter_tests(
+            &TestOpts {
+                filters: vec!["base".into()],
+                ..TestOpts::new()
             },
-            testfn: TestFn::DynTestFn(Box::new(f)),
-        };
-        let (tx, rx) = channel();
-        run_test(&TestOpts::new(), false, desc, tx, Concurrent::No);
-        let (_, res, _) = rx.recv().unwrap();
-        assert!(
-            res == TestResult::TrFailedMsg(format!(
-                "{} '{}'",
-                failed_msg, expected
-            ))
+            tests(),
         );
-    }
+        assert_eq!(substr.len(), 4);
 
-    #[test]
-    fn test_should_panic_but_succeeds() {
-        fn f() {}
-        let desc = TestDescAndFn {
-            desc: TestDesc {
-                name: TestName::StaticTestName("whatever"),
-                ignore: false,
-                should_panic: ShouldPanic::Yes,
-                allow_fail: false,
+        let substr = filter_tests(
+            &TestOpts {
+                filters: vec!["bas".into()],
+                ..TestOpts::new()
             },
-            testfn: TestFn::DynTestFn(Box::new(f)),
-        };
-        let (tx, rx) = channel();
-        run_test(&TestOpts::new(), false, desc, tx, Concurrent::No);
-        let (_, res, _) = rx.recv().unwrap();
-        assert!(res == TestResult::TrFailed);
-    }
+            tests(),
+        );
+        assert_eq!(substr.len(), 4);
+
+        let substr = filter_tests(
+            &TestOpts {
+                filters: vec!["::test".into()],
+                ..TestOpts::new()
+            },
+            tests(),
+        );
+        assert_eq!(substr.len(), 3);
+
+        let substr = filter_tests(
+            &TestOpts {
+                filters: vec!["base::test".into()],
+                ..TestOpts::new()
+            },
+            tests(),
+        );
+        assert_eq!(substr.len(), 3);
+
+        let exact = filter_tests(
+            &TestOpts {
+                filters: vec!["base".into()],
+                filter_exact: true,
+                ..TestOpts::new()
+            },
+            tests(),
+        );
+        assert_eq!(exact.len(), 1);
+
+        let exact = filter_tests(
+            &TestOpts {
+                filters: vec!["bas".into()],
+                filter_exact: true,
+                ..TestOpts::new()
+            },
+            tests(),
+        );
+        assert_eq!(exact.len(), 0);
+
+        let exact = filter_tests(
+            &TestOpts {
+                filters: vec!["::test".into()],
+                filter_exact: true,
+                ..TestOpts::new()
+            },
+            tests(),
+        );
+        assert_eq!(exact.len(), 0);
+
+        let exact = filter_tests(
+            &TestOpts {
+                filters: vec!["base::test".into()],
+                filter_exact: true,
+                ..TestOpts::new()
+            },
+            tests(),
+        );
+        assert_eq!(exact.len(), 1);
+
+        let exact = filter_tests(
+            &TestOpts {
+                filters: vec!["base".into(), "base::test1".into()],
+                filter_exact: true,
+                ..TestOpts::new()
+            },
+            tests(),
+        );
+        assert_eq!(exact.len(), 2);
 
-    #[test]
-    fn parse_ignored_flag() {
         let args = vec![
             "progname".to_string(),
-            "filter".to_string(),
-            "--ignored".to_string(),
+            "test1".to_string(),
+            "test2".to_string(),
         ];
         let opts = parse_opts(&args).unwrap().unwrap();
-        assert_eq!(opts.run_ignored, RunIgnored::Only);
+        let any: Vec<_> = filter_tests(&opts, tests())
+            .into_iter()
+            .map(|t| t.desc.name.to_string())
+            .collect();
+        assert_eq!(any, vec!["base::test1", "base::test2"]);
     }
 
     #[test]
-    fn parse_include_ignored_flag() {
+    pub fn prefix_filter_matches_whole_path_segments() {
+        let tests = || {
+            vec!["base", "base::test", "database", "based::test", "a::base"]
+                .into_iter()
+                .map(|name| TestDescAndFn {
+                    desc: TestDesc {
+                        name: TestName::StaticTestName(name),
+                        ignore: false,
+                        ignore_message: None,
+                        should_panic: ShouldPanic::No,
+                        allow_fail: false,
+                        test_type: TestType::Unknown,
+                        no_timeout_warning: false,
+                        is_converted_bench: false,
+                        tags: &[],
+                    },
+                    testfn: TestFn::DynTestFn(Box::new(move || {})),
+                })
+                .collect::<Vec<_>>()
+        };
+        let names = |filter: &str| {
+            let args = vec![
+                "progname".to_string(),
+                "--filter-prefix".to_string(),
+                filter.to_string(),
+            ];
+            let opts = parse_opts(&args).unwrap().unwrap();
+            filter_tests(&opts, tests())
+                .into_iter()
+                .map(|t| t.desc.name.as_slice().to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names("base"), vec!["base", "base::test"]);
+        assert_eq!(names("base::"), vec!["base::test"]);
+        assert_eq!(names("base::test"), vec!["base::test"]);
+        assert!(names("bas").is_empty());
+
         let args = vec![
             "progname".to_string(),
-            "filter".to_string(),
-            "-Zunstable-options".to_string(),
-            "--include-ignored".to_string(),
+            "--filter-prefix".to_string(),
+            "--exact".to_string(),
         ];
-        let opts = parse_opts(&args).unwrap().unwrap();
-        assert_eq!(opts.run_ignored, RunIgnored::Yes);
+        assert!(parse_opts(&args).unwrap().is_err());
     }
 
     #[test]
-    pub fn filter_for_ignored_option() {
-        // When we run ignored tests the test filter should filter out all the
-        // unignored tests and flip the ignore flag on the rest to false
-
-        let mut opts = TestOpts::new();
-        opts.run_tests = true;
-        opts.run_ignored = RunIgnored::Only;
-
-        let tests = one_ignored_one_unignored_test();
-        let filtered = filter_tests(&opts, tests);
-
-        assert_eq!(filtered.len(), 1);
-        assert_eq!(filtered[0].desc.name.to_string(), "1");
-        assert!(!filtered[0].desc.ignore);
+    pub fn skip_exact_removes_only_the_named_test() {
+        let tests = || {
+            vec!["foo", "foobar", "bar", "baz"]
+                .into_iter()
+                .map(|name| TestDescAndFn {
+                    desc: TestDesc {
+                        name: TestName::StaticTestName(name),
+                        ignore: false,
+                        ignore_message: None,
+                        should_panic: ShouldPanic::No,
+                        allow_fail: false,
+                        test_type: TestType::Unknown,
+                        no_timeout_warning: false,
+                        is_converted_bench: false,
+                        tags: &[],
+                    },
+                    testfn: TestFn::DynTestFn(Box::new(move || {})),
+                })
+                .collect::<Vec<_>>()
+        };
+        let names = |flags: &[&str]| {
+            let mut args = vec!["progname".to_string()];
+            args.extend(flags.iter().map(|f| f.to_string()));
+            let opts = parse_opts(&args).unwrap().unwrap();
+            filter_tests(&opts, tests())
+                .into_iter()
+                .map(|t| t.desc.name.as_slice().to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(&["--skip-exact", "foo"]),
+            vec!["bar", "baz", "foobar"]
+        );
+        assert_eq!(
+            names(&["--skip-exact", "foo", "--skip", "baz"]),
+            vec!["bar", "foobar"]
+        );
     }
 
     #[test]
-    pub fn run_include_ignored_option() {
-        // When we "--include-ignored" tests, the ignore flag should be set to false on
-        // all tests and no test filtered out
-
-        let mut opts = TestOpts::new();
-        opts.run_tests = true;
-        opts.run_ignored = RunIgnored::Yes;
+    pub fn tags_select_and_exclude_tests() {
+        let tests = || {
+            vec![
+                ("plain", &[][..]),
+                ("net", &["network"][..]),
+                ("net_slow", &["network", "slow"][..]),
+                ("gpu", &["gpu"][..]),
+            ]
+            .into_iter()
+            .map(|(name, tags)| TestDescAndFn {
+                desc: TestDesc {
+                    name: TestName::StaticTestName(name),
+                    ignore: false,
+                    ignore_message: None,
+                    should_panic: ShouldPanic::No,
+                    allow_fail: false,
+                    test_type: TestType::Unknown,
+                    no_timeout_warning: false,
+                    is_converted_bench: false,
+                    tags,
+                },
+                testfn: TestFn::DynTestFn(Box::new(move || {})),
+            })
+            .collect::<Vec<_>>()
+        };
+        let names = |flags: &[&str]| {
+            let mut args = vec!["progname".to_string()];
+            args.extend(flags.iter().map(|f| f.to_string()));
+            let opts = parse_opts(&args).unwrap().unwrap();
+            filter_tests(&opts, tests())
+                .into_iter()
+                .map(|t| t.desc.name.as_slice().to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(&["--include-tag", "network"]),
+            vec!["net", "net_slow"]
+        );
+        assert_eq!(
+            names(&["--include-tag", "network", "--include-tag", "gpu"]),
+            vec!["gpu", "net", "net_slow"]
+        );
+        assert_eq!(
+            names(&["--exclude-tag", "slow"]),
+            vec!["gpu", "net", "plain"]
+        );
+        assert_eq!(
+            names(&["--include-tag", "network", "--exclude-tag", "slow"]),
+            vec!["net"]
+        );
+    }
 
-        let tests = one_ignored_one_unignored_test();
+    #[cfg(feature = "regex")]
+    #[test]
+    pub fn filter_regex_matches_names_as_regular_expressions() {
+        let tests = vec!["a::fast", "a::io_slow", "b::cpu_slow", "b::slowish"]
+            .into_iter()
+            .map(|name| TestDescAndFn {
+                desc: TestDesc {
+                    name: TestName::StaticTestName(name),
+                    ignore: false,
+                    ignore_message: None,
+                    should_panic: ShouldPanic::No,
+                    allow_fail: false,
+                    test_type: TestType::Unknown,
+                    no_timeout_warning: false,
+                    is_converted_bench: false,
+                    tags: &[],
+                },
+                testfn: TestFn::DynTestFn(Box::new(move || {})),
+            })
+            .collect();
+        let args = vec![
+            "progname".to_string(),
+            "--filter-regex".to_string(),
+            "--skip=^a::".to_string(),
+            "_slow$".to_string(),
+        ];
+        let opts = parse_opts(&args).unwrap().unwrap();
         let filtered = filter_tests(&opts, tests);
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0].desc.name.as_slice(), "b::cpu_slow");
 
-        assert_eq!(filtered.len(), 2);
-        assert!(!filtered[0].desc.ignore);
-        assert!(!filtered[1].desc.ignore);
+        let args = vec![
+            "progname".to_string(),
+            "--filter-regex".to_string(),
+            "(unclosed".to_string(),
+        ];
+        let err = parse_opts(&args).unwrap().err().unwrap();
+        assert!(err.contains("invalid filter regex `(unclosed`"));
     }
 
+    #[cfg(not(feature = "regex"))]
     #[test]
-    pub fn exclude_should_panic_option() {
-        let mut opts = TestOpts::new();
-        opts.run_tests = true;
-        opts.exclude_should_panic = true;
+    pub fn filter_regex_requires_the_regex_feature() {
+        let args = vec![
+            "progname".to_string(),
+            "--filter-regex".to_string(),
+            "_slow$".to_string(),
+        ];
+        let err = parse_opts(&args).unwrap().err().unwrap();
+        assert!(err.contains("`regex` feature"));
+    }
 
-        let mut tests = one_ignored_one_unignored_test();
-        tests.push(TestDescAndFn {
+    #[test]
+    pub fn test_list_file_is_unioned_with_the_filter() {
+        let path = env::temp_dir()
+            .join(format!("libtest-test-list-{}", process::id()));
+        fs::write(&path, "base\n# comment\nbase::test2\nmissing\n").unwrap();
+
+        let names = |filter: Option<&str>| {
+            let tests =
+                vec!["base", "base::test", "base::test1", "base::test2"]
+                    .into_iter()
+                    .map(|name| TestDescAndFn {
+                        desc: TestDesc {
+                            name: TestName::StaticTestName(name),
+                            ignore: false,
+                            ignore_message: None,
+                            should_panic: ShouldPanic::No,
+                            allow_fail: false,
+                            test_type: TestType::Unknown,
+                            no_timeout_warning: false,
+                            is_converted_bench: false,
+                            tags: &[],
+                        },
+                        testfn: TestFn::DynTestFn(Box::new(move || {})),
+                    })
+                    .collect();
+            let opts = TestOpts {
+                filters: filter.map(str::to_owned).into_iter().collect(),
+                test_list_file: Some(path.clone()),
+                ..TestOpts::new()
+            };
+            filter_tests(&opts, tests)
+                .iter()
+                .map(|t| t.desc.name.to_string())
+                .collect::<Vec<_>>()
+        };
+        let listed = names(None);
+        let unioned = names(Some("test1"));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(listed, vec!["base", "base::test2"]);
+        assert_eq!(unioned, vec!["base", "base::test1", "base::test2"]);
+
+        // A list that can't be read doesn't widen the selection.
+        let unreadable = panic::catch_unwind(AssertUnwindSafe(|| names(None)));
+        assert!(unreadable.is_err());
+    }
+
+    #[test]
+    pub fn test_kind_keeps_only_tests_of_that_type() {
+        let tests = vec![
+            ("unit", TestType::UnitTest),
+            ("doc", TestType::DocTest),
+            ("unknown", TestType::Unknown),
+        ]
+        .into_iter()
+        .map(|(name, test_type)| TestDescAndFn {
             desc: TestDesc {
-                name: TestName::StaticTestName("3"),
+                name: TestName::StaticTestName(name),
                 ignore: false,
-                should_panic: ShouldPanic::Yes,
+                ignore_message: None,
+                should_panic: ShouldPanic::No,
                 allow_fail: false,
+                test_type,
+                no_timeout_warning: false,
+                is_converted_bench: false,
+                tags: &[],
             },
             testfn: TestFn::DynTestFn(Box::new(move || {})),
-        });
+        })
+        .collect();
+        let args = vec!["progname".to_string(), "--test-kind=doc".to_string()];
+        let opts = parse_opts(&args).unwrap().unwrap();
+        assert_eq!(opts.test_kind, Some(TestType::DocTest));
+        assert_eq!(TestOpts::decode(&opts.encode()), Ok(opts.clone()));
 
         let filtered = filter_tests(&opts, tests);
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0].desc.name.as_slice(), "doc");
 
-        assert_eq!(filtered.len(), 2);
-        assert!(filtered
-            .iter()
-            .all(|test| test.desc.should_panic == ShouldPanic::No));
-    }
-
-    #[test]
-    pub fn exact_filter_match() {
-        fn tests() -> Vec<TestDescAndFn> {
-            vec!["base", "base::test", "base::test1", "base::test2"]
-                .into_iter()
-                .map(|name| TestDescAndFn {
-                    desc: TestDesc {
-                        name: TestName::StaticTestName(name),
-                        ignore: false,
-                        should_panic: ShouldPanic::No,
-                        allow_fail: false,
-                    },
-                    testfn: TestFn::DynTestFn(Box::new(move || {})),
-                })
-                .collect()
-        }
-
-        let substr = filter_tests(
-            &TestOpts {
-                filter: Some("base".into()),
-                ..TestOpts::new()
-            },
-            tests(),
-        );
-        assert_eq!(substr.len(), 4);
-
-        let substr = filter_tests(
-            &TestOpts {
-                filter: Some("bas".into()),
-                ..TestOpts::new()
-            },
-            tests(),
-        );
-        assert_eq!(substr.len(), 4);
-
-        let substr = filter_tests(
-            &TestOpts {
-                filter: Some("::test".into()),
-                ..TestOpts::new()
-            },
-            tests(),
-        );
-        assert_eq!(substr.len(), 3);
-
-        let substr = filter_tests(
-            &TestOpts {
-                filter: Some("base::test".into()),
-                ..TestOpts::new()
-            },
-            tests(),
-        );
-        assert_eq!(substr.len(), 3);
-
-        let exact = filter_tests(
-            &TestOpts {
-                filter: Some("base".into()),
-                filter_exact: true,
-                ..TestOpts::new()
-            },
-            tests(),
-        );
-        assert_eq!(exact.len(), 1);
-
-        let exact = filter_tests(
-            &TestOpts {
-                filter: Some("bas".into()),
-                filter_exact: true,
-                ..TestOpts::new()
-            },
-            tests(),
-        );
-        assert_eq!(exact.len(), 0);
-
-        let exact = filter_tests(
-            &TestOpts {
-                filter: Some("::test".into()),
-                filter_exact: true,
-                ..TestOpts::new()
-            },
-            tests(),
+        let mut json = JsonFormatter::new(
+            OutputLocation::Raw(Vec::new()),
+            false,
+            false,
+            false,
         );
-        assert_eq!(exact.len(), 0);
+        json.write_test_start(&filtered[0].desc).unwrap();
+        match json.output_location() {
+            OutputLocation::Raw(ref m) => {
+                assert!(String::from_utf8_lossy(m)
+                    .contains(r#""test_type": "doc""#))
+            }
+            OutputLocation::Pretty(_) => unreachable!(),
+        }
 
-        let exact = filter_tests(
-            &TestOpts {
-                filter: Some("base::test".into()),
-                filter_exact: true,
-                ..TestOpts::new()
-            },
-            tests(),
-        );
-        assert_eq!(exact.len(), 1);
+        let args = vec!["progname".to_string(), "--test-kind=e2e".to_string()];
+        assert!(parse_opts(&args).unwrap().is_err());
     }
 
     #[test]
@@ -2189,8 +10042,13 @@ mod tests {
                     desc: TestDesc {
                         name: TestName::DynTestName((*name).clone()),
                         ignore: false,
+                        ignore_message: None,
                         should_panic: ShouldPanic::No,
                         allow_fail: false,
+                        test_type: TestType::Unknown,
+                        no_timeout_warning: false,
+                        is_converted_bench: false,
+                        tags: &[],
                     },
                     testfn: TestFn::DynTestFn(Box::new(testfn)),
                 };
@@ -2219,6 +10077,36 @@ mod tests {
         }
     }
 
+    #[test]
+    pub fn no_sort_keeps_declaration_order() {
+        let names = vec!["zeta", "alpha", "mu"];
+        let tests = names
+            .iter()
+            .map(|&name| TestDescAndFn {
+                desc: TestDesc {
+                    name: TestName::StaticTestName(name),
+                    ignore: false,
+                    ignore_message: None,
+                    should_panic: ShouldPanic::No,
+                    allow_fail: false,
+                    test_type: TestType::Unknown,
+                    no_timeout_warning: false,
+                    is_converted_bench: false,
+                    tags: &[],
+                },
+                testfn: TestFn::DynTestFn(Box::new(move || {})),
+            })
+            .collect();
+        let args = vec!["progname".to_string(), "--no-sort".to_string()];
+        let opts = parse_opts(&args).unwrap().unwrap();
+        assert!(opts.no_sort);
+
+        let filtered = filter_tests(&opts, tests);
+        let filtered: Vec<_> =
+            filtered.iter().map(|t| t.desc.name.as_slice()).collect();
+        assert_eq!(filtered, names);
+    }
+
     #[test]
     pub fn test_metricmap_compare() {
         let mut m1 = MetricMap::new();
@@ -2240,6 +10128,310 @@ mod tests {
 
         m1.insert_metric("in-both-want-upwards-and-improved", 1000.0, -10.0);
         m2.insert_metric("in-both-want-upwards-and-improved", 2000.0, -10.0);
+
+        let diff = m2.compare_to_old(&m1, None);
+        assert_eq!(diff.len(), 7);
+        assert_eq!(diff["in-both-noise"], MetricChange::Unchanged);
+        assert_eq!(diff["in-first-noise"], MetricChange::Removed);
+        assert_eq!(diff["in-second-noise"], MetricChange::Added);
+        assert_eq!(
+            diff["in-both-want-downwards-but-regressed"],
+            MetricChange::Regressed(100.0)
+        );
+        assert_eq!(
+            diff["in-both-want-downwards-and-improved"],
+            MetricChange::Improved(50.0)
+        );
+        assert_eq!(
+            diff["in-both-want-upwards-but-regressed"],
+            MetricChange::Regressed(50.0)
+        );
+        assert_eq!(
+            diff["in-both-want-upwards-and-improved"],
+            MetricChange::Improved(100.0)
+        );
+
+        let diff = m2.compare_to_old(&m1, Some(5.0));
+        assert_eq!(diff["in-both-noise"], MetricChange::Regressed(10.0));
+    }
+
+    #[test]
+    pub fn bench_comparison_markdown() {
+        let path = env::temp_dir()
+            .join(format!("libtest-bench-baseline-{}", process::id()));
+        let mut baseline = MetricMap::new();
+        baseline.insert_metric("fast", 1000.0, 10.0);
+        baseline.insert_metric("slow|er", 2000.0, 10.0);
+        baseline.save(&path).unwrap();
+        let baseline = MetricMap::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut current = MetricMap::new();
+        current.insert_metric("fast", 900.0, 10.0);
+        current.insert_metric("new", 5.0, 1.0);
+        current.insert_metric("slow|er", 3000.0, 10.0);
+
+        assert_eq!(
+            fmt_bench_comparison_md(&baseline, &current),
+            "| Name | Baseline | Current | Change% | Trend |\n\
+             |:-----|---------:|--------:|--------:|:-----:|\n\
+             | fast | 1,000 ns/iter | 900 ns/iter | -10.00% | ↓ |\n\
+             | new | - | 5 ns/iter | - |  |\n\
+             | slow\\|er | 2,000 ns/iter | 3,000 ns/iter | +50.00% | ↑ |\n"
+        );
+    }
+
+    #[test]
+    pub fn bench_note_survives_baseline_and_markdown() {
+        let path = env::temp_dir()
+            .join(format!("libtest-bench-note-{}", process::id()));
+        let mut baseline = MetricMap::new();
+        baseline.set_note("abc123\twith -O");
+        baseline.insert_metric("fast", 1000.0, 10.0);
+        baseline.save(&path).unwrap();
+        let baseline = MetricMap::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(baseline.note, Some("abc123 with -O".to_owned()));
+
+        let mut current = MetricMap::new();
+        current.set_note("def456");
+        current.insert_metric("fast", 1000.0, 10.0);
+        assert!(fmt_bench_comparison_md(&baseline, &current).starts_with(
+            "Baseline: abc123 with -O\n\n\
+             Current: def456\n\n\
+             | Name |"
+        ));
+    }
+
+    #[test]
+    pub fn bench_significance_report() {
+        let path = env::temp_dir()
+            .join(format!("libtest-bench-samples-{}", process::id()));
+        let low: Vec<f64> = (1..11).map(f64::from).collect();
+        let high: Vec<f64> = (11..21).map(f64::from).collect();
+        let mut baseline = MetricMap::new();
+        baseline.insert_metric("same", 5.0, 9.0);
+        baseline.insert_samples("same", low.clone());
+        baseline.insert_metric("slower", 5.0, 9.0);
+        baseline.insert_samples("slower", low.clone());
+        baseline.insert_metric("unsampled", 5.0, 9.0);
+        baseline.save(&path).unwrap();
+        let baseline = MetricMap::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut current = MetricMap::new();
+        current.insert_metric("same", 5.0, 9.0);
+        current.insert_samples("same", low.clone());
+        current.insert_metric("slower", 15.0, 9.0);
+        current.insert_samples("slower", high);
+        current.insert_metric("unsampled", 5.0, 9.0);
+        current.insert_samples("unsampled", low);
+
+        assert_eq!(
+            fmt_bench_significance(&baseline, &current),
+            "significance against baseline (Mann-Whitney U, alpha = 0.05):\n    \
+             same: p = 1.0000, no significant change\n    \
+             slower: p = 0.0002, significant regression\n    \
+             unsampled: no samples to compare\n"
+        );
+    }
+
+    #[test]
+    pub fn thousands_separators_cover_every_group() {
+        assert_eq!(fmt_thousands_sep(0, ','), "0");
+        assert_eq!(fmt_thousands_sep(999, ','), "999");
+        assert_eq!(fmt_thousands_sep(1_000, ','), "1,000");
+        assert_eq!(fmt_thousands_sep(1_234_567, ','), "1,234,567");
+        assert_eq!(
+            fmt_thousands_sep(1_000_000_000_000, ','),
+            "1,000,000,000,000"
+        );
+        #[cfg(target_pointer_width = "64")]
+        assert_eq!(
+            fmt_thousands_sep(usize::MAX, '_'),
+            "18_446_744_073_709_551_615"
+        );
+    }
+
+    #[test]
+    pub fn bench_rsd_formatting() {
+        let samples = |values: &[f64]| BenchSamples {
+            ns_iter_summ: stats::Summary::new(values),
+            mb_s: 0,
+            samples: Vec::new(),
+            iterations: 0,
+            saturated: false,
+            metrics: MetricMap::new(),
+        };
+
+        let steady = samples(&[100.0, 100.0, 100.0]);
+        assert_eq!(fmt_bench_rsd(&steady), " (rsd 0.00%)");
+        assert_eq!(bench_rsd_color(&steady), term::color::GREEN);
+
+        let noisy = samples(&[50.0, 100.0, 150.0]);
+        assert_eq!(fmt_bench_rsd(&noisy), " (rsd 50.00%)");
+        assert_eq!(bench_rsd_color(&noisy), term::color::RED);
+
+        assert_eq!(fmt_bench_rsd(&samples(&[0.0])), "");
+    }
+
+    #[test]
+    pub fn saturated_bench_is_reported() {
+        let mut bs = BenchSamples {
+            ns_iter_summ: stats::Summary::new(&[1.0]),
+            mb_s: 0,
+            samples: Vec::new(),
+            iterations: 0,
+            saturated: false,
+            metrics: MetricMap::new(),
+        };
+        assert!(!fmt_bench_samples(&bs).contains("saturated"));
+
+        bs.saturated = true;
+        assert!(
+            fmt_bench_samples(&bs).ends_with(" (iteration count saturated)")
+        );
+    }
+
+    #[test]
+    pub fn bench_json_has_iterations_and_samples() {
+        let bs = BenchSamples {
+            ns_iter_summ: stats::Summary::new(&[1.0, 2.5]),
+            mb_s: 0,
+            samples: vec![1.0, 2.5],
+            iterations: 10,
+            saturated: false,
+            metrics: MetricMap::new(),
+        };
+        assert_eq!(bs.iterations(), 10);
+        assert_eq!(bs.samples(), &[1.0, 2.5][..]);
+        assert_eq!(bs.percentile(0.0), Some(1.0));
+        assert_eq!(bs.percentile(50.0), Some(1.75));
+
+        let desc = TestDesc {
+            name: TestName::StaticTestName("sampled"),
+            ignore: false,
+            ignore_message: None,
+            should_panic: ShouldPanic::No,
+            allow_fail: false,
+            test_type: TestType::Unknown,
+            no_timeout_warning: false,
+            is_converted_bench: false,
+            tags: &[],
+        };
+        let mut json = JsonFormatter::new(
+            OutputLocation::Raw(Vec::new()),
+            false,
+            false,
+            false,
+        );
+        json.write_result(
+            &desc,
+            &TestResult::TrBench(bs),
+            None,
+            &TestOutput::default(),
+        )
+        .unwrap();
+        match json.output_location() {
+            OutputLocation::Raw(ref m) => {
+                let line = String::from_utf8_lossy(m);
+                assert!(line.contains(r#""iterations": 10"#));
+                assert!(line.contains(r#""samples": [1, 2.5]"#));
+                assert!(line.contains(r#""p90": 2.35, "p99": 2.485"#));
+            }
+            OutputLocation::Pretty(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    pub fn bench_throughput_in_text_and_json() {
+        let samples = |values: &[f64]| BenchSamples {
+            ns_iter_summ: stats::Summary::new(values),
+            mb_s: 0,
+            samples: Vec::new(),
+            iterations: 0,
+            saturated: false,
+            metrics: MetricMap::new(),
+        };
+        assert_eq!(
+            fmt_bench_throughput(&samples(&[250.0])),
+            " (4,000,000 iters/sec)"
+        );
+        assert_eq!(fmt_bench_throughput(&samples(&[0.0])), "");
+
+        let desc = TestDesc {
+            name: TestName::StaticTestName("fast"),
+            ignore: false,
+            ignore_message: None,
+            should_panic: ShouldPanic::No,
+            allow_fail: false,
+            test_type: TestType::Unknown,
+            no_timeout_warning: false,
+            is_converted_bench: false,
+            tags: &[],
+        };
+        for &show in &[false, true] {
+            let mut json = JsonFormatter::new(
+                OutputLocation::Raw(Vec::new()),
+                false,
+                false,
+                show,
+            );
+            json.write_result(
+                &desc,
+                &TestResult::TrBench(samples(&[250.0])),
+                None,
+                &TestOutput::default(),
+            )
+            .unwrap();
+            match json.output_location() {
+                OutputLocation::Raw(ref m) => assert_eq!(
+                    String::from_utf8_lossy(m)
+                        .contains(r#""iters_per_second": 4000000"#),
+                    show
+                ),
+                OutputLocation::Pretty(_) => unreachable!(),
+            }
+        }
+    }
+
+    #[test]
+    pub fn benchmarks_can_use_their_own_clock() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // Every reading is 7 ticks after the last, however long it took.
+        struct Ticker(AtomicUsize);
+        impl Clock for Ticker {
+            fn now(&self) -> u64 {
+                self.0.fetch_add(7, Ordering::SeqCst) as u64
+            }
+        }
+
+        let (tx, rx) = channel();
+        let desc = TestDesc {
+            name: TestName::StaticTestName("ticking"),
+            ignore: false,
+            ignore_message: None,
+            should_panic: ShouldPanic::No,
+            allow_fail: false,
+            test_type: TestType::Unknown,
+            no_timeout_warning: false,
+            is_converted_bench: false,
+            tags: &[],
+        };
+        crate::bench::benchmark(desc, &tx, true, 5, |b: &mut Bencher| {
+            b.set_clock(Ticker(AtomicUsize::new(0)));
+            b.iter_for(Duration::from_millis(30), || {})
+        });
+        let bs = match rx.recv().unwrap().1 {
+            TestResult::TrBench(bs) => bs,
+            _ => panic!("expected a benchmark result"),
+        };
+        // Every sample is the same, so measuring stops after the first ones.
+        assert_eq!(bs.iterations(), 1_000_000 / 7);
+        let per_iter = 7.0 / bs.iterations() as f64;
+        assert!(bs.samples().iter().all(|&s| s == per_iter));
+        assert_eq!(bs.ns_iter_summ.median, per_iter);
     }
 
     #[test]
@@ -2256,6 +10448,34 @@ mod tests {
         bench::run_once(f);
     }
 
+    #[test]
+    pub fn bench_once_and_auto_return_the_summary() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        fn nothing(_: &mut Bencher) {}
+        fn once(b: &mut Bencher) {
+            b.iter(|| {})
+        }
+        fn auto(b: &mut Bencher) {
+            b.set_clock(Ticker(AtomicUsize::new(0)));
+            b.iter_for(Duration::from_millis(30), || {})
+        }
+
+        // Every reading is 3 ticks after the last.
+        struct Ticker(AtomicUsize);
+        impl Clock for Ticker {
+            fn now(&self) -> u64 {
+                self.0.fetch_add(3, Ordering::SeqCst) as u64
+            }
+        }
+
+        assert!(bench::bench_once(nothing).is_none());
+        assert!(bench::bench_auto(nothing).is_none());
+        assert!(bench::bench_once(once).is_some());
+        let summary = bench::bench_auto(auto).unwrap();
+        assert_eq!(summary.median, 3.0 / (1_000_000 / 3) as f64);
+    }
+
     #[test]
     pub fn test_bench_no_iter() {
         fn f(_: &mut Bencher) {}
@@ -2265,11 +10485,16 @@ mod tests {
         let desc = TestDesc {
             name: TestName::StaticTestName("f"),
             ignore: false,
+            ignore_message: None,
             should_panic: ShouldPanic::No,
             allow_fail: false,
+            test_type: TestType::Unknown,
+            no_timeout_warning: false,
+            is_converted_bench: false,
+            tags: &[],
         };
 
-        crate::bench::benchmark(desc, &tx, true, f);
+        crate::bench::benchmark(desc, &tx, true, DEFAULT_BENCH_SAMPLES, f);
         rx.recv().unwrap();
     }
 
@@ -2284,11 +10509,102 @@ mod tests {
         let desc = TestDesc {
             name: TestName::StaticTestName("f"),
             ignore: false,
+            ignore_message: None,
             should_panic: ShouldPanic::No,
             allow_fail: false,
+            test_type: TestType::Unknown,
+            no_timeout_warning: false,
+            is_converted_bench: false,
+            tags: &[],
         };
 
-        crate::bench::benchmark(desc, &tx, true, f);
+        crate::bench::benchmark(desc, &tx, true, DEFAULT_BENCH_SAMPLES, f);
         rx.recv().unwrap();
     }
+
+    #[test]
+    pub fn longer_bench_time_runs_more_loops() {
+        let calls = |time| {
+            let mut calls = 0;
+            let (tx, rx) = channel();
+            let desc = TestDesc {
+                name: TestName::StaticTestName("f"),
+                ignore: false,
+                ignore_message: None,
+                should_panic: ShouldPanic::No,
+                allow_fail: false,
+                test_type: TestType::Unknown,
+                no_timeout_warning: false,
+                is_converted_bench: false,
+                tags: &[],
+            };
+            crate::bench::benchmark(
+                desc,
+                &tx,
+                true,
+                DEFAULT_BENCH_SAMPLES,
+                |b: &mut Bencher| {
+                    b.iter_for(time, || {
+                        calls += 1;
+                        // A slow first call keeps the iteration count, and so
+                        // each round of measurements, small.
+                        if calls == 1 {
+                            thread::sleep(Duration::from_millis(2));
+                        }
+                    })
+                },
+            );
+            rx.recv().unwrap();
+            calls
+        };
+        let short = calls(Duration::from_millis(1));
+        let long = calls(Duration::from_millis(300));
+        assert!(long > short, "{} calls, {} with a longer time", short, long);
+    }
+
+    #[test]
+    pub fn benchmarks_can_record_metrics() {
+        let (tx, rx) = channel();
+        let desc = TestDesc {
+            name: TestName::StaticTestName("allocating"),
+            ignore: false,
+            ignore_message: None,
+            should_panic: ShouldPanic::No,
+            allow_fail: false,
+            test_type: TestType::Unknown,
+            no_timeout_warning: false,
+            is_converted_bench: false,
+            tags: &[],
+        };
+        crate::bench::benchmark(
+            desc,
+            &tx,
+            true,
+            DEFAULT_BENCH_SAMPLES,
+            |b: &mut Bencher| b.record_metric("allocs", 1234.0, 0.0),
+        );
+        let (desc, result, _, output) = rx.recv().unwrap();
+        let bs = match result {
+            TestResult::TrBench(ref bs) => bs,
+            _ => panic!("expected a benchmark result"),
+        };
+        let mut expected = MetricMap::new();
+        expected.insert_metric("allocs", 1234.0, 0.0);
+        assert!(bs.metrics == expected);
+
+        let mut json = JsonFormatter::new(
+            OutputLocation::Raw(Vec::new()),
+            false,
+            false,
+            false,
+        );
+        json.write_result(&desc, &result, None, &output).unwrap();
+        match json.output_location() {
+            OutputLocation::Raw(ref m) => assert!(String::from_utf8_lossy(m)
+                .contains(
+                    r#""metrics": { "allocs": { "value": 1234, "noise": 0 } }"#
+                )),
+            OutputLocation::Pretty(_) => unreachable!(),
+        }
+    }
 }
@@ -0,0 +1,164 @@
+//! Loading tests from shared libraries at run time.
+//!
+//! A plugin is a library exporting a function named `PLUGIN_SYMBOL` with the
+//! `PluginTestsFn` signature:
+//!
+//! ```ignore
+//! #[no_mangle]
+//! pub fn __rust_test_plugin_tests() -> Vec<test::TestDescAndFn> {
+//!     vec![/* ... */]
+//! }
+//! ```
+//!
+//! The host merges the plugin's tests into its own before starting the run:
+//!
+//! ```ignore
+//! let mut tests = my_tests();
+//! tests.extend(unsafe { test::plugin::load_tests(path, "my_plugin")? });
+//! test::test_main(&args, tests, test::Options::new());
+//! ```
+//!
+//! # Soundness
+//!
+//! Nothing about the exported function can be checked when it's looked up,
+//! and Rust has no stable ABI. Loading a plugin is only sound if:
+//!
+//! * the plugin was built by the same compiler, against the same build of
+//!   this crate, as the host. The tests are passed by value, so the layout of
+//!   `Vec<TestDescAndFn>` must agree on both sides;
+//! * the plugin and the host share one copy of the standard library, i.e. the
+//!   plugin is a `dylib` and both are linked with `-C prefer-dynamic`. A
+//!   `cdylib` carries its own copy, so allocations, panics and output capture
+//!   would cross between two unrelated runtimes;
+//! * the exported symbol really has the `PluginTestsFn` signature.
+//!
+//! Libraries are never unloaded, since the tests they return point into them.
+
+use crate::{TestDescAndFn, TestName};
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::path::Path;
+
+/// Name of the function a plugin library must export.
+pub const PLUGIN_SYMBOL: &str = "__rust_test_plugin_tests";
+
+/// Signature of the function a plugin library must export.
+pub type PluginTestsFn = fn() -> Vec<TestDescAndFn>;
+
+/// Loads the library at `path` and returns its tests, with `prefix::`
+/// prepended to each name so they can't collide with the host's own.
+///
+/// # Safety
+///
+/// The library must meet the requirements listed in the module
+/// documentation.
+pub unsafe fn load_tests(
+    path: &Path,
+    prefix: &str,
+) -> io::Result<Vec<TestDescAndFn>> {
+    let symbol = sys::load_symbol(path, &CString::new(PLUGIN_SYMBOL)?)?;
+    let tests_fn: PluginTestsFn = mem::transmute(symbol);
+    Ok(tests_fn()
+        .into_iter()
+        .map(|mut test| {
+            test.desc.name = TestName::DynTestName(format!(
+                "{}::{}",
+                prefix, test.desc.name
+            ));
+            test
+        })
+        .collect())
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::ffi::{CStr, CString};
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    pub unsafe fn load_symbol(
+        path: &Path,
+        symbol: &CStr,
+    ) -> io::Result<*const ()> {
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        let handle = libc::dlopen(path.as_ptr(), libc::RTLD_NOW);
+        if handle.is_null() {
+            return Err(dl_error());
+        }
+        let sym = libc::dlsym(handle, symbol.as_ptr());
+        if sym.is_null() {
+            let err = dl_error();
+            libc::dlclose(handle);
+            return Err(err);
+        }
+        Ok(sym as *const ())
+    }
+
+    unsafe fn dl_error() -> io::Error {
+        let msg = libc::dlerror();
+        let msg = if msg.is_null() {
+            "unknown dynamic loader error".to_owned()
+        } else {
+            CStr::from_ptr(msg).to_string_lossy().into_owned()
+        };
+        io::Error::new(io::ErrorKind::Other, msg)
+    }
+}
+
+#[cfg(windows)]
+#[allow(nonstandard_style)]
+mod sys {
+    use std::ffi::CStr;
+    use std::io;
+    use std::os::raw::c_char;
+    use std::os::windows::ffi::OsStrExt;
+    use std::path::Path;
+
+    type HMODULE = *mut u8;
+    extern "system" {
+        fn LoadLibraryW(lpLibFileName: *const u16) -> HMODULE;
+        fn GetProcAddress(
+            hModule: HMODULE,
+            lpProcName: *const c_char,
+        ) -> *mut u8;
+        fn FreeLibrary(hLibModule: HMODULE) -> i32;
+    }
+
+    pub unsafe fn load_symbol(
+        path: &Path,
+        symbol: &CStr,
+    ) -> io::Result<*const ()> {
+        let path: Vec<u16> =
+            path.as_os_str().encode_wide().chain(Some(0)).collect();
+        let module = LoadLibraryW(path.as_ptr());
+        if module.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let sym = GetProcAddress(module, symbol.as_ptr());
+        if sym.is_null() {
+            let err = io::Error::last_os_error();
+            FreeLibrary(module);
+            return Err(err);
+        }
+        Ok(sym as *const ())
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod sys {
+    use std::ffi::CStr;
+    use std::io;
+    use std::path::Path;
+
+    pub unsafe fn load_symbol(
+        _path: &Path,
+        _symbol: &CStr,
+    ) -> io::Result<*const ()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "loading test plugins is not supported on this platform",
+        ))
+    }
+}
@@ -0,0 +1,374 @@
+//! Running each test in a forked child process, for `--process-isolation`.
+//!
+//! The child runs the test like the thread-based runner does, then writes
+//! the result and the captured output up a pipe and exits. A test that
+//! crashes its process, or leaves global state behind, only takes its own
+//! child with it: the parent reports the crash from the exit status and
+//! whatever the child wrote to `stderr`, which goes up a second pipe. A
+//! child that does send its result has that `stderr` added to the output
+//! it sent.
+//!
+//! Forking only copies the calling thread, so children are forked from the
+//! thread that schedules the tests, one at a time. That way no child
+//! inherits the write end of another test's pipe, which would keep the pipe
+//! open after that test's child died.
+
+use super::{
+    child_exit_result, AllocationStats, MonitorMsg, PanicLocation,
+    PanicMismatch, TestDesc, TestOutput, TestResult,
+};
+use std::fs::File;
+use std::io::{self, prelude::*};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A forked child running one test.
+pub(crate) struct Child {
+    pid: libc::pid_t,
+    pipe: File,
+    /// The read end of the child's `stderr`.
+    stderr: File,
+}
+
+impl Child {
+    /// Forks a child that calls `run` and sends what it returns back to the
+    /// parent. Only returns in the parent.
+    pub(crate) fn fork<F>(run: F) -> io::Result<Self>
+    where
+        F: FnOnce() -> MonitorMsg,
+    {
+        let mut fds = [0; 2];
+        cvt(unsafe { libc::pipe(fds.as_mut_ptr()) })?;
+        let (read_fd, write_fd) = (fds[0], fds[1]);
+        if let Err(e) = cvt(unsafe { libc::pipe(fds.as_mut_ptr()) }) {
+            unsafe {
+                libc::close(read_fd);
+                libc::close(write_fd);
+            }
+            return Err(e);
+        }
+        let (err_read_fd, err_write_fd) = (fds[0], fds[1]);
+
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            unsafe {
+                libc::close(read_fd);
+                libc::close(err_read_fd);
+                libc::dup2(err_write_fd, libc::STDERR_FILENO);
+                libc::close(err_write_fd);
+            }
+            let mut pipe = unsafe { File::from_raw_fd(write_fd) };
+            let (_, result, exec_time, output) = run();
+            let _ = pipe.write_all(&encode(&result, exec_time, &output));
+            let _ = io::stdout().flush();
+            // Skip the destructors and exit handlers of the parent's state.
+            unsafe { libc::_exit(0) };
+        }
+
+        let error = io::Error::last_os_error();
+        unsafe {
+            libc::close(write_fd);
+            libc::close(err_write_fd);
+        }
+        let pipe = unsafe { File::from_raw_fd(read_fd) };
+        let stderr = unsafe { File::from_raw_fd(err_read_fd) };
+        if pid < 0 {
+            return Err(error);
+        }
+        Ok(Self { pid, pipe, stderr })
+    }
+
+    /// Waits for the child to exit and returns the result it sent, or one
+    /// made from its exit status and `stderr` if it died without sending
+    /// it. The `stderr` of a child that did send its result is appended to
+    /// the output it sent, for the runner to report with the result rather
+    /// than written from this thread. A child still running at `deadline` is
+    /// killed.
+    pub(crate) fn wait(
+        mut self,
+        desc: TestDesc,
+        deadline: Option<Instant>,
+    ) -> MonitorMsg {
+        // Drained on a thread of its own so that a child writing a lot to
+        // `stderr` can't block before it sends its result.
+        let mut stderr = self.stderr;
+        let stderr = thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = stderr.read_to_end(&mut buf);
+            buf
+        });
+        let mut sent = Vec::new();
+        let read = read_result(&mut self.pipe, self.pid, &mut sent, deadline);
+
+        let mut status = 0;
+        while unsafe { libc::waitpid(self.pid, &mut status, 0) } < 0 {
+            if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted
+            {
+                let msg = "couldn't wait for the test process".to_owned();
+                let result = TestResult::TrFailedMsg(msg);
+                return (desc, result, None, TestOutput::default());
+            }
+        }
+        let status = ExitStatus::from_raw(status);
+        let stderr = stderr.join().unwrap_or_default();
+
+        match (read, status.success()) {
+            (Ok(_), true) => match decode(&sent) {
+                Some((result, exec_time, mut output)) => {
+                    output.combined.extend_from_slice(&stderr);
+                    output.stderr.extend(stderr);
+                    (desc, result, Some(exec_time), output)
+                }
+                None => {
+                    let msg = "the test process sent a malformed result";
+                    let result = TestResult::TrFailedMsg(msg.to_owned());
+                    (desc, result, None, TestOutput::default())
+                }
+            },
+            _ => {
+                let result = child_exit_result(&desc, status, &stderr);
+                let output = TestOutput {
+                    combined: stderr.clone(),
+                    stderr,
+                    ..TestOutput::default()
+                };
+                (desc, result, None, output)
+            }
+        }
+    }
+}
+
+/// Reads what child `pid` sends up `pipe` until it closes it, killing the
+/// child if it's still running at `deadline`. The child hasn't been waited
+/// for yet, so its pid can't have been reused.
+fn read_result(
+    pipe: &mut File,
+    pid: libc::pid_t,
+    buf: &mut Vec<u8>,
+    deadline: Option<Instant>,
+) -> io::Result<()> {
+    if let Some(deadline) = deadline {
+        let mut chunk = [0; 4096];
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                unsafe { libc::kill(pid, libc::SIGKILL) };
+                break;
+            }
+            // Rounded up, so that the deadline has passed on timeout,
+            // and in steps of at most a second to fit a `c_int`.
+            let ms = ((deadline - now).as_millis() + 1).min(1000);
+            let mut fd = libc::pollfd {
+                fd: pipe.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            if unsafe { libc::poll(&mut fd, 1, ms as libc::c_int) } < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            if fd.revents == 0 {
+                continue;
+            }
+            match pipe.read(&mut chunk) {
+                Ok(0) => return Ok(()),
+                Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    pipe.read_to_end(buf).map(|_| ())
+}
+
+// Each message is a tag byte for the result, the fields of that result, the
+// execution time in nanoseconds, the three output buffers, the allocation
+// counts, if any, as `allocations bytes` and the panic location, if any, as
+// `line column file`. Fields are byte strings prefixed with their length as
+// a little-endian `u64`.
+
+const OK: u8 = 0;
+const FAILED: u8 = 1;
+const FAILED_ERR: u8 = 2;
+const FAILED_MSG: u8 = 3;
+const FAILED_SHOULD_PANIC: u8 = 4;
+const IGNORED_MSG: u8 = 5;
+const ALLOWED_FAIL: u8 = 6;
+
+fn encode(
+    result: &TestResult,
+    exec_time: Option<Duration>,
+    output: &TestOutput,
+) -> Vec<u8> {
+    fn put(buf: &mut Vec<u8>, field: &[u8]) {
+        buf.extend_from_slice(&(field.len() as u64).to_le_bytes());
+        buf.extend_from_slice(field);
+    }
+
+    let mut buf = Vec::new();
+    match *result {
+        TestResult::TrOk => buf.push(OK),
+        TestResult::TrFailed => buf.push(FAILED),
+        TestResult::TrFailedErr => buf.push(FAILED_ERR),
+        TestResult::TrFailedMsg(ref msg) => {
+            buf.push(FAILED_MSG);
+            put(&mut buf, msg.as_bytes());
+        }
+        TestResult::TrFailedShouldPanic(ref mismatch) => {
+            buf.push(FAILED_SHOULD_PANIC);
+            put(&mut buf, mismatch.expected.as_bytes());
+            put(&mut buf, if mismatch.regex { b"1" } else { b"0" });
+            match mismatch.actual {
+                Some(ref actual) => {
+                    put(&mut buf, b"1");
+                    put(&mut buf, actual.as_bytes());
+                }
+                None => put(&mut buf, b"0"),
+            }
+        }
+        TestResult::TrIgnoredMsg(ref msg) => {
+            buf.push(IGNORED_MSG);
+            put(&mut buf, msg.as_bytes());
+        }
+        TestResult::TrAllowedFail => buf.push(ALLOWED_FAIL),
+        // Retries, timeouts, ignoring and benchmarks are all up to the
+        // parent.
+        TestResult::TrOkWithRetries(_)
+        | TestResult::TrTimedOut(_)
+        | TestResult::TrIgnored
+        | TestResult::TrBench(_) => {
+            unreachable!("not a result of running a test in a child")
+        }
+    }
+    let exec_time = super::ns_from_dur(exec_time.unwrap_or_default());
+    put(&mut buf, exec_time.to_string().as_bytes());
+    put(&mut buf, &output.combined);
+    put(&mut buf, &output.stdout);
+    put(&mut buf, &output.stderr);
+    match output.allocations {
+        Some(stats) => {
+            let stats = format!("{} {}", stats.allocations, stats.bytes);
+            put(&mut buf, stats.as_bytes());
+        }
+        None => put(&mut buf, b""),
+    }
+    match output.panic_location {
+        Some(ref l) => {
+            let location = format!("{} {} {}", l.line, l.column, l.file);
+            put(&mut buf, location.as_bytes());
+        }
+        None => put(&mut buf, b""),
+    }
+    buf
+}
+
+fn decode(buf: &[u8]) -> Option<(TestResult, Duration, TestOutput)> {
+    struct Fields<'a>(&'a [u8]);
+
+    impl<'a> Fields<'a> {
+        fn bytes(&mut self) -> Option<&'a [u8]> {
+            if self.0.len() < 8 {
+                return None;
+            }
+            let (len, rest) = self.0.split_at(8);
+            let mut len_bytes = [0; 8];
+            len_bytes.copy_from_slice(len);
+            let len = u64::from_le_bytes(len_bytes) as usize;
+            if rest.len() < len {
+                return None;
+            }
+            let (field, rest) = rest.split_at(len);
+            self.0 = rest;
+            Some(field)
+        }
+
+        fn string(&mut self) -> Option<String> {
+            String::from_utf8(self.bytes()?.to_vec()).ok()
+        }
+
+        fn allocations(&mut self) -> Option<Option<AllocationStats>> {
+            let stats = self.string()?;
+            if stats.is_empty() {
+                return Some(None);
+            }
+            let mut counts = stats.split(' ').map(str::parse);
+            match (counts.next(), counts.next()) {
+                (Some(Ok(allocations)), Some(Ok(bytes))) => {
+                    Some(Some(AllocationStats { allocations, bytes }))
+                }
+                _ => None,
+            }
+        }
+
+        fn panic_location(&mut self) -> Option<Option<PanicLocation>> {
+            let location = self.string()?;
+            if location.is_empty() {
+                return Some(None);
+            }
+            let mut parts = location.splitn(3, ' ');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(line), Some(column), Some(file)) => {
+                    Some(Some(PanicLocation {
+                        file: file.to_owned(),
+                        line: line.parse().ok()?,
+                        column: column.parse().ok()?,
+                    }))
+                }
+                _ => None,
+            }
+        }
+
+        fn nanos(&mut self) -> Option<Duration> {
+            let ns: u64 = self.string()?.parse().ok()?;
+            Some(Duration::from_nanos(ns))
+        }
+    }
+
+    let (&tag, rest) = buf.split_first()?;
+    let mut fields = Fields(rest);
+    let result = match tag {
+        OK => TestResult::TrOk,
+        FAILED => TestResult::TrFailed,
+        FAILED_ERR => TestResult::TrFailedErr,
+        FAILED_MSG => TestResult::TrFailedMsg(fields.string()?),
+        FAILED_SHOULD_PANIC => {
+            let expected = fields.string()?;
+            let regex = fields.bytes()? == b"1";
+            let actual = match fields.bytes()? {
+                b"1" => Some(fields.string()?),
+                _ => None,
+            };
+            TestResult::TrFailedShouldPanic(PanicMismatch {
+                expected,
+                regex,
+                actual,
+            })
+        }
+        IGNORED_MSG => TestResult::TrIgnoredMsg(fields.string()?),
+        ALLOWED_FAIL => TestResult::TrAllowedFail,
+        _ => return None,
+    };
+    let exec_time = fields.nanos()?;
+    let output = TestOutput {
+        combined: fields.bytes()?.to_vec(),
+        stdout: fields.bytes()?.to_vec(),
+        stderr: fields.bytes()?.to_vec(),
+        allocations: fields.allocations()?,
+        panic_location: fields.panic_location()?,
+    };
+    Some((result, exec_time, output))
+}
+
+fn cvt(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}